@@ -0,0 +1,322 @@
+// Typed calls to the `[local_api]` JSON-RPC endpoint of air780e-uart-server, for
+// Rust services on the same host
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// The server answers a poll after at most this long
+const MAX_POLL: Duration = Duration::from_secs(60);
+// Added to the poll timeout for the request and the answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum Error {
+    // The server could not be reached or the answer not read
+    Http(reqwest::Error),
+    // The request was refused, e.g. 401 for a missing or wrong token
+    Status(u16, String),
+    // The method failed, with its JSON-RPC error code
+    Rpc { code: i64, message: String },
+    // The answer is not what this client expects
+    Decode(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "{}", e),
+            Error::Status(status, body) => write!(f, "HTTP {}: {}", status, body),
+            Error::Rpc { code, message } => write!(f, "{} ({})", message, code),
+            Error::Decode(message) => write!(f, "Unexpected answer: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// An SMS to queue, with either `content` or a saved `template` and its variables
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SendSms {
+    pub to: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub variables: HashMap<String, String>,
+    // The `[[devices]]` id, any device when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    // Send even during quiet hours
+    pub urgent: bool,
+}
+
+impl SendSms {
+    pub fn text(to: impl Into<String>, content: impl Into<String>) -> Self {
+        SendSms {
+            to: to.into(),
+            content: Some(content.into()),
+            ..Default::default()
+        }
+    }
+
+    pub fn template(to: impl Into<String>, name: impl Into<String>) -> Self {
+        SendSms {
+            to: to.into(),
+            template: Some(name.into()),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Queued {
+    pub sms_id: i64,
+    pub status: String,
+}
+
+// "pending", "sending", "sent" or "failed" with the error
+#[derive(Debug, Clone, Deserialize)]
+pub struct SmsStatus {
+    pub sms_id: i64,
+    pub status: String,
+    pub error: Option<String>,
+}
+
+// How far a received SMS got: "stored", "notified", "held" or "acked"
+#[derive(Debug, Clone, Deserialize)]
+pub struct MessageStatus {
+    pub id: String,
+    pub status: String,
+    pub device: Option<String>,
+    pub stored_at: i64,
+    pub notified_at: Option<i64>,
+    pub ack_sent_at: Option<i64>,
+    pub spam: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Device {
+    pub device_id: String,
+    // Connection state, e.g. "connected"
+    pub state: Option<String>,
+    pub imei: String,
+    pub number: String,
+    pub iccid: String,
+    pub firmware: Option<String>,
+    pub script_version: Option<String>,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Template {
+    pub name: String,
+    pub body: String,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OptOut {
+    pub number: String,
+    pub source: String,
+    pub created_at: i64,
+}
+
+// A notification, in the shape of the webhook payload plus its number
+#[derive(Debug, Clone, Deserialize)]
+pub struct Event {
+    pub seq: u64,
+    pub schema_version: u32,
+    pub event: String,
+    // The SMS a notification is about
+    pub message_id: Option<String>,
+    pub title: String,
+    pub body: String,
+    pub created_at: i64,
+    pub group: Option<String>,
+    pub ack_url: Option<String>,
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Poll {
+    pub events: Vec<Event>,
+    // The `after` of the following poll
+    pub next: u64,
+}
+
+pub struct Client {
+    url: String,
+    token: Option<String>,
+    http: reqwest::Client,
+    next_id: AtomicU64,
+}
+
+impl Client {
+    // `base_url` is where `[local_api] listen` is reached, e.g. "http://127.0.0.1:8788"
+    pub fn new(base_url: &str) -> Self {
+        Client {
+            url: format!("{}/rpc", base_url.trim_end_matches('/')),
+            token: None,
+            http: reqwest::Client::new(),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    // The `[local_api] token` the server asks for
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    // Queues an SMS, see `sms_status` for how sending went
+    pub async fn send_sms(&self, sms: &SendSms) -> Result<Queued> {
+        self.call("send_sms", json!(sms), REQUEST_TIMEOUT).await
+    }
+
+    pub async fn sms_status(&self, sms_id: i64) -> Result<SmsStatus> {
+        self.call("sms_status", json!({"sms_id": sms_id}), REQUEST_TIMEOUT)
+            .await
+    }
+
+    pub async fn message_status(&self, id: &str) -> Result<MessageStatus> {
+        self.call("message_status", json!({"id": id}), REQUEST_TIMEOUT)
+            .await
+    }
+
+    pub async fn devices(&self) -> Result<Vec<Device>> {
+        #[derive(Deserialize)]
+        struct Devices {
+            devices: Vec<Device>,
+        }
+        let list: Devices = self.call("devices", json!({}), REQUEST_TIMEOUT).await?;
+        Ok(list.devices)
+    }
+
+    pub async fn templates(&self) -> Result<Vec<Template>> {
+        #[derive(Deserialize)]
+        struct Templates {
+            templates: Vec<Template>,
+        }
+        let list: Templates = self.call("templates", json!({}), REQUEST_TIMEOUT).await?;
+        Ok(list.templates)
+    }
+
+    pub async fn opt_outs(&self) -> Result<Vec<OptOut>> {
+        #[derive(Deserialize)]
+        struct OptOuts {
+            opt_outs: Vec<OptOut>,
+        }
+        let list: OptOuts = self.call("opt_outs", json!({}), REQUEST_TIMEOUT).await?;
+        Ok(list.opt_outs)
+    }
+
+    // Whether the number was added, false when it already opted out
+    pub async fn opt_out(&self, number: &str) -> Result<bool> {
+        let result: Value = self
+            .call("opt_out", json!({"number": number}), REQUEST_TIMEOUT)
+            .await?;
+        Ok(result["added"].as_bool().unwrap_or_default())
+    }
+
+    // Whether the number was on the list
+    pub async fn opt_in(&self, number: &str) -> Result<bool> {
+        let result: Value = self
+            .call("opt_in", json!({"number": number}), REQUEST_TIMEOUT)
+            .await?;
+        Ok(result["removed"].as_bool().unwrap_or_default())
+    }
+
+    // Events numbered above `after`, waiting up to `timeout` (at most a minute)
+    // for one
+    pub async fn poll(&self, after: u64, timeout: Duration) -> Result<Poll> {
+        let timeout = timeout.min(MAX_POLL);
+        let params = json!({"after": after, "timeout_secs": timeout.as_secs()});
+        self.call("poll", params, timeout + REQUEST_TIMEOUT).await
+    }
+
+    // Every event from now on, one at a time
+    pub fn events(&self) -> Events<'_> {
+        self.events_after(0)
+    }
+
+    // Every event numbered above `after`, e.g. the last one a previous run saw
+    pub fn events_after(&self, after: u64) -> Events<'_> {
+        Events {
+            client: self,
+            after,
+            pending: VecDeque::new(),
+        }
+    }
+
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Value,
+        timeout: Duration,
+    ) -> Result<T> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut request = self
+            .http
+            .post(&self.url)
+            .timeout(timeout)
+            .json(&json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(Error::Status(status.as_u16(), body));
+        }
+        let mut answer: Value = response.json().await?;
+        if let Some(error) = answer.get("error") {
+            return Err(Error::Rpc {
+                code: error["code"].as_i64().unwrap_or_default(),
+                message: error["message"].as_str().unwrap_or_default().to_string(),
+            });
+        }
+        serde_json::from_value(answer["result"].take()).map_err(|e| Error::Decode(e.to_string()))
+    }
+}
+
+// Polls in a loop and hands out the events one by one
+pub struct Events<'a> {
+    client: &'a Client,
+    after: u64,
+    pending: VecDeque<Event>,
+}
+
+impl Events<'_> {
+    // Waits for the next event, however long that takes
+    pub async fn next(&mut self) -> Result<Event> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+            let poll = self.client.poll(self.after, MAX_POLL).await?;
+            self.after = poll.next;
+            self.pending.extend(poll.events);
+        }
+    }
+
+    // The `after` to resume from without missing an event handed out
+    pub fn position(&self) -> u64 {
+        self.pending
+            .front()
+            .map_or(self.after, |event| event.seq.saturating_sub(1))
+    }
+}
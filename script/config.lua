@@ -5,6 +5,8 @@ return {
     HEART_BEAT_INTERVAL = 1000 * 60 * 1,
     ENABLE_HEART_BEAT = true,
     SMS_FORWARD_ENABLED = true,
+    -- Send log lines of this level and above to the server (nil to disable)
+    DEVICE_LOG_LEVEL = "warn",
 
     -- SMS retry configuration
     SMS_RETRY_INTERVAL_BASE = 5000,   -- 5 seconds initial retry
@@ -12,4 +14,5 @@ return {
     SMS_MAX_RETRY_COUNT = 5,          -- Max 5 retries (~10 min total)
     SMS_QUEUE_CHECK_INTERVAL = 5000,  -- Check queue every 5 seconds
     SMS_MAX_QUEUE_SIZE = 100,         -- Max 100 pending messages
+    SMS_READ_RECEIPT_TIMEOUT = 1000 * 60 * 60 * 24, -- Drop ACKed SMS without read receipt after 1 day
 }
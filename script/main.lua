@@ -1,14 +1,18 @@
 PROJECT = "Air780e_SMS_UART_Sender"
-VERSION = "1.0.0"
+VERSION = "1.7.0"
 
 log.setLevel("DEBUG")
 log.info("main", PROJECT, VERSION)
 
 sys = require("sys")
+-- 先检查脚本更新，新脚本启动失败时回滚到旧版本
+script_updater = require("script_updater")
+script_updater.check_pending()
 config = require("config")
 util = require("util")
 sms_handler = require("sms_handler")
 uart_handler = require("uart_handler")
+network = require("network")
 
 if wdt then
     --添加硬狗防止程序卡死，在支持的设备上启用这个功能
@@ -27,6 +31,11 @@ if uart_setup_res ~= 0 then
     rtos.reboot()
 end
 
+-- 将设备日志转发到服务器
+if config.DEVICE_LOG_LEVEL then
+    util.forward_logs(config.DEVICE_LOG_LEVEL)
+end
+
 -- fskv 初始化
 if fskv.init() then
     local used, total, kv_count = fskv.status()
@@ -48,7 +57,10 @@ sys.taskInit(function()
     -- init UART handler
     uart_handler.init()
     log.info("main", "SMS handler initialized")
+    -- report network registration changes
+    network.init()
     util.uart_send("", "SYSTEM_INIT", { imei = imei, number = number, status = status })
+    script_updater.confirm_later()
     -- sys.wait(60000)
     -- -- EC618配置小区重选信号差值门限，不能大于15dbm，必须在飞行模式下才能用
     -- mobile.flymode(0, true)
@@ -0,0 +1,57 @@
+local util = require("util")
+local network = {}
+
+-- How often the registration is polled for changes
+local CHECK_INTERVAL = 10000
+
+-- Last reported status and operator, to only report changes
+local last_status = nil
+local last_operator = nil
+
+-- MCC and MNC of the serving cell, e.g. "46000"
+local function operator()
+    if not mobile.scell then
+        return nil
+    end
+    local cell = mobile.scell()
+    if type(cell) ~= "table" or not cell.mcc or not cell.mnc or cell.mcc == 0 then
+        return nil
+    end
+    -- The firmware reports the codes as BCD, 0x460 for 460
+    return string.format("%x%02x", cell.mcc, cell.mnc)
+end
+
+function network.current()
+    local status = mobile.status() or 4
+    local registered = status == 1 or status == 5
+    return {
+        status = status,
+        operator = registered and operator() or nil,
+        -- Air780E is an LTE Cat.1 module
+        network = registered and "LTE" or nil,
+        rssi = mobile.rssi()
+    }
+end
+
+function network.report()
+    local current = network.current()
+    last_status = current.status
+    last_operator = current.operator
+    util.uart_send("", "NET_STATUS", current)
+end
+
+local function check()
+    local current = network.current()
+    if current.status ~= last_status or current.operator ~= last_operator then
+        log.info("network", "Registration changed: status=" .. current.status .. ", operator=" ..
+            (current.operator or "N/A"))
+        network.report()
+    end
+end
+
+function network.init()
+    sys.timerLoopStart(check, CHECK_INTERVAL)
+    log.info("network", "Network monitor started")
+end
+
+return network
@@ -0,0 +1,165 @@
+local util = require("util")
+local script_updater = {}
+
+-- Updated scripts are written here, LuatOS loads them in place of the packaged ones
+local SCRIPT_DIR = "/lua/"
+-- The new script is confirmed once the device has run this long after the update
+local CONFIRM_DELAY = 1000 * 60 * 2
+local PENDING_KEY = "script_update_pending"
+local BOOTS_KEY = "script_update_boots"
+
+-- Loaded before the rollback check runs, so a broken copy could not be rolled back
+local PROTECTED = { ["main.lua"] = true, ["script_updater.lua"] = true, ["util.lua"] = true }
+
+-- Transfer in progress: name, size, crc, next expected seq and the temp file
+local transfer = nil
+
+local function reply(op, ok, err, seq)
+    util.uart_send("", "SCRIPT_UPDATE", { op = op, ok = ok, error = err, seq = seq })
+end
+
+local function discard()
+    if transfer then
+        if transfer.file then
+            transfer.file:close()
+        end
+        os.remove(transfer.tmp)
+        transfer = nil
+    end
+end
+
+local function handle_begin(cmd)
+    discard()
+    if type(cmd.name) ~= "string" or not cmd.name:match("^[%w_]+%.lua$") or PROTECTED[cmd.name] then
+        reply("begin", false, "invalid script name")
+        return
+    end
+
+    local tmp = SCRIPT_DIR .. cmd.name .. ".new"
+    local file = io.open(tmp, "wb")
+    if not file then
+        reply("begin", false, "cannot open " .. tmp)
+        return
+    end
+    transfer = { name = cmd.name, size = cmd.size, crc = cmd.crc, seq = 0, tmp = tmp, file = file }
+    log.info("script_updater", "Receiving " .. cmd.name .. ", " .. tostring(cmd.size) .. " bytes")
+    reply("begin", true)
+end
+
+local function handle_chunk(cmd)
+    if not transfer then
+        reply("chunk", false, "no transfer in progress", cmd.seq)
+        return
+    end
+    -- A re-sent chunk whose reply got lost was already written
+    if cmd.seq == transfer.seq - 1 then
+        reply("chunk", true, nil, cmd.seq)
+        return
+    end
+    if cmd.seq ~= transfer.seq then
+        reply("chunk", false, "expected chunk " .. transfer.seq, cmd.seq)
+        return
+    end
+
+    transfer.file:write(string.fromBase64(cmd.data or ""))
+    transfer.seq = transfer.seq + 1
+    reply("chunk", true, nil, cmd.seq)
+end
+
+local function handle_commit()
+    if not transfer then
+        reply("commit", false, "no transfer in progress")
+        return
+    end
+    transfer.file:close()
+    transfer.file = nil
+
+    local data = io.readFile(transfer.tmp) or ""
+    if #data ~= transfer.size or crypto.crc32(data) ~= transfer.crc then
+        log.warn("script_updater", "Checksum mismatch for " .. transfer.name)
+        discard()
+        reply("commit", false, "checksum mismatch")
+        return
+    end
+
+    -- Keep the current script so a failed boot can be rolled back
+    local target = SCRIPT_DIR .. transfer.name
+    os.remove(target .. ".bak")
+    if io.exists(target) then
+        os.rename(target, target .. ".bak")
+    end
+    os.rename(transfer.tmp, target)
+    fskv.set(PENDING_KEY, transfer.name)
+    fskv.set(BOOTS_KEY, 0)
+    log.info("script_updater", transfer.name .. " updated, rebooting")
+    transfer = nil
+
+    reply("commit", true)
+    sys.timerStart(rtos.reboot, 2000)
+end
+
+local function rollback(name)
+    local target = SCRIPT_DIR .. name
+    os.remove(target)
+    if io.exists(target .. ".bak") then
+        os.rename(target .. ".bak", target)
+    end
+    fskv.del(PENDING_KEY)
+    fskv.del(BOOTS_KEY)
+    log.warn("script_updater", "Rolled back " .. name)
+end
+
+-- Called first thing in main.lua: a second boot without confirmation means the
+-- new script keeps crashing the device, so the previous version is restored
+function script_updater.check_pending()
+    if not fskv.init() then
+        return
+    end
+    local name = fskv.get(PENDING_KEY)
+    if not name then
+        return
+    end
+
+    local boots = (fskv.get(BOOTS_KEY) or 0) + 1
+    if boots > 1 then
+        rollback(name)
+        rtos.reboot()
+        return
+    end
+    fskv.set(BOOTS_KEY, boots)
+end
+
+-- Called once the device is fully up, confirms a pending update after it has run for a while
+function script_updater.confirm_later()
+    if not fskv.get(PENDING_KEY) then
+        return
+    end
+    sys.timerStart(function()
+        log.info("script_updater", "Update of " .. tostring(fskv.get(PENDING_KEY)) .. " confirmed")
+        fskv.del(PENDING_KEY)
+        fskv.del(BOOTS_KEY)
+    end, CONFIRM_DELAY)
+end
+
+function script_updater.handle_command(arg)
+    local ok, cmd = pcall(json.decode, string.fromBase64(arg or ""))
+    if not ok or type(cmd) ~= "table" then
+        log.warn("script_updater", "Malformed UPDATE_SCRIPT payload")
+        return
+    end
+
+    if cmd.op == "begin" then
+        handle_begin(cmd)
+    elseif cmd.op == "chunk" then
+        handle_chunk(cmd)
+    elseif cmd.op == "commit" then
+        handle_commit()
+    elseif cmd.op == "abort" then
+        discard()
+        log.info("script_updater", "Update aborted")
+    else
+        log.warn("script_updater", "Unknown UPDATE_SCRIPT op: " .. tostring(cmd.op))
+    end
+end
+
+return script_updater
@@ -3,6 +3,14 @@ local config = require("config")
 
 local sms_handler = {}
 
+-- Set by CMD:READ_RECEIPTS: ACKed SMS are kept until the server confirms with
+-- CMD:SMS_READ that they were notified
+sms_handler.read_receipts = false
+
+-- Set by CMD:ACK_REQUESTS: retries ask for the ACK with ACK_REQ:<sms_id> and
+-- the whole SMS is only sent again when the server answers RESEND:<sms_id>
+sms_handler.ack_requests = false
+
 -- ======================== Queue Index Management ========================
 
 local function add_to_index(sms_id)
@@ -85,9 +93,7 @@ end
 
 -- ======================== ACK Handler ========================
 
-function sms_handler.handle_ack(sms_id)
-    log.info("sms_handler", "Received ACK for: " .. sms_id)
-
+local function remove_sms(sms_id)
     -- Delete from FSKV
     fskv.del("sms_queue:" .. sms_id)
 
@@ -97,8 +103,56 @@ function sms_handler.handle_ack(sms_id)
     log.info("sms_handler", "SMS removed from queue: " .. sms_id)
 end
 
+function sms_handler.handle_ack(sms_id)
+    log.info("sms_handler", "Received ACK for: " .. sms_id)
+
+    if sms_handler.read_receipts then
+        -- Stop retrying but keep the copy until the read receipt
+        local key = "sms_queue:" .. sms_id
+        local data_str = fskv.get(key)
+        if data_str then
+            local queue_item = json.decode(data_str)
+            queue_item.acked_at = os.time()
+            fskv.set(key, json.encode(queue_item))
+        end
+        return
+    end
+
+    remove_sms(sms_id)
+end
+
+-- The server stored and notified the SMS, its local copy is no longer needed
+function sms_handler.handle_read(sms_id)
+    log.info("sms_handler", "Received read receipt for: " .. sms_id)
+    remove_sms(sms_id)
+end
+
 -- ======================== Retry Logic ========================
 
+local function send_queued(queue_item)
+    local payload = {
+        id = queue_item.id,
+        sender = queue_item.sender,
+        content = queue_item.content,
+        received_at = queue_item.received_at,
+        metas = queue_item.metas
+    }
+
+    -- Send with the same UUID as message ID
+    util.uart_send(queue_item.id, "SMS_RECEIVED", payload)
+end
+
+-- The server never stored the SMS, send it again right away
+function sms_handler.handle_resend(sms_id)
+    local data_str = fskv.get("sms_queue:" .. sms_id)
+    if not data_str then
+        log.warn("sms_handler", "Resend requested for unknown SMS: " .. sms_id)
+        return
+    end
+    log.info("sms_handler", "Resending SMS: " .. sms_id)
+    send_queued(json.decode(data_str))
+end
+
 local function calculate_retry_delay(retry_count)
     local base = config.SMS_RETRY_INTERVAL_BASE / 1000 -- Convert to seconds
     local multiplier = config.SMS_RETRY_BACKOFF_MULTIPLIER
@@ -121,8 +175,16 @@ local function retry_pending_sms()
         if data_str then
             local queue_item = json.decode(data_str)
 
+            if queue_item.acked_at then
+                -- Waiting for the read receipt, give up on it eventually so a
+                -- lost receipt doesn't fill the queue
+                if current_time - queue_item.acked_at >= config.SMS_READ_RECEIPT_TIMEOUT / 1000 then
+                    log.warn("sms_handler", "No read receipt for: " .. id .. ", removing")
+                    fskv.del(key)
+                    remove_from_index(id)
+                end
             -- Check if it's time to retry
-            if current_time >= queue_item.next_retry_time then
+            elseif current_time >= queue_item.next_retry_time then
                 if queue_item.retry_count >= config.SMS_MAX_RETRY_COUNT then
                     -- Max retries exceeded, remove
                     log.error("sms_handler", "Max retries exceeded for: " .. id)
@@ -132,16 +194,12 @@ local function retry_pending_sms()
                     -- Retry sending
                     log.info("sms_handler", "Retrying SMS: " .. id .. " attempt: " .. (queue_item.retry_count + 1))
 
-                    local payload = {
-                        id = queue_item.id,
-                        sender = queue_item.sender,
-                        content = queue_item.content,
-                        received_at = queue_item.received_at,
-                        metas = queue_item.metas
-                    }
-
-                    -- Send with the same UUID as message ID
-                    util.uart_send(id, "SMS_RECEIVED", payload)
+                    if sms_handler.ack_requests then
+                        -- Usually only the ACK got lost, so ask for it first
+                        uart.write(uart.VUART_0, "ACK_REQ:" .. id .. "\r\n")
+                    else
+                        send_queued(queue_item)
+                    end
 
                     -- Update retry metadata
                     queue_item.retry_count = queue_item.retry_count + 1
@@ -191,6 +249,66 @@ local function on_sms_received(sender_number, txt, metas)
     end
 end
 
+-- ======================== Outbound SMS ========================
+
+-- ref identifies a message queued by the server and is echoed back in SMS_SENT
+-- The result is sent under the request id `rid` when the server gave one
+function sms_handler.send_sms(to, content, ref, rid)
+    sys.taskInit(function()
+        local result = sms.send(to, content)
+        if result then
+            log.info("sms_handler", "SMS sent to " .. to)
+        else
+            log.warn("sms_handler", "Failed to send SMS to " .. to)
+        end
+        util.uart_send(rid or "", "SMS_SENT", { to = to, success = result == true, ref = ref })
+    end)
+end
+
+-- ======================== Stored SMS Management ========================
+
+-- SMS waiting in the queue for an ACK or read receipt, oldest first
+function sms_handler.list_stored()
+    local messages = {}
+    local index_str = fskv.get("sms_queue_index") or ""
+    for id in string.gmatch(index_str, "[^,]+") do
+        local data_str = fskv.get("sms_queue:" .. id)
+        if data_str then
+            local queue_item = json.decode(data_str)
+            table.insert(messages, {
+                id = id,
+                sender = queue_item.sender,
+                content = queue_item.content,
+                received_at = queue_item.received_at,
+                retry_count = queue_item.retry_count,
+                acked = queue_item.acked_at ~= nil
+            })
+        end
+    end
+    return messages
+end
+
+-- Delete the given SMS from the queue, or all of them when ids is nil
+function sms_handler.delete_stored(ids)
+    if ids == nil then
+        ids = {}
+        for id in string.gmatch(fskv.get("sms_queue_index") or "", "[^,]+") do
+            table.insert(ids, id)
+        end
+    end
+
+    local deleted = 0
+    for _, id in ipairs(ids) do
+        if fskv.get("sms_queue:" .. id) then
+            fskv.del("sms_queue:" .. id)
+            deleted = deleted + 1
+        end
+        remove_from_index(id)
+    end
+    log.info("sms_handler", "Deleted " .. deleted .. " stored SMS")
+    return deleted
+end
+
 -- ======================== Initialization ========================
 
 function sms_handler.init()
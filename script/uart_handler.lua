@@ -1,10 +1,29 @@
 local sms_handler = require("sms_handler")
+local script_updater = require("script_updater")
+local network = require("network")
 local util = require("util")
+local config = require("config")
 local uart_handler = {}
 
+-- Bytes received so far that do not yet form a complete line
+local rx_buffer = ""
+
 -- ======================== Command Handlers ========================
 
-local function handle_get_device_info()
+local function handle_get_device_info(arg)
+    -- The server offers zlib compression with "CMD:GET_DEVICE_INFO:z", or with
+    -- {"compression": true, "rid": ...} when it wants the reply under that id
+    local rid = ""
+    if arg == "z" or arg == nil or arg == "" then
+        util.compression = arg == "z" and miniz ~= nil
+    else
+        local ok, payload = pcall(json.decode, string.fromBase64(arg))
+        if ok and type(payload) == "table" then
+            util.compression = payload.compression == true and miniz ~= nil
+            rid = payload.rid or ""
+        end
+    end
+
     local imei = mobile.imei()
     local number = mobile.number()
     local status = mobile.status()
@@ -17,11 +36,58 @@ local function handle_get_device_info()
         status = status or 0,
         rssi = rssi or 0,
         iccid = iccid or "",
-        timestamp = os.time()
+        timestamp = os.time(),
+        compression = util.compression and "zlib" or nil,
+        firmware = rtos.version(),
+        script_version = VERSION
     }
 
-    util.uart_send("", "DEVICE_INFO", device_info)
+    util.uart_send(rid, "DEVICE_INFO", device_info)
     log.info("uart_handler", "Device info sent: IMEI=" .. (imei or "N/A"))
+    network.report()
+end
+
+local function handle_send_sms(arg)
+    local ok, payload = pcall(json.decode, string.fromBase64(arg or ""))
+    if not ok or type(payload) ~= "table" or not payload.to or not payload.content then
+        log.warn("uart_handler", "Malformed SEND_SMS payload")
+        return
+    end
+    sms_handler.send_sms(payload.to, payload.content, payload.ref, payload.rid)
+end
+
+local function handle_list_stored(arg)
+    -- The server may pass {"rid": ...} to have the reply sent under that id
+    local rid = ""
+    if arg and arg ~= "" then
+        local ok, payload = pcall(json.decode, string.fromBase64(arg))
+        if ok and type(payload) == "table" and payload.rid then
+            rid = payload.rid
+        end
+    end
+    local messages = sms_handler.list_stored()
+    local used, total = fskv.status()
+    util.uart_send(rid, "STORED_SMS", {
+        -- json.encode turns an empty table into an object, leave it out instead
+        messages = #messages > 0 and messages or nil,
+        capacity = config.SMS_MAX_QUEUE_SIZE,
+        fskv_used = used,
+        fskv_total = total
+    })
+end
+
+local function handle_delete_stored(arg)
+    local ok, payload = pcall(json.decode, string.fromBase64(arg or ""))
+    if not ok or type(payload) ~= "table" then
+        log.warn("uart_handler", "Malformed DELETE_STORED payload")
+        return
+    end
+    local ids = nil
+    if not payload.all then
+        ids = payload.ids or {}
+    end
+    local deleted = sms_handler.delete_stored(ids)
+    util.uart_send(payload.rid or "", "STORED_DELETED", { deleted = deleted, remaining = #sms_handler.list_stored() })
 end
 
 -- ======================== Message Handler ========================
@@ -38,12 +104,37 @@ function uart_handler.handle_uart_message(message)
         else
             log.warn("uart_handler", "Malformed ACK message: " .. message)
         end
+        -- Parse resend requests: RESEND:<sms_id>
+    elseif message:match("^RESEND:") then
+        local sms_id = message:match("^RESEND:(.+)")
+        if sms_id then
+            sms_handler.handle_resend(sms_id)
+        end
         -- Parse command messages: CMD:<command>
     elseif message:match("^CMD:") then
-        local command = message:match("^CMD:(.+)")
+        local command, arg = message:match("^CMD:([%w_]+):?(.*)")
         if command == "GET_DEVICE_INFO" then
             log.info("uart_handler", "Received command: GET_DEVICE_INFO")
-            handle_get_device_info()
+            handle_get_device_info(arg)
+        elseif command == "SEND_SMS" then
+            log.info("uart_handler", "Received command: SEND_SMS")
+            handle_send_sms(arg)
+        elseif command == "LIST_STORED" then
+            log.info("uart_handler", "Received command: LIST_STORED")
+            handle_list_stored(arg)
+        elseif command == "DELETE_STORED" then
+            log.info("uart_handler", "Received command: DELETE_STORED")
+            handle_delete_stored(arg)
+        elseif command == "READ_RECEIPTS" then
+            log.info("uart_handler", "Received command: READ_RECEIPTS")
+            sms_handler.read_receipts = true
+        elseif command == "ACK_REQUESTS" then
+            log.info("uart_handler", "Received command: ACK_REQUESTS")
+            sms_handler.ack_requests = true
+        elseif command == "SMS_READ" then
+            sms_handler.handle_read(arg)
+        elseif command == "UPDATE_SCRIPT" then
+            script_updater.handle_command(arg)
         else
             log.warn("uart_handler", "Unknown command: " .. (command or "N/A"))
         end
@@ -57,7 +148,18 @@ function uart_handler.init()
     uart.on(uart.VUART_0, "receive", function(id, len)
         local data = uart.read(id, len)
         if data and data ~= "" then
-            uart_handler.handle_uart_message(data)
+            -- A single read may contain a partial line or several lines
+            rx_buffer = rx_buffer .. data
+            while true do
+                local line, rest = rx_buffer:match("^(.-)\r?\n(.*)$")
+                if not line then
+                    break
+                end
+                rx_buffer = rest
+                if line ~= "" then
+                    uart_handler.handle_uart_message(line)
+                end
+            end
         end
     end)
     log.info("uart_handler", "UART receive handler registered")
@@ -1,5 +1,11 @@
 local random = math.random
 local util = {}
+
+-- Payloads at least this long are zlib-compressed once the server has offered it
+local COMPRESS_MIN_BYTES = 512
+-- Set during the GET_DEVICE_INFO handshake when the server accepts compressed frames
+util.compression = false
+
 function util.uuid()
     local template = 'xxxxxxxx-xxxx-4xxx-yxxx-xxxxxxxxxxxx'
     return string.gsub(template, '[xy]', function(c)
@@ -20,8 +26,46 @@ function util.uart_send(id, dataType, body)
             data = body
         }
     end
-    local rawData = id .. ":" .. dataType .. ":" .. string.toBase64(json.encode(body)) .. "\r\n"
+    local payload = json.encode(body)
+    if util.compression and #payload >= COMPRESS_MIN_BYTES then
+        local compressed = miniz.compress(payload)
+        if compressed and #compressed < #payload then
+            payload = "z:" .. string.toBase64(compressed)
+        else
+            payload = string.toBase64(payload)
+        end
+    else
+        payload = string.toBase64(payload)
+    end
+    local rawData = id .. ":" .. dataType .. ":" .. payload .. "\r\n"
     return uart.write(uart.VUART_0, rawData)
 end
 
+-- Log levels from least to most severe, as used by LuatOS' log module
+local LOG_LEVELS = { "debug", "info", "warn", "error" }
+
+-- Also send log lines of `min_level` and above to the server as DEVICE_LOG frames
+function util.forward_logs(min_level)
+    local forwarding = false
+    for _, level in ipairs(LOG_LEVELS) do
+        forwarding = forwarding or level == min_level
+        if forwarding then
+            local original = log[level]
+            log[level] = function(tag, ...)
+                original(tag, ...)
+                local parts = {}
+                for i = 1, select("#", ...) do
+                    parts[#parts + 1] = tostring(select(i, ...))
+                end
+                util.uart_send("", "DEVICE_LOG", {
+                    level = level,
+                    tag = tostring(tag),
+                    message = table.concat(parts, " "),
+                    timestamp = os.time()
+                })
+            end
+        end
+    end
+end
+
 return util
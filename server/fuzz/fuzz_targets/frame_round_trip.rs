@@ -0,0 +1,30 @@
+// Frames the server encodes are read back unchanged
+#![no_main]
+
+#[path = "../../src/protocol.rs"]
+#[allow(dead_code)]
+mod protocol;
+
+use libfuzzer_sys::fuzz_target;
+use protocol::{Frame, decode_frame, encode_frame};
+
+fuzz_target!(|input: (String, String, String)| {
+    let (id, kind, json) = input;
+    // Ids are letters, digits, "-" and "_", types SCREAMING_SNAKE_CASE, and
+    // an empty payload encodes to nothing
+    let valid_id = !id.is_empty()
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    let valid_kind = !kind.is_empty()
+        && kind
+            .bytes()
+            .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_');
+    if !valid_id || !valid_kind || json.is_empty() {
+        return;
+    }
+
+    let line = encode_frame(&id, &kind, &json);
+    let raw = None;
+    assert_eq!(decode_frame(&line), Some(Frame { id, kind, json, raw }));
+});
@@ -0,0 +1,13 @@
+// Any line the serial port may hand over, parsing must never panic
+#![no_main]
+
+#[path = "../../src/protocol.rs"]
+#[allow(dead_code)]
+mod protocol;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let line = String::from_utf8_lossy(data);
+    let _ = protocol::parse_message(&line);
+});
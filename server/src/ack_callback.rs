@@ -0,0 +1,284 @@
+use crate::audit;
+use crate::config::AckCallbackConfig;
+use crate::database::Database;
+use crate::handover;
+use crate::http::{self, Request};
+use anyhow::Result;
+use ring::hmac;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+// A body is read and ignored, the link carries everything
+const MAX_BODY_BYTES: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const TEXT: &str = "text/plain; charset=utf-8";
+const HTML: &str = "text/html; charset=utf-8";
+
+// Signed links that acknowledge a message, so whoever can open them needs no
+// other credentials
+#[derive(Clone)]
+pub struct AckLinks {
+    public_url: String,
+    key: hmac::Key,
+}
+
+impl AckLinks {
+    pub fn new(config: &AckCallbackConfig) -> Option<Self> {
+        if !config.enabled() {
+            return None;
+        }
+        Some(AckLinks {
+            public_url: config
+                .public_url
+                .as_deref()
+                .unwrap_or_default()
+                .trim_end_matches('/')
+                .to_string(),
+            key: hmac::Key::new(hmac::HMAC_SHA256, config.secret.as_bytes()),
+        })
+    }
+
+    pub fn url(&self, message_id: &str) -> String {
+        let tag = hmac::sign(&self.key, message_id.as_bytes());
+        format!(
+            "{}/messages/{}/ack-human?token={}",
+            self.public_url,
+            urlencoding::encode(message_id),
+            hex(tag.as_ref())
+        )
+    }
+
+    // Shows the whole message. Signed apart from the acknowledgment, so whoever
+    // gets to read a message can't acknowledge it with the same token
+    pub fn view_url(&self, message_id: &str) -> String {
+        let tag = hmac::sign(&self.key, view_input(message_id).as_bytes());
+        format!(
+            "{}/messages/{}?token={}",
+            self.public_url,
+            urlencoding::encode(message_id),
+            hex(tag.as_ref())
+        )
+    }
+
+    fn verify(&self, message_id: &str, token: &str) -> bool {
+        unhex(token).is_some_and(|tag| hmac::verify(&self.key, message_id.as_bytes(), &tag).is_ok())
+    }
+
+    fn verify_view(&self, message_id: &str, token: &str) -> bool {
+        unhex(token).is_some_and(|tag| {
+            hmac::verify(&self.key, view_input(message_id).as_bytes(), &tag).is_ok()
+        })
+    }
+}
+
+fn view_input(message_id: &str) -> String {
+    format!("view:{}", message_id)
+}
+
+// Serves the links: `GET /messages/{id}/ack-human?token=...` asks for confirmation,
+// posting it stops the escalation of the message and records who handled it.
+// `GET /messages/{id}?token=...` shows the message a push only had a preview of
+#[derive(Clone)]
+pub struct AckServer {
+    listen: String,
+    // Set to the user by an authenticating reverse proxy in front of the server
+    user_header: Option<String>,
+    links: AckLinks,
+    db: Database,
+}
+
+impl AckServer {
+    pub fn new(config: &AckCallbackConfig, links: AckLinks, db: Database) -> Self {
+        AckServer {
+            listen: config.listen.clone().unwrap_or_default(),
+            user_header: config
+                .user_header
+                .clone()
+                .filter(|header| !header.trim().is_empty()),
+            links,
+            db,
+        }
+    }
+
+    pub async fn run(self) {
+        let listener = match handover::listen(&self.listen).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!(
+                    "Failed to listen for acknowledgments on {}: {}",
+                    self.listen,
+                    e
+                );
+                return;
+            }
+        };
+        log::info!("Serving acknowledgment links on {}", self.listen);
+
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warn!("Failed to accept an acknowledgment request: {}", e);
+                    continue;
+                }
+            };
+            let server = self.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(REQUEST_TIMEOUT, server.handle(stream, peer)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => log::debug!("Acknowledgment request from {}: {:#}", peer, e),
+                    Err(_) => log::debug!("Acknowledgment request from {} timed out", peer),
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, mut stream: TcpStream, peer: SocketAddr) -> Result<()> {
+        let (status, content_type, body) =
+            match http::read_request(&mut stream, MAX_BODY_BYTES).await? {
+                Some(request) => self.respond(&request, peer),
+                None => ("400 Bad Request", TEXT, "Invalid request".to_string()),
+            };
+        http::write_response(&mut stream, status, content_type, &body).await
+    }
+
+    fn respond(&self, request: &Request, peer: SocketAddr) -> (&'static str, &'static str, String) {
+        if let Some(id) = request
+            .path
+            .strip_prefix("/messages/")
+            .filter(|id| !id.contains('/'))
+            .and_then(|id| urlencoding::decode(id).ok())
+        {
+            let (status, body) = self.view(request, &id, peer);
+            return (status, TEXT, body);
+        }
+        let Some(id) = request
+            .path
+            .strip_prefix("/messages/")
+            .and_then(|rest| rest.strip_suffix("/ack-human"))
+            .and_then(|id| urlencoding::decode(id).ok())
+        else {
+            return ("404 Not Found", TEXT, "Not found".to_string());
+        };
+        if request.method != "GET" && request.method != "POST" {
+            return (
+                "405 Method Not Allowed",
+                TEXT,
+                "Use GET or POST".to_string(),
+            );
+        }
+        let Some(token) = request
+            .param("token")
+            .filter(|token| self.links.verify(&id, token))
+        else {
+            log::warn!("Refused acknowledgment of {} from {}: bad token", id, peer);
+            return ("403 Forbidden", TEXT, "Invalid link".to_string());
+        };
+        // Link previewers and mail scanners open links too, only a person
+        // confirming the form acknowledges
+        if request.method == "GET" {
+            return ("200 OK", HTML, confirmation_page(&id, token));
+        }
+        let (status, body) = self.acknowledge(request, &id, peer);
+        (status, TEXT, body)
+    }
+
+    fn acknowledge(&self, request: &Request, id: &str, peer: SocketAddr) -> (&'static str, String) {
+        let user = self
+            .user_header
+            .as_deref()
+            .and_then(|name| request.header(name))
+            .filter(|user| !user.trim().is_empty())
+            .map(|user| user.trim().to_string())
+            .unwrap_or_else(|| format!("link from {}", peer.ip()));
+        match self.db.mark_handled(id, &user) {
+            Ok(Some(handled_by)) if handled_by != user => (
+                "200 OK",
+                format!("Message {} was already acknowledged by {}", id, handled_by),
+            ),
+            Ok(Some(_)) => {
+                log::info!("Message {} acknowledged by {}", id, user);
+                audit::record(&self.db, &user, "ack", Some(id));
+                ("200 OK", format!("Message {} acknowledged", id))
+            }
+            Ok(None) => ("404 Not Found", format!("No message {}", id)),
+            Err(e) => {
+                log::warn!("{:#}", e);
+                (
+                    "500 Internal Server Error",
+                    "Failed to acknowledge the message".to_string(),
+                )
+            }
+        }
+    }
+
+    fn view(&self, request: &Request, id: &str, peer: SocketAddr) -> (&'static str, String) {
+        if request.method != "GET" {
+            return ("405 Method Not Allowed", "Use GET".to_string());
+        }
+        if !request
+            .param("token")
+            .is_some_and(|token| self.links.verify_view(id, token))
+        {
+            log::warn!("Refused showing {} to {}: bad token", id, peer);
+            return ("403 Forbidden", "Invalid link".to_string());
+        }
+        match self.db.get_sms(id) {
+            Ok(Some(message)) => (
+                "200 OK",
+                format!("{}\n\n{}", message.sender, message.content),
+            ),
+            Ok(None) => ("404 Not Found", format!("No message {}", id)),
+            Err(e) => {
+                log::warn!("{:#}", e);
+                (
+                    "500 Internal Server Error",
+                    "Failed to load the message".to_string(),
+                )
+            }
+        }
+    }
+}
+
+// Posts back to the link itself, the token is hex and needs no escaping
+fn confirmation_page(id: &str, token: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width\">\
+         <title>Acknowledge message</title></head><body>\
+         <p>Acknowledge message {}?</p>\
+         <form method=\"post\" action=\"?token={}\">\
+         <button type=\"submit\">Acknowledge</button></form></body></html>\n",
+        escape_html(id),
+        token
+    )
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,218 @@
+use crate::config::ArchiveConfig;
+use crate::database::{self, ArchivedOutbound, ArchivedSms, Database};
+use crate::timezone::TimeFormatter;
+use anyhow::{Context, Result};
+use flate2::Compression;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// One line of an archive file
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Record {
+    Received(ArchivedSms),
+    Sent(ArchivedOutbound),
+}
+
+impl Record {
+    // Sender of received messages, recipient of sent ones
+    fn number(&self) -> &str {
+        match self {
+            Record::Received(msg) => &msg.sender,
+            Record::Sent(msg) => &msg.recipient,
+        }
+    }
+
+    fn created_at(&self) -> i64 {
+        match self {
+            Record::Received(msg) => msg.created_at,
+            Record::Sent(msg) => msg.created_at,
+        }
+    }
+}
+
+// Moves old messages out of the database into one gzip-compressed JSONL file
+// per month, so the database stays small without losing history
+#[derive(Clone)]
+pub struct Archiver {
+    db: Database,
+    directory: PathBuf,
+    after_secs: i64,
+    interval: Duration,
+    time: TimeFormatter,
+}
+
+impl Archiver {
+    pub fn new(config: &ArchiveConfig, time: TimeFormatter, db: Database) -> Self {
+        Archiver {
+            db,
+            directory: PathBuf::from(config.directory.clone().unwrap_or_default()),
+            after_secs: config.after_days as i64 * 86400,
+            interval: Duration::from_secs(config.interval_hours * 3600),
+            time,
+        }
+    }
+
+    pub async fn run(self) {
+        log::info!(
+            "Archiving messages older than {} days to {}",
+            self.after_secs / 86400,
+            self.directory.display()
+        );
+
+        loop {
+            match self.archive() {
+                Ok(0) => {}
+                Ok(count) => log::info!("Archived {} message(s)", count),
+                Err(e) => log::warn!("Failed to archive messages: {:#}", e),
+            }
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+
+    // Returns how many messages were moved
+    pub fn archive(&self) -> Result<usize> {
+        let before = database::unix_timestamp() - self.after_secs;
+        let received = self.db.archivable_sms(before)?;
+        let sent = self.db.archivable_outbound(before)?;
+        if received.is_empty() && sent.is_empty() {
+            return Ok(0);
+        }
+
+        let sms_ids = received
+            .iter()
+            .map(|msg| msg.id.clone())
+            .collect::<Vec<_>>();
+        let outbound_ids = sent.iter().map(|msg| msg.id).collect::<Vec<_>>();
+        let mut months: BTreeMap<String, Vec<Record>> = BTreeMap::new();
+        for record in received
+            .into_iter()
+            .map(Record::Received)
+            .chain(sent.into_iter().map(Record::Sent))
+        {
+            months
+                .entry(self.time.format(record.created_at(), "%Y-%m"))
+                .or_default()
+                .push(record);
+        }
+
+        std::fs::create_dir_all(&self.directory)
+            .context(format!("Failed to create {}", self.directory.display()))?;
+        for (month, records) in &months {
+            let path = self.directory.join(format!("sms-{}.jsonl.gz", month));
+            append(&path, records)?;
+        }
+
+        // Only deleted once the files are on disk. A crash in between leaves
+        // duplicates in the archive, which a restore skips
+        self.db.delete_archived(&sms_ids, &outbound_ids)?;
+        Ok(sms_ids.len() + outbound_ids.len())
+    }
+}
+
+// Every run adds a gzip member to the month's file, readers decode them as one stream
+fn append(path: &Path, records: &[Record]) -> Result<()> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .context(format!("Failed to open {}", path.display()))?;
+    let mut encoder = GzEncoder::new(file, Compression::default());
+    for record in records {
+        serde_json::to_writer(&mut encoder, record)?;
+        encoder.write_all(b"\n")?;
+    }
+    let file = encoder
+        .finish()
+        .context(format!("Failed to write {}", path.display()))?;
+    file.sync_all()
+        .context(format!("Failed to sync {}", path.display()))
+}
+
+fn read(path: &Path) -> Result<Vec<Record>> {
+    let file = std::fs::File::open(path).context(format!("Failed to open {}", path.display()))?;
+    let mut records = Vec::new();
+    for (number, line) in BufReader::new(MultiGzDecoder::new(file))
+        .lines()
+        .enumerate()
+    {
+        let line = line.context(format!("Failed to read {}", path.display()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(serde_json::from_str(&line).context(format!(
+            "Invalid record on line {} of {}",
+            number + 1,
+            path.display()
+        ))?);
+    }
+    Ok(records)
+}
+
+// Drop the messages from or to numbers `matches` accepts from every archive file
+// in `dir`, rewriting the files that held any. Returns how many were dropped
+pub fn erase(dir: &Path, matches: &dyn Fn(&str) -> bool, dry_run: bool) -> Result<usize> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let mut paths = std::fs::read_dir(dir)
+        .context(format!("Failed to read {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.retain(|path| {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("sms-") && name.ends_with(".jsonl.gz"))
+    });
+    paths.sort();
+
+    let mut erased = 0;
+    for path in paths {
+        let records = read(&path)?;
+        let total = records.len();
+        let kept = records
+            .into_iter()
+            .filter(|record| !matches(record.number()))
+            .collect::<Vec<_>>();
+        if kept.len() == total {
+            continue;
+        }
+        erased += total - kept.len();
+        if dry_run {
+            continue;
+        }
+
+        if kept.is_empty() {
+            std::fs::remove_file(&path).context(format!("Failed to remove {}", path.display()))?;
+            continue;
+        }
+        // Replaced in one rename, so a crash leaves either file complete
+        let tmp = path.with_extension("gz.tmp");
+        let _ = std::fs::remove_file(&tmp);
+        append(&tmp, &kept)?;
+        std::fs::rename(&tmp, &path).context(format!("Failed to replace {}", path.display()))?;
+    }
+    Ok(erased)
+}
+
+// Put the messages of an archive file back into the database, returns
+// (restored, already present)
+pub fn restore(db: &Database, path: &Path) -> Result<(usize, usize)> {
+    let mut received = Vec::new();
+    let mut sent = Vec::new();
+    for record in read(path)? {
+        match record {
+            Record::Received(msg) => received.push(msg),
+            Record::Sent(msg) => sent.push(msg),
+        }
+    }
+
+    let total = received.len() + sent.len();
+    let restored = db.restore_archived(&received, &sent)?;
+    Ok((restored, total - restored))
+}
@@ -0,0 +1,15 @@
+use crate::database::Database;
+
+// There are no user accounts, commands are attributed to the OS user running them
+pub fn cli_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+// The action has already happened, so a failure to record it is only logged
+pub fn record(db: &Database, actor: &str, action: &str, detail: Option<&str>) {
+    if let Err(e) = db.insert_audit(actor, action, detail) {
+        log::warn!("{:#}", e);
+    }
+}
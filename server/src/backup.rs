@@ -0,0 +1,448 @@
+use crate::config::{BackupConfig, S3Config, WebDavConfig};
+use crate::database::{self, Database};
+use crate::sigv4;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use regex::Regex;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// How often the server checks whether a backup is due
+const CHECK_INTERVAL: Duration = Duration::from_secs(600);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+// Database snapshots are named air780e-<UTC time>.db.gz, so they sort by age
+const SNAPSHOT_PREFIX: &str = "air780e-";
+const SNAPSHOT_SUFFIX: &str = ".db.gz";
+
+// Offsite storage the backups are uploaded to
+#[async_trait]
+pub trait Target: Send + Sync {
+    fn name(&self) -> String;
+    async fn put(&self, name: &str, body: Vec<u8>) -> Result<()>;
+    async fn list(&self) -> Result<Vec<String>>;
+    async fn delete(&self, name: &str) -> Result<()>;
+}
+
+pub fn targets(config: &BackupConfig) -> Vec<Box<dyn Target>> {
+    let mut targets: Vec<Box<dyn Target>> = Vec::new();
+    if let Some(s3) = &config.s3 {
+        targets.push(Box::new(S3Target::new(s3)));
+    }
+    if let Some(webdav) = &config.webdav {
+        targets.push(Box::new(WebDavTarget::new(webdav)));
+    }
+    targets
+}
+
+// Uploads a compressed database snapshot and the changed archive files, keeping
+// the newest `keep` snapshots on every target
+pub struct Backup {
+    db: Database,
+    targets: Vec<Box<dyn Target>>,
+    interval_secs: i64,
+    keep: usize,
+    archive_dir: Option<PathBuf>,
+}
+
+impl Backup {
+    pub fn new(config: &BackupConfig, archive_dir: Option<&str>, db: Database) -> Self {
+        Backup {
+            db,
+            targets: targets(config),
+            interval_secs: config.interval_hours as i64 * 3600,
+            keep: config.keep,
+            archive_dir: archive_dir
+                .filter(|_| config.include_archives)
+                .map(PathBuf::from),
+        }
+    }
+
+    pub async fn run(self) {
+        let names = self
+            .targets
+            .iter()
+            .map(|target| target.name())
+            .collect::<Vec<_>>();
+        log::info!("Backups to {} started", names.join(", "));
+
+        loop {
+            let due = match self.db.last_backup() {
+                Ok(last) => {
+                    last.is_none_or(|at| database::unix_timestamp() - at >= self.interval_secs)
+                }
+                Err(e) => {
+                    log::warn!("{:#}", e);
+                    false
+                }
+            };
+            if due && let Err(e) = self.backup().await {
+                log::warn!("Backup failed: {:#}", e);
+            }
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+
+    pub async fn backup(&self) -> Result<()> {
+        let started = database::unix_timestamp();
+        let since = self.db.last_backup()?;
+        let name = format!(
+            "{}{}{}",
+            SNAPSHOT_PREFIX,
+            chrono::Utc::now().format("%Y%m%d-%H%M%S"),
+            SNAPSHOT_SUFFIX
+        );
+        let snapshot = snapshot(self.db.clone()).await?;
+        let archives = match &self.archive_dir {
+            Some(dir) => changed_archives(dir, since)?,
+            None => Vec::new(),
+        };
+
+        let mut failed = Vec::new();
+        for target in &self.targets {
+            if let Err(e) = self
+                .upload(target.as_ref(), &name, &snapshot, &archives)
+                .await
+            {
+                log::warn!("Backup to {} failed: {:#}", target.name(), e);
+                failed.push(target.name());
+            }
+        }
+        if !failed.is_empty() {
+            anyhow::bail!("Upload to {} failed", failed.join(", "));
+        }
+
+        self.db.set_last_backup(started)?;
+        log::info!(
+            "Backup {} uploaded ({} bytes, {} archive file(s))",
+            name,
+            snapshot.len(),
+            archives.len()
+        );
+        Ok(())
+    }
+
+    async fn upload(
+        &self,
+        target: &dyn Target,
+        name: &str,
+        snapshot: &[u8],
+        archives: &[PathBuf],
+    ) -> Result<()> {
+        for path in archives {
+            let file_name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default();
+            let body = std::fs::read(path).context(format!("Failed to read {}", path.display()))?;
+            target.put(file_name, body).await?;
+        }
+        target.put(name, snapshot.to_vec()).await?;
+
+        let mut snapshots = target
+            .list()
+            .await?
+            .into_iter()
+            .filter(|name| name.starts_with(SNAPSHOT_PREFIX) && name.ends_with(SNAPSHOT_SUFFIX))
+            .collect::<Vec<_>>();
+        snapshots.sort();
+        let expired = snapshots.len().saturating_sub(self.keep);
+        for old in &snapshots[..expired] {
+            log::info!("Deleting old backup {} from {}", old, target.name());
+            target.delete(old).await?;
+        }
+        Ok(())
+    }
+}
+
+// VACUUM INTO writes a consistent copy while the server keeps running
+async fn snapshot(db: Database) -> Result<Vec<u8>> {
+    tokio::task::spawn_blocking(move || {
+        let path = std::env::temp_dir().join(format!("air780e-backup-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        db.snapshot(&path)?;
+        let data = std::fs::read(&path).context(format!("Failed to read {}", path.display()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data?)?;
+        encoder.finish().context("Failed to compress the snapshot")
+    })
+    .await
+    .context("Database snapshot panicked")?
+}
+
+// Archive files written since the last backup, all of them on the first one
+fn changed_archives(dir: &Path, since: Option<i64>) -> Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).context(format!("Failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let modified = std::fs::metadata(&path)
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|age| age.as_secs() as i64)
+            .unwrap_or(i64::MAX);
+        if since.is_none_or(|since| modified >= since) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn check_status(response: reqwest::Response, action: &str) -> Result<reqwest::Response> {
+    if !response.status().is_success() {
+        anyhow::bail!("{} returned status {}", action, response.status());
+    }
+    Ok(response)
+}
+
+// S3 or a compatible service (MinIO, Backblaze B2, Cloudflare R2), addressed
+// path-style and signed with AWS Signature Version 4
+pub struct S3Target {
+    endpoint: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    prefix: String,
+    client: reqwest::Client,
+}
+
+impl S3Target {
+    pub fn new(config: &S3Config) -> Self {
+        S3Target {
+            endpoint: config.endpoint.trim_end_matches('/').to_string(),
+            bucket: config.bucket.clone(),
+            region: config.region.clone(),
+            access_key: config.access_key.clone(),
+            secret_key: config.secret_key.clone(),
+            prefix: config.prefix.clone(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    async fn request(
+        &self,
+        method: reqwest::Method,
+        key: Option<&str>,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response> {
+        let mut path = format!("/{}", encode_path(&self.bucket));
+        if let Some(key) = key {
+            path.push('/');
+            path.push_str(&encode_path(&format!("{}{}", self.prefix, key)));
+        }
+        let mut query = query
+            .iter()
+            .map(|(name, value)| {
+                format!(
+                    "{}={}",
+                    urlencoding::encode(name),
+                    urlencoding::encode(value)
+                )
+            })
+            .collect::<Vec<_>>();
+        query.sort();
+        let query = query.join("&");
+        let mut url = format!("{}{}", self.endpoint, path);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
+        let parsed = reqwest::Url::parse(&url).context(format!("Invalid S3 URL {}", url))?;
+        let host = match parsed.port() {
+            Some(port) => format!("{}:{}", parsed.host_str().unwrap_or_default(), port),
+            None => parsed.host_str().unwrap_or_default().to_string(),
+        };
+
+        let amz_date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = sigv4::payload_hash(&body);
+        let authorization = sigv4::authorization(
+            &sigv4::Credentials {
+                access_key: &self.access_key,
+                secret_key: &self.secret_key,
+                region: &self.region,
+            },
+            &sigv4::Request {
+                method: method.as_str(),
+                // The whole path, including a path the endpoint itself has
+                // such as "https://gateway.example.com/s3"
+                uri: parsed.path(),
+                query: &query,
+                host: &host,
+                payload_hash: &payload_hash,
+                amz_date: &amz_date,
+            },
+        );
+
+        self.client
+            .request(method, parsed)
+            .timeout(REQUEST_TIMEOUT)
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("Authorization", authorization)
+            .body(body)
+            .send()
+            .await
+            .context(format!("Failed to reach {}", self.endpoint))
+    }
+}
+
+#[async_trait]
+impl Target for S3Target {
+    fn name(&self) -> String {
+        format!("s3://{}/{}", self.bucket, self.prefix)
+    }
+
+    async fn put(&self, name: &str, body: Vec<u8>) -> Result<()> {
+        let response = self
+            .request(reqwest::Method::PUT, Some(name), &[], body)
+            .await?;
+        check_status(response, &format!("S3 upload of {}", name))?;
+        Ok(())
+    }
+
+    // A listing returns up to 1000 keys, the rest follow page by page
+    async fn list(&self) -> Result<Vec<String>> {
+        let keys = Regex::new(r"<Key>([^<]*)</Key>").unwrap();
+        let next = Regex::new(r"<NextContinuationToken>([^<]*)</NextContinuationToken>").unwrap();
+        let mut names = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", self.prefix.as_str())];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token));
+            }
+            let response = self
+                .request(reqwest::Method::GET, None, &query, Vec::new())
+                .await?;
+            let xml = check_status(response, "S3 listing")?.text().await?;
+            names.extend(keys.captures_iter(&xml).filter_map(|caps| {
+                unescape_xml(&caps[1])
+                    .strip_prefix(&self.prefix)
+                    .map(str::to_string)
+            }));
+            if !xml.contains("<IsTruncated>true</IsTruncated>") {
+                return Ok(names);
+            }
+            let Some(caps) = next.captures(&xml) else {
+                anyhow::bail!("S3 listing is truncated but has no continuation token");
+            };
+            token = Some(unescape_xml(&caps[1]));
+        }
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        let response = self
+            .request(reqwest::Method::DELETE, Some(name), &[], Vec::new())
+            .await?;
+        check_status(response, &format!("S3 deletion of {}", name))?;
+        Ok(())
+    }
+}
+
+// A WebDAV folder, e.g. on Nextcloud. The folder must exist
+pub struct WebDavTarget {
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+    client: reqwest::Client,
+}
+
+impl WebDavTarget {
+    pub fn new(config: &WebDavConfig) -> Self {
+        WebDavTarget {
+            url: format!("{}/", config.url.trim_end_matches('/')),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, url).timeout(REQUEST_TIMEOUT);
+        match &self.username {
+            Some(username) => request.basic_auth(username, self.password.as_ref()),
+            None => request,
+        }
+    }
+}
+
+#[async_trait]
+impl Target for WebDavTarget {
+    fn name(&self) -> String {
+        self.url.clone()
+    }
+
+    async fn put(&self, name: &str, body: Vec<u8>) -> Result<()> {
+        let url = format!("{}{}", self.url, urlencoding::encode(name));
+        let response = self
+            .request(reqwest::Method::PUT, &url)
+            .body(body)
+            .send()
+            .await
+            .context(format!("Failed to reach {}", self.url))?;
+        check_status(response, &format!("WebDAV upload of {}", name))?;
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let response = self
+            .request(reqwest::Method::from_bytes(b"PROPFIND")?, &self.url)
+            .header("Depth", "1")
+            .send()
+            .await
+            .context(format!("Failed to reach {}", self.url))?;
+        let xml = check_status(response, "WebDAV listing")?.text().await?;
+        // Servers differ in the namespace prefix, "d:href", "D:href" or none
+        let re = Regex::new(r"<(?:[A-Za-z0-9]+:)?href>([^<]*)</").unwrap();
+        Ok(re
+            .captures_iter(&xml)
+            .filter_map(|caps| {
+                let href = unescape_xml(&caps[1]);
+                let name = href.rsplit('/').next()?.to_string();
+                urlencoding::decode(&name)
+                    .ok()
+                    .map(|name| name.into_owned())
+                    .filter(|name| !name.is_empty())
+            })
+            .collect())
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        let url = format!("{}{}", self.url, urlencoding::encode(name));
+        let response = self
+            .request(reqwest::Method::DELETE, &url)
+            .send()
+            .await
+            .context(format!("Failed to reach {}", self.url))?;
+        check_status(response, &format!("WebDAV deletion of {}", name))?;
+        Ok(())
+    }
+}
+
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| urlencoding::encode(segment).into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
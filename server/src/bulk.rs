@@ -0,0 +1,72 @@
+use crate::forwarding;
+use crate::templates;
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+// Column of the recipients file holding the phone number, every column
+// (including this one) can be used as a {placeholder} in the template
+const NUMBER_COLUMN: &str = "number";
+
+pub struct Recipient {
+    pub number: String,
+    pub variables: HashMap<String, String>,
+}
+
+// Read a CSV file with a header row, skipping rows without a number and repeated numbers
+pub fn load_recipients(path: &Path) -> Result<Vec<Recipient>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .context(format!(
+            "Failed to open recipients file: {}",
+            path.display()
+        ))?;
+    let headers = reader
+        .headers()
+        .context(format!("Failed to read header of {}", path.display()))?
+        .clone();
+    if !headers.iter().any(|header| header == NUMBER_COLUMN) {
+        anyhow::bail!(
+            "{} has no \"{}\" column, found: {}",
+            path.display(),
+            NUMBER_COLUMN,
+            headers.iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+
+    let mut recipients = Vec::new();
+    let mut seen = HashSet::new();
+    for (index, row) in reader.records().enumerate() {
+        // Header is line 1
+        let line = index + 2;
+        let row = row.context(format!("Invalid row at line {}", line))?;
+        let variables = headers
+            .iter()
+            .zip(row.iter())
+            .map(|(header, value)| (header.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+
+        let number = variables.get(NUMBER_COLUMN).cloned().unwrap_or_default();
+        if forwarding::normalize_number(&number).is_empty() {
+            log::warn!("Line {}: no phone number, skipped", line);
+            continue;
+        }
+        if !seen.insert(forwarding::normalize_number(&number)) {
+            log::warn!(
+                "Line {}: {} is listed more than once, skipped",
+                line,
+                number
+            );
+            continue;
+        }
+        recipients.push(Recipient { number, variables });
+    }
+
+    Ok(recipients)
+}
+
+// Fill {column} placeholders from the recipient's row
+pub fn render(template: &str, recipient: &Recipient) -> Result<String> {
+    templates::fill(template, &recipient.variables)
+}
@@ -0,0 +1,79 @@
+use crate::config::DatabaseConfig;
+use crate::database::{self, Database, FileStats};
+use anyhow::{Context, Result};
+use std::time::Duration;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+// Free space below this is not worth rewriting the file for, whatever its share
+const MIN_FREE_BYTES: i64 = 1024 * 1024;
+
+// Runs `PRAGMA optimize` regularly and VACUUM on a schedule or once enough of the
+// file is free, so long deployments on SD cards don't keep a fragmented file
+#[derive(Clone)]
+pub struct Compactor {
+    db: Database,
+    interval_secs: i64,
+    free_percent: u64,
+}
+
+impl Compactor {
+    pub fn new(config: &DatabaseConfig, db: Database) -> Self {
+        Compactor {
+            db,
+            interval_secs: config.vacuum_interval_hours as i64 * 3600,
+            free_percent: config.vacuum_free_percent,
+        }
+    }
+
+    pub async fn run(self) {
+        log::info!("Database compaction started");
+
+        loop {
+            if let Err(e) = self.check().await {
+                log::warn!("Database compaction failed: {:#}", e);
+            }
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+
+    async fn check(&self) -> Result<()> {
+        self.db.optimize()?;
+
+        let stats = self.db.file_stats()?;
+        let now = database::unix_timestamp();
+        let reason = if self.free_percent > 0
+            && stats.free_bytes() >= MIN_FREE_BYTES
+            && stats.free_pages as u64 * 100 >= stats.page_count as u64 * self.free_percent
+        {
+            format!("{}% of the file is free", stats.free_percent())
+        } else {
+            let Some(last) = self.db.last_vacuum()? else {
+                // Start counting the interval from the first run
+                return self.db.set_last_vacuum(now);
+            };
+            if self.interval_secs == 0 || now - last < self.interval_secs {
+                return Ok(());
+            }
+            "scheduled".to_string()
+        };
+
+        log::info!("Compacting the database ({})", reason);
+        let after = vacuum(self.db.clone()).await?;
+        log::info!(
+            "Database compacted from {} to {} bytes",
+            stats.size_bytes(),
+            after.size_bytes()
+        );
+        Ok(())
+    }
+}
+
+// VACUUM rewrites the whole file, so it runs off the async workers
+pub async fn vacuum(db: Database) -> Result<FileStats> {
+    tokio::task::spawn_blocking(move || {
+        db.vacuum()?;
+        db.file_stats()
+    })
+    .await
+    .context("Database compaction panicked")?
+}
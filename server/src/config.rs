@@ -1,41 +1,1181 @@
+use crate::content_cipher::ContentCipher;
+use crate::error;
+use crate::forwarding;
+use crate::quiet_hours;
+use crate::serial_port;
 use anyhow::{Context, Result};
-use serde::Deserialize;
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Deserializer};
 use std::fs;
+use std::path::{Path, PathBuf};
+
+// Directory next to the main config whose files are merged over it in name order
+const OVERRIDE_DIR: &str = "config.d";
+const CONFIG_EXTENSIONS: &[&str] = &["toml", "yaml", "yml", "json"];
+
+// `baud_rate = "auto"` (or 0) probes common rates during the handshake
+pub const AUTO_BAUD_RATE: u32 = 0;
+
+// Commented default configuration written by `config init`
+const DEFAULT_CONFIG: &str = include_str!("../config.example.toml");
 
 #[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    // IANA time zone name (e.g. "Asia/Shanghai") used to display timestamps,
+    // defaults to the host's local time zone
+    #[serde(default)]
+    pub timezone: Option<String>,
+    // Language of notification texts: "en" or "zh-CN"
+    #[serde(default = "default_locale")]
+    pub locale: String,
+    #[serde(default)]
     pub serial: SerialConfig,
+    #[serde(default)]
     pub database: DatabaseConfig,
+    #[serde(default)]
     pub notification: NotificationConfig,
+    #[serde(default)]
+    pub devices: Vec<DeviceConfig>,
+    #[serde(default)]
+    pub forwarding: ForwardingConfig,
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+    #[serde(default)]
+    pub senders: SendersConfig,
+    #[serde(default)]
+    pub spam: SpamConfig,
+    #[serde(default)]
+    pub escalation: EscalationConfig,
+    #[serde(default)]
+    pub connection_events: ConnectionEventsConfig,
+    #[serde(default)]
+    pub ops_alerts: OpsAlertsConfig,
+    #[serde(default)]
+    pub device_log: DeviceLogConfig,
+    #[serde(default)]
+    pub device_storage: DeviceStorageConfig,
+    #[serde(default)]
+    pub network: NetworkConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub webhook: WebhookConfig,
+    #[serde(default)]
+    pub notifier_plugins: Vec<NotifierPluginConfig>,
+    #[serde(default)]
+    pub wasm_hook: WasmHookConfig,
+    #[serde(default)]
+    pub lua_rules: LuaRulesConfig,
+    #[serde(default)]
+    pub schedules: Vec<ScheduleConfig>,
+    #[serde(default)]
+    pub quiet_hours: QuietHoursConfig,
+    #[serde(default)]
+    pub archive: ArchiveConfig,
+    #[serde(default)]
+    pub backup: BackupConfig,
+    #[serde(default)]
+    pub ack_callback: AckCallbackConfig,
+    #[serde(default)]
+    pub mqtt: MqttConfig,
+    #[serde(default)]
+    pub local_api: LocalApiConfig,
+    #[serde(default)]
+    pub grafana: GrafanaConfig,
+    #[serde(default)]
+    pub otp: OtpConfig,
+    #[serde(default)]
+    pub opt_out: OptOutConfig,
+    #[serde(default)]
+    pub self_test: SelfTestConfig,
+    #[serde(default)]
+    pub usage: UsageConfig,
+    #[serde(default)]
+    pub pricing: PricingConfig,
+    #[serde(default)]
+    pub watchdog: WatchdogConfig,
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+}
+
+fn default_locale() -> String {
+    "en".to_string()
 }
 
 #[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct SerialConfig {
-    pub port_name: String,
+    // One port or "auto", or a list tried in order when the preferred one fails
+    #[serde(rename = "port_name", deserialize_with = "deserialize_port_names")]
+    pub port_names: Vec<String>,
+    #[serde(deserialize_with = "deserialize_baud_rate")]
     pub baud_rate: u32,
     pub timeout_ms: u64,
     pub max_retry_count: u32,
     pub retry_delay_ms: u64,
+    // How long to wait for the DEVICE_INFO reply when probing a port
+    pub handshake_timeout_ms: u64,
+    // Length of a window without any data from the device. After
+    // `probe_after_windows` silent windows in a row GET_DEVICE_INFO is sent, after
+    // `reconnect_after_windows` the port is reopened (0 turns either off)
+    pub silence_window_secs: u64,
+    pub probe_after_windows: u32,
+    pub reconnect_after_windows: u32,
+    // Ask for DEVICE_INFO this often while connected to keep the number, ICCID
+    // and signal current (0 only asks when connecting)
+    pub device_info_refresh_secs: u64,
+    // A device lost after it was connected is reconnected with a delay from
+    // `retry_delay_ms` doubling up to `reconnect_backoff_max_ms`, giving up after
+    // `max_reconnect_attempts` failed attempts (0 retries forever)
+    pub max_reconnect_attempts: u32,
+    pub reconnect_backoff_max_ms: u64,
+    // Frames longer than this are dropped without being buffered
+    pub max_frame_bytes: usize,
+    // Frames beyond this rate are dropped to protect against a runaway device
+    pub max_frames_per_second: u32,
+    // Offer zlib compression of large payloads during the handshake
+    pub compression: bool,
+    // Device scripts older than this are reported, or refused when `refuse_outdated_script` is set
+    pub min_script_version: Option<String>,
+    pub refuse_outdated_script: bool,
+    // Ports ("/dev/ttyACM0") or USB ids ("2341:0043") auto-detection may probe
+    // even though they are in the built-in deny list
+    pub probe_allow: Vec<String>,
+    // With "auto", attach only to the modem reporting this IMEI. Once connected the
+    // reported IMEI and number are checked against these, refused unless
+    // `refuse_wrong_device` is turned off
+    pub expected_imei: Option<String>,
+    pub expected_number: Option<String>,
+    pub refuse_wrong_device: bool,
+}
+
+impl SerialConfig {
+    pub fn auto_detects(&self) -> bool {
+        self.port_names.iter().any(|port| is_auto_port(port))
+    }
+
+    // The ports as shown in logs and notifications
+    pub fn port_label(&self) -> String {
+        self.port_names.join(", ")
+    }
+}
+
+pub fn is_auto_port(port_name: &str) -> bool {
+    port_name.eq_ignore_ascii_case("auto")
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        SerialConfig {
+            port_names: vec!["auto".to_string()],
+            baud_rate: 115200,
+            timeout_ms: 1000,
+            max_retry_count: 30,
+            retry_delay_ms: 10000,
+            handshake_timeout_ms: 1000,
+            silence_window_secs: 30,
+            probe_after_windows: 3,
+            reconnect_after_windows: 6,
+            device_info_refresh_secs: 600,
+            max_reconnect_attempts: 10,
+            reconnect_backoff_max_ms: 300_000,
+            max_frame_bytes: 16 * 1024,
+            max_frames_per_second: 50,
+            compression: true,
+            min_script_version: None,
+            refuse_outdated_script: false,
+            probe_allow: Vec::new(),
+            expected_imei: None,
+            expected_number: None,
+            refuse_wrong_device: true,
+        }
+    }
+}
+
+// Per-device overrides, unset fields fall back to the global [serial] section
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    // Label stored with every message received through this device
+    pub id: String,
+    #[serde(
+        rename = "port_name",
+        default,
+        deserialize_with = "deserialize_optional_port_names"
+    )]
+    pub port_names: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "deserialize_optional_baud_rate")]
+    pub baud_rate: Option<u32>,
+    pub timeout_ms: Option<u64>,
+    pub max_retry_count: Option<u32>,
+    pub retry_delay_ms: Option<u64>,
+    pub handshake_timeout_ms: Option<u64>,
+    pub expected_imei: Option<String>,
+    pub expected_number: Option<String>,
+    // Prepended to notification titles, defaults to "[<id>] "
+    pub notification_prefix: Option<String>,
+}
+
+// Effective settings of one device after applying its overrides
+#[derive(Debug, Clone)]
+pub struct DeviceProfile {
+    pub id: Option<String>,
+    pub serial: SerialConfig,
+    pub notification_prefix: String,
 }
 
 #[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct DatabaseConfig {
     pub path: String,
+    // Compact the file with VACUUM this often (0 disables)
+    pub vacuum_interval_hours: u64,
+    // Also compact once this share of the file is free space, e.g. after large
+    // deletions (0 disables)
+    pub vacuum_free_percent: u64,
+    // Base64 of a 32-byte key encrypting message text in the database
+    pub content_key: String,
+    pub content_key_file: Option<String>,
+    pub content_key_command: Option<String>,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            path: "sms.db".to_string(),
+            vacuum_interval_hours: 24 * 7,
+            vacuum_free_percent: 20,
+            content_key: String::new(),
+            content_key_file: None,
+            content_key_command: None,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    pub fn content_cipher(&self) -> Result<Option<ContentCipher>> {
+        if self.content_key.is_empty() {
+            return Ok(None);
+        }
+        ContentCipher::new(&self.content_key)
+            .map(Some)
+            .context("Invalid database.content_key")
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct NotificationConfig {
     pub bark_server_url: String,
+    // One key, or a list of keys to notify several phones
+    #[serde(deserialize_with = "deserialize_keys")]
     pub bark_device_key: String,
+    // Read the device key from a file or a command's output instead of the config.
+    // Several keys are separated by commas or whitespace
+    pub bark_device_key_file: Option<String>,
+    pub bark_device_key_command: Option<String>,
+    pub enabled: bool,
+    // On startup, re-send notifications that never succeeded for messages
+    // stored within this many hours (0 disables the recovery)
+    pub recovery_max_age_hours: u64,
+    // Skip notifying an SMS identical (sender and content) to one notified within
+    // this many seconds, also across restarts (0 disables)
+    pub dedup_window_secs: u64,
+    // Deliver the notifications of each sender in the order the SMS arrived: a
+    // failed one is retried and holds back the later ones of its sender
+    pub strict_order: bool,
+    // Longest body a Bark push carries in characters (0 for no limit), longer
+    // ones are cut or split as `overflow` says
+    pub max_length: usize,
+    pub overflow: Overflow,
+    // Push only the first this many characters of an SMS, with a link to the
+    // whole message when [ack_callback] serves links (0 pushes everything)
+    pub preview_chars: usize,
+}
+
+// What a notifier does with a body over its `max_length`
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    // Cut it, noting how many characters are missing
+    #[default]
+    Truncate,
+    // Send it as several numbered notifications
+    Split,
+}
+
+impl NotificationConfig {
+    pub fn bark_device_keys(&self) -> Vec<String> {
+        split_keys(&self.bark_device_key)
+    }
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        NotificationConfig {
+            bark_server_url: "https://api.day.app".to_string(),
+            bark_device_key: String::new(),
+            bark_device_key_file: None,
+            bark_device_key_command: None,
+            enabled: true,
+            recovery_max_age_hours: 24,
+            dedup_window_secs: 600,
+            strict_order: false,
+            max_length: 1000,
+            overflow: Overflow::Truncate,
+            preview_chars: 0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardingConfig {
+    // Prepended to forwarded SMS, supports `{sender}` and `{rule}` placeholders
+    pub prefix_template: String,
+}
+
+impl Default for ForwardingConfig {
+    fn default() -> Self {
+        ForwardingConfig {
+            prefix_template: "[Fwd from {sender}] ".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub name: String,
+    // Regex matched against the sender number
+    #[serde(default)]
+    pub sender: Option<String>,
+    // Regex matched against the SMS content
+    #[serde(default)]
+    pub content: Option<String>,
+    // Phone numbers the matching SMS is forwarded to via the modem
+    #[serde(default)]
+    pub forward_to: Vec<String>,
+    // Repeat the notification until it is acknowledged
+    #[serde(default)]
+    pub critical: bool,
+    // Notification group (Bark `group`), supports {sender} and {rule} placeholders
+    #[serde(default)]
+    pub group: Option<String>,
+    // "shortcode", "number" or "alphanumeric"
+    #[serde(default)]
+    pub sender_type: Option<String>,
+    // ISO country codes of the sender, any of them matches
+    #[serde(default)]
+    pub countries: Vec<String>,
+    // Whether the sender is from another country than [senders] home_country
+    #[serde(default)]
+    pub foreign: Option<bool>,
+    // Tag matching messages as spam
+    #[serde(default)]
+    pub spam: bool,
+    // Alphanumeric sender ids such as "AMAZON", matched ignoring case; a trailing
+    // `*` matches ids starting with the rest
+    #[serde(default)]
+    pub sender_ids: Vec<String>,
+    // SMS class reported by the module, 0 for flash messages
+    #[serde(default)]
+    pub message_class: Option<u8>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SendersConfig {
+    // ISO country code of the SIM, e.g. "CN", used for numbers without a country
+    // code and for telling foreign senders apart
+    pub home_country: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpamConfig {
+    pub enabled: bool,
+    // Messages scoring at or above this are tagged as spam and not notified
+    pub threshold: f64,
+    pub patterns: Vec<SpamPatternConfig>,
+    // Added when the sender already has non-spam messages in the database
+    pub known_sender_weight: f64,
+    // Added when the sender was classified as spam within `spam_sender_days`
+    // (0 for ever)
+    pub spam_sender_weight: f64,
+    pub spam_sender_days: u64,
+    // Optional external classifier, receives {"sender", "content"} and returns {"score"}
+    pub api_url: Option<String>,
+    pub api_timeout_ms: u64,
+}
+
+impl Default for SpamConfig {
+    fn default() -> Self {
+        SpamConfig {
+            enabled: false,
+            threshold: 5.0,
+            patterns: Vec::new(),
+            known_sender_weight: -2.0,
+            spam_sender_weight: 3.0,
+            spam_sender_days: 30,
+            api_url: None,
+            api_timeout_ms: 3000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SpamPatternConfig {
+    // Regex matched against the SMS content
+    pub pattern: String,
+    pub weight: f64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct EscalationConfig {
+    pub interval_minutes: u64,
+    // Stop repeating after this many re-sends even if nobody acknowledged
+    pub max_repeats: u32,
+}
+
+impl Default for EscalationConfig {
+    fn default() -> Self {
+        EscalationConfig {
+            interval_minutes: 5,
+            max_repeats: 12,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtpConfig {
+    // Recognize SMS with one-time codes, group them by service and let them expire
     pub enabled: bool,
+    pub expire_minutes: u64,
+}
+
+impl Default for OtpConfig {
+    fn default() -> Self {
+        OtpConfig {
+            enabled: false,
+            expire_minutes: 10,
+        }
+    }
+}
+
+// SMS sent and mobile data used per billing cycle, alerted on near the plan's limits
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsageConfig {
+    // Day of the month a billing cycle starts on, 1 to 28
+    pub cycle_start_day: u32,
+    // SMS included in the plan per cycle, 0 for no limit
+    pub sms_limit: u64,
+    // Mobile data included per cycle in MiB, sent and received together, 0 for no limit
+    pub data_limit_mb: u64,
+    // Alert once per cycle when usage reaches this share of a limit
+    pub alert_percent: u64,
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        UsageConfig {
+            cycle_start_day: 1,
+            sms_limit: 0,
+            data_limit_mb: 0,
+            alert_percent: 80,
+        }
+    }
+}
+
+// What one SMS part costs per destination, to estimate the spend of each cycle
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PricingConfig {
+    // Shown with the amounts, e.g. "CNY"
+    pub currency: String,
+    // Price of destinations no prefix matches
+    pub default_price: f64,
+    pub prefixes: Vec<PrefixPriceConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PrefixPriceConfig {
+    // "+86", "00852" or a short code such as "10086", the longest match wins
+    pub prefix: String,
+    pub price: f64,
+}
+
+// `self-test` texts a number and waits for the SMS to be received
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelfTestConfig {
+    // Where the test SMS goes, the number the device reported when unset
+    pub number: Option<String>,
+    // The test fails when the SMS is not back within this long
+    pub timeout_secs: u64,
+}
+
+impl Default for SelfTestConfig {
+    fn default() -> Self {
+        SelfTestConfig {
+            number: None,
+            timeout_secs: 180,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct OptOutConfig {
+    // Put senders of an SMS saying just one of `keywords` on the opt-out list,
+    // queued SMS to them are not sent
+    pub enabled: bool,
+    pub keywords: Vec<String>,
+    // Take a sender off the list again
+    pub resume_keywords: Vec<String>,
+    // Sent back to confirm, nothing when unset
+    pub reply: Option<String>,
+    pub resume_reply: Option<String>,
+}
+
+impl Default for OptOutConfig {
+    fn default() -> Self {
+        OptOutConfig {
+            enabled: true,
+            keywords: [
+                "STOP",
+                "STOPALL",
+                "UNSUBSCRIBE",
+                "CANCEL",
+                "END",
+                "QUIT",
+                "退订",
+                "TD",
+            ]
+            .map(String::from)
+            .to_vec(),
+            resume_keywords: ["START", "UNSTOP"].map(String::from).to_vec(),
+            reply: None,
+            resume_reply: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchdogConfig {
+    // Restart internal tasks that panic, end or stop making progress
+    pub enabled: bool,
+    // How long a task may be late with its heartbeat before it is restarted
+    pub stall_secs: u64,
+    // Exit once a task needed this many restarts within restart_window_minutes
+    pub max_restarts: u32,
+    pub restart_window_minutes: u64,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            enabled: true,
+            stall_secs: 300,
+            max_restarts: 5,
+            restart_window_minutes: 60,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeConfig {
+    pub flavor: RuntimeFlavor,
+    // Worker threads of the multi-threaded runtime, 0 for one per CPU core
+    pub worker_threads: usize,
+    // Most threads kept for blocking work such as hooks, backups and compaction
+    pub max_blocking_threads: usize,
+    // Stack size of every runtime thread
+    pub thread_stack_kib: usize,
+}
+
+// How tokio runs the server's tasks. On the current thread everything shares
+// the main thread, which saves a worker per CPU core on small boards
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum RuntimeFlavor {
+    #[default]
+    MultiThread,
+    CurrentThread,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            flavor: RuntimeFlavor::MultiThread,
+            worker_threads: 0,
+            max_blocking_threads: 512,
+            thread_stack_kib: 2048,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionEventsConfig {
+    // Notify when the serial connection is lost, restored or fails permanently
+    pub enabled: bool,
+    // Send these alerts to other Bark devices instead of [notification]'s keys
+    #[serde(deserialize_with = "deserialize_optional_keys")]
+    pub bark_device_key: Option<String>,
+    // Alert when reconnecting failed this many times in a row, 0 never
+    pub alert_after_attempts: u32,
+}
+
+impl Default for ConnectionEventsConfig {
+    fn default() -> Self {
+        ConnectionEventsConfig {
+            enabled: false,
+            bark_device_key: None,
+            alert_after_attempts: 3,
+        }
+    }
+}
+
+// Anomalies of the server itself, kept apart from SMS notifications so they can
+// go to the people running it
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpsAlertsConfig {
+    pub enabled: bool,
+    // Bark devices getting the alerts, without them and without webhook_url
+    // they go to [notification]'s keys
+    #[serde(deserialize_with = "deserialize_optional_keys")]
+    pub bark_device_key: Option<String>,
+    // Every alert is POSTed here as JSON once, not through [webhook]'s queue
+    pub webhook_url: Option<String>,
+    // Key of the HMAC-SHA256 X-Signature header of webhook_url, signed like [webhook]
+    pub webhook_secret: Option<String>,
+    // The same alert for the same device at most this often
+    pub min_interval_secs: u64,
+    // Alert when this many frames failed to parse within
+    // parse_failure_window_secs, 0 never
+    pub parse_failures: u32,
+    pub parse_failure_window_secs: u64,
+}
+
+impl Default for OpsAlertsConfig {
+    fn default() -> Self {
+        OpsAlertsConfig {
+            enabled: false,
+            bark_device_key: None,
+            webhook_url: None,
+            webhook_secret: None,
+            min_interval_secs: 600,
+            parse_failures: 10,
+            parse_failure_window_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceLogConfig {
+    // Write DEVICE_LOG frames to `path`, rotating to path.1 .. path.<max_files>
+    pub enabled: bool,
+    pub path: String,
+    pub max_bytes: u64,
+    pub max_files: u32,
+}
+
+impl Default for DeviceLogConfig {
+    fn default() -> Self {
+        DeviceLogConfig {
+            enabled: true,
+            path: "device.log".to_string(),
+            max_bytes: 1024 * 1024,
+            max_files: 3,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceStorageConfig {
+    // Ask the device for the SMS waiting in its queue this often (0 disables)
+    pub check_interval_minutes: u64,
+    // Delete queued SMS that are already in the database, their ACK was lost
+    pub delete_processed: bool,
+    // Send a connection event alert once the queue is this full, the device
+    // stops keeping new SMS when it is full
+    pub alert_percent: usize,
+    // Have the device keep ACKed SMS until they were notified, and tell it with a
+    // read receipt when it can delete them
+    pub read_receipts: bool,
+}
+
+impl Default for DeviceStorageConfig {
+    fn default() -> Self {
+        DeviceStorageConfig {
+            check_interval_minutes: 60,
+            delete_processed: true,
+            alert_percent: 80,
+            read_receipts: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    // Send a connection event alert when the module has been without network
+    // registration this long (0 disables)
+    pub lost_alert_minutes: u64,
+    // Alert when the module starts roaming, or attaches to another operator
+    pub notify_roaming: bool,
+    pub notify_operator_change: bool,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            lost_alert_minutes: 5,
+            notify_roaming: true,
+            notify_operator_change: false,
+        }
+    }
+}
+
+// Prometheus metrics without an open port: written for node_exporter's textfile
+// collector and/or pushed to a Pushgateway
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    // e.g. "/var/lib/node_exporter/textfile_collector/air780e.prom"
+    pub textfile_path: Option<String>,
+    // e.g. "http://localhost:9091"
+    pub pushgateway_url: Option<String>,
+    // Pushgateway job name
+    pub job: String,
+    pub interval_secs: u64,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            textfile_path: None,
+            pushgateway_url: None,
+            job: "air780e_sms".to_string(),
+            interval_secs: 15,
+        }
+    }
+}
+
+impl MetricsConfig {
+    pub fn enabled(&self) -> bool {
+        self.textfile_path.is_some() || self.pushgateway_url.is_some()
+    }
+}
+
+// Every notification and connection event POSTed as JSON, retried until delivered
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: Option<String>,
+    // Key of the HMAC-SHA256 X-Signature header over the X-Signature-Timestamp and the
+    // body, deliveries are unsigned without it
+    pub secret: Option<String>,
+    // Deliveries still failing after this many attempts are moved to the dead letters
+    pub max_attempts: u32,
+    // Delay before the first retry, doubled after every failed attempt
+    pub retry_base_secs: u64,
+    // Longest body delivered in characters (0 for no limit), see [notification]
+    pub max_length: usize,
+    pub overflow: Overflow,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            url: None,
+            secret: None,
+            max_attempts: 8,
+            retry_base_secs: 10,
+            max_length: 0,
+            overflow: Overflow::Truncate,
+        }
+    }
+}
+
+impl WebhookConfig {
+    pub fn enabled(&self) -> bool {
+        self.url.is_some()
+    }
+}
+
+// An external program notified like a built-in notifier: it gets the
+// notification as JSON on stdin and answers with a JSON result on stdout
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct NotifierPluginConfig {
+    pub name: String,
+    pub path: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    // The plugin is killed and the notification failed after this long
+    #[serde(default = "default_plugin_timeout_secs")]
+    pub timeout_secs: u64,
+    // Longest body passed in characters (0 for no limit), e.g. 4096 for Telegram
+    #[serde(default)]
+    pub max_length: usize,
+    #[serde(default)]
+    pub overflow: Overflow,
+}
+
+fn default_plugin_timeout_secs() -> u64 {
+    10
+}
+
+// A WebAssembly module deciding what happens to every received SMS
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct WasmHookConfig {
+    // .wasm or .wat file
+    pub path: Option<String>,
+    // Instructions budget of one call
+    pub fuel: u64,
+    // Wall-clock limit of one call
+    pub timeout_ms: u64,
+}
+
+impl Default for WasmHookConfig {
+    fn default() -> Self {
+        WasmHookConfig {
+            path: None,
+            fuel: 10_000_000,
+            timeout_ms: 100,
+        }
+    }
+}
+
+// Old messages moved from the database to compressed monthly files
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchiveConfig {
+    // Directory of the archive files, archiving is off without it
+    pub directory: Option<String>,
+    // Messages stored longer than this are archived
+    pub after_days: u64,
+    pub interval_hours: u64,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        ArchiveConfig {
+            directory: None,
+            after_days: 365,
+            interval_hours: 24,
+        }
+    }
+}
+
+impl ArchiveConfig {
+    pub fn enabled(&self) -> bool {
+        self.directory.is_some()
+    }
+}
+
+// Links in notifications that acknowledge the message when opened
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AckCallbackConfig {
+    // Address the links are served on, off when unset, e.g. "127.0.0.1:8787"
+    pub listen: Option<String>,
+    // How phones reach it, e.g. "https://sms.example.com" behind a reverse proxy
+    pub public_url: Option<String>,
+    // Header the authenticating reverse proxy sets to the user, e.g. "Remote-User".
+    // Without it acknowledgments are recorded with the client address
+    pub user_header: Option<String>,
+    // Signs the links
+    pub secret: String,
+    pub secret_file: Option<String>,
+    pub secret_command: Option<String>,
+}
+
+impl AckCallbackConfig {
+    pub fn enabled(&self) -> bool {
+        self.listen.is_some()
+    }
+}
+
+// Commands taken from an MQTT broker, e.g. for Home Assistant or Node-RED
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    // Broker host name, off when unset
+    pub host: Option<String>,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: String,
+    pub password_file: Option<String>,
+    pub password_command: Option<String>,
+    // Connect over TLS, checking the broker against the system's certificates
+    // or those in `ca_file` (PEM)
+    pub tls: bool,
+    pub ca_file: Option<String>,
+    // Commands arrive on `{topic_prefix}/{device}/cmd/{command}`, results are
+    // published on `{topic_prefix}/{device}/reply/{command}`
+    pub topic_prefix: String,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            host: None,
+            port: 1883,
+            client_id: "air780e-sms-server".to_string(),
+            username: None,
+            password: String::new(),
+            password_file: None,
+            password_command: None,
+            tls: false,
+            ca_file: None,
+            topic_prefix: "air780e".to_string(),
+        }
+    }
+}
+
+impl MqttConfig {
+    pub fn enabled(&self) -> bool {
+        self.host.is_some()
+    }
+}
+
+// JSON-RPC endpoint for tools on the same host, e.g. Node-RED
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalApiConfig {
+    // Loopback address to listen on, off when unset, e.g. "127.0.0.1:8788"
+    pub listen: Option<String>,
+    // Clients must send `Authorization: Bearer <token>` when set
+    pub token: String,
+    pub token_file: Option<String>,
+    pub token_command: Option<String>,
+}
+
+impl LocalApiConfig {
+    pub fn enabled(&self) -> bool {
+        self.listen.is_some()
+    }
+}
+
+// Device events posted as Grafana annotations
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrafanaConfig {
+    // Grafana base URL, off when unset, e.g. "http://127.0.0.1:3000"
+    pub url: Option<String>,
+    // Service account token allowed to write annotations
+    pub api_token: String,
+    pub api_token_file: Option<String>,
+    pub api_token_command: Option<String>,
+    // Show the annotations on one dashboard (and panel), all dashboards of the
+    // organization filtering by tag see them when unset
+    pub dashboard_uid: Option<String>,
+    pub panel_id: Option<u64>,
+    // Added to the tags "air780e", the device and the event
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    // Hours between backups
+    pub interval_hours: u64,
+    // Database snapshots kept on each target, older ones are deleted
+    pub keep: usize,
+    // Also upload the archive files written since the last backup
+    pub include_archives: bool,
+    pub s3: Option<S3Config>,
+    pub webdav: Option<WebDavConfig>,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        BackupConfig {
+            interval_hours: 24,
+            keep: 7,
+            include_archives: true,
+            s3: None,
+            webdav: None,
+        }
+    }
+}
+
+impl BackupConfig {
+    pub fn enabled(&self) -> bool {
+        self.s3.is_some() || self.webdav.is_some()
+    }
+}
+
+// An S3-compatible bucket (AWS, MinIO, Backblaze B2, Cloudflare R2)
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct S3Config {
+    // e.g. "https://s3.eu-central-1.amazonaws.com"
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    // Prepended to every object name, e.g. "air780e/"
+    #[serde(default)]
+    pub prefix: String,
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+// A WebDAV folder, e.g. "https://cloud.example.com/remote.php/dav/files/me/backups"
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WebDavConfig {
+    pub url: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+// An SMS sent on a cron schedule, e.g. to keep a prepaid SIM active
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    pub name: String,
+    // Standard 5-field cron expression in the configured time zone, e.g. "0 10 1 * *"
+    pub cron: String,
+    pub to: String,
+    // Supports {name}, {date}, {time} and {month} placeholders
+    pub body: String,
+    // Device sending it, any device when unset
+    #[serde(default)]
+    pub device: Option<String>,
+    // Send it even during [quiet_hours]
+    #[serde(default)]
+    pub urgent: bool,
+}
+
+// Local hours in which queued SMS wait, so automated messages don't wake anyone up.
+// SMS queued as urgent and forwarded SMS are sent anyway
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuietHoursConfig {
+    // "HH:MM-HH:MM" in the configured time zone, may wrap midnight, e.g.
+    // "22:00-08:00". Off when unset
+    pub window: Option<String>,
+    // Recipients with a window of their own
+    pub recipients: Vec<QuietRecipientConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct QuietRecipientConfig {
+    pub numbers: Vec<String>,
+    // "" lets them be texted at any time
+    pub window: String,
+}
+
+// A rules.lua script whose process(sms) function decides what happens to every received SMS
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LuaRulesConfig {
+    pub path: Option<String>,
+    // Wall-clock limit of one process() call
+    pub timeout_ms: u64,
+}
+
+impl Default for LuaRulesConfig {
+    fn default() -> Self {
+        LuaRulesConfig {
+            path: None,
+            timeout_ms: 100,
+        }
+    }
 }
 
 impl Config {
+    // All failures are reported as configuration errors
     pub fn load(path: &str) -> Result<Self> {
+        Self::read(path).map_err(error::config_error)
+    }
+
+    fn read(path: &str) -> Result<Self> {
         let content =
             fs::read_to_string(path).context(format!("Failed to read config file: {}", path))?;
 
-        let config: Config = toml::from_str(&content).context("Failed to parse config file")?;
+        let overrides = override_files(path)?;
+        let mut config = if overrides.is_empty() {
+            Self::parse(path, &content)
+        } else {
+            Self::parse_layered(path, &content, &overrides)
+        }
+        .context("Failed to parse config file")?;
+
+        // Load secrets kept outside the config file
+        let notification = &mut config.notification;
+        resolve_secret(
+            "bark_device_key",
+            &mut notification.bark_device_key,
+            notification.bark_device_key_file.as_deref(),
+            notification.bark_device_key_command.as_deref(),
+        )?;
+
+        let database = &mut config.database;
+        resolve_secret(
+            "content_key",
+            &mut database.content_key,
+            database.content_key_file.as_deref(),
+            database.content_key_command.as_deref(),
+        )?;
+
+        let ack_callback = &mut config.ack_callback;
+        resolve_secret(
+            "secret",
+            &mut ack_callback.secret,
+            ack_callback.secret_file.as_deref(),
+            ack_callback.secret_command.as_deref(),
+        )?;
+
+        let grafana = &mut config.grafana;
+        resolve_secret(
+            "api_token",
+            &mut grafana.api_token,
+            grafana.api_token_file.as_deref(),
+            grafana.api_token_command.as_deref(),
+        )?;
+
+        let local_api = &mut config.local_api;
+        resolve_secret(
+            "token",
+            &mut local_api.token,
+            local_api.token_file.as_deref(),
+            local_api.token_command.as_deref(),
+        )?;
+
+        let mqtt = &mut config.mqtt;
+        resolve_secret(
+            "password",
+            &mut mqtt.password,
+            mqtt.password_file.as_deref(),
+            mqtt.password_command.as_deref(),
+        )?;
 
         // Validate configuration
         config.validate()?;
@@ -43,24 +1183,117 @@ impl Config {
         Ok(config)
     }
 
-    fn validate(&self) -> Result<()> {
-        // Validate baud rate
-        if self.serial.baud_rate == 0 {
-            anyhow::bail!("Invalid baud_rate: must be greater than 0");
+    fn parse(path: &str, content: &str) -> Result<Self> {
+        deserialize(path, content).map_err(with_hint)
+    }
+
+    // Merge override files over the base config before deserializing it
+    fn parse_layered(path: &str, content: &str, overrides: &[PathBuf]) -> Result<Self> {
+        let mut merged: serde_json::Value =
+            deserialize(path, content).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+
+        for file in overrides {
+            let file_name = file.display().to_string();
+            let content = fs::read_to_string(file)
+                .context(format!("Failed to read override file: {}", file_name))?;
+            let layer = deserialize(&file_name, &content)
+                .map_err(|e| anyhow::anyhow!("{}: {}", file_name, e))?;
+            merge_values(&mut merged, layer);
+            log::info!("Applied config override: {}", file_name);
         }
 
-        // Validate timeout
-        if self.serial.timeout_ms == 0 {
-            anyhow::bail!("Invalid timeout_ms: must be greater than 0");
+        serde_json::from_value(merged).map_err(|e| with_hint(e.to_string()))
+    }
+
+    // Without [[devices]] a single device is configured by [serial]
+    pub fn device_profiles(&self) -> Vec<DeviceProfile> {
+        if self.devices.is_empty() {
+            return vec![DeviceProfile {
+                id: None,
+                serial: self.serial.clone(),
+                notification_prefix: String::new(),
+            }];
         }
 
-        // Validate retry settings
-        if self.serial.max_retry_count == 0 {
-            anyhow::bail!("Invalid max_retry_count: must be greater than 0");
+        self.devices
+            .iter()
+            .map(|device| {
+                let defaults = &self.serial;
+                DeviceProfile {
+                    id: Some(device.id.clone()),
+                    serial: SerialConfig {
+                        port_names: device
+                            .port_names
+                            .clone()
+                            .unwrap_or_else(|| defaults.port_names.clone()),
+                        baud_rate: device.baud_rate.unwrap_or(defaults.baud_rate),
+                        timeout_ms: device.timeout_ms.unwrap_or(defaults.timeout_ms),
+                        max_retry_count: device.max_retry_count.unwrap_or(defaults.max_retry_count),
+                        retry_delay_ms: device.retry_delay_ms.unwrap_or(defaults.retry_delay_ms),
+                        handshake_timeout_ms: device
+                            .handshake_timeout_ms
+                            .unwrap_or(defaults.handshake_timeout_ms),
+                        expected_imei: device
+                            .expected_imei
+                            .clone()
+                            .or_else(|| defaults.expected_imei.clone()),
+                        expected_number: device
+                            .expected_number
+                            .clone()
+                            .or_else(|| defaults.expected_number.clone()),
+                        ..defaults.clone()
+                    },
+                    notification_prefix: device
+                        .notification_prefix
+                        .clone()
+                        .unwrap_or_else(|| format!("[{}] ", device.id)),
+                }
+            })
+            .collect()
+    }
+
+    // Write the commented default configuration, refusing to overwrite unless forced
+    pub fn write_default(path: &str, force: bool) -> Result<()> {
+        if Path::new(path).exists() && !force {
+            anyhow::bail!("{} already exists, use --force to overwrite it", path);
         }
 
-        if self.serial.retry_delay_ms == 0 {
-            anyhow::bail!("Invalid retry_delay_ms: must be greater than 0");
+        fs::write(path, DEFAULT_CONFIG).context(format!("Failed to write config file: {}", path))
+    }
+
+    fn validate(&self) -> Result<()> {
+        // Validate devices
+        let mut seen_ids = std::collections::HashSet::new();
+        let mut seen_ports = std::collections::HashSet::new();
+        let mut seen_imeis = std::collections::HashSet::new();
+        for device in &self.devices {
+            if device.id.is_empty() {
+                anyhow::bail!("Device id cannot be empty");
+            }
+            if !seen_ids.insert(device.id.as_str()) {
+                anyhow::bail!("Duplicate device id: {}", device.id);
+            }
+        }
+        for profile in self.device_profiles() {
+            for port in &profile.serial.port_names {
+                if !is_auto_port(port) && !seen_ports.insert(port.to_lowercase()) {
+                    anyhow::bail!("Port {} is configured for more than one device", port);
+                }
+            }
+            // Several auto-detected devices are told apart by their IMEI
+            if profile.serial.auto_detects() && self.devices.len() > 1 {
+                match &profile.serial.expected_imei {
+                    Some(imei) if !seen_imeis.insert(imei.clone()) => {
+                        anyhow::bail!("IMEI {} is expected by more than one device", imei);
+                    }
+                    Some(_) => {}
+                    None => anyhow::bail!(
+                        "Device {} uses port_name = \"auto\" alongside other devices, set expected_imei",
+                        profile.id.as_deref().unwrap_or_default()
+                    ),
+                }
+            }
+            Self::validate_serial(&profile.serial)?;
         }
 
         // Validate database path
@@ -73,11 +1306,584 @@ impl Config {
             if self.notification.bark_server_url.is_empty() {
                 anyhow::bail!("Bark server URL cannot be empty when notifications are enabled");
             }
-            if self.notification.bark_device_key.is_empty() {
+            if self.notification.bark_device_keys().is_empty() {
                 anyhow::bail!("Bark device key cannot be empty when notifications are enabled");
             }
         }
 
+        // Forwarded SMS are recognized by the template's own text, placeholders alone can't tell
+        let template = &self.forwarding.prefix_template;
+        if !template.is_empty()
+            && template
+                .replace("{sender}", "")
+                .replace("{rule}", "")
+                .trim()
+                .is_empty()
+        {
+            anyhow::bail!(
+                "Invalid forwarding.prefix_template '{}': needs text besides its placeholders",
+                template
+            );
+        }
+
+        // Validate rules
+        for rule in &self.rules {
+            if rule.name.is_empty() {
+                anyhow::bail!("Rule name cannot be empty");
+            }
+            if rule
+                .forward_to
+                .iter()
+                .any(|number| number.trim().is_empty())
+            {
+                anyhow::bail!("Rule '{}' has an empty forward_to number", rule.name);
+            }
+            if let Some(kind) = &rule.sender_type
+                && !["shortcode", "number", "alphanumeric"].contains(&kind.as_str())
+            {
+                anyhow::bail!(
+                    "Rule '{}' has an invalid sender_type '{}': must be shortcode, number or alphanumeric",
+                    rule.name,
+                    kind
+                );
+            }
+            if rule.message_class.is_some_and(|class| class > 3) {
+                anyhow::bail!(
+                    "Rule '{}' has an invalid message_class: must be 0 to 3",
+                    rule.name
+                );
+            }
+            for id in &rule.sender_ids {
+                if !id.chars().any(char::is_alphabetic) {
+                    anyhow::bail!(
+                        "Rule '{}' has an invalid sender id '{}': ids must contain a letter, use sender for numbers",
+                        rule.name,
+                        id
+                    );
+                }
+            }
+            if rule.foreign.is_some() && self.senders.home_country.is_none() {
+                anyhow::bail!(
+                    "Rule '{}' uses foreign, which requires [senders] home_country",
+                    rule.name
+                );
+            }
+        }
+
+        if self.database.vacuum_free_percent > 100 {
+            anyhow::bail!("Invalid database.vacuum_free_percent: must be at most 100");
+        }
+        self.database.content_cipher()?;
+
+        // Validate escalation
+        if self.escalation.interval_minutes == 0 {
+            anyhow::bail!("Invalid escalation.interval_minutes: must be greater than 0");
+        }
+
+        // Validate one-time code detection
+        if self.otp.enabled && self.otp.expire_minutes == 0 {
+            anyhow::bail!("Invalid otp.expire_minutes: must be greater than 0");
+        }
+
+        if self.runtime.max_blocking_threads == 0 {
+            anyhow::bail!("Invalid runtime.max_blocking_threads: must be greater than 0");
+        }
+        if self.runtime.thread_stack_kib < 64 {
+            anyhow::bail!("Invalid runtime.thread_stack_kib: must be at least 64");
+        }
+
+        // Validate watchdog
+        if self.watchdog.enabled && self.watchdog.stall_secs == 0 {
+            anyhow::bail!("Invalid watchdog.stall_secs: must be greater than 0");
+        }
+
+        // Validate spam classifier
+        if self.spam.enabled && self.spam.api_timeout_ms == 0 {
+            anyhow::bail!("Invalid spam.api_timeout_ms: must be greater than 0");
+        }
+
+        // Validate device log
+        if self.device_log.enabled {
+            if self.device_log.path.is_empty() {
+                anyhow::bail!("Invalid device_log.path: cannot be empty");
+            }
+            if self.device_log.max_bytes == 0 {
+                anyhow::bail!("Invalid device_log.max_bytes: must be greater than 0");
+            }
+            if self.device_log.max_files == 0 {
+                anyhow::bail!("Invalid device_log.max_files: must be greater than 0");
+            }
+        }
+
+        // Validate metrics
+        if self.metrics.enabled() {
+            if self.metrics.interval_secs == 0 {
+                anyhow::bail!("Invalid metrics.interval_secs: must be greater than 0");
+            }
+            if self.metrics.job.is_empty() {
+                anyhow::bail!("Invalid metrics.job: cannot be empty");
+            }
+        }
+
+        // Validate webhook
+        if let Some(url) = &self.webhook.url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                anyhow::bail!("Invalid webhook.url: must start with http:// or https://");
+            }
+            if self.webhook.max_attempts == 0 {
+                anyhow::bail!("Invalid webhook.max_attempts: must be greater than 0");
+            }
+            if self.webhook.retry_base_secs == 0 {
+                anyhow::bail!("Invalid webhook.retry_base_secs: must be greater than 0");
+            }
+        }
+
+        // Validate operational alerts
+        if let Some(url) = &self.ops_alerts.webhook_url
+            && !url.starts_with("http://")
+            && !url.starts_with("https://")
+        {
+            anyhow::bail!("Invalid ops_alerts.webhook_url: must start with http:// or https://");
+        }
+        if self.ops_alerts.parse_failures > 0 && self.ops_alerts.parse_failure_window_secs == 0 {
+            anyhow::bail!("Invalid ops_alerts.parse_failure_window_secs: must be greater than 0");
+        }
+
+        if !(1..=28).contains(&self.usage.cycle_start_day) {
+            anyhow::bail!("Invalid usage.cycle_start_day: must be between 1 and 28");
+        }
+        if !(1..=100).contains(&self.usage.alert_percent) {
+            anyhow::bail!("Invalid usage.alert_percent: must be between 1 and 100");
+        }
+        let prices = std::iter::once(self.pricing.default_price)
+            .chain(self.pricing.prefixes.iter().map(|rate| rate.price));
+        for price in prices {
+            if !price.is_finite() || price < 0.0 {
+                anyhow::bail!("Invalid pricing price {}: must not be negative", price);
+            }
+        }
+        for rate in &self.pricing.prefixes {
+            if forwarding::normalize_number(&rate.prefix).is_empty() {
+                anyhow::bail!(
+                    "Invalid pricing prefix '{}': must contain digits",
+                    rate.prefix
+                );
+            }
+        }
+        if self.self_test.timeout_secs == 0 {
+            anyhow::bail!("Invalid self_test.timeout_secs: must be greater than 0");
+        }
+
+        // Validate notifier plugins
+        let mut plugin_names = std::collections::HashSet::new();
+        for plugin in &self.notifier_plugins {
+            if plugin.name.is_empty() {
+                anyhow::bail!("Notifier plugin name cannot be empty");
+            }
+            if !plugin_names.insert(plugin.name.as_str()) {
+                anyhow::bail!("Duplicate notifier plugin name: {}", plugin.name);
+            }
+            if plugin.path.is_empty() {
+                anyhow::bail!("Notifier plugin '{}' has an empty path", plugin.name);
+            }
+            if plugin.timeout_secs == 0 {
+                anyhow::bail!(
+                    "Notifier plugin '{}' has an invalid timeout_secs: must be greater than 0",
+                    plugin.name
+                );
+            }
+        }
+
+        // Validate WASM hook
+        if self.wasm_hook.path.is_some() {
+            if self.wasm_hook.fuel == 0 {
+                anyhow::bail!("Invalid wasm_hook.fuel: must be greater than 0");
+            }
+            if self.wasm_hook.timeout_ms == 0 {
+                anyhow::bail!("Invalid wasm_hook.timeout_ms: must be greater than 0");
+            }
+        }
+
+        if self.archive.enabled() {
+            if self.archive.after_days == 0 {
+                anyhow::bail!("Invalid archive.after_days: must be greater than 0");
+            }
+            if self.archive.interval_hours == 0 {
+                anyhow::bail!("Invalid archive.interval_hours: must be greater than 0");
+            }
+        }
+
+        if self.backup.enabled() {
+            if self.backup.interval_hours == 0 {
+                anyhow::bail!("Invalid backup.interval_hours: must be greater than 0");
+            }
+            if self.backup.keep == 0 {
+                anyhow::bail!("Invalid backup.keep: must be greater than 0");
+            }
+        }
+        if let Some(s3) = &self.backup.s3 {
+            if !s3.endpoint.starts_with("http://") && !s3.endpoint.starts_with("https://") {
+                anyhow::bail!("Invalid backup.s3.endpoint: must start with http:// or https://");
+            }
+            if s3.bucket.is_empty() || s3.bucket.contains('/') {
+                anyhow::bail!("Invalid backup.s3.bucket: {}", s3.bucket);
+            }
+        }
+        if let Some(webdav) = &self.backup.webdav
+            && !webdav.url.starts_with("http://")
+            && !webdav.url.starts_with("https://")
+        {
+            anyhow::bail!("Invalid backup.webdav.url: must start with http:// or https://");
+        }
+
+        if let Some(listen) = &self.ack_callback.listen {
+            if listen.parse::<std::net::SocketAddr>().is_err() {
+                anyhow::bail!("Invalid ack_callback.listen: {}", listen);
+            }
+            match &self.ack_callback.public_url {
+                Some(url) if url.starts_with("http://") || url.starts_with("https://") => {}
+                Some(_) => anyhow::bail!(
+                    "Invalid ack_callback.public_url: must start with http:// or https://"
+                ),
+                None => anyhow::bail!("ack_callback.public_url is required with listen"),
+            }
+            if self.ack_callback.secret.len() < 16 {
+                anyhow::bail!("ack_callback.secret must be at least 16 characters");
+            }
+        }
+
+        if self.mqtt.enabled() {
+            let prefix = &self.mqtt.topic_prefix;
+            if prefix.is_empty() || prefix.contains(['+', '#']) || prefix.ends_with('/') {
+                anyhow::bail!("Invalid mqtt.topic_prefix: {}", prefix);
+            }
+            if self.mqtt.client_id.is_empty() {
+                anyhow::bail!("mqtt.client_id cannot be empty");
+            }
+            if self.mqtt.ca_file.is_some() && !self.mqtt.tls {
+                anyhow::bail!("mqtt.ca_file needs mqtt.tls = true");
+            }
+        }
+
+        if let Some(url) = &self.grafana.url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                anyhow::bail!("Invalid grafana.url: must start with http:// or https://");
+            }
+            if self.grafana.api_token.is_empty() {
+                anyhow::bail!("grafana.api_token is required with url");
+            }
+        }
+
+        // Anyone who can reach the endpoint can send SMS unless a token is set
+        if let Some(listen) = &self.local_api.listen {
+            match listen.parse::<std::net::SocketAddr>() {
+                Ok(addr) if addr.ip().is_loopback() => {}
+                Ok(_) => anyhow::bail!(
+                    "local_api.listen must be a loopback address such as 127.0.0.1:8788"
+                ),
+                Err(_) => anyhow::bail!("Invalid local_api.listen: {}", listen),
+            }
+        }
+
+        // Validate schedules, their cron expressions are checked when the scheduler starts
+        let mut schedule_names = std::collections::HashSet::new();
+        for schedule in &self.schedules {
+            if schedule.name.is_empty() {
+                anyhow::bail!("Schedule name cannot be empty");
+            }
+            if !schedule_names.insert(schedule.name.as_str()) {
+                anyhow::bail!("Duplicate schedule name: {}", schedule.name);
+            }
+            if forwarding::normalize_number(&schedule.to).is_empty() {
+                anyhow::bail!("Schedule '{}' has an invalid to number", schedule.name);
+            }
+            if schedule.body.trim().is_empty() {
+                anyhow::bail!("Schedule '{}' has an empty body", schedule.name);
+            }
+            if let Some(device) = &schedule.device
+                && !self.devices.iter().any(|d| &d.id == device)
+            {
+                anyhow::bail!(
+                    "Schedule '{}' uses unknown device {}",
+                    schedule.name,
+                    device
+                );
+            }
+        }
+
+        // Validate quiet hours
+        if let Some(window) = &self.quiet_hours.window {
+            quiet_hours::parse_window(window)
+                .context(format!("Invalid quiet_hours.window: {}", window))?;
+        }
+        for recipient in &self.quiet_hours.recipients {
+            quiet_hours::parse_window(&recipient.window).context(format!(
+                "Invalid quiet_hours window for {}: {}",
+                recipient.numbers.join(", "),
+                recipient.window
+            ))?;
+        }
+
+        // Validate Lua rules
+        if self.lua_rules.path.is_some() && self.lua_rules.timeout_ms == 0 {
+            anyhow::bail!("Invalid lua_rules.timeout_ms: must be greater than 0");
+        }
+
+        // Validate device storage
+        if self.device_storage.alert_percent > 100 {
+            anyhow::bail!("Invalid device_storage.alert_percent: must be at most 100");
+        }
+
         Ok(())
     }
+
+    fn validate_serial(serial: &SerialConfig) -> Result<()> {
+        if serial.port_names.is_empty() || serial.port_names.iter().any(|p| p.is_empty()) {
+            anyhow::bail!("Invalid port_name: give \"auto\", a port or a list of them");
+        }
+
+        // Validate timeout
+        if serial.timeout_ms == 0 {
+            anyhow::bail!("Invalid timeout_ms: must be greater than 0");
+        }
+        if serial.silence_window_secs == 0 {
+            anyhow::bail!("Invalid silence_window_secs: must be greater than 0");
+        }
+
+        if serial.handshake_timeout_ms == 0 {
+            anyhow::bail!("Invalid handshake_timeout_ms: must be greater than 0");
+        }
+
+        // Validate flood protection
+        if serial.max_frame_bytes == 0 {
+            anyhow::bail!("Invalid max_frame_bytes: must be greater than 0");
+        }
+
+        if serial.max_frames_per_second == 0 {
+            anyhow::bail!("Invalid max_frames_per_second: must be greater than 0");
+        }
+
+        if let Some(version) = &serial.min_script_version
+            && serial_port::parse_version(version).is_none()
+        {
+            anyhow::bail!(
+                "Invalid min_script_version '{}': expected a dotted version like \"1.1.0\"",
+                version
+            );
+        }
+
+        // Validate retry settings
+        if serial.max_retry_count == 0 {
+            anyhow::bail!("Invalid max_retry_count: must be greater than 0");
+        }
+
+        if serial.retry_delay_ms == 0 {
+            anyhow::bail!("Invalid retry_delay_ms: must be greater than 0");
+        }
+
+        Ok(())
+    }
+}
+
+// Accepts a number or "auto"
+fn deserialize_baud_rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BaudRate {
+        Rate(u32),
+        Name(String),
+    }
+
+    match BaudRate::deserialize(deserializer)? {
+        BaudRate::Rate(rate) => Ok(rate),
+        BaudRate::Name(name) if name.eq_ignore_ascii_case("auto") => Ok(AUTO_BAUD_RATE),
+        BaudRate::Name(name) => Err(D::Error::custom(format!(
+            "invalid baud_rate \"{}\", expected a number or \"auto\"",
+            name
+        ))),
+    }
+}
+
+// Keys are given as one string or a list, kept as one comma-separated string so
+// they can also come from a secret file or command
+fn deserialize_keys<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Keys {
+        One(String),
+        List(Vec<String>),
+    }
+
+    match Keys::deserialize(deserializer)? {
+        Keys::One(key) => Ok(key),
+        Keys::List(keys) => Ok(keys.join(",")),
+    }
+}
+
+fn deserialize_optional_keys<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    deserialize_keys(deserializer).map(Some)
+}
+
+pub fn split_keys(keys: &str) -> Vec<String> {
+    keys.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|key| !key.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+// Accepts one port name or a list of them
+fn deserialize_port_names<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum PortNames {
+        One(String),
+        List(Vec<String>),
+    }
+
+    match PortNames::deserialize(deserializer)? {
+        PortNames::One(port) => Ok(vec![port]),
+        PortNames::List(ports) => Ok(ports),
+    }
+}
+
+fn deserialize_optional_port_names<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    deserialize_port_names(deserializer).map(Some)
+}
+
+fn deserialize_optional_baud_rate<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u32>, D::Error> {
+    deserialize_baud_rate(deserializer).map(Some)
+}
+
+// Pick the format from the file extension, TOML being the default
+fn deserialize<T: DeserializeOwned>(path: &str, content: &str) -> std::result::Result<T, String> {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    match extension.as_str() {
+        "yaml" | "yml" => serde_yaml::from_str(content).map_err(|e| e.to_string()),
+        "json" => serde_json::from_str(content).map_err(|e| e.to_string()),
+        _ => toml::from_str(content).map_err(|e| e.to_string()),
+    }
+}
+
+fn override_files(path: &str) -> Result<Vec<PathBuf>> {
+    let dir = Path::new(path)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(OVERRIDE_DIR);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut files = fs::read_dir(&dir)
+        .context(format!("Failed to read {}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|file| {
+            file.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| CONFIG_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+
+    Ok(files)
+}
+
+// Tables are merged key by key, any other value replaces the base value
+fn merge_values(base: &mut serde_json::Value, layer: serde_json::Value) {
+    match (base, layer) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(layer)) => {
+            for (key, value) in layer {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_values(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, layer) => *base = layer,
+    }
+}
+
+fn with_hint(error: String) -> anyhow::Error {
+    match suggest_field(&error) {
+        Some(hint) => anyhow::anyhow!("{}\n{}", error.trim_end(), hint),
+        None => anyhow::anyhow!("{}", error.trim_end()),
+    }
+}
+
+// Fill a secret from `<name>_file` or `<name>_command` when configured
+fn resolve_secret(
+    name: &str,
+    value: &mut String,
+    file: Option<&str>,
+    command: Option<&str>,
+) -> Result<()> {
+    let sources = [!value.is_empty(), file.is_some(), command.is_some()];
+    if sources.iter().filter(|set| **set).count() > 1 {
+        anyhow::bail!("Only one of {0}, {0}_file and {0}_command can be set", name);
+    }
+
+    if let Some(path) = file {
+        *value = fs::read_to_string(path)
+            .context(format!("Failed to read {}_file: {}", name, path))?
+            .trim()
+            .to_string();
+    } else if let Some(command) = command {
+        *value = run_secret_command(command).context(format!("Failed to run {}_command", name))?;
+    }
+
+    Ok(())
+}
+
+fn run_secret_command(command: &str) -> Result<String> {
+    #[cfg(windows)]
+    let output = std::process::Command::new("cmd")
+        .args(["/C", command])
+        .output()?;
+    #[cfg(not(windows))]
+    let output = std::process::Command::new("sh")
+        .args(["-c", command])
+        .output()?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "`{}` exited with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8(output.stdout)
+        .context("Command output is not valid UTF-8")?
+        .trim()
+        .to_string())
+}
+
+// Turn serde's "unknown field `x`, expected one of `a`, `b`" into a "did you mean" hint
+fn suggest_field(message: &str) -> Option<String> {
+    let rest = message.split("unknown field `").nth(1)?;
+    let (unknown, expected) = rest.split_once('`')?;
+
+    expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|candidate| (candidate, strsim::jaro_winkler(unknown, candidate)))
+        .filter(|(_, similarity)| *similarity >= 0.8)
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(candidate, _)| format!("did you mean `{}`?", candidate))
 }
@@ -1,64 +1,509 @@
-use crate::config::SerialConfig;
-use crate::database::{Database, SmsMessage};
-use crate::notification::Notifier;
-use crate::serial_port::{self, MessageType, ParsedMessage};
+use crate::ack_callback::AckLinks;
+use crate::audit;
+use crate::config::{
+    self, AUTO_BAUD_RATE, Config, DeviceProfile, DeviceStorageConfig, NetworkConfig, SerialConfig,
+    is_auto_port,
+};
+use crate::connection_state::{ConnectionState, StateMachine};
+use crate::correlation::{Correlator, Request};
+use crate::database::{
+    self, ConnectionStatus, Database, DeviceRecord, NetworkEvent, NetworkRecord, SessionStats,
+    SmsMessage, SmsTiming, UnacknowledgedSms, UsageLimit,
+};
+use crate::device_log::DeviceLog;
+use crate::error::{self, AppError};
+use crate::escalation::Escalator;
+use crate::flood_guard::{Admission, FloodGuard};
+use crate::forwarding::{self, Forwarder};
+use crate::grafana::GrafanaAnnotations;
+use crate::handover::{self, ParkedPort};
+use crate::i18n::Translations;
+use crate::lua_hook::LuaHook;
+use crate::message_class;
+use crate::network::{self, RegistrationWatch};
+use crate::notification::{BarkNotifier, FanoutNotifier, LimitedNotifier, Notifier, SendOptions};
+use crate::ops_alerts::{OpsAlert, OpsAlerts};
+use crate::opt_out::{self, OptOutKeywords};
+use crate::otp::OtpDetector;
+use crate::port_access;
+use crate::quiet_hours::QuietHours;
+use crate::rules::{HookActions, HookMessage, RuleEngine};
+use crate::self_test;
+use crate::sender::{SenderClassifier, SenderInfo};
+use crate::sender_queue::{QueuedNotification, SenderQueues};
+use crate::serial_port::{
+    self, CountingWriter, DeleteStoredCommand, DeviceInfoPayload, FrameRead, MessageType,
+    NetStatusPayload, ParsedMessage, SendSmsPayload, SmsPayload, StoredSms, StoredSmsPayload,
+};
+use crate::spam::SpamClassifier;
+use crate::timezone::TimeFormatter;
+use crate::usage::{self, UsageTracker};
+use crate::wasm_hook::WasmHook;
+use crate::watchdog::Heartbeat;
 use anyhow::{Context, Result};
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
+use tokio::sync::watch;
 use tokio_serial::{SerialPortBuilderExt, SerialStream};
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum ConnectionState {
-    Initializing,
-    Validating,
-    Connected,
-    Reconnecting { attempts: u32 },
-    Failed,
+// Requests queued in the database by the `reconnect`, `rescan` and `unacked`
+// subcommands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AdminCommand {
+    // Close the session and connect again
+    Reconnect,
+    // Same, forgetting the detected baud rate so detection starts from scratch
+    Rescan,
+    // Send the ACKs of stored messages the device never got one for
+    ReplayAcks,
+    // Notify those messages again
+    Renotify,
+}
+
+impl AdminCommand {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdminCommand::Reconnect => "reconnect",
+            AdminCommand::Rescan => "rescan",
+            AdminCommand::ReplayAcks => "replay-acks",
+            AdminCommand::Renotify => "renotify",
+        }
+    }
+
+    fn parse(command: &str) -> Option<Self> {
+        match command {
+            "reconnect" => Some(AdminCommand::Reconnect),
+            "rescan" => Some(AdminCommand::Rescan),
+            "replay-acks" => Some(AdminCommand::ReplayAcks),
+            "renotify" => Some(AdminCommand::Renotify),
+            _ => None,
+        }
+    }
+
+    fn ends_session(&self) -> bool {
+        matches!(self, AdminCommand::Reconnect | AdminCommand::Rescan)
+    }
+}
+
+// Why a session ended without an error
+enum SessionEnd {
+    Requested(AdminCommand),
+    Maintenance,
+    // The port stays open for the process replacing this one
+    Handover,
+}
+
+// The halves of the port a session reads and writes
+type SessionReader = BufReader<ReadHalf<SerialStream>>;
+type SessionWriter = CountingWriter<WriteHalf<SerialStream>>;
+
+// Waits before each attempt to reopen a port the device closed, about 4
+// seconds in all before falling back to detection and validation
+const EOF_REOPEN_DELAYS_MS: &[u64] = &[100, 250, 500, 1000, 2000];
+// How often the session statistics are written to the database while connected
+const STATS_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+// How often the outbound queue is checked, also bounds how long a read waits
+const OUTBOUND_POLL_INTERVAL: Duration = Duration::from_secs(2);
+// How often notifications held back by strict ordering are retried
+const HELD_BACK_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+// A queued SMS without an SMS_SENT reply by then is marked as failed
+const OUTBOUND_REPLY_TIMEOUT: Duration = Duration::from_secs(120);
+// First device script version answering LIST_STORED and DELETE_STORED
+const STORAGE_MIN_SCRIPT_VERSION: &str = "1.3.0";
+// First device script version keeping ACKed SMS until CMD:SMS_READ
+const READ_RECEIPTS_MIN_SCRIPT_VERSION: &str = "1.5.0";
+// First device script version replying under the request id of a command
+const CORRELATION_MIN_SCRIPT_VERSION: &str = "1.6.0";
+// First device script version retrying with ACK_REQ instead of the whole SMS
+const ACK_REQUESTS_MIN_SCRIPT_VERSION: &str = "1.7.0";
+// An ACK for the same SMS is sent at most once within this time per session,
+// a device resending its whole queue at once would otherwise get several. Below
+// the script's first retry (5s), so a retry after a lost ACK is answered
+const ACK_REPEAT_INTERVAL: Duration = Duration::from_secs(3);
+// A DEVICE_INFO refresh without a reply by then is sent again, up to
+// DEVICE_INFO_ATTEMPTS times before waiting for the next refresh
+const DEVICE_INFO_REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+const DEVICE_INFO_ATTEMPTS: u32 = 3;
+
+// Logs every change and keeps the stored state current for `status` and the
+// metrics between the full status saves
+fn state_machine(
+    device: &str,
+    db: &Database,
+    updates: Arc<watch::Sender<ConnectionState>>,
+) -> StateMachine {
+    let mut machine = StateMachine::default();
+    let label = device.to_string();
+    let store = db.clone();
+    machine.on_change(move |change| {
+        if let Err(e) = store.update_connection_state(&label, &change.to.to_string()) {
+            log::warn!("{:#}", e);
+        }
+        updates.send_replace(change.to.clone());
+    });
+    let label = device.to_string();
+    machine.on_enter("connected", move |change| {
+        // A port reopened after EOF or taken over on restart skips validation
+        if *change.from == ConnectionState::Validating {
+            log::info!(
+                "Device {} connected, validation took {}ms",
+                label,
+                change.elapsed.as_millis()
+            );
+        } else {
+            log::info!("Device {} connected without validation", label);
+        }
+    });
+    let label = device.to_string();
+    machine.on_exit("connected", move |change| {
+        log::info!(
+            "Device {} was connected for {}s",
+            label,
+            change.elapsed.as_secs()
+        );
+    });
+    machine
+}
+
+// DEVICE_INFO refresh of one session: the request waiting for its reply, when
+// it was sent and on which attempt
+struct InfoRefresh {
+    pending: Option<(Request, Instant, u32)>,
+    last_refresh: Instant,
+}
+
+impl InfoRefresh {
+    // The session starts with the handshake's GET_DEVICE_INFO
+    fn new() -> Self {
+        InfoRefresh {
+            pending: None,
+            last_refresh: Instant::now(),
+        }
+    }
+
+    fn finish(&mut self) {
+        self.pending = None;
+        self.last_refresh = Instant::now();
+    }
+}
+
+// Close the device before it is opened again. The serial ports are opened
+// exclusively and some USB drivers answer EBUSY while an old descriptor lives,
+// so one left open by mistake would keep the device from ever coming back
+fn close_port(port: SerialStream, port_name: &str) {
+    drop(port);
+    if port_access::held_by_self(port_name) {
+        log::error!(
+            "Serial port {} is still open after closing the session, a descriptor leaked",
+            port_name
+        );
+    }
+}
+
+// Protocol errors such as a refused device script stop the connection
+// instead of reconnecting
+fn is_fatal(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<AppError>(),
+        Some(AppError::Protocol(_))
+    )
+}
+
+fn is_database_error(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .any(|cause| cause.downcast_ref::<rusqlite::Error>().is_some())
+}
+
+fn version_at_least(version: Option<&str>, minimum: &str) -> bool {
+    version
+        .and_then(serial_port::parse_version)
+        .zip(serial_port::parse_version(minimum))
+        .is_some_and(|(version, minimum)| version >= minimum)
 }
 
 pub struct SerialConnection {
     config: SerialConfig,
-    state: ConnectionState,
+    // Baud rate in use, remembered across reconnects when auto-detected
+    baud_rate: u32,
+    device_id: Option<String>,
+    notification_prefix: String,
+    state: StateMachine,
+    // Observers subscribe to state transitions instead of polling
+    state_updates: Arc<watch::Sender<ConnectionState>>,
+    // Behind a mutex so counters can be updated from `&self` message handlers
+    stats: Mutex<SessionStats>,
+    // Category and message of the error that ended the last session
+    last_error: Option<(&'static str, String)>,
+    // Queued SMS handed to the device and still waiting for its SMS_SENT reply
+    outbound_in_flight: Mutex<Option<(i64, Instant)>>,
+    storage: DeviceStorageConfig,
+    // Whether the connected device's script manages its stored SMS queue
+    storage_supported: bool,
+    // Whether the device keeps ACKed SMS until their read receipt
+    read_receipts: bool,
+    // Whether the device sends replies under the request id of the command
+    correlation_supported: bool,
+    // Whether the device retries with ACK_REQ, see ACK_REQUESTS_MIN_SCRIPT_VERSION
+    ack_requests: bool,
+    // When each SMS was last ACKed in this session
+    acks_sent: Mutex<HashMap<String, Instant>>,
+    correlator: Correlator,
+    // Notified SMS whose read receipt is sent on the next loop iteration
+    pending_receipts: Mutex<Vec<String>>,
+    // Set while the queue is above the alert threshold, so it alerts once
+    storage_alerted: Mutex<bool>,
+    // Set after maintenance until the SMS the device buffered meanwhile are imported
+    resync_pending: Mutex<bool>,
+    network: NetworkConfig,
+    registration: Mutex<RegistrationWatch>,
+    // When `pause-notifications` took effect, SMS notifications are held meanwhile
+    paused_since: Mutex<Option<i64>>,
     db: Database,
     notifier: Arc<dyn Notifier>,
+    // Receives connection lost/restored/failed alerts when enabled
+    event_notifier: Option<Arc<dyn Notifier>>,
+    ops_alerts: OpsAlerts,
+    // Posts reconnects, reboots and SIM swaps as Grafana annotations
+    grafana: Option<GrafanaAnnotations>,
+    // When the current outage started, if the connection was lost
+    lost_at: Option<Instant>,
+    // The device closed the last session (EOF), e.g. a USB adapter that
+    // re-enumerated, so its port is reopened right away
+    closed_by_device: bool,
+    // Alert once a reconnect failed this often, 0 never
+    reconnect_alert_after: u32,
+    recovery_max_age_hours: u64,
+    // Identical SMS notified within this many seconds are not notified again
+    dedup_window_secs: u64,
+    // Set with `[notification] strict_order`
+    sender_queues: Option<SenderQueues>,
+    held_back_retry_at: Mutex<Option<Instant>>,
+    rules: RuleEngine,
+    senders: SenderClassifier,
+    wasm_hook: Option<WasmHook>,
+    lua_hook: Option<Arc<LuaHook>>,
+    forwarder: Forwarder,
+    spam: SpamClassifier,
+    escalator: Escalator,
+    ack_links: Option<AckLinks>,
+    otp: Option<OtpDetector>,
+    opt_out: Option<OptOutKeywords>,
+    // Beaten by the message loop, waiting for a port or a maintenance window is idle
+    heartbeat: Heartbeat,
+    time: TimeFormatter,
+    usage: UsageTracker,
+    // Hours in which queued SMS wait unless they are urgent
+    quiet_hours: QuietHours,
+    translations: Translations,
+    device_log: Option<DeviceLog>,
 }
 
 impl SerialConnection {
-    pub fn new(config: SerialConfig, db: Database, notifier: Arc<dyn Notifier>) -> Self {
-        SerialConnection {
-            config,
-            state: ConnectionState::Initializing,
+    pub fn new(
+        config: &Config,
+        device: DeviceProfile,
+        db: Database,
+        notifier: Arc<dyn Notifier>,
+        escalator: Escalator,
+        translations: Translations,
+        device_log: Option<DeviceLog>,
+    ) -> Result<Self> {
+        let rules = RuleEngine::new(&config.rules)
+            .context("Failed to load message rules")
+            .map_err(error::config_error)?;
+        let wasm_hook = config
+            .wasm_hook
+            .path
+            .as_deref()
+            .map(|path| WasmHook::new(&config.wasm_hook, path))
+            .transpose()
+            .context("Failed to load the WASM hook")
+            .map_err(error::config_error)?;
+        let lua_hook = config
+            .lua_rules
+            .path
+            .as_deref()
+            .map(|path| LuaHook::new(&config.lua_rules, path).map(Arc::new))
+            .transpose()
+            .context("Failed to load the Lua rules")
+            .map_err(error::config_error)?;
+        let senders = SenderClassifier::new(config.senders.home_country.as_deref())
+            .context("Invalid [senders] home_country")
+            .map_err(error::config_error)?;
+        let spam = SpamClassifier::new(&config.spam)
+            .context("Failed to initialize spam classifier")
+            .map_err(error::config_error)?;
+        let time = TimeFormatter::new(config.timezone.as_deref()).map_err(error::config_error)?;
+        let quiet_hours = QuietHours::new(&config.quiet_hours, time)
+            .context("Invalid [quiet_hours]")
+            .map_err(error::config_error)?;
+        let mut storage = config.device_storage.clone();
+        // Without notifications the ACK is all the device needs to wait for
+        storage.read_receipts &= config.notification.enabled;
+        let events = &config.connection_events;
+        let event_notifier: Option<Arc<dyn Notifier>> = if !events.enabled {
+            None
+        } else if let Some(keys) = &events.bark_device_key {
+            let notifiers = config::split_keys(keys)
+                .into_iter()
+                .map(|key| {
+                    LimitedNotifier::wrap(
+                        Arc::new(BarkNotifier::new(
+                            config.notification.bark_server_url.clone(),
+                            key,
+                        )),
+                        config.notification.max_length,
+                        config.notification.overflow,
+                    )
+                })
+                .collect();
+            Some(Arc::new(FanoutNotifier::new(notifiers)))
+        } else {
+            Some(notifier.clone())
+        };
+
+        let state_updates = Arc::new(watch::Sender::new(ConnectionState::Initializing));
+        let state = state_machine(
+            device.id.as_deref().unwrap_or("default"),
+            &db,
+            state_updates.clone(),
+        );
+
+        Ok(SerialConnection {
+            baud_rate: device.serial.baud_rate,
+            config: device.serial,
+            device_id: device.id,
+            notification_prefix: device.notification_prefix,
+            state,
+            state_updates,
+            stats: Mutex::new(SessionStats::default()),
+            last_error: None,
+            outbound_in_flight: Mutex::new(None),
+            storage,
+            storage_supported: false,
+            read_receipts: false,
+            correlation_supported: false,
+            ack_requests: false,
+            acks_sent: Mutex::new(HashMap::new()),
+            correlator: Correlator::new(),
+            pending_receipts: Mutex::new(Vec::new()),
+            storage_alerted: Mutex::new(false),
+            resync_pending: Mutex::new(false),
+            network: config.network.clone(),
+            registration: Mutex::new(RegistrationWatch::default()),
+            paused_since: Mutex::new(None),
             db,
             notifier,
+            event_notifier,
+            ops_alerts: OpsAlerts::default(),
+            grafana: GrafanaAnnotations::new(&config.grafana),
+            lost_at: None,
+            closed_by_device: false,
+            reconnect_alert_after: if events.enabled {
+                events.alert_after_attempts
+            } else {
+                0
+            },
+            // Nothing to recover when notifications are switched off
+            recovery_max_age_hours: if config.notification.enabled {
+                config.notification.recovery_max_age_hours
+            } else {
+                0
+            },
+            dedup_window_secs: config.notification.dedup_window_secs,
+            sender_queues: config.notification.strict_order.then(SenderQueues::default),
+            held_back_retry_at: Mutex::new(None),
+            rules,
+            senders,
+            wasm_hook,
+            lua_hook,
+            forwarder: Forwarder::new(&config.forwarding),
+            spam,
+            escalator,
+            ack_links: AckLinks::new(&config.ack_callback),
+            otp: OtpDetector::new(&config.otp),
+            opt_out: OptOutKeywords::new(&config.opt_out),
+            heartbeat: Heartbeat::default(),
+            time,
+            usage: UsageTracker::new(&config.usage, time),
+            quiet_hours,
+            translations,
+            device_log,
+        })
+    }
+
+    pub fn get_state(&self) -> ConnectionState {
+        self.state.get()
+    }
+
+    pub fn subscribe_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state_updates.subscribe()
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        self.state.transition(state);
+    }
+
+    pub fn get_stats(&self) -> SessionStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    // Persist state and counters so `status` can show them from another process
+    fn save_status(&self) {
+        let mut stats = self.get_stats();
+        stats.held_notifications = self.sender_queues.as_ref().map_or(0, |q| q.len() as u64);
+        stats.pending_receipts = self.pending_receipts.lock().unwrap().len() as u64;
+        let status = ConnectionStatus {
+            device_id: self.device_label().to_string(),
+            state: self.get_state().to_string(),
+            stats,
+            error_category: self
+                .last_error
+                .as_ref()
+                .map(|(category, _)| category.to_string()),
+            error: self.last_error.as_ref().map(|(_, message)| message.clone()),
+            updated_at: database::unix_timestamp(),
+        };
+        if let Err(e) = self.db.save_connection_status(&status) {
+            log::warn!("Failed to store connection status: {}", e);
         }
     }
 
-    pub async fn establish(&mut self) -> Result<String> {
+    fn set_last_error(&mut self, e: &anyhow::Error) {
+        self.last_error = Some((error::category(e), format!("{:#}", e)));
+    }
+
+    async fn establish(&mut self) -> Result<String> {
         log::info!("Establishing serial connection...");
-        self.state = ConnectionState::Initializing;
+        self.set_state(ConnectionState::Initializing);
+
+        let [configured] = self.config.port_names.as_slice() else {
+            return self.establish_fallback().await;
+        };
 
         // Determine port name
-        let port_name = if self.config.port_name.to_lowercase() == "auto" {
+        let port_name = if is_auto_port(configured) {
             log::info!("Auto-detecting serial port...");
-            match serial_port::auto_detect_port(self.config.baud_rate).await {
-                Some(port) => {
-                    log::info!("Auto-detected port: {}", port);
+            match serial_port::auto_detect_port(&self.config, self.baud_candidates()).await {
+                Ok(device) => {
+                    log::info!("Auto-detected port: {}", device.port_name);
 
                     // Add delay after auto-detection to ensure port is fully released
                     // Auto-detection validates the port, so we need time before re-validating
                     log::debug!("Waiting for port to be released after auto-detection...");
                     tokio::time::sleep(Duration::from_millis(1000)).await;
 
-                    port
-                }
-                None => {
-                    anyhow::bail!("Failed to auto-detect serial port. No valid device found.");
+                    device.port_name
                 }
+                Err(e) => return Err(e.into()),
             }
         } else {
-            log::info!("Using configured port: {}", self.config.port_name);
-            self.config.port_name.clone()
+            log::info!("Using configured port: {}", configured);
+            configured.clone()
         };
 
         // Validate port with retry (limited to handle multiple serial devices)
@@ -69,192 +514,2215 @@ impl SerialConnection {
                 attempt,
                 self.config.max_retry_count
             );
-            self.state = ConnectionState::Validating;
+            self.set_state(ConnectionState::Validating);
+
+            if self.validate_port(&port_name).await? {
+                return Ok(port_name);
+            }
+            log::warn!(
+                "Port validation failed (attempt {}/{})",
+                attempt,
+                self.config.max_retry_count
+            );
+            if attempt < self.config.max_retry_count {
+                log::info!("Retrying in {}ms...", self.config.retry_delay_ms);
+                tokio::time::sleep(Duration::from_millis(self.config.retry_delay_ms)).await;
+            }
+        }
 
-            match serial_port::check_port(&port_name, self.config.baud_rate).await {
-                Some(_) => {
-                    log::info!("Port {} validated successfully", port_name);
+        // Validation failed - if using auto-detect, this will trigger re-detection on next loop
+        port_access::check(&port_name)?;
+        Err(AppError::PortNotFound(format!(
+            "Failed to validate port '{}' after {} attempts. Will retry auto-detection.",
+            port_name, self.config.max_retry_count
+        ))
+        .into())
+    }
 
-                    // Add small delay to ensure port is fully released after validation
-                    tokio::time::sleep(Duration::from_millis(500)).await;
+    // With a list of ports every entry gets one attempt per round, in order, so
+    // a fallback takes over as soon as the preferred port stops answering and
+    // the preferred one is used again once it is back
+    async fn establish_fallback(&mut self) -> Result<String> {
+        let candidates = self.config.port_names.clone();
+        for round in 1..=self.config.max_retry_count {
+            log::info!(
+                "Trying ports {} (round {}/{})",
+                self.config.port_label(),
+                round,
+                self.config.max_retry_count
+            );
+            self.set_state(ConnectionState::Validating);
 
-                    self.state = ConnectionState::Connected;
-                    return Ok(port_name);
-                }
-                None => {
-                    log::warn!(
-                        "Port validation failed (attempt {}/{})",
-                        attempt,
-                        self.config.max_retry_count
-                    );
-                    if attempt < self.config.max_retry_count {
-                        log::info!("Retrying in {}ms...", self.config.retry_delay_ms);
-                        tokio::time::sleep(Duration::from_millis(self.config.retry_delay_ms)).await;
+            for candidate in &candidates {
+                let port_name = if is_auto_port(candidate) {
+                    let found = match serial_port::scan_ports(&self.config, &self.baud_candidates())
+                        .await
+                    {
+                        Ok(found) => found,
+                        Err(e) => {
+                            log::warn!("Failed to list available ports: {}", e);
+                            continue;
+                        }
+                    };
+                    match serial_port::select_device(found, self.config.expected_imei.as_deref())? {
+                        Some(device) => device.port_name,
+                        None => continue,
+                    }
+                } else {
+                    candidate.clone()
+                };
+
+                if self.validate_port(&port_name).await? {
+                    if candidate != &candidates[0] {
+                        log::warn!(
+                            "Preferred port {} unavailable, using {}",
+                            candidates[0],
+                            port_name
+                        );
                     }
+                    return Ok(port_name);
                 }
+                log::warn!("No device answered on {}", port_name);
+            }
+
+            if round < self.config.max_retry_count {
+                log::info!("Retrying in {}ms...", self.config.retry_delay_ms);
+                tokio::time::sleep(Duration::from_millis(self.config.retry_delay_ms)).await;
             }
         }
 
-        // Validation failed - if using auto-detect, this will trigger re-detection on next loop
-        self.state = ConnectionState::Failed;
-        anyhow::bail!(
-            "Failed to validate port '{}' after {} attempts. Will retry auto-detection.",
-            port_name,
+        Err(AppError::PortNotFound(format!(
+            "No device answered on any of the ports {} after {} rounds",
+            self.config.port_label(),
             self.config.max_retry_count
+        ))
+        .into())
+    }
+
+    // One handshake on `port_name`, taking the device if it answers. Fails when
+    // the device that answered is refused
+    async fn validate_port(&mut self, port_name: &str) -> Result<bool> {
+        let Some((baud_rate, info)) = serial_port::probe_port(
+            port_name,
+            &self.baud_candidates(),
+            self.config.handshake_timeout_ms,
+            self.config.compression,
         )
+        .await
+        else {
+            return Ok(false);
+        };
+
+        self.check_identity(&info)?;
+        log::info!(
+            "Port {} validated successfully at {} baud",
+            port_name,
+            baud_rate
+        );
+        self.baud_rate = baud_rate;
+        self.use_script_version(info.script_version.as_deref());
+
+        // Add small delay to ensure port is fully released after validation
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        self.set_state(ConnectionState::Connected);
+        Ok(true)
+    }
+
+    // Rates to try during the handshake: the configured one, or with "auto"
+    // the last working rate first followed by the common rates
+    fn baud_candidates(&self) -> Vec<u32> {
+        if self.config.baud_rate != AUTO_BAUD_RATE {
+            return vec![self.config.baud_rate];
+        }
+
+        let mut rates = Vec::new();
+        if self.baud_rate != AUTO_BAUD_RATE {
+            rates.push(self.baud_rate);
+        }
+        rates.extend(
+            serial_port::AUTO_BAUD_RATES
+                .iter()
+                .filter(|rate| **rate != self.baud_rate),
+        );
+        rates
+    }
+
+    pub fn set_heartbeat(&mut self, heartbeat: Heartbeat) {
+        self.heartbeat = heartbeat;
+    }
+
+    // Shared by every connection, so an alert is not repeated after a restart
+    pub fn set_ops_alerts(&mut self, ops_alerts: OpsAlerts) {
+        self.ops_alerts = ops_alerts;
     }
 
     pub async fn maintain_loop(&mut self) -> Result<()> {
+        self.sync_pause().await;
+        self.report_unacknowledged().await;
+        self.recover_notifications().await;
+
+        let result = self.connection_loop().await;
+        if let Err(e) = &result {
+            self.set_last_error(e);
+        }
+        self.save_status();
+        if let Err(e) = &result {
+            self.annotate(
+                "connection_failed",
+                format!("Serial connection failed: {:#}", e),
+            );
+            self.notify_event(
+                "connection_failed_title",
+                "connection_failed_body",
+                &[("error", &e.to_string())],
+            )
+            .await;
+        }
+        result
+    }
+
+    async fn connection_loop(&mut self) -> Result<()> {
+        let mut inherited = self.inherited_port();
+        let mut reopen = None;
         loop {
-            // Establish connection
-            let port_name = match self.establish().await {
-                Ok(name) => name,
+            self.heartbeat.idle();
+            self.wait_maintenance().await;
+            if handover::requested() {
+                self.stop_for_handover(None).await;
+            }
+
+            let reopened = match reopen.take() {
+                Some(port_name) => self.reopen_port(port_name).await,
+                None => None,
+            };
+            let opened = match (inherited.take(), reopened) {
+                (Some(parked), _) => Ok(self.resume_port(parked)),
+                (None, Some(reopened)) => Ok(reopened),
+                (None, None) => self.open_port().await,
+            };
+            let (port_name, port) = match opened {
+                Ok(opened) => opened,
+                // A device that was connected before is waited for, e.g. after a replug
+                Err(e) if self.stats.lock().unwrap().connected_at.is_some() && !is_fatal(&e) => {
+                    self.reconnect_failed(e).await?;
+                    continue;
+                }
                 Err(e) => {
-                    log::error!("Failed to establish connection: {}", e);
+                    self.set_state(ConnectionState::Failed);
                     return Err(e);
                 }
             };
 
-            // Open serial port
-            log::info!("Opening serial port: {}", port_name);
-            let port_result = tokio_serial::new(&port_name, self.config.baud_rate)
-                .timeout(Duration::from_millis(self.config.timeout_ms))
-                .open_native_async();
+            log::info!("Serial port opened successfully, entering message loop");
 
-            let port = match port_result {
-                Ok(p) => p,
-                Err(e) => {
-                    log::error!("Failed to open serial port '{}': {}", port_name, e);
-                    log::error!("Error details: {:?}", e);
-                    anyhow::bail!("Failed to open serial port '{}': {}", port_name, e);
-                }
-            };
+            // Start a new session, keeping the lifetime reconnect count
+            {
+                let mut stats = self.stats.lock().unwrap();
+                *stats = SessionStats {
+                    connected_at: Some(database::unix_timestamp()),
+                    reconnects: stats.reconnects,
+                    ..SessionStats::default()
+                };
+            }
+            self.last_error = None;
+            self.save_status();
 
-            log::info!("Serial port opened successfully, entering message loop");
+            if let Some(lost_at) = self.lost_at.take() {
+                let outage = format!("{}s", lost_at.elapsed().as_secs());
+                self.annotate(
+                    "connection_restored",
+                    format!("Serial connection restored after {}", outage),
+                );
+                self.notify_event(
+                    "connection_restored_title",
+                    "connection_restored_body",
+                    &[("duration", &outage)],
+                )
+                .await;
+            }
 
             // Start message handling loop
-            if let Err(e) = self.handle_messages(port).await {
-                log::error!("Message handling error: {}", e);
-                self.state = ConnectionState::Reconnecting { attempts: 0 };
+            let (end, port) = self.run_session(port).await;
+            if let Ok(SessionEnd::Handover) = end {
+                log::info!("Keeping serial port {} open for the restart", port_name);
+                self.stop_for_handover(Some(ParkedPort {
+                    device: self.device_label().to_string(),
+                    port_name,
+                    baud_rate: self.baud_rate,
+                    port,
+                }))
+                .await;
+            }
+            close_port(port, &port_name);
+            match end {
+                Ok(SessionEnd::Handover) => unreachable!("the port was handed over"),
+                Ok(SessionEnd::Maintenance) => {
+                    log::warn!("Maintenance started, releasing serial port {}", port_name);
+                    self.set_state(ConnectionState::Reconnecting { attempts: 0 });
+                    continue;
+                }
+                Ok(SessionEnd::Requested(command)) => {
+                    log::info!(
+                        "Closing the serial session on request ({})",
+                        command.as_str()
+                    );
+                    if command == AdminCommand::Rescan {
+                        self.baud_rate = self.config.baud_rate;
+                    }
+                    self.set_state(ConnectionState::Reconnecting { attempts: 0 });
+                    self.stats.lock().unwrap().reconnects += 1;
+                    self.save_status();
+                    continue;
+                }
+                Err(e) if is_fatal(&e) => {
+                    log::error!("Refusing device: {}", e);
+                    self.set_state(ConnectionState::Failed);
+                    return Err(e);
+                }
+                Err(e) => {
+                    log::error!("Message handling error: {}", e);
+                    self.set_state(ConnectionState::Reconnecting { attempts: 0 });
+                    self.stats.lock().unwrap().reconnects += 1;
+                    self.set_last_error(&e);
+                    self.save_status();
 
-                // Reconnect logic
-                log::warn!("Connection lost, attempting to reconnect...");
-                tokio::time::sleep(Duration::from_millis(self.config.retry_delay_ms)).await;
+                    // Reconnect logic
+                    log::warn!("Connection lost, attempting to reconnect...");
+                    if self.lost_at.is_none() {
+                        self.lost_at = Some(Instant::now());
+                        self.annotate("connection_lost", format!("Serial connection lost: {}", e));
+                        self.notify_event(
+                            "connection_lost_title",
+                            "connection_lost_body",
+                            &[("error", &e.to_string())],
+                        )
+                        .await;
+                    }
+                    if std::mem::take(&mut self.closed_by_device) {
+                        reopen = Some(port_name);
+                    } else {
+                        tokio::time::sleep(Duration::from_millis(self.config.retry_delay_ms)).await;
+                    }
+                    continue;
+                }
+            }
+        }
+    }
+
+    // Reopen the port of a session the device closed, skipping detection and
+    // validation: a USB adapter that re-enumerated comes back at the same path
+    // within a few seconds. The DEVICE_INFO reply of the new session still
+    // checks the identity. None falls back to the full procedure
+    async fn reopen_port(&mut self, port_name: String) -> Option<(String, SerialStream)> {
+        for delay_ms in EOF_REOPEN_DELAYS_MS {
+            tokio::time::sleep(Duration::from_millis(*delay_ms)).await;
+            if !std::path::Path::new(&port_name).exists() {
                 continue;
             }
+            match tokio_serial::new(&port_name, self.baud_rate)
+                .timeout(Duration::from_millis(self.config.timeout_ms))
+                .open_native_async()
+            {
+                Ok(port) => {
+                    log::info!("Reopened serial port {} after EOF", port_name);
+                    self.set_state(ConnectionState::Connected);
+                    return Some((port_name, port));
+                }
+                Err(e) => log::debug!("Reopening {} failed: {}", port_name, e),
+            }
         }
+        log::warn!(
+            "Serial port {} did not come back, detecting the device again",
+            port_name
+        );
+        None
     }
 
-    async fn handle_messages(&mut self, port: SerialStream) -> Result<()> {
-        let (reader, mut writer) = tokio::io::split(port);
-        let mut reader = BufReader::new(reader);
-        let mut line = String::new();
+    // The port the process this one replaced had open, unless the configuration
+    // now names another one
+    fn inherited_port(&self) -> Option<ParkedPort> {
+        let parked = handover::take_port(self.device_label())?;
+        let configured = &self.config.port_names;
+        if !self.config.auto_detects() && !configured.contains(&parked.port_name) {
+            log::info!(
+                "Closing inherited serial port {}, {} is configured now",
+                parked.port_name,
+                self.config.port_label()
+            );
+            return None;
+        }
+        Some(parked)
+    }
 
-        // Send initial GET_DEVICE_INFO command to verify connection
-        log::info!("Sending GET_DEVICE_INFO command to device...");
-        if let Err(e) = writer.write_all(b"CMD:GET_DEVICE_INFO\r\n").await {
-            log::error!("Failed to send GET_DEVICE_INFO command: {}", e);
-        } else {
-            log::info!("GET_DEVICE_INFO command sent successfully");
+    // Carry on with an inherited port without probing it, the device and its
+    // script are the ones last reported
+    fn resume_port(&mut self, parked: ParkedPort) -> (String, SerialStream) {
+        log::info!("Continuing on inherited serial port {}", parked.port_name);
+        let script_version = match self.db.devices() {
+            Ok(devices) => devices
+                .into_iter()
+                .find(|device| device.device_id == self.device_label())
+                .and_then(|device| device.script_version),
+            Err(e) => {
+                log::warn!("{:#}", e);
+                None
+            }
+        };
+        self.baud_rate = parked.baud_rate;
+        self.use_script_version(script_version.as_deref());
+        self.set_state(ConnectionState::Connected);
+        (parked.port_name, parked.port)
+    }
+
+    fn use_script_version(&mut self, version: Option<&str>) {
+        self.storage_supported = version_at_least(version, STORAGE_MIN_SCRIPT_VERSION);
+        self.read_receipts = self.storage.read_receipts
+            && version_at_least(version, READ_RECEIPTS_MIN_SCRIPT_VERSION);
+        self.correlation_supported = version_at_least(version, CORRELATION_MIN_SCRIPT_VERSION);
+        self.ack_requests = version_at_least(version, ACK_REQUESTS_MIN_SCRIPT_VERSION);
+    }
+
+    // Waits for the process to be replaced, the restart times out sessions
+    // that never get here
+    async fn stop_for_handover(&self, port: Option<ParkedPort>) -> ! {
+        self.heartbeat.idle();
+        handover::stopped(port);
+        std::future::pending().await
+    }
+
+    async fn open_port(&mut self) -> Result<(String, SerialStream)> {
+        let port_name = match self.establish().await {
+            Ok(name) => name,
+            Err(e) => {
+                log::error!("Failed to establish connection: {}", e);
+                return Err(e);
+            }
+        };
+
+        log::info!("Opening serial port: {}", port_name);
+        match tokio_serial::new(&port_name, self.baud_rate)
+            .timeout(Duration::from_millis(self.config.timeout_ms))
+            .open_native_async()
+        {
+            Ok(port) => Ok((port_name, port)),
+            Err(e) => {
+                log::error!("Failed to open serial port '{}': {}", port_name, e);
+                log::error!("Error details: {:?}", e);
+                Err(error::port_open_error(&port_name, &e))
+            }
         }
+    }
 
-        log::info!("Message handling loop started, waiting for data...");
+    // Counts a failed reconnect and waits before the next attempt, each one
+    // twice as long as the last. Fails once `max_reconnect_attempts` are used up
+    async fn reconnect_failed(&mut self, e: anyhow::Error) -> Result<()> {
+        let attempts = {
+            let mut stats = self.stats.lock().unwrap();
+            stats.reconnect_attempts += 1;
+            stats.reconnect_attempts
+        };
+        self.set_last_error(&e);
+        let max_attempts = self.config.max_reconnect_attempts;
+        if max_attempts > 0 && attempts >= max_attempts {
+            self.set_state(ConnectionState::Failed);
+            return Err(e.context(format!("Gave up after {} reconnect attempts", attempts)));
+        }
+        self.set_state(ConnectionState::Reconnecting { attempts });
+        self.save_status();
 
-        loop {
-            line.clear();
+        if attempts == self.reconnect_alert_after {
+            self.notify_event(
+                "reconnect_failing_title",
+                "reconnect_failing_body",
+                &[
+                    ("attempts", &attempts.to_string()),
+                    ("error", &e.to_string()),
+                ],
+            )
+            .await;
+        }
 
-            // Use timeout to detect if we're stuck waiting
-            let read_result =
-                tokio::time::timeout(Duration::from_secs(30), reader.read_line(&mut line)).await;
+        let delay = Duration::from_millis(
+            self.config
+                .retry_delay_ms
+                .saturating_mul(1 << (attempts - 1).min(16))
+                .min(self.config.reconnect_backoff_max_ms),
+        );
+        log::warn!(
+            "Reconnect attempt {} failed, retrying in {:?}",
+            attempts,
+            delay
+        );
+        tokio::time::sleep(delay).await;
+        Ok(())
+    }
 
-            match read_result {
-                Ok(Ok(0)) => {
-                    log::warn!("Connection closed (EOF)");
-                    anyhow::bail!("Connection closed");
-                }
-                Ok(Ok(bytes_read)) => {
-                    log::info!("Received {} bytes: '{}'", bytes_read, line.trim());
-                    log::debug!("Raw bytes: {:?}", line.as_bytes());
+    // Re-send notifications lost to a crash or a push service outage
+    async fn recover_notifications(&self) {
+        // They are sent when notifications are resumed
+        if self.recovery_max_age_hours == 0 || self.notifications_paused() {
+            return;
+        }
 
-                    // Parse message
-                    match serial_port::parse_message(&line) {
-                        Some(msg) => {
-                            log::info!("Successfully parsed message with ID: {}", msg.id);
-                            if let Err(e) = self.process_message(msg, &mut writer).await {
-                                log::error!("Failed to process message: {}", e);
-                                // Continue processing other messages
-                            }
-                        }
-                        None => {
-                            log::warn!("Failed to parse message: '{}'", line.trim());
-                            log::warn!("Raw bytes: {:?}", line.as_bytes());
-                        }
-                    }
-                }
-                Ok(Err(e)) => {
-                    log::error!("Read error: {}", e);
-                    anyhow::bail!("Read error: {}", e);
-                }
-                Err(_) => {
-                    // Timeout - no data received
-                    log::info!("No data received in last 30 seconds, still waiting...");
-                    // Continue waiting
-                }
+        let since = database::unix_timestamp() - (self.recovery_max_age_hours * 3600) as i64;
+        let pending = match self.db.unnotified_since(self.device_id.as_deref(), since) {
+            Ok(pending) => pending,
+            Err(e) => {
+                log::warn!("Failed to look up unsent notifications: {}", e);
+                return;
             }
+        };
+        if pending.is_empty() {
+            return;
+        }
+
+        log::info!("Re-sending {} unsent notification(s)", pending.len());
+        for msg in pending {
+            self.heartbeat.beat(Duration::ZERO);
+            let (title, body) = self.notification_text(
+                &msg.sender,
+                &msg.content,
+                msg.received_at,
+                msg.message_class,
+            );
+            let sender = self.senders.classify(&msg.sender);
+            let group = self
+                .rules
+                .group(&msg.sender, &sender, &msg.content, msg.message_class);
+            self.notify_in_order(QueuedNotification {
+                id: msg.id,
+                sender: msg.sender,
+                content: msg.content,
+                title,
+                body,
+                group,
+            })
+            .await;
         }
     }
 
-    async fn process_message<W: tokio::io::AsyncWriteExt + Unpin>(
+    // Flash messages are titled differently, they are usually urgent carrier alerts
+    fn notification_text(
         &self,
-        msg: ParsedMessage,
-        writer: &mut W,
-    ) -> Result<()> {
-        match msg.message_type {
-            MessageType::SmsReceived(payload) => {
-                log::info!("SMS received from {}: {}", payload.sender, payload.content);
+        sender: &str,
+        content: &str,
+        received_at: i64,
+        class: Option<u8>,
+    ) -> (String, String) {
+        let key = if class == Some(message_class::FLASH) {
+            "flash_sms_title"
+        } else {
+            "sms_title"
+        };
+        let title = format!(
+            "{}{}",
+            self.notification_prefix,
+            self.translations.text(key, &[("sender", sender)])
+        );
+        let received_at = self.translations.text(
+            "sms_received_at",
+            &[("time", &self.time.display(received_at))],
+        );
+        (title, format!("{}\n\n{}", content, received_at))
+    }
 
-                // Store in database
-                let sms_msg = SmsMessage {
-                    id: payload.id.clone(),
-                    sender: payload.sender.clone(),
-                    content: payload.content.clone(),
-                    received_at: payload.received_at,
-                    metas: serde_json::to_string(&payload.metas).unwrap_or_default(),
-                };
+    // Returns whether the notification was sent. One-time codes are grouped by
+    // their service unless a rule or hook gave a group, and expire
+    async fn notify_sms(
+        &self,
+        id: &str,
+        sender: &str,
+        content: &str,
+        title: &str,
+        body: &str,
+        group: Option<String>,
+    ) -> bool {
+        let otp = self
+            .otp
+            .as_ref()
+            .and_then(|detector| Some((detector.detect(sender, content)?, detector.expire_secs())));
+        let group = group.or_else(|| otp.as_ref().map(|(otp, _)| otp.service.clone()));
+        let action_url = self.ack_links.as_ref().map(|links| links.url(id));
+        let options = SendOptions {
+            group: group.as_deref(),
+            action_url: action_url.as_deref(),
+            expires_at: otp
+                .as_ref()
+                .map(|(_, expire_secs)| database::unix_timestamp() + expire_secs),
+            copy: otp.as_ref().map(|(otp, _)| otp.code.as_str()),
+            message_id: Some(id),
+        };
+        match self.notifier.send_with_options(title, body, &options).await {
+            Ok(()) => {
+                self.mark_notified(id);
+                true
+            }
+            Err(e) => {
+                // Don't fail the whole process if notification fails,
+                // it is retried by the recovery on next startup
+                log::warn!("Failed to send notification: {}", e);
+                false
+            }
+        }
+    }
+
+    // With strict ordering a notification waits behind the failed ones of its
+    // sender, and a failed one holds back the later ones
+    async fn notify_in_order(&self, notification: QueuedNotification) -> bool {
+        let QueuedNotification {
+            id,
+            sender,
+            content,
+            title,
+            body,
+            group,
+        } = &notification;
+        let Some(queues) = &self.sender_queues else {
+            return self
+                .notify_sms(id, sender, content, title, body, group.clone())
+                .await;
+        };
 
-                self.db
-                    .insert_sms(&sms_msg)
-                    .context("Failed to insert SMS into database")?;
+        let waiting = queues.waiting(sender);
+        if waiting > 0 {
+            log::info!(
+                "Holding notification of {} behind {} earlier one(s) from {}",
+                id,
+                waiting,
+                sender
+            );
+            queues.push(notification);
+            return false;
+        }
+        let notified = self
+            .notify_sms(id, sender, content, title, body, group.clone())
+            .await;
+        if !notified {
+            log::info!(
+                "Holding later notifications from {} until {} is delivered",
+                sender,
+                id
+            );
+            queues.push(notification);
+            *self.held_back_retry_at.lock().unwrap() =
+                Some(Instant::now() + HELD_BACK_RETRY_INTERVAL);
+        }
+        notified
+    }
 
-                // Send notification
-                let title = format!("SMS from {}", payload.sender);
-                let content = &payload.content;
+    // Retries the first notification of every sender queue, then the ones
+    // behind it as long as they get through
+    async fn retry_held_back(&self) {
+        let Some(queues) = &self.sender_queues else {
+            return;
+        };
+        if queues.is_empty() || self.notifications_paused() {
+            return;
+        }
+        {
+            let mut retry_at = self.held_back_retry_at.lock().unwrap();
+            if retry_at.is_some_and(|at| at > Instant::now()) {
+                return;
+            }
+            *retry_at = Some(Instant::now() + HELD_BACK_RETRY_INTERVAL);
+        }
 
-                if let Err(e) = self.notifier.send(&title, content).await {
-                    log::warn!("Failed to send notification: {}", e);
-                    // Don't fail the whole process if notification fails
+        for sender in queues.senders() {
+            while let Some(queued) = queues.front(&sender) {
+                self.heartbeat.beat(Duration::ZERO);
+                let notified = self
+                    .notify_sms(
+                        &queued.id,
+                        &queued.sender,
+                        &queued.content,
+                        &queued.title,
+                        &queued.body,
+                        queued.group,
+                    )
+                    .await;
+                if !notified {
+                    break;
                 }
+                queues.pop(&sender);
+            }
+        }
+    }
 
-                // Send acknowledgment
-                serial_port::send_ack(writer, &msg.id)
-                    .await
-                    .context("Failed to send ACK")?;
+    // Also used for skipped notifications, so the recovery doesn't send them later
+    fn mark_notified(&self, id: &str) {
+        if let Err(e) = self.db.mark_notified(id) {
+            log::warn!("Failed to record notification of {}: {}", id, e);
+            return;
+        }
+        self.queue_receipt(id);
+    }
+
+    fn queue_receipt(&self, id: &str) {
+        if self.read_receipts {
+            self.pending_receipts.lock().unwrap().push(id.to_string());
+        }
+    }
 
-                // Mark as acknowledged in database
-                self.db
-                    .mark_acknowledged(&msg.id)
-                    .context("Failed to mark message as acknowledged")?;
+    async fn send_receipts<W: AsyncWriteExt + Unpin>(&self, writer: &mut W) {
+        let ids = std::mem::take(&mut *self.pending_receipts.lock().unwrap());
+        for id in ids {
+            if let Err(e) = serial_port::send_read_receipt(writer, &id).await {
+                // The device drops its copy after a timeout when no receipt comes
+                log::warn!("Failed to send read receipt for {}: {}", id, e);
             }
-            MessageType::DeviceInfo(info) => {
-                log::info!(
-                    "Device info - IMEI: {}, Number: {}, Status: {}",
-                    info.imei,
-                    info.number,
-                    info.status
-                );
+        }
+    }
+
+    fn notified_recently(&self, sender: &str, content: &str) -> bool {
+        if self.dedup_window_secs == 0 {
+            return false;
+        }
+        let since = database::unix_timestamp() - self.dedup_window_secs as i64;
+        self.db
+            .notified_since(sender, content, since)
+            .unwrap_or_else(|e| {
+                log::warn!("{:#}", e);
+                false
+            })
+    }
+
+    fn annotate(&self, event: &str, text: String) {
+        if let Some(grafana) = &self.grafana {
+            grafana.annotate(self.device_label(), event, text);
+        }
+    }
+
+    async fn notify_event(&self, title_key: &str, body_key: &str, args: &[(&str, &str)]) {
+        let Some(notifier) = &self.event_notifier else {
+            return;
+        };
+
+        let device = self.notified_device();
+        let mut args = args.to_vec();
+        args.push(("device", &device));
+
+        let title = self.translations.text(title_key, &args);
+        let body = self.translations.text(body_key, &args);
+        if let Err(e) = notifier.send(&title, &body).await {
+            log::warn!("Failed to send connection event notification: {}", e);
+        }
+    }
+
+    // Names the device in notifications, the port when it has no id
+    fn notified_device(&self) -> String {
+        self.device_id
+            .clone()
+            .unwrap_or_else(|| self.config.port_label())
+    }
+
+    // A flooding device is an operational alert when those are set up, a
+    // connection event otherwise
+    async fn notify_flood(&self, reason: &str) {
+        if self.ops_alerts.enabled() {
+            self.ops_alerts
+                .send(
+                    OpsAlert::Flood,
+                    Some(&self.notified_device()),
+                    &[("reason", reason)],
+                )
+                .await;
+        } else {
+            self.notify_event("flood_title", "flood_body", &[("reason", reason)])
+                .await;
+        }
+    }
+
+    // Runs until the connection fails, or returns the admin command that ended it
+    // Runs a session on `port` and hands it back however the session ended, so
+    // the caller decides when the device is closed
+    async fn run_session(&mut self, port: SerialStream) -> (Result<SessionEnd>, SerialStream) {
+        let (reader, writer) = tokio::io::split(port);
+        let mut reader = BufReader::new(reader);
+        let mut writer = CountingWriter::new(writer);
+        let end = self.handle_messages(&mut reader, &mut writer).await;
+        // Unsplitting needs both halves back, none of them outlives the session
+        (end, reader.into_inner().unsplit(writer.into_inner()))
+    }
+
+    async fn handle_messages(
+        &mut self,
+        reader: &mut SessionReader,
+        writer: &mut SessionWriter,
+    ) -> Result<SessionEnd> {
+        let mut last_saved = Instant::now();
+        let mut last_data = Instant::now();
+        let silence_window = Duration::from_secs(self.config.silence_window_secs);
+        let mut silent_windows = 0;
+        let mut last_storage_check: Option<Instant> = None;
+        let mut frame = Vec::new();
+        let mut flood_guard = FloodGuard::new(self.config.max_frames_per_second);
+        let mut parse_failures = self.ops_alerts.parse_failure_window();
+        let mut info_refresh = InfoRefresh::new();
+
+        // Send initial GET_DEVICE_INFO command to verify connection
+        log::info!("Sending GET_DEVICE_INFO command to device...");
+        if let Err(e) = writer
+            .write_all(serial_port::handshake_command(self.config.compression))
+            .await
+        {
+            log::error!("Failed to send GET_DEVICE_INFO command: {}", e);
+        } else {
+            log::info!("GET_DEVICE_INFO command sent successfully");
+        }
+        if self.read_receipts
+            && let Err(e) = serial_port::send_read_receipts_on(writer).await
+        {
+            log::warn!("Failed to send READ_RECEIPTS command: {}", e);
+        }
+        if self.ack_requests
+            && let Err(e) = serial_port::send_ack_requests_on(writer).await
+        {
+            log::warn!("Failed to send ACK_REQUESTS command: {}", e);
+        }
+        self.acks_sent.lock().unwrap().clear();
+
+        log::info!("Message handling loop started, waiting for data...");
+
+        loop {
+            // Nothing read may be left unhandled, the rest waits in the kernel buffer
+            if handover::requested() && frame.is_empty() && reader.buffer().is_empty() {
+                self.save_status();
+                return Ok(SessionEnd::Handover);
+            }
+            self.heartbeat.beat(OUTBOUND_POLL_INTERVAL);
+            self.stats.lock().unwrap().bytes_written = writer.written();
+            if last_saved.elapsed() >= STATS_SAVE_INTERVAL {
+                self.save_status();
+                last_saved = Instant::now();
+            }
+            let commands = self.take_admin_commands();
+            if commands.contains(&AdminCommand::ReplayAcks) {
+                self.replay_acks(writer).await;
+            }
+            if commands.contains(&AdminCommand::Renotify) {
+                self.renotify_unacknowledged().await;
+            }
+            // Several queued requests amount to one, a rescan covers a reconnect
+            if let Some(command) = commands
+                .into_iter()
+                .filter(AdminCommand::ends_session)
+                .max_by_key(|command| *command == AdminCommand::Rescan)
+            {
+                return Ok(SessionEnd::Requested(command));
+            }
+            if self.maintenance_active() {
+                // Stop reading before anything else is ACKed, the device keeps it
+                return Ok(SessionEnd::Maintenance);
+            }
+            self.send_queued(writer).await;
+            self.send_receipts(writer).await;
+            self.sync_pause().await;
+            self.retry_held_back().await;
+            if self.storage_check_due(last_storage_check) {
+                if let Err(e) = serial_port::send_list_stored(writer, None).await {
+                    log::warn!("Failed to send LIST_STORED command: {}", e);
+                }
+                last_storage_check = Some(Instant::now());
+            }
+            self.check_network().await;
+            self.refresh_device_info(writer, &mut info_refresh).await?;
+
+            // Wake up regularly to check the outbound queue
+            let read_result = tokio::time::timeout(
+                OUTBOUND_POLL_INTERVAL,
+                serial_port::read_frame(reader, &mut frame, self.config.max_frame_bytes),
+            )
+            .await;
+            // A partial frame stays in `frame` across timeouts and is completed
+            // by the next read, unless the device sent it without a newline
+            let read_result = match read_result {
+                Err(_) if serial_port::is_unterminated_frame(&frame) => {
+                    log::debug!("Taking a frame that arrived without a newline");
+                    frame.push(b'\n');
+                    Ok(Ok(FrameRead::Frame))
+                }
+                other => other,
+            };
+
+            match read_result {
+                Ok(Ok(FrameRead::Eof)) => {
+                    log::warn!("Connection closed (EOF)");
+                    self.closed_by_device = true;
+                    anyhow::bail!("Connection closed");
+                }
+                Ok(Ok(FrameRead::Oversized(bytes_read))) => {
+                    {
+                        let mut stats = self.stats.lock().unwrap();
+                        stats.bytes_read += bytes_read as u64;
+                        stats.dropped_bytes += bytes_read as u64;
+                    }
+                    flood_guard.record_oversized();
+                    log::warn!(
+                        "Dropped oversized frame of {} bytes (limit {}, {} dropped so far)",
+                        bytes_read,
+                        self.config.max_frame_bytes,
+                        flood_guard.oversized_total()
+                    );
+                    if flood_guard.should_alert() {
+                        self.notify_flood(&format!("{} byte frame", bytes_read))
+                            .await;
+                    }
+                }
+                Ok(Ok(FrameRead::Frame)) => {
+                    last_data = Instant::now();
+                    silent_windows = 0;
+                    // The line break of a frame taken without it, or a "\n\r" ending
+                    if frame.iter().all(|b| matches!(b, b'\r' | b'\n')) {
+                        self.stats.lock().unwrap().bytes_read += frame.len() as u64;
+                        continue;
+                    }
+                    {
+                        let mut stats = self.stats.lock().unwrap();
+                        stats.frames_received += 1;
+                        stats.bytes_read += frame.len() as u64;
+                    }
+                    if let Admission::Throttled { first_in_window } = flood_guard.admit() {
+                        self.stats.lock().unwrap().dropped_bytes += frame.len() as u64;
+                        if first_in_window {
+                            log::warn!(
+                                "Device exceeds {} frames per second, dropping frames ({} dropped so far)",
+                                self.config.max_frames_per_second,
+                                flood_guard.throttled_total()
+                            );
+                            if flood_guard.should_alert() {
+                                self.notify_flood(&format!(
+                                    "more than {} frames per second",
+                                    self.config.max_frames_per_second
+                                ))
+                                .await;
+                            }
+                        }
+                        continue;
+                    }
+
+                    // Noise on the line shows up as invalid UTF-8, the frame is
+                    // still tried with the bytes replaced rather than dropped
+                    let line = String::from_utf8_lossy(&frame);
+                    if let Cow::Owned(_) = line {
+                        self.stats.lock().unwrap().invalid_utf8_frames += 1;
+                        log::warn!("Frame with invalid UTF-8: {:?}", frame);
+                    }
+                    let line: &str = &line;
+                    log::info!("Received {} bytes: '{}'", frame.len(), line.trim());
+                    log::debug!("Raw bytes: {:?}", line.as_bytes());
+
+                    if let Some(id) = serial_port::parse_ack_request(line) {
+                        self.answer_ack_request(writer, id).await;
+                        continue;
+                    }
+
+                    let Some(msg) = serial_port::parse_message(line) else {
+                        {
+                            let mut stats = self.stats.lock().unwrap();
+                            stats.parse_failures += 1;
+                            stats.dropped_bytes += frame.len() as u64;
+                            // Not even the frame around the payload could be read
+                            if serial_port::decode_frame(line).is_none() {
+                                stats.framing_errors += 1;
+                            }
+                        }
+                        log::warn!("Failed to parse message: '{}'", line.trim());
+                        log::warn!("Raw bytes: {:?}", frame);
+                        if let Some(count) = parse_failures.record() {
+                            let window = parse_failures.window().as_secs().to_string();
+                            self.ops_alerts
+                                .send(
+                                    OpsAlert::ParseFailures,
+                                    Some(&self.notified_device()),
+                                    &[
+                                        ("count", &count.to_string()),
+                                        ("window", &window),
+                                        ("frame", line.trim()),
+                                    ],
+                                )
+                                .await;
+                        }
+                        continue;
+                    };
+
+                    // Replies to requests made here are taken by them
+                    match self.correlator.resolve(msg) {
+                        Some(msg) => {
+                            log::info!("Successfully parsed message with ID: {}", msg.id);
+                            if let Err(e) = self.process_message(msg, writer).await {
+                                if is_fatal(&e) {
+                                    return Err(e);
+                                }
+                                log::error!("Failed to process message: {}", e);
+                                if is_database_error(&e) {
+                                    self.ops_alerts
+                                        .send(
+                                            OpsAlert::DatabaseError,
+                                            Some(&self.notified_device()),
+                                            &[("error", &format!("{:#}", e))],
+                                        )
+                                        .await;
+                                }
+                                // Continue processing other messages
+                            }
+                        }
+                        None => log::debug!("Reply handed to the request waiting for it"),
+                    }
+                }
+                Ok(Err(e)) => {
+                    log::error!("Read error: {}", e);
+                    anyhow::bail!("Read error: {}", e);
+                }
+                Err(_) => {
+                    // Timeout - no data received
+                    if last_data.elapsed() < silence_window {
+                        continue;
+                    }
+                    last_data = Instant::now();
+                    silent_windows += 1;
+                    let silent_secs = silent_windows as u64 * self.config.silence_window_secs;
+                    let reconnect_after = self.config.reconnect_after_windows;
+                    let probe_after = self.config.probe_after_windows;
+                    if reconnect_after > 0 && silent_windows >= reconnect_after {
+                        log::warn!("No data received in {}s, reconnecting", silent_secs);
+                        anyhow::bail!("Device silent for {}s", silent_secs);
+                    }
+                    if probe_after > 0 && silent_windows >= probe_after {
+                        // The device answers with DEVICE_INFO if its script still runs
+                        log::warn!(
+                            "No data received in {}s, sending GET_DEVICE_INFO",
+                            silent_secs
+                        );
+                        if let Err(e) = writer
+                            .write_all(serial_port::handshake_command(self.config.compression))
+                            .await
+                        {
+                            log::warn!("Failed to send GET_DEVICE_INFO command: {}", e);
+                        }
+                    } else {
+                        log::info!(
+                            "No data received in last {}s, still waiting...",
+                            silent_secs
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // Hand the next queued SMS to the device, one at a time so SMS_SENT replies
+    // without a reference from older scripts cannot be confused
+    async fn send_queued<W: AsyncWriteExt + Unpin>(&self, writer: &mut W) {
+        let in_flight = *self.outbound_in_flight.lock().unwrap();
+        if let Some((id, since)) = in_flight {
+            if since.elapsed() < OUTBOUND_REPLY_TIMEOUT {
+                return;
+            }
+            log::warn!(
+                "No SMS_SENT reply for outbound SMS {}, marking it failed",
+                id
+            );
+            self.finish_outbound(id, false, Some("no reply from the device"));
+        }
+
+        let now = database::unix_timestamp();
+        let held = |recipient: &str| self.quiet_hours.holds(recipient, now);
+        let sms = match self.db.claim_outbound(self.device_id.as_deref(), held) {
+            Ok(Some(sms)) => sms,
+            Ok(None) => return,
+            Err(e) => {
+                log::warn!("Failed to read the outbound queue: {}", e);
+                return;
+            }
+        };
+        match opt_out::opted_out(&self.db, &sms.recipient) {
+            Ok(false) => {}
+            Ok(true) => {
+                log::info!(
+                    "Not sending queued SMS {}: {} opted out",
+                    sms.id,
+                    sms.recipient
+                );
+                self.finish_outbound(sms.id, false, Some("recipient opted out"));
+                return;
+            }
+            Err(e) => log::warn!("{:#}", e),
+        }
+
+        log::info!(
+            "Sending queued SMS {} ({}) to {}",
+            sms.id,
+            sms.batch_id.as_deref().unwrap_or("single"),
+            sms.recipient
+        );
+        let payload = SendSmsPayload {
+            to: sms.recipient.clone(),
+            content: sms.content,
+            reference: Some(sms.id.to_string()),
+            rid: None,
+        };
+        match serial_port::send_sms(writer, &payload).await {
+            Ok(()) => *self.outbound_in_flight.lock().unwrap() = Some((sms.id, Instant::now())),
+            Err(e) => {
+                log::warn!("Failed to send queued SMS to {}: {}", sms.recipient, e);
+                self.finish_outbound(sms.id, false, Some(&e.to_string()));
+            }
+        }
+    }
+
+    fn notifications_paused(&self) -> bool {
+        self.paused_since.lock().unwrap().is_some()
+    }
+
+    // Pick up `pause-notifications` and `resume-notifications`, sending the
+    // notifications held during the pause once it ends
+    async fn sync_pause(&self) {
+        let current = match self.db.notifications_paused_since() {
+            Ok(current) => current,
+            Err(e) => {
+                log::warn!("Failed to check whether notifications are paused: {}", e);
+                return;
+            }
+        };
+        let previous = std::mem::replace(&mut *self.paused_since.lock().unwrap(), current);
+        match (previous, current) {
+            (None, Some(_)) => {
+                log::warn!("Notifications paused, SMS are still stored and acknowledged")
+            }
+            (Some(since), None) => {
+                log::info!("Notifications resumed");
+                self.send_held_notifications(since).await;
+            }
+            _ => {}
+        }
+    }
+
+    async fn send_held_notifications(&self, since: i64) {
+        let held = match self.db.unnotified_since(self.device_id.as_deref(), since) {
+            Ok(held) => held,
+            Err(e) => {
+                log::warn!("Failed to look up held notifications: {}", e);
+                return;
+            }
+        };
+        if held.is_empty() {
+            return;
+        }
+
+        log::info!(
+            "Sending {} notification(s) held during the pause",
+            held.len()
+        );
+        for msg in held {
+            self.heartbeat.beat(Duration::ZERO);
+            let (title, body) = self.notification_text(
+                &msg.sender,
+                &msg.content,
+                msg.received_at,
+                msg.message_class,
+            );
+            let sender = self.senders.classify(&msg.sender);
+            let group = self
+                .rules
+                .group(&msg.sender, &sender, &msg.content, msg.message_class);
+            self.notify_in_order(QueuedNotification {
+                id: msg.id,
+                sender: msg.sender,
+                content: msg.content,
+                title,
+                body,
+                group,
+            })
+            .await;
+        }
+    }
+
+    fn take_admin_commands(&self) -> Vec<AdminCommand> {
+        let commands = match self.db.take_admin_commands(self.device_label()) {
+            Ok(commands) => commands,
+            Err(e) => {
+                log::warn!("Failed to check admin commands: {}", e);
+                return Vec::new();
+            }
+        };
+        commands
+            .iter()
+            .filter_map(|command| {
+                let parsed = AdminCommand::parse(command);
+                if parsed.is_none() {
+                    log::warn!("Ignoring unknown admin command: {}", command);
+                }
+                parsed
+            })
+            .collect()
+    }
+
+    fn unacknowledged(&self) -> Vec<UnacknowledgedSms> {
+        self.db
+            .get_unacknowledged(self.device_id.as_deref())
+            .unwrap_or_else(|e| {
+                log::warn!("Failed to look up unacknowledged messages: {:#}", e);
+                Vec::new()
+            })
+    }
+
+    // Messages left without an ACK by an earlier run, the device retransmits
+    // them until `unacked replay-acks` or the next delivery acknowledges them
+    async fn report_unacknowledged(&self) {
+        let unacked = self.unacknowledged();
+        let Some(oldest) = unacked.first() else {
+            return;
+        };
+        let unnotified = unacked
+            .iter()
+            .filter(|unacked| unacked.notified_at.is_none() && !unacked.message.is_spam)
+            .count();
+        let oldest = self.time.display(oldest.created_at);
+        log::warn!(
+            "{} message(s) stored but never acknowledged to the device, oldest from {}, {} not notified",
+            unacked.len(),
+            oldest,
+            unnotified
+        );
+        self.notify_event(
+            "unacknowledged_title",
+            "unacknowledged_body",
+            &[
+                ("count", &unacked.len().to_string()),
+                ("unnotified", &unnotified.to_string()),
+                ("oldest", &oldest),
+            ],
+        )
+        .await;
+    }
+
+    // Skips an ACK sent for the same SMS moments ago, so resync storms don't
+    // double the traffic
+    async fn send_ack<W: AsyncWriteExt + Unpin>(
+        &self,
+        writer: &mut W,
+        id: &str,
+    ) -> std::io::Result<()> {
+        {
+            let mut sent = self.acks_sent.lock().unwrap();
+            sent.retain(|_, at| at.elapsed() < ACK_REPEAT_INTERVAL);
+            if sent.contains_key(id) {
+                log::debug!("ACK for {} was just sent, not repeating it", id);
+                return Ok(());
+            }
+            sent.insert(id.to_string(), Instant::now());
+        }
+        if let Err(e) = serial_port::send_ack(writer, id).await {
+            self.acks_sent.lock().unwrap().remove(id);
+            return Err(e);
+        }
+        self.stats.lock().unwrap().acks_sent += 1;
+        Ok(())
+    }
+
+    // The device got no ACK for `id`: ACK it when it is stored, otherwise ask
+    // for the whole SMS again
+    async fn answer_ack_request<W: AsyncWriteExt + Unpin>(&self, writer: &mut W, id: &str) {
+        let notified = match self.db.sms_notified(id) {
+            Ok(notified) => notified,
+            Err(e) => {
+                // The device asks again on its next retry
+                log::warn!("{:#}", e);
+                return;
+            }
+        };
+        let Some(notified) = notified else {
+            if let Err(e) = serial_port::send_resend(writer, id).await {
+                log::warn!("Failed to ask for SMS {} again: {}", id, e);
+            }
+            return;
+        };
+        log::info!("Device asked for the ACK of {}, which is stored", id);
+        if let Err(e) = self.send_ack(writer, id).await {
+            log::warn!("Failed to send ACK for {}: {}", id, e);
+            return;
+        }
+        if let Err(e) = self.db.mark_acknowledged(id) {
+            log::warn!("{:#}", e);
+        }
+        // Otherwise the read receipt follows the notification
+        if notified {
+            self.queue_receipt(id);
+        }
+    }
+
+    async fn replay_acks<W: AsyncWriteExt + Unpin>(&self, writer: &mut W) {
+        let unacked = self.unacknowledged();
+        log::info!("Replaying {} ACK(s)", unacked.len());
+        for unacked in unacked {
+            let id = &unacked.message.id;
+            if let Err(e) = self.send_ack(writer, id).await {
+                log::warn!("Failed to replay ACK for {}: {}", id, e);
+                return;
+            }
+            if let Err(e) = self.db.mark_acknowledged(id) {
+                log::warn!("{:#}", e);
+            }
+            // Otherwise the read receipt follows the notification
+            if unacked.notified_at.is_some() || unacked.message.is_spam {
+                self.queue_receipt(id);
+            }
+        }
+    }
+
+    async fn renotify_unacknowledged(&self) {
+        let unacked = self.unacknowledged();
+        log::info!(
+            "Notifying {} unacknowledged message(s) again",
+            unacked.len()
+        );
+        for msg in unacked.into_iter().map(|unacked| unacked.message) {
+            if msg.is_spam {
+                continue;
+            }
+            self.heartbeat.beat(Duration::ZERO);
+            let (title, body) = self.notification_text(
+                &msg.sender,
+                &msg.content,
+                msg.received_at,
+                msg.message_class,
+            );
+            let sender = self.senders.classify(&msg.sender);
+            let group = self
+                .rules
+                .group(&msg.sender, &sender, &msg.content, msg.message_class);
+            self.notify_sms(&msg.id, &msg.sender, &msg.content, &title, &body, group)
+                .await;
+        }
+    }
+
+    fn maintenance_active(&self) -> bool {
+        match self.db.maintenance_since() {
+            Ok(since) => since.is_some(),
+            Err(e) => {
+                log::warn!("Failed to check maintenance mode: {}", e);
+                false
+            }
+        }
+    }
+
+    // Keep the serial port closed while maintenance mode is on
+    async fn wait_maintenance(&mut self) {
+        if !self.maintenance_active() {
+            return;
+        }
+
+        self.set_state(ConnectionState::Maintenance);
+        self.save_status();
+        log::warn!("Maintenance mode, the serial port stays closed until it ends");
+        while self.maintenance_active() {
+            tokio::time::sleep(OUTBOUND_POLL_INTERVAL).await;
+        }
+
+        log::info!("Maintenance ended, reconnecting and importing buffered SMS");
+        *self.resync_pending.lock().unwrap() = true;
+        self.set_state(ConnectionState::Reconnecting { attempts: 0 });
+    }
+
+    fn storage_check_due(&self, last_check: Option<Instant>) -> bool {
+        if !self.storage_supported {
+            return false;
+        }
+        // A resync after maintenance does not wait for the regular check
+        if last_check.is_none() && *self.resync_pending.lock().unwrap() {
+            return true;
+        }
+        let interval = Duration::from_secs(self.storage.check_interval_minutes * 60);
+        !interval.is_zero() && last_check.is_none_or(|at| at.elapsed() >= interval)
+    }
+
+    // Drop SMS the device still keeps although they are stored here, and alert
+    // before its queue fills up
+    async fn review_stored<W: AsyncWriteExt + Unpin>(
+        &self,
+        stored: StoredSmsPayload,
+        writer: &mut W,
+    ) -> Result<()> {
+        log::info!(
+            "Device keeps {} SMS awaiting ACK (capacity {})",
+            stored.messages.len(),
+            stored.capacity
+        );
+
+        let imported = if std::mem::take(&mut *self.resync_pending.lock().unwrap()) {
+            self.import_stored(&stored.messages, writer).await?
+        } else {
+            Vec::new()
+        };
+        let mut remaining = stored.messages.len() - imported.len();
+        if self.storage.delete_processed {
+            let mut processed = Vec::new();
+            // Imported SMS are removed by their ACK or read receipt
+            for sms in stored
+                .messages
+                .iter()
+                .filter(|sms| !imported.contains(&sms.id))
+            {
+                // ACKed SMS wait for their notification, unless the receipt was lost
+                let done = if sms.acked {
+                    self.db.sms_settled(&sms.id)?
+                } else {
+                    self.db.contains_sms(&sms.id)?
+                };
+                if done {
+                    processed.push(sms.id.clone());
+                }
+            }
+            if !processed.is_empty() {
+                log::info!(
+                    "Deleting {} SMS from the device that are already in the database",
+                    processed.len()
+                );
+                remaining -= processed.len();
+                let command = DeleteStoredCommand {
+                    ids: processed,
+                    all: false,
+                    rid: None,
+                };
+                serial_port::send_delete_stored(writer, &command)
+                    .await
+                    .context("Failed to send DELETE_STORED command")?;
+            }
+        }
+
+        let threshold = self.storage.alert_percent;
+        let full = threshold > 0 && remaining * 100 >= threshold * stored.capacity;
+        let alerted = std::mem::replace(&mut *self.storage_alerted.lock().unwrap(), full);
+        if full && !alerted {
+            log::warn!(
+                "Device SMS queue is {}/{} full, new SMS are no longer kept once it is full",
+                remaining,
+                stored.capacity
+            );
+            self.notify_event(
+                "storage_full_title",
+                "storage_full_body",
+                &[
+                    ("count", &remaining.to_string()),
+                    ("capacity", &stored.capacity.to_string()),
+                ],
+            )
+            .await;
+        }
+        Ok(())
+    }
+
+    // Take over the SMS buffered during maintenance now instead of waiting for
+    // the device's next retry, ACKing them also removes them from its queue
+    async fn import_stored<W: AsyncWriteExt + Unpin>(
+        &self,
+        messages: &[StoredSms],
+        writer: &mut W,
+    ) -> Result<Vec<String>> {
+        let mut imported = Vec::new();
+        for sms in messages {
+            if self.db.contains_sms(&sms.id)? {
+                continue;
+            }
+            let payload = SmsPayload {
+                id: sms.id.clone(),
+                sender: sms.sender.clone(),
+                content: sms.content.clone(),
+                received_at: sms.received_at,
+                metas: None,
+                raw_payload: None,
+            };
+            self.receive_sms(payload, &sms.id, writer).await?;
+            imported.push(sms.id.clone());
+        }
+        if !imported.is_empty() {
+            log::info!(
+                "Imported {} SMS buffered by the device during maintenance",
+                imported.len()
+            );
+        }
+        Ok(imported)
+    }
+
+    async fn record_network(&self, status: &NetStatusPayload) {
+        let state = network::registration_name(status.status);
+        let operator = status.operator.as_deref().unwrap_or("unknown operator");
+        if network::is_registered(status.status) {
+            log::info!(
+                "Network: {} to {} ({}), rssi {}",
+                state,
+                operator,
+                status.network.as_deref().unwrap_or("unknown type"),
+                status.rssi.unwrap_or_default()
+            );
+        } else {
+            log::warn!("Network: {}", state);
+        }
+
+        let record = NetworkRecord {
+            device_id: self.device_label().to_string(),
+            status: status.status,
+            operator: status.operator.clone(),
+            network: status.network.clone(),
+            rssi: status.rssi,
+            updated_at: database::unix_timestamp(),
+        };
+        if let Err(e) = self.db.save_network_status(&record) {
+            log::warn!("Failed to store network status: {}", e);
+        }
+
+        if let (Some(tx), Some(rx)) = (status.data_tx_bytes, status.data_rx_bytes) {
+            match self.db.advance_data_counters(self.device_label(), tx, rx) {
+                Ok((0, 0)) => {}
+                Ok((tx, rx)) => self.record_usage(0, tx, rx).await,
+                Err(e) => log::warn!("Failed to store data counters: {}", e),
+            }
+        }
+
+        if let Some(current) = &status.operator {
+            self.record_operator(current, network::is_roaming(status.status))
+                .await;
+        }
+
+        let restored = self.registration.lock().unwrap().update(status.status);
+        if let Some(outage) = restored {
+            let duration = format!("{}s", outage.as_secs());
+            self.notify_event(
+                "network_restored_title",
+                "network_restored_body",
+                &[("duration", &duration), ("operator", operator)],
+            )
+            .await;
+        }
+    }
+
+    // Count usage towards the current billing cycle and alert once per cycle
+    // when it nears a plan limit
+    async fn record_usage(&self, sms_sent: u64, data_tx_bytes: u64, data_rx_bytes: u64) {
+        let cycle = self.usage.cycle_start(database::unix_timestamp());
+        let usage = match self.db.add_usage(
+            self.device_label(),
+            &cycle,
+            sms_sent,
+            data_tx_bytes,
+            data_rx_bytes,
+        ) {
+            Ok(usage) => usage,
+            Err(e) => {
+                log::warn!("Failed to record usage: {}", e);
+                return;
+            }
+        };
+
+        for (limit, used, allowed) in self.usage.due_alerts(&usage) {
+            if let Err(e) = self
+                .db
+                .mark_usage_alerted(self.device_label(), &cycle, limit)
+            {
+                log::warn!("Failed to record the usage alert: {}", e);
+                continue;
+            }
+            let percent = (used * 100 / allowed).to_string();
+            let (kind, used, allowed) = match limit {
+                UsageLimit::Sms => ("sms", used.to_string(), allowed.to_string()),
+                UsageLimit::Data => ("data", usage::mib(used), usage::mib(allowed)),
+            };
+            log::warn!(
+                "Usage since {} reached {}% of the {} limit: {} of {}",
+                cycle,
+                percent,
+                kind,
+                used,
+                allowed
+            );
+            self.notify_event(
+                &format!("usage_{}_title", kind),
+                &format!("usage_{}_body", kind),
+                &[
+                    ("used", &used),
+                    ("limit", &allowed),
+                    ("percent", &percent),
+                    ("cycle", &cycle),
+                ],
+            )
+            .await;
+        }
+    }
+
+    // Record operator and roaming transitions, a roaming data SIM gets expensive
+    async fn record_operator(&self, operator: &str, roaming: bool) {
+        let previous = match self.db.last_network_event(self.device_label()) {
+            Ok(previous) => previous,
+            Err(e) => {
+                log::warn!("Failed to look up the previous operator: {}", e);
+                return;
+            }
+        };
+        if previous
+            .as_ref()
+            .is_some_and(|previous| previous.operator == operator && previous.roaming == roaming)
+        {
+            return;
+        }
+
+        let event = NetworkEvent {
+            device_id: self.device_label().to_string(),
+            operator: operator.to_string(),
+            roaming,
+            previous_operator: previous.as_ref().map(|previous| previous.operator.clone()),
+            previous_roaming: previous.as_ref().map(|previous| previous.roaming),
+            changed_at: database::unix_timestamp(),
+        };
+        if let Err(e) = self.db.insert_network_event(&event) {
+            log::warn!("Failed to store network event: {}", e);
+        }
+
+        // Nothing to compare the first registration with
+        let Some(previous) = previous else {
+            return;
+        };
+        log::warn!(
+            "Operator changed from {}{} to {}{}",
+            previous.operator,
+            if previous.roaming { " (roaming)" } else { "" },
+            operator,
+            if roaming { " (roaming)" } else { "" }
+        );
+
+        let args = [
+            ("operator", operator),
+            ("previous", previous.operator.as_str()),
+        ];
+        if roaming && !previous.roaming && self.network.notify_roaming {
+            self.notify_event("roaming_title", "roaming_body", &args)
+                .await;
+        } else if self.network.notify_operator_change {
+            self.notify_event("operator_changed_title", "operator_changed_body", &args)
+                .await;
+        }
+    }
+
+    async fn check_network(&self) {
+        let (overdue, status) = {
+            let mut registration = self.registration.lock().unwrap();
+            (
+                registration.overdue(Duration::from_secs(self.network.lost_alert_minutes * 60)),
+                registration.status(),
+            )
+        };
+        let Some(outage) = overdue else {
+            return;
+        };
+
+        let duration = format!("{}s", outage.as_secs());
+        let status = network::registration_name(status);
+        log::warn!(
+            "Module has had no network registration for {} ({})",
+            duration,
+            status
+        );
+        self.notify_event(
+            "network_lost_title",
+            "network_lost_body",
+            &[("duration", &duration), ("status", status)],
+        )
+        .await;
+    }
+
+    fn finish_outbound(&self, id: i64, sent: bool, error: Option<&str>) {
+        let mut in_flight = self.outbound_in_flight.lock().unwrap();
+        if in_flight.is_some_and(|(current, _)| current == id) {
+            *in_flight = None;
+        }
+        if let Err(e) = self.db.finish_outbound(id, sent, error) {
+            log::warn!("Failed to update outbound SMS {}: {}", id, e);
+        }
+    }
+
+    // Configured device id, "default" for a single unnamed device
+    fn device_label(&self) -> &str {
+        self.device_id.as_deref().unwrap_or("default")
+    }
+
+    fn handle_device_info(&self, info: &DeviceInfoPayload) -> Result<()> {
+        log::info!(
+            "Device info - IMEI: {}, Number: {}, Status: {}",
+            info.imei,
+            info.number,
+            info.status
+        );
+        if let Some(compression) = &info.compression {
+            log::info!("Device compresses large payloads with {}", compression);
+        }
+        self.record_device_info(info);
+        self.check_script_version(info)
+    }
+
+    // Ask for DEVICE_INFO every `device_info_refresh_secs`, re-sending the
+    // request when the reply does not come
+    async fn refresh_device_info<W: AsyncWriteExt + Unpin>(
+        &self,
+        writer: &mut W,
+        refresh: &mut InfoRefresh,
+    ) -> Result<()> {
+        let interval = Duration::from_secs(self.config.device_info_refresh_secs);
+        if interval.is_zero() {
+            return Ok(());
+        }
+        let attempt = match &mut refresh.pending {
+            Some((request, sent_at, attempt)) => {
+                if let Some(reply) = request.try_take() {
+                    refresh.finish();
+                    return match reply {
+                        MessageType::DeviceInfo(info) => self.handle_device_info(&info),
+                        other => {
+                            log::warn!("Unexpected reply to GET_DEVICE_INFO: {:?}", other);
+                            Ok(())
+                        }
+                    };
+                }
+                if sent_at.elapsed() < DEVICE_INFO_REPLY_TIMEOUT {
+                    return Ok(());
+                }
+                if *attempt >= DEVICE_INFO_ATTEMPTS {
+                    log::warn!(
+                        "No reply to GET_DEVICE_INFO after {} attempts, trying again in {}s",
+                        attempt,
+                        interval.as_secs()
+                    );
+                    refresh.finish();
+                    return Ok(());
+                }
+                *attempt + 1
+            }
+            None if refresh.last_refresh.elapsed() < interval => return Ok(()),
+            None => 1,
+        };
+
+        let request = self.correlator.request("GET_DEVICE_INFO", "DEVICE_INFO");
+        // Older scripts would take the JSON argument for a refused compression offer
+        let rid = self.correlation_supported.then_some(request.rid.as_str());
+        log::debug!("Refreshing device info (attempt {})", attempt);
+        if let Err(e) =
+            serial_port::send_device_info_request(writer, self.config.compression, rid).await
+        {
+            log::warn!("Failed to send GET_DEVICE_INFO command: {}", e);
+        }
+        refresh.pending = Some((request, Instant::now(), attempt));
+        Ok(())
+    }
+
+    fn record_device_info(&self, info: &DeviceInfoPayload) {
+        log::info!(
+            "Device firmware: {}, script version: {}",
+            info.firmware.as_deref().unwrap_or("unknown"),
+            info.script_version.as_deref().unwrap_or("unknown")
+        );
+
+        self.check_sim_swap(&info.iccid);
+        let record = DeviceRecord {
+            device_id: self.device_label().to_string(),
+            imei: info.imei.clone(),
+            number: info.number.clone(),
+            iccid: info.iccid.clone(),
+            firmware: info.firmware.clone(),
+            script_version: info.script_version.clone(),
+            updated_at: database::unix_timestamp(),
+        };
+        if let Err(e) = self.db.upsert_device(&record) {
+            log::warn!("Failed to store device info: {}", e);
+        }
+    }
+
+    // A different ICCID than last reported means another SIM is in the module
+    fn check_sim_swap(&self, iccid: &str) {
+        let previous = match self.db.devices() {
+            Ok(devices) => devices
+                .into_iter()
+                .find(|device| device.device_id == self.device_label())
+                .map(|device| device.iccid),
+            Err(e) => {
+                log::warn!("{:#}", e);
+                return;
+            }
+        };
+        let Some(previous) = previous.filter(|previous| previous != iccid) else {
+            return;
+        };
+        let text = match (previous.is_empty(), iccid.is_empty()) {
+            (true, _) => format!("SIM {} inserted", iccid),
+            (_, true) => format!("SIM {} removed", previous),
+            _ => format!("SIM swapped from {} to {}", previous, iccid),
+        };
+        log::warn!("{}", text);
+        self.annotate("sim_swap", text);
+    }
+
+    // Keeps a multi-modem host from serving messages of the wrong SIM under this device
+    fn check_identity(&self, info: &DeviceInfoPayload) -> Result<()> {
+        let mut mismatches = Vec::new();
+        if let Some(imei) = &self.config.expected_imei
+            && info.imei != *imei
+        {
+            mismatches.push(format!("IMEI {} (expected {})", info.imei, imei));
+        }
+        if let Some(number) = &self.config.expected_number
+            && !forwarding::same_number(&info.number, number)
+        {
+            mismatches.push(format!("number {} (expected {})", info.number, number));
+        }
+        if mismatches.is_empty() {
+            return Ok(());
+        }
+
+        let message = format!(
+            "wrong device for {}: {}",
+            self.device_label(),
+            mismatches.join(", ")
+        );
+        if self.config.refuse_wrong_device {
+            return Err(AppError::Protocol(message).into());
+        }
+        log::warn!("{}", message);
+        Ok(())
+    }
+
+    // Scripts that predate version reporting count as outdated
+    fn check_script_version(&self, info: &DeviceInfoPayload) -> Result<()> {
+        let Some(minimum) = &self.config.min_script_version else {
+            return Ok(());
+        };
+
+        let outdated = match info
+            .script_version
+            .as_deref()
+            .and_then(serial_port::parse_version)
+        {
+            Some(version) => serial_port::parse_version(minimum).is_some_and(|min| version < min),
+            None => true,
+        };
+        if !outdated {
+            return Ok(());
+        }
+
+        let message = format!(
+            "device script version {} is older than the required {}, update the script on the device",
+            info.script_version.as_deref().unwrap_or("unknown"),
+            minimum
+        );
+        if self.config.refuse_outdated_script {
+            return Err(AppError::Protocol(message).into());
+        }
+        log::warn!("{}, messages may not be acknowledged reliably", message);
+        Ok(())
+    }
+
+    // `ack_id` is the id of the frame, the device sends SMS in frames with their SMS id
+    async fn receive_sms<W: AsyncWriteExt + Unpin>(
+        &self,
+        payload: SmsPayload,
+        ack_id: &str,
+        writer: &mut W,
+    ) -> Result<()> {
+        log::info!("SMS received from {}: {}", payload.sender, payload.content);
+        let read_at_ms = database::unix_timestamp_millis();
+
+        let class = payload.metas.as_ref().and_then(message_class::from_metas);
+        if let Some(class) = class {
+            log::info!(
+                "SMS {} is a {} message",
+                payload.id,
+                message_class::name(class)
+            );
+        }
+
+        // Classify before storing so the score is persisted with the message
+        let sender = self.senders.classify(&payload.sender);
+        let spam_score = self
+            .spam
+            .score(&self.db, &payload.sender, &payload.content)
+            .await;
+        let spam_rule = self
+            .rules
+            .matching(&payload.sender, &sender, &payload.content, class)
+            .find(|rule| rule.spam);
+        if let Some(rule) = spam_rule {
+            log::info!("Rule '{}': tagging {} as spam", rule.name, payload.id);
+        }
+        let is_spam =
+            spam_rule.is_some() || spam_score.is_some_and(|score| self.spam.is_spam(score));
+
+        // Store in database
+        let sms_msg = SmsMessage {
+            id: payload.id.clone(),
+            sender: payload.sender.clone(),
+            content: payload.content.clone(),
+            received_at: payload.received_at,
+            metas: serde_json::to_string(&payload.metas).unwrap_or_default(),
+            spam_score,
+            is_spam,
+            device_id: self.device_id.clone(),
+            sender_type: Some(sender.kind.as_str().to_string()),
+            sender_country: sender.country.clone(),
+            message_class: class,
+            encoding_suspect: payload.raw_payload.is_some(),
+        };
+        if sms_msg.encoding_suspect {
+            log::warn!(
+                "SMS {} from {} is not valid UTF-8, storing it with replacement characters and the original bytes",
+                payload.id,
+                payload.sender
+            );
+        }
+
+        let stored = self
+            .db
+            .insert_sms(&sms_msg, payload.raw_payload.as_deref())
+            .context("Failed to insert SMS into database")?;
+        if !stored {
+            // The device retransmits SMS whose ACK it never got, e.g. across a
+            // server restart. The first delivery was already handled
+            log::info!(
+                "SMS {} is already stored, acknowledging the retransmission",
+                payload.id
+            );
+            self.send_ack(writer, ack_id)
+                .await
+                .context("Failed to send ACK")?;
+            self.db
+                .mark_acknowledged(ack_id)
+                .context("Failed to mark message as acknowledged")?;
+            return Ok(());
+        }
+        let stored_at_ms = database::unix_timestamp_millis();
+        self.handle_opt_out(&payload.sender, &payload.content, writer)
+            .await;
+
+        let self_test = self_test::is_self_test(&payload.content);
+        // Spam and self-test SMS never trigger rule or hook actions
+        let (matched_rules, actions): (Vec<_>, _) = if is_spam || self_test {
+            (Vec::new(), HookActions::default())
+        } else {
+            (
+                self.rules
+                    .matching(&payload.sender, &sender, &payload.content, class)
+                    .collect(),
+                self.run_hooks(&payload, &sender, class).await,
+            )
+        };
+        let content = actions.content.as_deref().unwrap_or(&payload.content);
+
+        let mut notified_at_ms = None;
+        if self_test {
+            log::info!(
+                "Self-test SMS {} received from {}, not notified",
+                payload.id,
+                payload.sender
+            );
+            self.mark_notified(&payload.id);
+        } else if is_spam {
+            // Never notified, the device copy can go right away
+            self.queue_receipt(&payload.id);
+            log::info!(
+                "SMS {} from {} tagged as spam{}, notification suppressed",
+                payload.id,
+                payload.sender,
+                spam_score
+                    .map(|score| format!(" (score {:.2})", score))
+                    .unwrap_or_default()
+            );
+        } else if actions.suppress {
+            log::info!("Hook suppressed the notification of {}", payload.id);
+            self.mark_notified(&payload.id);
+        } else if self.notified_recently(&payload.sender, &payload.content) {
+            log::info!(
+                "Identical SMS from {} was notified within {}s, skipping notification of {}",
+                payload.sender,
+                self.dedup_window_secs,
+                payload.id
+            );
+            self.mark_notified(&payload.id);
+        } else if self.notifications_paused() {
+            // Sent without escalation once notifications are resumed
+            log::info!(
+                "Notifications paused, holding notification of {}",
+                payload.id
+            );
+        } else {
+            // Send notification
+            let (title, body) =
+                self.notification_text(&payload.sender, content, payload.received_at, class);
+            let group = actions.group.clone().or_else(|| {
+                self.rules
+                    .group(&payload.sender, &sender, &payload.content, class)
+            });
+            let notified = self
+                .notify_in_order(QueuedNotification {
+                    id: payload.id.clone(),
+                    sender: payload.sender.clone(),
+                    content: content.to_string(),
+                    title: title.clone(),
+                    body: body.clone(),
+                    group,
+                })
+                .await;
+            if notified {
+                notified_at_ms = Some(database::unix_timestamp_millis());
+            }
+            if notified && self.dedup_window_secs > 0 {
+                let prune_before = database::unix_timestamp() - self.dedup_window_secs as i64;
+                if let Err(e) =
+                    self.db
+                        .record_notification(&payload.sender, &payload.content, prune_before)
+                {
+                    log::warn!("{:#}", e);
+                }
+            }
+
+            // Keep re-sending critical messages until someone acknowledges them
+            if let Some(rule) = matched_rules.iter().find(|rule| rule.critical) {
+                log::info!("Rule '{}': escalating message {}", rule.name, payload.id);
+                self.escalator.start(&payload.id, &title, &body);
+            } else if actions.critical {
+                log::info!("Hook: escalating message {}", payload.id);
+                self.escalator.start(&payload.id, &title, &body);
+            }
+        }
+
+        // Send acknowledgment
+        self.send_ack(writer, ack_id)
+            .await
+            .context("Failed to send ACK")?;
+
+        let ack_sent_at_ms = database::unix_timestamp_millis();
+
+        // Mark as acknowledged in database
+        self.db
+            .mark_acknowledged(ack_id)
+            .context("Failed to mark message as acknowledged")?;
+
+        let timing = SmsTiming {
+            sms_id: payload.id.clone(),
+            device_id: self.device_label().to_string(),
+            received_at: payload.received_at,
+            read_at_ms,
+            stored_at_ms,
+            notified_at_ms,
+            ack_sent_at_ms,
+        };
+        if let Err(e) = self.db.insert_sms_timing(&timing) {
+            log::warn!("{:#}", e);
+        }
+
+        // Forward to other numbers according to matching rules
+        let hook_forwards =
+            self.forwarder
+                .plan_targets("hook", &actions.forward_to, &payload.sender, content);
+        let forwards = matched_rules
+            .into_iter()
+            .flat_map(|rule| {
+                self.forwarder
+                    .plan(rule, &payload.sender, content)
+                    .into_iter()
+                    .map(|outgoing| (rule.name.as_str(), outgoing))
+            })
+            .chain(hook_forwards.into_iter().map(|outgoing| ("hook", outgoing)));
+        for (name, outgoing) in forwards {
+            // Forwards skip the outbound queue, so they check the opt-out list themselves
+            match opt_out::opted_out(&self.db, &outgoing.to) {
+                Ok(false) => {}
+                Ok(true) => {
+                    log::info!(
+                        "Rule '{}': not forwarding SMS to {}, opted out",
+                        name,
+                        outgoing.to
+                    );
+                    continue;
+                }
+                Err(e) => log::warn!("{:#}", e),
+            }
+            log::info!("Rule '{}': forwarding SMS to {}", name, outgoing.to);
+            if let Err(e) = self.send_direct(writer, outgoing.clone()).await {
+                log::warn!("Failed to forward SMS to {}: {}", outgoing.to, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Put the sender of a "STOP" on the opt-out list, or take the sender of a
+    // "START" off it, and confirm with the configured reply
+    async fn handle_opt_out<W: AsyncWriteExt + Unpin>(
+        &self,
+        sender: &str,
+        content: &str,
+        writer: &mut W,
+    ) {
+        let Some(keywords) = &self.opt_out else {
+            return;
+        };
+        let (action, changed, reply) = match keywords.detect(content) {
+            Some(opt_out::Request::OptOut) => {
+                log::info!("{} opted out of SMS", sender);
+                let added = self.db.add_opt_out(sender, opt_out::SOURCE_SMS);
+                ("opt_out", added, &keywords.stop_reply)
+            }
+            Some(opt_out::Request::OptIn) => {
+                log::info!("{} opted in to SMS again", sender);
+                let removed = self
+                    .db
+                    .remove_opt_outs(|number| forwarding::same_number(number, sender))
+                    .map(|count| count > 0);
+                ("opt_in", removed, &keywords.start_reply)
+            }
+            None => return,
+        };
+        match changed {
+            Ok(true) => audit::record(&self.db, opt_out::SOURCE_SMS, action, Some(sender)),
+            Ok(false) => {}
+            Err(e) => log::warn!("{:#}", e),
+        }
+        // The confirmation goes out even right after a STOP, it is the only SMS
+        // an opted-out number still gets
+        if let Some(reply) = reply {
+            let payload = SendSmsPayload {
+                to: sender.to_string(),
+                content: reply.clone(),
+                reference: None,
+                rid: None,
+            };
+            if let Err(e) = self.send_direct(writer, payload).await {
+                log::warn!("Failed to confirm the opt-out of {}: {}", sender, e);
+            }
+        }
+    }
+
+    // Sends an SMS past the queue, recorded in outbound_sms under its id so the
+    // device's SMS_SENT marks it sent and it is priced like queued ones
+    async fn send_direct<W: AsyncWriteExt + Unpin>(
+        &self,
+        writer: &mut W,
+        mut payload: SendSmsPayload,
+    ) -> std::io::Result<()> {
+        let id = self
+            .db
+            .insert_direct_outbound(self.device_id.as_deref(), &payload.to, &payload.content)
+            .inspect_err(|e| log::warn!("{:#}", e))
+            .ok();
+        payload.reference = id.map(|id| id.to_string());
+        let result = serial_port::send_sms(writer, &payload).await;
+        if let (Some(id), Err(e)) = (id, &result) {
+            self.finish_outbound(id, false, Some(&e.to_string()));
+        }
+        result
+    }
+
+    // A failing hook is logged and the message handled by the rules alone.
+    // The Lua script runs after the WASM module
+    async fn run_hooks(
+        &self,
+        payload: &SmsPayload,
+        sender: &SenderInfo,
+        class: Option<u8>,
+    ) -> HookActions {
+        let mut actions = HookActions::default();
+        if self.wasm_hook.is_none() && self.lua_hook.is_none() {
+            return actions;
+        }
+        let message = HookMessage {
+            id: payload.id.clone(),
+            sender: payload.sender.clone(),
+            sender_type: sender.kind.as_str(),
+            sender_country: sender.country.clone(),
+            message_class: class,
+            content: payload.content.clone(),
+            received_at: payload.received_at,
+            device_id: self.device_id.clone(),
+        };
+
+        if let Some(hook) = self.wasm_hook.clone() {
+            let message = message.clone();
+            let result = tokio::task::spawn_blocking(move || hook.on_sms(&message)).await;
+            actions.merge(Self::hook_result("WASM hook", &payload.id, result));
+        }
+        if let Some(hook) = self.lua_hook.clone() {
+            let result = tokio::task::spawn_blocking(move || hook.process(&message)).await;
+            actions.merge(Self::hook_result("Lua rules", &payload.id, result));
+        }
+        actions
+    }
+
+    fn hook_result(
+        name: &str,
+        id: &str,
+        result: std::result::Result<Result<HookActions>, tokio::task::JoinError>,
+    ) -> HookActions {
+        match result {
+            Ok(Ok(actions)) => {
+                log::debug!("{} actions for {}: {:?}", name, id, actions);
+                actions
+            }
+            Ok(Err(e)) => {
+                log::warn!("{} failed for {}: {:#}", name, id, e);
+                HookActions::default()
+            }
+            Err(e) => {
+                log::warn!("{} failed for {}: {}", name, id, e);
+                HookActions::default()
+            }
+        }
+    }
+
+    async fn process_message<W: tokio::io::AsyncWriteExt + Unpin>(
+        &self,
+        msg: ParsedMessage,
+        writer: &mut W,
+    ) -> Result<()> {
+        match msg.message_type {
+            MessageType::SmsReceived(payload) => self.receive_sms(payload, &msg.id, writer).await?,
+            MessageType::SmsSent(result) => {
+                if result.success {
+                    log::info!("Device sent SMS to {}", result.to);
+                    self.record_usage(1, 0, 0).await;
+                } else {
+                    log::warn!("Device failed to send SMS to {}", result.to);
+                }
+                // Replies carry the outbound_sms id, of queued SMS as well as forwards
+                // and opt-out confirmations
+                if let Some(id) = result.reference.and_then(|r| r.parse::<i64>().ok()) {
+                    let error = (!result.success).then_some("the device failed to send it");
+                    self.finish_outbound(id, result.success, error);
+                }
+            }
+            MessageType::DeviceLog(entry) => match &self.device_log {
+                Some(device_log) => device_log.write(self.device_label(), &entry),
+                None => log::debug!(
+                    "Device log [{}] {}: {}",
+                    entry.level,
+                    entry.tag,
+                    entry.message
+                ),
+            },
+            MessageType::NetStatus(status) => self.record_network(&status).await,
+            MessageType::StoredSms(stored) => {
+                self.review_stored(stored, writer).await?;
+            }
+            MessageType::StoredDeleted(result) => {
+                log::info!(
+                    "Device deleted {} stored SMS, {} left",
+                    result.deleted,
+                    result.remaining
+                );
+            }
+            MessageType::ScriptUpdate(reply) => {
+                // Only expected while `flash-script` holds the port
+                log::warn!("Unexpected SCRIPT_UPDATE reply: {:?}", reply);
             }
+            MessageType::DeviceInfo(info) => self.handle_device_info(&info)?,
             MessageType::SystemInit(data) => {
                 log::info!("System init: {:?}", data);
+                self.annotate("reboot", "Device script started".to_string());
             }
             MessageType::HeartBeat(data) => {
                 log::debug!("Heartbeat: {:?}", data);
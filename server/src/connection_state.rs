@@ -0,0 +1,160 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionState {
+    Initializing,
+    Validating,
+    Connected,
+    Reconnecting { attempts: u32 },
+    // The serial port is released for external tools
+    Maintenance,
+    // The connection loop gave up, nothing leaves this state
+    Failed,
+}
+
+impl ConnectionState {
+    // Stable name without details such as the attempt count
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConnectionState::Initializing => "initializing",
+            ConnectionState::Validating => "validating",
+            ConnectionState::Connected => "connected",
+            ConnectionState::Reconnecting { .. } => "reconnecting",
+            ConnectionState::Maintenance => "maintenance",
+            ConnectionState::Failed => "failed",
+        }
+    }
+
+    fn can_become(&self, next: &ConnectionState) -> bool {
+        use ConnectionState::*;
+        match (self, next) {
+            (Failed, _) => false,
+            // Any state can fail, and maintenance starts between sessions
+            (_, Failed) | (_, Maintenance) => true,
+            (Initializing | Reconnecting { .. }, Initializing) => true,
+            (Initializing | Validating, Validating) => true,
+            (Validating, Connected) => true,
+            // A port reopened after EOF or taken over on restart skips validation
+            (Initializing | Reconnecting { .. }, Connected) => true,
+            // Reconnecting counts attempts that failed before getting connected
+            (_, Reconnecting { .. }) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Reconnecting { attempts } if *attempts > 0 => {
+                write!(f, "reconnecting (attempt {})", attempts)
+            }
+            state => write!(f, "{}", state.name()),
+        }
+    }
+}
+
+// A change of state, passed to the hooks
+pub struct Transition<'a> {
+    pub from: &'a ConnectionState,
+    pub to: &'a ConnectionState,
+    // How long the connection was in `from`
+    pub elapsed: Duration,
+}
+
+type Hook = Box<dyn Fn(&Transition) + Send + Sync>;
+
+enum HookPoint {
+    // Every change, including a changed attempt count
+    Change,
+    Enter(&'static str),
+    Exit(&'static str),
+}
+
+// Holds the connection state and only accepts the transitions the connection
+// loop can make, running the registered hooks on every change
+pub struct StateMachine {
+    current: Mutex<(ConnectionState, Instant)>,
+    hooks: Vec<(HookPoint, Hook)>,
+}
+
+impl Default for StateMachine {
+    fn default() -> Self {
+        StateMachine {
+            current: Mutex::new((ConnectionState::Initializing, Instant::now())),
+            hooks: Vec::new(),
+        }
+    }
+}
+
+impl StateMachine {
+    pub fn on_change(&mut self, hook: impl Fn(&Transition) + Send + Sync + 'static) {
+        self.hooks.push((HookPoint::Change, Box::new(hook)));
+    }
+
+    // `state` is a name from `ConnectionState::name`
+    pub fn on_enter(
+        &mut self,
+        state: &'static str,
+        hook: impl Fn(&Transition) + Send + Sync + 'static,
+    ) {
+        self.hooks.push((HookPoint::Enter(state), Box::new(hook)));
+    }
+
+    pub fn on_exit(
+        &mut self,
+        state: &'static str,
+        hook: impl Fn(&Transition) + Send + Sync + 'static,
+    ) {
+        self.hooks.push((HookPoint::Exit(state), Box::new(hook)));
+    }
+
+    pub fn get(&self) -> ConnectionState {
+        self.current.lock().unwrap().0.clone()
+    }
+
+    // Returns whether the state changed. Transitions the connection loop never
+    // makes are refused and logged, they point at a bug
+    pub fn transition(&self, next: ConnectionState) -> bool {
+        let (from, elapsed) = {
+            let mut current = self.current.lock().unwrap();
+            if current.0 == next {
+                return false;
+            }
+            if current.0.name() != next.name() && !current.0.can_become(&next) {
+                log::error!(
+                    "Refused connection state change from {} to {}",
+                    current.0,
+                    next
+                );
+                return false;
+            }
+            let from = std::mem::replace(&mut current.0, next.clone());
+            let elapsed = current.1.elapsed();
+            if from.name() != next.name() {
+                current.1 = Instant::now();
+            }
+            (from, elapsed)
+        };
+
+        let transition = Transition {
+            from: &from,
+            to: &next,
+            elapsed,
+        };
+        let renamed = from.name() != next.name();
+        for (point, hook) in &self.hooks {
+            let applies = match point {
+                HookPoint::Change => true,
+                HookPoint::Enter(state) => renamed && *state == next.name(),
+                HookPoint::Exit(state) => renamed && *state == from.name(),
+            };
+            if applies {
+                hook(&transition);
+            }
+        }
+        true
+    }
+}
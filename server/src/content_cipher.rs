@@ -0,0 +1,93 @@
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ring::aead::{self, Aad, CHACHA20_POLY1305, LessSafeKey, Nonce, UnboundKey};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+// Marks a sealed value, followed by base64 of the nonce and the ciphertext
+const PREFIX: &str = "enc1:";
+const KEY_LEN: usize = 32;
+
+// Encrypts message text stored in the database with ChaCha20-Poly1305, so a
+// copied database file or backup doesn't expose it. Senders and times stay in
+// plaintext for filtering
+pub struct ContentCipher {
+    key: LessSafeKey,
+    // Keys the hashes derived from message text, so they can't be brute-forced
+    mac_key: hmac::Key,
+    rng: SystemRandom,
+}
+
+impl ContentCipher {
+    // `key` is 32 bytes in base64, e.g. from `head -c 32 /dev/urandom | base64`
+    pub fn new(key: &str) -> Result<Self> {
+        let key = STANDARD
+            .decode(key.trim())
+            .context("Content key is not valid base64")?;
+        if key.len() != KEY_LEN {
+            anyhow::bail!(
+                "Content key must be {} bytes, it has {}",
+                KEY_LEN,
+                key.len()
+            );
+        }
+        let mac_key = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, &key),
+            b"air780e content hash",
+        );
+
+        Ok(ContentCipher {
+            key: LessSafeKey::new(
+                UnboundKey::new(&CHACHA20_POLY1305, &key)
+                    .map_err(|_| anyhow::anyhow!("Invalid content key"))?,
+            ),
+            mac_key: hmac::Key::new(hmac::HMAC_SHA256, mac_key.as_ref()),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    pub fn seal(&self, plaintext: &str) -> Result<String> {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| anyhow::anyhow!("Failed to generate a nonce"))?;
+        let mut data = plaintext.as_bytes().to_vec();
+        self.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut data)
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt content"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&data);
+        Ok(format!("{}{}", PREFIX, STANDARD.encode(sealed)))
+    }
+
+    // Values stored before the key was set are returned as they are
+    pub fn open(&self, stored: &str) -> Result<String> {
+        let Some(encoded) = stored.strip_prefix(PREFIX) else {
+            return Ok(stored.to_string());
+        };
+        let mut data = STANDARD
+            .decode(encoded)
+            .context("Encrypted content is not valid base64")?;
+        if data.len() < aead::NONCE_LEN {
+            anyhow::bail!("Encrypted content is truncated");
+        }
+        let nonce = Nonce::try_assume_unique_for_key(&data[..aead::NONCE_LEN])
+            .map_err(|_| anyhow::anyhow!("Invalid nonce"))?;
+        let plaintext = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut data[aead::NONCE_LEN..])
+            .map_err(|_| anyhow::anyhow!("Failed to decrypt content, is the content key right?"))?;
+
+        String::from_utf8(plaintext.to_vec()).context("Decrypted content is not valid UTF-8")
+    }
+
+    pub fn hash(&self, data: &[u8]) -> Vec<u8> {
+        hmac::sign(&self.mac_key, data).as_ref().to_vec()
+    }
+}
+
+pub fn is_sealed(stored: &str) -> bool {
+    stored.starts_with(PREFIX)
+}
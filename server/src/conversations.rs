@@ -0,0 +1,51 @@
+use crate::database::HistoryEntry;
+use crate::forwarding;
+use crate::sender;
+
+// Messages exchanged with one counterpart, oldest first
+pub struct Conversation {
+    // Number as it appeared in the latest message
+    pub number: String,
+    pub entries: Vec<HistoryEntry>,
+}
+
+impl Conversation {
+    pub fn last(&self) -> &HistoryEntry {
+        self.entries.last().unwrap()
+    }
+}
+
+// Group chronological history by counterpart, so "+8613800000000" and "13800000000"
+// end up in the same conversation. The most recently active conversation comes first.
+pub fn group(history: Vec<HistoryEntry>) -> Vec<Conversation> {
+    let mut conversations: Vec<Conversation> = Vec::new();
+    for entry in history {
+        match conversations
+            .iter_mut()
+            .find(|conversation| same_counterpart(&conversation.number, &entry.number))
+        {
+            Some(conversation) => {
+                conversation.number = entry.number.clone();
+                conversation.entries.push(entry);
+            }
+            None => conversations.push(Conversation {
+                number: entry.number.clone(),
+                entries: vec![entry],
+            }),
+        }
+    }
+
+    conversations.sort_by_key(|conversation| std::cmp::Reverse(conversation.last().at));
+    conversations
+}
+
+pub fn find(conversations: Vec<Conversation>, number: &str) -> Option<Conversation> {
+    conversations
+        .into_iter()
+        .find(|conversation| same_counterpart(&conversation.number, number))
+}
+
+// Alphanumeric senders such as "Amazon" have no digits to compare
+pub fn same_counterpart(a: &str, b: &str) -> bool {
+    sender::normalize_id(a) == sender::normalize_id(b) || forwarding::same_number(a, b)
+}
@@ -0,0 +1,114 @@
+use crate::serial_port::{MessageType, ParsedMessage};
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+// Matches device replies to the commands that asked for them. A command carries
+// a request id as `rid` and scripts from 1.6.0 send their reply under that id.
+// Older scripts reply under a fresh id, so a reply no request claims by id goes
+// to the oldest request waiting for its type
+#[derive(Default)]
+pub struct Correlator {
+    next_id: AtomicU64,
+    pending: Mutex<VecDeque<Pending>>,
+}
+
+struct Pending {
+    rid: String,
+    kind: &'static str,
+    reply: oneshot::Sender<MessageType>,
+}
+
+// A command waiting for its reply, dropping it gives up on the reply
+pub struct Request {
+    pub rid: String,
+    command: String,
+    reply: oneshot::Receiver<MessageType>,
+}
+
+impl Correlator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Register a `command` answered by a frame of type `kind`, send the command
+    // with the returned `rid` and await the reply
+    pub fn request(&self, command: &str, kind: &'static str) -> Request {
+        let n = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let rid = format!("req-{}-{}", std::process::id(), n);
+        let (reply, receiver) = oneshot::channel();
+        let mut pending = self.pending.lock().unwrap();
+        // Requests whose caller gave up would otherwise take replies meant for others
+        pending.retain(|p| !p.reply.is_closed());
+        pending.push_back(Pending {
+            rid: rid.clone(),
+            kind,
+            reply,
+        });
+        Request {
+            rid,
+            command: command.to_string(),
+            reply: receiver,
+        }
+    }
+
+    // Hand `msg` to the request it answers, it is given back when there is none
+    pub fn resolve(&self, msg: ParsedMessage) -> Option<ParsedMessage> {
+        let mut pending = self.pending.lock().unwrap();
+        let kind = msg.message_type.kind();
+        let index = pending.iter().position(|p| p.rid == msg.id).or_else(|| {
+            pending
+                .iter()
+                .position(|p| p.kind == kind && !p.reply.is_closed())
+        });
+        let Some(index) = index else {
+            return Some(msg);
+        };
+        let request = pending.remove(index).expect("index of a pending request");
+        if request.rid != msg.id {
+            log::debug!(
+                "{} reply without a request id, taken for request {}",
+                kind,
+                request.rid
+            );
+        }
+        match request.reply.send(msg.message_type) {
+            Ok(()) => None,
+            Err(message_type) => Some(ParsedMessage {
+                id: msg.id,
+                message_type,
+            }),
+        }
+    }
+}
+
+impl Request {
+    // The reply if it has arrived, for callers checking between other work
+    pub fn try_take(&mut self) -> Option<MessageType> {
+        self.reply.try_recv().ok()
+    }
+
+    // Wait for the reply and turn it into the type the caller expects
+    pub async fn response<T>(
+        self,
+        timeout: Duration,
+        extract: impl FnOnce(MessageType) -> Option<T>,
+    ) -> Result<T> {
+        let message = match tokio::time::timeout(timeout, self.reply).await {
+            Ok(Ok(message)) => message,
+            Ok(Err(_)) => anyhow::bail!("{} was cancelled", self.command),
+            Err(_) => anyhow::bail!(
+                "No reply to {} within {}ms",
+                self.command,
+                timeout.as_millis()
+            ),
+        };
+        match extract(message) {
+            Some(reply) => Ok(reply),
+            None => anyhow::bail!("Unexpected reply to {}", self.command),
+        }
+    }
+}
@@ -1,5 +1,12 @@
+use crate::content_cipher::{self, ContentCipher};
+use crate::error::AppError;
+use crate::sender;
 use anyhow::{Context, Result};
-use rusqlite::{Connection, params};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use rusqlite::{Connection, OpenFlags, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 
 #[derive(Debug, Clone)]
@@ -9,14 +16,494 @@ pub struct SmsMessage {
     pub content: String,
     pub received_at: i64,
     pub metas: String,
+    pub spam_score: Option<f64>,
+    pub is_spam: bool,
+    pub device_id: Option<String>,
+    // "shortcode", "number" or "alphanumeric"
+    pub sender_type: Option<String>,
+    pub sender_country: Option<String>,
+    // 0 for flash SMS, None when the module didn't report it
+    pub message_class: Option<u8>,
+    // The payload was not valid UTF-8, the text holds U+FFFD in place of the
+    // invalid bytes and the bytes themselves are kept in `raw_payload`
+    pub encoding_suspect: bool,
+}
+
+// Where a received SMS is in its lifecycle, with the time of each step taken
+#[derive(Debug, Clone)]
+pub struct SmsProgress {
+    pub status: String,
+    pub device_id: Option<String>,
+    pub stored_at: i64,
+    pub notified_at: Option<i64>,
+    pub ack_sent_at: Option<i64>,
+    pub is_spam: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct UnacknowledgedSms {
+    pub message: SmsMessage,
+    pub created_at: i64,
+    pub notified_at: Option<i64>,
+}
+
+// Every column of a received SMS, as written to and restored from archives
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedSms {
+    pub id: String,
+    pub sender: String,
+    pub content: String,
+    pub received_at: i64,
+    pub metas: Option<String>,
+    pub acknowledged: bool,
+    pub ack_sent_at: Option<i64>,
+    pub created_at: i64,
+    pub spam_score: Option<f64>,
+    pub is_spam: bool,
+    pub device_id: Option<String>,
+    pub notified_at: Option<i64>,
+    pub sender_type: Option<String>,
+    pub sender_country: Option<String>,
+    pub message_class: Option<u8>,
+    #[serde(default)]
+    pub handled_at: Option<i64>,
+    #[serde(default)]
+    pub handled_by: Option<String>,
+    #[serde(default)]
+    pub encoding_suspect: bool,
+    #[serde(default)]
+    pub raw_payload: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedOutbound {
+    pub id: i64,
+    pub batch_id: Option<String>,
+    pub device_id: Option<String>,
+    pub recipient: String,
+    pub content: String,
+    pub status: String,
+    pub error: Option<String>,
+    pub created_at: i64,
+    pub sent_at: Option<i64>,
+}
+
+// Page counts of the database file, free pages are left behind by deletions
+#[derive(Debug, Clone, Copy)]
+pub struct FileStats {
+    pub page_size: i64,
+    pub page_count: i64,
+    pub free_pages: i64,
+}
+
+impl FileStats {
+    pub fn size_bytes(&self) -> i64 {
+        self.page_size * self.page_count
+    }
+
+    pub fn free_bytes(&self) -> i64 {
+        self.page_size * self.free_pages
+    }
+
+    pub fn free_percent(&self) -> i64 {
+        if self.page_count == 0 {
+            return 0;
+        }
+        self.free_pages * 100 / self.page_count
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SenderHistory {
+    // Messages not tagged as spam
+    pub known: i64,
+    // Spam since the time asked for
+    pub spam: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Escalation {
+    pub message_id: String,
+    pub title: String,
+    pub content: String,
+    pub repeats: u32,
+}
+
+// Last DEVICE_INFO reported by a device, keyed by its configured id
+#[derive(Debug, Clone)]
+pub struct DeviceRecord {
+    pub device_id: String,
+    pub imei: String,
+    pub number: String,
+    pub iccid: String,
+    pub firmware: Option<String>,
+    pub script_version: Option<String>,
+    pub updated_at: i64,
+}
+
+// Counters of the current serial session, `reconnects` covers the whole process lifetime
+#[derive(Debug, Clone, Default)]
+pub struct SessionStats {
+    pub connected_at: Option<i64>,
+    pub reconnects: u32,
+    // Failed attempts of the reconnect in progress, 0 once a session is up
+    pub reconnect_attempts: u32,
+    pub frames_received: u64,
+    pub parse_failures: u64,
+    pub acks_sent: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    // Line problems, typically a bad cable or interference: frames whose
+    // `id:TYPE:payload` shape could not be read, frames holding invalid UTF-8
+    // and the bytes of frames dropped for any reason
+    pub framing_errors: u64,
+    pub invalid_utf8_frames: u64,
+    pub dropped_bytes: u64,
+    // Queue depths when the counters were saved: notifications held back by
+    // `strict_order` and read receipts waiting for the next batch
+    pub held_notifications: u64,
+    pub pending_receipts: u64,
+}
+
+// Resource usage of the running server, sampled every 10 seconds. The fields
+// read from /proc are None on other systems
+#[derive(Debug, Clone)]
+pub struct ProcessStats {
+    pub pid: u32,
+    pub rss_bytes: Option<u64>,
+    pub open_fds: Option<u64>,
+    pub threads: Option<u64>,
+    pub workers: u64,
+    pub alive_tasks: u64,
+    pub global_queue_depth: u64,
+    pub updated_at: i64,
+}
+
+// What a device used in one billing cycle, which starts on `cycle_start`
+// ("YYYY-MM-DD"). Data is counted from the traffic counters the device reports
+#[derive(Debug, Clone)]
+pub struct MonthlyUsage {
+    pub device_id: String,
+    pub cycle_start: String,
+    pub sms_sent: u64,
+    pub data_tx_bytes: u64,
+    pub data_rx_bytes: u64,
+    // Whether the plan limit alert of the cycle was sent
+    pub sms_alerted: bool,
+    pub data_alerted: bool,
+    pub updated_at: i64,
+}
+
+impl MonthlyUsage {
+    pub fn data_bytes(&self) -> u64 {
+        self.data_tx_bytes + self.data_rx_bytes
+    }
+}
+
+// The plan limits usage is alerted on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsageLimit {
+    Sms,
+    Data,
+}
+
+// When a received SMS passed each stage, in milliseconds except the device's received_at
+#[derive(Debug, Clone)]
+pub struct SmsTiming {
+    pub sms_id: String,
+    pub device_id: String,
+    pub received_at: i64,
+    pub read_at_ms: i64,
+    pub stored_at_ms: i64,
+    pub notified_at_ms: Option<i64>,
+    pub ack_sent_at_ms: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionStatus {
+    pub device_id: String,
+    pub state: String,
+    pub stats: SessionStats,
+    // Category and message of the error that ended the last session, if any
+    pub error_category: Option<String>,
+    pub error: Option<String>,
+    pub updated_at: i64,
+}
+
+// Last network registration reported by a device in NET_STATUS
+#[derive(Debug, Clone)]
+pub struct NetworkRecord {
+    pub device_id: String,
+    pub status: i32,
+    pub operator: Option<String>,
+    pub network: Option<String>,
+    pub rssi: Option<i32>,
+    pub updated_at: i64,
+}
+
+// A module attaching to another operator or starting or stopping roaming
+#[derive(Debug, Clone)]
+pub struct NetworkEvent {
+    pub device_id: String,
+    pub operator: String,
+    pub roaming: bool,
+    // None for the first registration seen
+    pub previous_operator: Option<String>,
+    pub previous_roaming: Option<bool>,
+    pub changed_at: i64,
+}
+
+// An administrative or send action, recorded because several people may
+// share the gateway
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub actor: String,
+    pub action: String,
+    pub detail: Option<String>,
+    pub created_at: i64,
+}
+
+// Delivery states of a webhook event
+pub const WEBHOOK_PENDING: &str = "pending";
+pub const WEBHOOK_DELIVERED: &str = "delivered";
+pub const WEBHOOK_DEAD: &str = "dead";
+
+// A webhook event, its payload is stored so every attempt sends the same body
+#[derive(Debug, Clone)]
+pub struct WebhookDelivery {
+    pub id: String,
+    pub payload: String,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+}
+
+// Settings key holding when notifications were paused
+const NOTIFICATIONS_PAUSED_KEY: &str = "notifications_paused";
+// Settings key holding when maintenance mode was started
+const MAINTENANCE_KEY: &str = "maintenance";
+// Settings key prefix, followed by the schedule name, holding when it last ran
+const SCHEDULE_KEY_PREFIX: &str = "schedule:";
+// Settings key holding when the database was last compacted with VACUUM
+const LAST_VACUUM_KEY: &str = "last_vacuum";
+// Settings key holding when the last offsite backup started
+const LAST_BACKUP_KEY: &str = "last_backup";
+
+// Columns holding message text, encrypted when a content key is set
+const CONTENT_COLUMNS: &[(&str, &str)] = &[
+    ("sms_messages", "content"),
+    ("sms_messages", "raw_payload"),
+    ("outbound_sms", "content"),
+    ("escalations", "content"),
+    ("webhook_deliveries", "payload"),
+];
+
+// Status of a queued outbound SMS
+pub const OUTBOUND_PENDING: &str = "pending";
+pub const OUTBOUND_SENDING: &str = "sending";
+pub const OUTBOUND_SENT: &str = "sent";
+pub const OUTBOUND_FAILED: &str = "failed";
+
+// Step a received SMS has reached, moved along in the same statement as the
+// column it stands for. Spam counts as notified, it never will be. An SMS whose
+// ACK went out before its notification is held until the notification is sent
+pub const SMS_STORED: &str = "stored";
+pub const SMS_NOTIFIED: &str = "notified";
+pub const SMS_HELD: &str = "held";
+pub const SMS_ACKED: &str = "acked";
+
+// The status of a row as told by its other columns
+const SMS_STATUS_OF_ROW: &str = "CASE
+    WHEN notified_at IS NULL AND is_spam = 0 THEN
+        CASE WHEN acknowledged = 1 THEN 'held' ELSE 'stored' END
+    ELSE
+        CASE WHEN acknowledged = 1 THEN 'acked' ELSE 'notified' END
+END";
+
+// SMS waiting to be sent by the running server, `device_id` None lets any device send it
+#[derive(Debug, Clone)]
+pub struct OutboundSms {
+    pub id: i64,
+    pub batch_id: Option<String>,
+    pub recipient: String,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BatchProgress {
+    pub batch_id: String,
+    pub total: u64,
+    pub pending: u64,
+    pub sent: u64,
+    pub failed: u64,
+}
+
+impl BatchProgress {
+    pub fn is_finished(&self) -> bool {
+        self.sent + self.failed == self.total
+    }
+}
+
+// A saved message text, see templates.rs
+#[derive(Debug, Clone)]
+pub struct Template {
+    pub name: String,
+    pub body: String,
+    pub updated_at: i64,
+}
+
+// A number queued SMS are not sent to, `source` is "sms" when its owner replied
+// with a keyword and the user otherwise
+#[derive(Debug, Clone)]
+pub struct OptOut {
+    pub number: String,
+    pub source: String,
+    pub created_at: i64,
+}
+
+// One received or queued message, as shown in a conversation
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub outbound: bool,
+    // Sender of received messages, recipient of sent ones
+    pub number: String,
+    pub content: String,
+    pub at: i64,
+    // Send status of outbound messages, spam tag of received ones
+    pub status: Option<String>,
+}
+
+// An outbound SMS the device reported as sent, for cost estimates
+#[derive(Debug, Clone)]
+pub struct SentOutbound {
+    // None when any device could send it
+    pub device_id: Option<String>,
+    pub recipient: String,
+    pub content: String,
+    pub sent_at: i64,
+}
+
+// Rows holding data about one correspondent, found before erasing them
+#[derive(Debug, Clone, Default)]
+pub struct CorrespondentData {
+    pub sms_ids: Vec<String>,
+    pub outbound_ids: Vec<i64>,
+    pub notification_hashes: Vec<String>,
+    pub webhook_ids: Vec<String>,
+    pub audit_ids: Vec<i64>,
+}
+
+// Imported messages have no device id, theirs is derived from number, time and
+// a FNV-1a hash of the content so importing the same archive twice is harmless.
+// With a content key the hash is keyed instead
+const USAGE_QUERY: &str = "SELECT device_id, cycle_start, sms_sent, data_tx_bytes, data_rx_bytes,
+                                  sms_alerted, data_alerted, updated_at
+                           FROM monthly_usage";
+
+fn usage_from_row(row: &rusqlite::Row) -> rusqlite::Result<MonthlyUsage> {
+    Ok(MonthlyUsage {
+        device_id: row.get(0)?,
+        cycle_start: row.get(1)?,
+        sms_sent: row.get::<_, i64>(2)? as u64,
+        data_tx_bytes: row.get::<_, i64>(3)? as u64,
+        data_rx_bytes: row.get::<_, i64>(4)? as u64,
+        sms_alerted: row.get(5)?,
+        data_alerted: row.get(6)?,
+        updated_at: row.get(7)?,
+    })
+}
+
+fn import_id(cipher: Option<&ContentCipher>, number: &str, at: i64, content: &str) -> String {
+    let hash = match cipher {
+        Some(cipher) => {
+            let mac = cipher.hash(content.as_bytes());
+            u64::from_be_bytes(mac[..8].try_into().unwrap())
+        }
+        None => content.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        }),
+    };
+    format!("import-{}-{}-{:016x}", number, at, hash)
+}
+
+// Only the hash is kept, the history holds no message content
+fn notification_hash(cipher: Option<&ContentCipher>, sender: &str, content: &str) -> String {
+    let sender = if sender::is_alphanumeric(sender) {
+        sender::normalize_id(sender)
+    } else {
+        sender.to_string()
+    };
+    let mut data = sender.into_bytes();
+    data.push(0);
+    data.extend_from_slice(content.as_bytes());
+    let hash = match cipher {
+        Some(cipher) => cipher.hash(&data),
+        None => ring::digest::digest(&ring::digest::SHA256, &data)
+            .as_ref()
+            .to_vec(),
+    };
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Whether `token` appears in `text` on its own, so "+5" isn't found in "+55"
+fn mentions(text: &str, token: &str) -> bool {
+    if token.is_empty() {
+        return false;
+    }
+    text.match_indices(token).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + token.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+pub fn unix_timestamp() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+pub fn unix_timestamp_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
 }
 
 pub struct Database {
     conn: Arc<Mutex<Connection>>,
+    cipher: Option<Arc<ContentCipher>>,
 }
 
 impl Database {
     pub fn new(path: &str) -> Result<Self> {
+        Self::open(path).map_err(|e| AppError::Database(format!("{:#}", e)).into())
+    }
+
+    // For an instance that only reads the database another one writes, so the
+    // schema is left alone
+    pub fn open_read_only(path: &str) -> Result<Self> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .context(format!("Failed to open database read-only: {}", path))
+        .map_err(|e| AppError::Database(format!("{:#}", e)))?;
+
+        Ok(Database {
+            conn: Arc::new(Mutex::new(conn)),
+            cipher: None,
+        })
+    }
+
+    // Message text is encrypted from now on, and readable whether encrypted or not
+    pub fn with_content_cipher(mut self, cipher: ContentCipher) -> Self {
+        self.cipher = Some(Arc::new(cipher));
+        self
+    }
+
+    fn open(path: &str) -> Result<Self> {
         let conn = Connection::open(path).context(format!("Failed to open database: {}", path))?;
 
         // Create table if not exists
@@ -29,40 +516,409 @@ impl Database {
                 metas TEXT,
                 acknowledged INTEGER NOT NULL DEFAULT 0,
                 ack_sent_at INTEGER,
-                created_at INTEGER NOT NULL
+                created_at INTEGER NOT NULL,
+                spam_score REAL,
+                is_spam INTEGER NOT NULL DEFAULT 0,
+                device_id TEXT,
+                notified_at INTEGER,
+                sender_type TEXT,
+                sender_country TEXT,
+                message_class INTEGER,
+                status TEXT NOT NULL DEFAULT 'stored'
             )",
             [],
         )
         .context("Failed to create sms_messages table")?;
 
+        // Upgrade databases created before these columns existed
+        Self::ensure_column(&conn, "sms_messages", "spam_score", "REAL")?;
+        Self::ensure_column(
+            &conn,
+            "sms_messages",
+            "is_spam",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        Self::ensure_column(&conn, "sms_messages", "device_id", "TEXT")?;
+        Self::ensure_column(&conn, "sms_messages", "sender_type", "TEXT")?;
+        Self::ensure_column(&conn, "sms_messages", "sender_country", "TEXT")?;
+        Self::ensure_column(&conn, "sms_messages", "message_class", "INTEGER")?;
+        // Who acknowledged the notification, through a link or the CLI
+        Self::ensure_column(&conn, "sms_messages", "handled_at", "INTEGER")?;
+        Self::ensure_column(&conn, "sms_messages", "handled_by", "TEXT")?;
+        // Base64 of a payload that was not valid UTF-8, encrypted like the content
+        Self::ensure_column(&conn, "sms_messages", "raw_payload", "TEXT")?;
+        Self::ensure_column(
+            &conn,
+            "sms_messages",
+            "encoding_suspect",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        if Self::ensure_column(&conn, "sms_messages", "notified_at", "INTEGER")? {
+            // Older messages were notified before this was tracked, don't re-send them
+            conn.execute(
+                "UPDATE sms_messages SET notified_at = created_at WHERE is_spam = 0",
+                [],
+            )
+            .context("Failed to backfill notified_at")?;
+        }
+        if Self::ensure_column(
+            &conn,
+            "sms_messages",
+            "status",
+            "TEXT NOT NULL DEFAULT 'stored'",
+        )? {
+            conn.execute(
+                &format!("UPDATE sms_messages SET status = {}", SMS_STATUS_OF_ROW),
+                [],
+            )
+            .context("Failed to backfill the status of messages")?;
+        }
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_sms_messages_status ON sms_messages(status)",
+            [],
+        )
+        .context("Failed to create index on sms_messages.status")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS escalations (
+                message_id TEXT PRIMARY KEY,
+                title TEXT NOT NULL,
+                content TEXT NOT NULL,
+                repeats INTEGER NOT NULL DEFAULT 0,
+                next_at INTEGER NOT NULL,
+                acknowledged_at INTEGER,
+                created_at INTEGER NOT NULL
+            )",
+            [],
+        )
+        .context("Failed to create escalations table")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS devices (
+                device_id TEXT PRIMARY KEY,
+                imei TEXT NOT NULL,
+                number TEXT NOT NULL,
+                iccid TEXT NOT NULL,
+                firmware TEXT,
+                script_version TEXT,
+                updated_at INTEGER NOT NULL
+            )",
+            [],
+        )
+        .context("Failed to create devices table")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS connection_status (
+                device_id TEXT PRIMARY KEY,
+                state TEXT NOT NULL,
+                connected_at INTEGER,
+                reconnects INTEGER NOT NULL,
+                reconnect_attempts INTEGER NOT NULL DEFAULT 0,
+                frames_received INTEGER NOT NULL,
+                parse_failures INTEGER NOT NULL,
+                acks_sent INTEGER NOT NULL,
+                bytes_read INTEGER NOT NULL,
+                bytes_written INTEGER NOT NULL,
+                error_category TEXT,
+                error TEXT,
+                updated_at INTEGER NOT NULL
+            )",
+            [],
+        )
+        .context("Failed to create connection_status table")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS process_stats (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                pid INTEGER NOT NULL,
+                rss_bytes INTEGER,
+                open_fds INTEGER,
+                threads INTEGER,
+                workers INTEGER NOT NULL,
+                alive_tasks INTEGER NOT NULL,
+                global_queue_depth INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+            )",
+            [],
+        )
+        .context("Failed to create process_stats table")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS monthly_usage (
+                device_id TEXT NOT NULL,
+                cycle_start TEXT NOT NULL,
+                sms_sent INTEGER NOT NULL DEFAULT 0,
+                data_tx_bytes INTEGER NOT NULL DEFAULT 0,
+                data_rx_bytes INTEGER NOT NULL DEFAULT 0,
+                sms_alerted INTEGER NOT NULL DEFAULT 0,
+                data_alerted INTEGER NOT NULL DEFAULT 0,
+                updated_at INTEGER NOT NULL,
+                PRIMARY KEY (device_id, cycle_start)
+            )",
+            [],
+        )
+        .context("Failed to create monthly_usage table")?;
+
+        // The traffic counters a device reported last, usage is what they grew by
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS data_counters (
+                device_id TEXT PRIMARY KEY,
+                tx_bytes INTEGER NOT NULL,
+                rx_bytes INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+            )",
+            [],
+        )
+        .context("Failed to create data_counters table")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS network_status (
+                device_id TEXT PRIMARY KEY,
+                status INTEGER NOT NULL,
+                operator TEXT,
+                network TEXT,
+                rssi INTEGER,
+                updated_at INTEGER NOT NULL
+            )",
+            [],
+        )
+        .context("Failed to create network_status table")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS network_events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                device_id TEXT NOT NULL,
+                operator TEXT NOT NULL,
+                roaming INTEGER NOT NULL,
+                previous_operator TEXT,
+                previous_roaming INTEGER,
+                changed_at INTEGER NOT NULL
+            )",
+            [],
+        )
+        .context("Failed to create network_events table")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS settings (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            )",
+            [],
+        )
+        .context("Failed to create settings table")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS admin_commands (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                device_id TEXT NOT NULL,
+                command TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                handled_at INTEGER
+            )",
+            [],
+        )
+        .context("Failed to create admin_commands table")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS audit_log (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                actor TEXT NOT NULL,
+                action TEXT NOT NULL,
+                detail TEXT,
+                created_at INTEGER NOT NULL
+            )",
+            [],
+        )
+        .context("Failed to create audit_log table")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS webhook_deliveries (
+                id TEXT PRIMARY KEY,
+                payload TEXT NOT NULL,
+                status TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt_at INTEGER NOT NULL,
+                last_error TEXT,
+                created_at INTEGER NOT NULL,
+                delivered_at INTEGER
+            )",
+            [],
+        )
+        .context("Failed to create webhook_deliveries table")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS sms_timings (
+                sms_id TEXT PRIMARY KEY,
+                device_id TEXT NOT NULL,
+                received_at INTEGER NOT NULL,
+                read_at_ms INTEGER NOT NULL,
+                stored_at_ms INTEGER NOT NULL,
+                notified_at_ms INTEGER,
+                ack_sent_at_ms INTEGER NOT NULL
+            )",
+            [],
+        )
+        .context("Failed to create sms_timings table")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS notification_history (
+                hash TEXT PRIMARY KEY,
+                notified_at INTEGER NOT NULL
+            )",
+            [],
+        )
+        .context("Failed to create notification_history table")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS outbound_sms (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                batch_id TEXT,
+                device_id TEXT,
+                recipient TEXT NOT NULL,
+                content TEXT NOT NULL,
+                status TEXT NOT NULL,
+                error TEXT,
+                created_at INTEGER NOT NULL,
+                sent_at INTEGER
+            )",
+            [],
+        )
+        .context("Failed to create outbound_sms table")?;
+
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_outbound_status ON outbound_sms(status)",
+            [],
+        )
+        .context("Failed to create outbound status index")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS templates (
+                name TEXT PRIMARY KEY,
+                body TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            )",
+            [],
+        )
+        .context("Failed to create templates table")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS opt_outs (
+                number TEXT PRIMARY KEY,
+                source TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )",
+            [],
+        )
+        .context("Failed to create opt_outs table")?;
+        Self::ensure_column(
+            &conn,
+            "outbound_sms",
+            "urgent",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        Self::ensure_column(&conn, "connection_status", "error_category", "TEXT")?;
+        Self::ensure_column(&conn, "connection_status", "error", "TEXT")?;
+        Self::ensure_column(
+            &conn,
+            "connection_status",
+            "reconnect_attempts",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        Self::ensure_column(
+            &conn,
+            "connection_status",
+            "held_notifications",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        Self::ensure_column(
+            &conn,
+            "connection_status",
+            "pending_receipts",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        for column in ["framing_errors", "invalid_utf8_frames", "dropped_bytes"] {
+            Self::ensure_column(
+                &conn,
+                "connection_status",
+                column,
+                "INTEGER NOT NULL DEFAULT 0",
+            )?;
+        }
+
         log::info!("Database initialized at: {}", path);
 
         Ok(Database {
             conn: Arc::new(Mutex::new(conn)),
+            cipher: None,
         })
     }
 
-    pub fn insert_sms(&self, msg: &SmsMessage) -> Result<()> {
+    // Returns true when the column had to be added
+    fn ensure_column(
+        conn: &Connection,
+        table: &str,
+        column: &str,
+        definition: &str,
+    ) -> Result<bool> {
+        let mut stmt = conn
+            .prepare(&format!("PRAGMA table_info({})", table))
+            .context(format!("Failed to read schema of table {}", table))?;
+        let exists = stmt
+            .query_map([], |row| row.get::<_, String>(1))?
+            .filter_map(|name| name.ok())
+            .any(|name| name == column);
+
+        if !exists {
+            conn.execute(
+                &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+                [],
+            )
+            .context(format!("Failed to add column {}.{}", table, column))?;
+            log::info!("Database schema upgraded: added {}.{}", table, column);
+        }
+
+        Ok(!exists)
+    }
+
+    // Returns false when a message with this id is already stored
+    // `raw_payload` is the payload as received when it was not valid UTF-8
+    pub fn insert_sms(&self, msg: &SmsMessage, raw_payload: Option<&[u8]>) -> Result<bool> {
+        let content = self.seal_content(&msg.content)?;
+        let raw_payload = raw_payload
+            .map(|raw| self.seal_content(&STANDARD.encode(raw)))
+            .transpose()?;
         let conn = self.conn.lock().unwrap();
         let created_at = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_secs() as i64;
 
-        conn.execute(
-            "INSERT INTO sms_messages (id, sender, content, received_at, metas, acknowledged, created_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, 0, ?6)",
+        let rows_affected = conn.execute(
+            "INSERT OR IGNORE INTO sms_messages (id, sender, content, received_at, metas, acknowledged, created_at, spam_score, is_spam, device_id, sender_type, sender_country, message_class, status, encoding_suspect, raw_payload)
+             VALUES (?1, ?2, ?3, ?4, ?5, 0, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
             params![
                 &msg.id,
                 &msg.sender,
-                &msg.content,
+                content,
                 msg.received_at,
                 &msg.metas,
                 created_at,
+                msg.spam_score,
+                msg.is_spam,
+                &msg.device_id,
+                &msg.sender_type,
+                &msg.sender_country,
+                msg.message_class,
+                if msg.is_spam { SMS_NOTIFIED } else { SMS_STORED },
+                msg.encoding_suspect,
+                raw_payload,
             ],
         ).context(format!("Failed to insert SMS message: {}", msg.id))?;
+        if rows_affected == 0 {
+            return Ok(false);
+        }
 
         log::info!("SMS message inserted into database: {}", msg.id);
-        Ok(())
+        Ok(true)
     }
 
     pub fn mark_acknowledged(&self, id: &str) -> Result<()> {
@@ -74,8 +930,11 @@ impl Database {
 
         let rows_affected = conn
             .execute(
-                "UPDATE sms_messages SET acknowledged = 1, ack_sent_at = ?1 WHERE id = ?2",
-                params![ack_time, id],
+                "UPDATE sms_messages
+                 SET acknowledged = 1, ack_sent_at = ?1,
+                     status = CASE WHEN status IN (?3, ?4) THEN ?4 ELSE ?5 END
+                 WHERE id = ?2",
+                params![ack_time, id, SMS_STORED, SMS_HELD, SMS_ACKED],
             )
             .context(format!("Failed to mark message as acknowledged: {}", id))?;
 
@@ -88,27 +947,2032 @@ impl Database {
         Ok(())
     }
 
-    pub fn count_total(&self) -> Result<i64> {
+    // None when the SMS is not stored, otherwise whether it was notified or tagged as spam
+    pub fn sms_notified(&self, id: &str) -> Result<Option<bool>> {
         let conn = self.conn.lock().unwrap();
-        let count: i64 = conn
-            .query_row("SELECT COUNT(*) FROM sms_messages", [], |row| row.get(0))
-            .context("Failed to count total messages")?;
+        conn.query_row(
+            "SELECT status NOT IN (?2, ?3) FROM sms_messages WHERE id = ?1",
+            params![id, SMS_STORED, SMS_HELD],
+            |row| row.get(0),
+        )
+        .optional()
+        .context(format!("Failed to look up SMS {}", id))
+    }
 
-        Ok(count)
+    pub fn sms_progress(&self, id: &str) -> Result<Option<SmsProgress>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT status, device_id, created_at, notified_at, ack_sent_at, is_spam
+             FROM sms_messages WHERE id = ?1",
+            params![id],
+            |row| {
+                Ok(SmsProgress {
+                    status: row.get(0)?,
+                    device_id: row.get(1)?,
+                    stored_at: row.get(2)?,
+                    notified_at: row.get(3)?,
+                    ack_sent_at: row.get(4)?,
+                    is_spam: row.get(5)?,
+                })
+            },
+        )
+        .optional()
+        .context(format!("Failed to look up SMS {}", id))
     }
 
-    pub fn count_unacknowledged(&self) -> Result<i64> {
+    pub fn mark_notified(&self, id: &str) -> Result<()> {
         let conn = self.conn.lock().unwrap();
-        let count: i64 = conn
-            .query_row(
-                "SELECT COUNT(*) FROM sms_messages WHERE acknowledged = 0",
-                [],
+        conn.execute(
+            "UPDATE sms_messages
+             SET notified_at = ?1,
+                 status = CASE WHEN status IN (?3, ?4) THEN ?4 ELSE ?5 END
+             WHERE id = ?2",
+            params![unix_timestamp(), id, SMS_HELD, SMS_ACKED, SMS_NOTIFIED],
+        )
+        .context(format!("Failed to mark message as notified: {}", id))?;
+
+        Ok(())
+    }
+
+    pub fn insert_sms_timing(&self, timing: &SmsTiming) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO sms_timings
+                 (sms_id, device_id, received_at, read_at_ms, stored_at_ms, notified_at_ms, ack_sent_at_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                timing.sms_id,
+                timing.device_id,
+                timing.received_at,
+                timing.read_at_ms,
+                timing.stored_at_ms,
+                timing.notified_at_ms,
+                timing.ack_sent_at_ms
+            ],
+        )
+        .context(format!("Failed to record timing of SMS {}", timing.sms_id))?;
+
+        Ok(())
+    }
+
+    pub fn sms_timings(&self) -> Result<Vec<SmsTiming>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT sms_id, device_id, received_at, read_at_ms, stored_at_ms, notified_at_ms, ack_sent_at_ms
+             FROM sms_timings ORDER BY device_id, read_at_ms",
+        )?;
+        let timings = stmt
+            .query_map([], |row| {
+                Ok(SmsTiming {
+                    sms_id: row.get(0)?,
+                    device_id: row.get(1)?,
+                    received_at: row.get(2)?,
+                    read_at_ms: row.get(3)?,
+                    stored_at_ms: row.get(4)?,
+                    notified_at_ms: row.get(5)?,
+                    ack_sent_at_ms: row.get(6)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to read SMS timings")?;
+
+        Ok(timings)
+    }
+
+    // Whether an SMS with this sender and content was notified at or after `since`
+    pub fn notified_since(&self, sender: &str, content: &str, since: i64) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let notified_at: Option<i64> = conn
+            .query_row(
+                "SELECT notified_at FROM notification_history WHERE hash = ?1",
+                params![notification_hash(self.cipher.as_deref(), sender, content)],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("Failed to read notification history")?;
+
+        Ok(notified_at.is_some_and(|at| at >= since))
+    }
+
+    // Remember a sent notification, forgetting those notified before `prune_before`
+    pub fn record_notification(
+        &self,
+        sender: &str,
+        content: &str,
+        prune_before: i64,
+    ) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO notification_history (hash, notified_at) VALUES (?1, ?2)",
+            params![
+                notification_hash(self.cipher.as_deref(), sender, content),
+                unix_timestamp()
+            ],
+        )
+        .context("Failed to record notification")?;
+        conn.execute(
+            "DELETE FROM notification_history WHERE notified_at < ?1",
+            params![prune_before],
+        )
+        .context("Failed to prune notification history")?;
+
+        Ok(())
+    }
+
+    // Non-spam messages of a device stored since `since` whose notification never succeeded
+    pub fn unnotified_since(&self, device_id: Option<&str>, since: i64) -> Result<Vec<SmsMessage>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, sender, content, received_at, metas, spam_score, is_spam, device_id,
+                    sender_type, sender_country, message_class, encoding_suspect
+             FROM sms_messages
+             WHERE status IN (?3, ?4) AND device_id IS ?1 AND created_at >= ?2
+             ORDER BY created_at",
+        )?;
+        let messages = stmt
+            .query_map(params![device_id, since, SMS_STORED, SMS_HELD], |row| {
+                Ok(SmsMessage {
+                    id: row.get(0)?,
+                    sender: row.get(1)?,
+                    content: row.get(2)?,
+                    received_at: row.get(3)?,
+                    metas: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+                    spam_score: row.get(5)?,
+                    is_spam: row.get(6)?,
+                    device_id: row.get(7)?,
+                    sender_type: row.get(8)?,
+                    sender_country: row.get(9)?,
+                    message_class: row.get(10)?,
+                    encoding_suspect: row.get(11)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query unnotified messages")?;
+
+        messages
+            .into_iter()
+            .map(|mut msg| {
+                msg.content = self.open_content(msg.content)?;
+                Ok(msg)
+            })
+            .collect()
+    }
+
+    pub fn get_sms(&self, id: &str) -> Result<Option<SmsMessage>> {
+        let conn = self.conn.lock().unwrap();
+        let message = conn
+            .query_row(
+                "SELECT id, sender, content, received_at, metas, spam_score, is_spam, device_id,
+                        sender_type, sender_country, message_class, encoding_suspect
+                 FROM sms_messages WHERE id = ?1",
+                params![id],
+                |row| {
+                    Ok(SmsMessage {
+                        id: row.get(0)?,
+                        sender: row.get(1)?,
+                        content: row.get(2)?,
+                        received_at: row.get(3)?,
+                        metas: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+                        spam_score: row.get(5)?,
+                        is_spam: row.get(6)?,
+                        device_id: row.get(7)?,
+                        sender_type: row.get(8)?,
+                        sender_country: row.get(9)?,
+                        message_class: row.get(10)?,
+                        encoding_suspect: row.get(11)?,
+                    })
+                },
+            )
+            .optional()
+            .context(format!("Failed to look up SMS {}", id))?;
+
+        match message {
+            Some(mut msg) => {
+                msg.content = self.open_content(msg.content)?;
+                Ok(Some(msg))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Messages of a device stored but never ACKed to it, e.g. because the server
+    // stopped in between. The device keeps retransmitting them meanwhile
+    pub fn get_unacknowledged(&self, device_id: Option<&str>) -> Result<Vec<UnacknowledgedSms>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, sender, content, received_at, metas, spam_score, is_spam, device_id,
+                    sender_type, sender_country, message_class, created_at, notified_at,
+                    encoding_suspect
+             FROM sms_messages
+             WHERE status IN (?2, ?3) AND device_id IS ?1
+             ORDER BY created_at",
+        )?;
+        let messages = stmt
+            .query_map(params![device_id, SMS_STORED, SMS_NOTIFIED], |row| {
+                Ok(UnacknowledgedSms {
+                    message: SmsMessage {
+                        id: row.get(0)?,
+                        sender: row.get(1)?,
+                        content: row.get(2)?,
+                        received_at: row.get(3)?,
+                        metas: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+                        spam_score: row.get(5)?,
+                        is_spam: row.get(6)?,
+                        device_id: row.get(7)?,
+                        sender_type: row.get(8)?,
+                        sender_country: row.get(9)?,
+                        message_class: row.get(10)?,
+                        encoding_suspect: row.get(13)?,
+                    },
+                    created_at: row.get(11)?,
+                    notified_at: row.get(12)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query unacknowledged messages")?;
+
+        messages
+            .into_iter()
+            .map(|mut unacked| {
+                unacked.message.content = self.open_content(unacked.message.content)?;
+                Ok(unacked)
+            })
+            .collect()
+    }
+
+    pub fn contains_sms(&self, id: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let found = conn
+            .query_row(
+                "SELECT 1 FROM sms_messages WHERE id = ?1",
+                params![id],
+                |_| Ok(()),
+            )
+            .optional()
+            .context(format!("Failed to look up SMS message: {}", id))?;
+
+        Ok(found.is_some())
+    }
+
+    // Whether the SMS was notified, or tagged as spam and never will be
+    pub fn sms_settled(&self, id: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let found = conn
+            .query_row(
+                "SELECT 1 FROM sms_messages
+                 WHERE id = ?1 AND status IN (?2, ?3)",
+                params![id, SMS_NOTIFIED, SMS_ACKED],
+                |_| Ok(()),
+            )
+            .optional()
+            .context(format!("Failed to look up SMS message: {}", id))?;
+
+        Ok(found.is_some())
+    }
+
+    // Alphanumeric ids are compared ignoring case, networks differ in how they spell them
+    pub fn sender_history(&self, sender: &str, spam_since: i64) -> Result<SenderHistory> {
+        let conn = self.conn.lock().unwrap();
+        let history = conn
+            .query_row(
+                "SELECT COALESCE(SUM(is_spam = 0), 0),
+                        COALESCE(SUM(is_spam = 1 AND created_at >= ?2), 0)
+                 FROM sms_messages
+                 WHERE TRIM(sender) = ?1 COLLATE NOCASE",
+                params![sender.trim(), spam_since],
+                |row| {
+                    Ok(SenderHistory {
+                        known: row.get(0)?,
+                        spam: row.get(1)?,
+                    })
+                },
+            )
+            .context(format!("Failed to query history of sender: {}", sender))?;
+
+        Ok(history)
+    }
+
+    pub fn insert_escalation(
+        &self,
+        message_id: &str,
+        title: &str,
+        content: &str,
+        next_at: i64,
+    ) -> Result<()> {
+        let content = self.seal_content(content)?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR IGNORE INTO escalations (message_id, title, content, repeats, next_at, created_at)
+             VALUES (?1, ?2, ?3, 0, ?4, ?5)",
+            params![message_id, title, content, next_at, unix_timestamp()],
+        )
+        .context(format!("Failed to insert escalation: {}", message_id))?;
+
+        log::info!("Escalation scheduled for message: {}", message_id);
+        Ok(())
+    }
+
+    pub fn due_escalations(&self, now: i64, max_repeats: u32) -> Result<Vec<Escalation>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT message_id, title, content, repeats FROM escalations
+             WHERE acknowledged_at IS NULL AND next_at <= ?1 AND repeats < ?2
+             ORDER BY next_at",
+        )?;
+        let escalations = stmt
+            .query_map(params![now, max_repeats], |row| {
+                Ok(Escalation {
+                    message_id: row.get(0)?,
+                    title: row.get(1)?,
+                    content: row.get(2)?,
+                    repeats: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query due escalations")?;
+
+        escalations
+            .into_iter()
+            .map(|mut escalation| {
+                escalation.content = self.open_content(escalation.content)?;
+                Ok(escalation)
+            })
+            .collect()
+    }
+
+    pub fn record_escalation_repeat(&self, message_id: &str, next_at: i64) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE escalations SET repeats = repeats + 1, next_at = ?1 WHERE message_id = ?2",
+            params![next_at, message_id],
+        )
+        .context(format!("Failed to update escalation: {}", message_id))?;
+
+        Ok(())
+    }
+
+    // Returns false when there is no pending escalation for the message
+    // Stop the escalation of a message and record who handled it. Returns who
+    // handled it first, None when neither the message nor an escalation exists
+    pub fn mark_handled(&self, message_id: &str, by: &str) -> Result<Option<String>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let now = unix_timestamp();
+        let messages = tx
+            .execute(
+                "UPDATE sms_messages
+                 SET handled_at = COALESCE(handled_at, ?1), handled_by = COALESCE(handled_by, ?2)
+                 WHERE id = ?3",
+                params![now, by, message_id],
+            )
+            .context(format!("Failed to mark message handled: {}", message_id))?;
+        let escalations = tx
+            .execute(
+                "UPDATE escalations SET acknowledged_at = ?1
+                 WHERE message_id = ?2 AND acknowledged_at IS NULL",
+                params![now, message_id],
+            )
+            .context(format!("Failed to acknowledge escalation: {}", message_id))?;
+        let handled_by = tx
+            .query_row(
+                "SELECT handled_by FROM sms_messages WHERE id = ?1",
+                params![message_id],
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .optional()?
+            .flatten();
+        tx.commit()?;
+
+        if messages + escalations == 0 {
+            return Ok(None);
+        }
+        Ok(Some(handled_by.unwrap_or_else(|| by.to_string())))
+    }
+
+    pub fn upsert_device(&self, device: &DeviceRecord) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO devices
+             (device_id, imei, number, iccid, firmware, script_version, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                device.device_id,
+                device.imei,
+                device.number,
+                device.iccid,
+                device.firmware,
+                device.script_version,
+                device.updated_at,
+            ],
+        )
+        .context(format!("Failed to store device info: {}", device.device_id))?;
+
+        Ok(())
+    }
+
+    pub fn devices(&self) -> Result<Vec<DeviceRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT device_id, imei, number, iccid, firmware, script_version, updated_at
+             FROM devices ORDER BY device_id",
+        )?;
+        let devices = stmt
+            .query_map([], |row| {
+                Ok(DeviceRecord {
+                    device_id: row.get(0)?,
+                    imei: row.get(1)?,
+                    number: row.get(2)?,
+                    iccid: row.get(3)?,
+                    firmware: row.get(4)?,
+                    script_version: row.get(5)?,
+                    updated_at: row.get(6)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query devices")?;
+
+        Ok(devices)
+    }
+
+    pub fn save_connection_status(&self, status: &ConnectionStatus) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let stats = &status.stats;
+        conn.execute(
+            "INSERT OR REPLACE INTO connection_status
+             (device_id, state, connected_at, reconnects, frames_received, parse_failures,
+              acks_sent, bytes_read, bytes_written, error_category, error, updated_at,
+              reconnect_attempts, held_notifications, pending_receipts, framing_errors,
+              invalid_utf8_frames, dropped_bytes)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16,
+                     ?17, ?18)",
+            params![
+                status.device_id,
+                status.state,
+                stats.connected_at,
+                stats.reconnects,
+                stats.frames_received as i64,
+                stats.parse_failures as i64,
+                stats.acks_sent as i64,
+                stats.bytes_read as i64,
+                stats.bytes_written as i64,
+                status.error_category,
+                status.error,
+                status.updated_at,
+                stats.reconnect_attempts,
+                stats.held_notifications as i64,
+                stats.pending_receipts as i64,
+                stats.framing_errors as i64,
+                stats.invalid_utf8_frames as i64,
+                stats.dropped_bytes as i64,
+            ],
+        )
+        .context(format!(
+            "Failed to store connection status: {}",
+            status.device_id
+        ))?;
+
+        Ok(())
+    }
+
+    // Only the state, the other columns are written by `save_connection_status`
+    pub fn update_connection_state(&self, device_id: &str, state: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE connection_status SET state = ?2, updated_at = ?3 WHERE device_id = ?1",
+            params![device_id, state, unix_timestamp()],
+        )
+        .context(format!("Failed to store connection state: {}", device_id))?;
+        Ok(())
+    }
+
+    pub fn connection_statuses(&self) -> Result<Vec<ConnectionStatus>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT device_id, state, connected_at, reconnects, frames_received, parse_failures,
+                    acks_sent, bytes_read, bytes_written, error_category, error, updated_at,
+                    reconnect_attempts, held_notifications, pending_receipts, framing_errors,
+                    invalid_utf8_frames, dropped_bytes
+             FROM connection_status ORDER BY device_id",
+        )?;
+        let statuses = stmt
+            .query_map([], |row| {
+                Ok(ConnectionStatus {
+                    device_id: row.get(0)?,
+                    state: row.get(1)?,
+                    stats: SessionStats {
+                        connected_at: row.get(2)?,
+                        reconnects: row.get(3)?,
+                        reconnect_attempts: row.get(12)?,
+                        frames_received: row.get::<_, i64>(4)? as u64,
+                        parse_failures: row.get::<_, i64>(5)? as u64,
+                        acks_sent: row.get::<_, i64>(6)? as u64,
+                        bytes_read: row.get::<_, i64>(7)? as u64,
+                        bytes_written: row.get::<_, i64>(8)? as u64,
+                        held_notifications: row.get::<_, i64>(13)? as u64,
+                        pending_receipts: row.get::<_, i64>(14)? as u64,
+                        framing_errors: row.get::<_, i64>(15)? as u64,
+                        invalid_utf8_frames: row.get::<_, i64>(16)? as u64,
+                        dropped_bytes: row.get::<_, i64>(17)? as u64,
+                    },
+                    error_category: row.get(9)?,
+                    error: row.get(10)?,
+                    updated_at: row.get(11)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query connection status")?;
+
+        Ok(statuses)
+    }
+
+    pub fn save_process_stats(&self, stats: &ProcessStats) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO process_stats
+             (id, pid, rss_bytes, open_fds, threads, workers, alive_tasks,
+              global_queue_depth, updated_at)
+             VALUES (1, ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                stats.pid,
+                stats.rss_bytes.map(|bytes| bytes as i64),
+                stats.open_fds.map(|fds| fds as i64),
+                stats.threads.map(|threads| threads as i64),
+                stats.workers as i64,
+                stats.alive_tasks as i64,
+                stats.global_queue_depth as i64,
+                stats.updated_at,
+            ],
+        )
+        .context("Failed to store process stats")?;
+
+        Ok(())
+    }
+
+    // The last sample of the server, None before one was taken
+    pub fn process_stats(&self) -> Result<Option<ProcessStats>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT pid, rss_bytes, open_fds, threads, workers, alive_tasks,
+                    global_queue_depth, updated_at
+             FROM process_stats WHERE id = 1",
+            [],
+            |row| {
+                Ok(ProcessStats {
+                    pid: row.get(0)?,
+                    rss_bytes: row.get::<_, Option<i64>>(1)?.map(|bytes| bytes as u64),
+                    open_fds: row.get::<_, Option<i64>>(2)?.map(|fds| fds as u64),
+                    threads: row.get::<_, Option<i64>>(3)?.map(|threads| threads as u64),
+                    workers: row.get::<_, i64>(4)? as u64,
+                    alive_tasks: row.get::<_, i64>(5)? as u64,
+                    global_queue_depth: row.get::<_, i64>(6)? as u64,
+                    updated_at: row.get(7)?,
+                })
+            },
+        )
+        .optional()
+        .context("Failed to read process stats")
+    }
+
+    // Add to the usage of a device in the cycle starting on `cycle_start`,
+    // returning the cycle's totals
+    pub fn add_usage(
+        &self,
+        device_id: &str,
+        cycle_start: &str,
+        sms_sent: u64,
+        data_tx_bytes: u64,
+        data_rx_bytes: u64,
+    ) -> Result<MonthlyUsage> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO monthly_usage
+                 (device_id, cycle_start, sms_sent, data_tx_bytes, data_rx_bytes, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(device_id, cycle_start) DO UPDATE SET
+                 sms_sent = sms_sent + ?3,
+                 data_tx_bytes = data_tx_bytes + ?4,
+                 data_rx_bytes = data_rx_bytes + ?5,
+                 updated_at = ?6",
+            params![
+                device_id,
+                cycle_start,
+                sms_sent as i64,
+                data_tx_bytes as i64,
+                data_rx_bytes as i64,
+                unix_timestamp()
+            ],
+        )
+        .context(format!("Failed to record usage of {}", device_id))?;
+        conn.query_row(
+            &format!("{} WHERE device_id = ?1 AND cycle_start = ?2", USAGE_QUERY),
+            params![device_id, cycle_start],
+            usage_from_row,
+        )
+        .context(format!("Failed to read usage of {}", device_id))
+    }
+
+    pub fn mark_usage_alerted(
+        &self,
+        device_id: &str,
+        cycle_start: &str,
+        limit: UsageLimit,
+    ) -> Result<()> {
+        let column = match limit {
+            UsageLimit::Sms => "sms_alerted",
+            UsageLimit::Data => "data_alerted",
+        };
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            &format!(
+                "UPDATE monthly_usage SET {} = 1 WHERE device_id = ?1 AND cycle_start = ?2",
+                column
+            ),
+            params![device_id, cycle_start],
+        )
+        .context(format!("Failed to record usage alert of {}", device_id))?;
+
+        Ok(())
+    }
+
+    // Usage in the cycle starting on `cycle_start`, or the latest cycle of
+    // every device when None
+    pub fn monthly_usage(&self, cycle_start: Option<&str>) -> Result<Vec<MonthlyUsage>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "{} WHERE cycle_start = COALESCE(?1, (SELECT MAX(cycle_start) FROM monthly_usage AS latest
+                                                  WHERE latest.device_id = monthly_usage.device_id))
+             ORDER BY device_id",
+            USAGE_QUERY
+        ))?;
+        let usage = stmt
+            .query_map(params![cycle_start], usage_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query usage")?;
+
+        Ok(usage)
+    }
+
+    // Store the traffic counters a device reported and return how much they
+    // grew since the last report. Counters that went down were reset by a
+    // restart of the module and count from zero; the first report only sets
+    // the starting point
+    pub fn advance_data_counters(
+        &self,
+        device_id: &str,
+        tx_bytes: u64,
+        rx_bytes: u64,
+    ) -> Result<(u64, u64)> {
+        let conn = self.conn.lock().unwrap();
+        let previous: Option<(i64, i64)> = conn
+            .query_row(
+                "SELECT tx_bytes, rx_bytes FROM data_counters WHERE device_id = ?1",
+                params![device_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO data_counters (device_id, tx_bytes, rx_bytes, updated_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                device_id,
+                tx_bytes as i64,
+                rx_bytes as i64,
+                unix_timestamp()
+            ],
+        )
+        .context(format!("Failed to store data counters of {}", device_id))?;
+
+        let grown =
+            |current: u64, previous: i64| current.checked_sub(previous as u64).unwrap_or(current);
+        Ok(match previous {
+            Some((tx, rx)) => (grown(tx_bytes, tx), grown(rx_bytes, rx)),
+            None => (0, 0),
+        })
+    }
+
+    pub fn save_network_status(&self, record: &NetworkRecord) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO network_status
+             (device_id, status, operator, network, rssi, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                record.device_id,
+                record.status,
+                record.operator,
+                record.network,
+                record.rssi,
+                record.updated_at,
+            ],
+        )
+        .context(format!(
+            "Failed to store network status: {}",
+            record.device_id
+        ))?;
+
+        Ok(())
+    }
+
+    pub fn network_statuses(&self) -> Result<Vec<NetworkRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT device_id, status, operator, network, rssi, updated_at
+             FROM network_status ORDER BY device_id",
+        )?;
+        let records = stmt
+            .query_map([], |row| {
+                Ok(NetworkRecord {
+                    device_id: row.get(0)?,
+                    status: row.get(1)?,
+                    operator: row.get(2)?,
+                    network: row.get(3)?,
+                    rssi: row.get(4)?,
+                    updated_at: row.get(5)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query network status")?;
+
+        Ok(records)
+    }
+
+    pub fn insert_network_event(&self, event: &NetworkEvent) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO network_events
+             (device_id, operator, roaming, previous_operator, previous_roaming, changed_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                event.device_id,
+                event.operator,
+                event.roaming,
+                event.previous_operator,
+                event.previous_roaming,
+                event.changed_at,
+            ],
+        )
+        .context(format!(
+            "Failed to store network event: {}",
+            event.device_id
+        ))?;
+
+        Ok(())
+    }
+
+    // Most recent operator change of a device, which holds the operator it is attached to
+    pub fn last_network_event(&self, device_id: &str) -> Result<Option<NetworkEvent>> {
+        let conn = self.conn.lock().unwrap();
+        let event = conn
+            .query_row(
+                "SELECT device_id, operator, roaming, previous_operator, previous_roaming, changed_at
+                 FROM network_events WHERE device_id = ?1 ORDER BY id DESC LIMIT 1",
+                params![device_id],
+                |row| {
+                    Ok(NetworkEvent {
+                        device_id: row.get(0)?,
+                        operator: row.get(1)?,
+                        roaming: row.get(2)?,
+                        previous_operator: row.get(3)?,
+                        previous_roaming: row.get(4)?,
+                        changed_at: row.get(5)?,
+                    })
+                },
+            )
+            .optional()
+            .context(format!("Failed to query network events: {}", device_id))?;
+
+        Ok(event)
+    }
+
+    pub fn insert_audit(&self, actor: &str, action: &str, detail: Option<&str>) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO audit_log (actor, action, detail, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![actor, action, detail, unix_timestamp()],
+        )
+        .context(format!("Failed to record {} in the audit log", action))?;
+
+        Ok(())
+    }
+
+    // The latest `limit` entries, oldest first
+    pub fn audit_entries(&self, limit: usize) -> Result<Vec<AuditEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT actor, action, detail, created_at FROM (
+                SELECT id, actor, action, detail, created_at FROM audit_log
+                ORDER BY id DESC LIMIT ?1
+             ) ORDER BY id",
+        )?;
+        let entries = stmt
+            .query_map(params![limit as i64], |row| {
+                Ok(AuditEntry {
+                    actor: row.get(0)?,
+                    action: row.get(1)?,
+                    detail: row.get(2)?,
+                    created_at: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query audit log")?;
+
+        Ok(entries)
+    }
+
+    pub fn insert_webhook_delivery(&self, id: &str, payload: &str) -> Result<()> {
+        let payload = self.seal_content(payload)?;
+        let conn = self.conn.lock().unwrap();
+        let now = unix_timestamp();
+        // The same notification sent again is delivered again under its old id,
+        // with fresh attempts even if it was given up before
+        conn.execute(
+            "INSERT INTO webhook_deliveries (id, payload, status, next_attempt_at, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?4)
+             ON CONFLICT(id) DO UPDATE SET
+                payload = excluded.payload,
+                status = excluded.status,
+                attempts = 0,
+                next_attempt_at = excluded.next_attempt_at,
+                last_error = NULL,
+                delivered_at = NULL",
+            params![id, payload, WEBHOOK_PENDING, now],
+        )
+        .context(format!("Failed to queue webhook delivery: {}", id))?;
+
+        Ok(())
+    }
+
+    pub fn due_webhook_deliveries(&self, now: i64) -> Result<Vec<WebhookDelivery>> {
+        self.webhook_deliveries(
+            "WHERE status = ?1 AND next_attempt_at <= ?2 ORDER BY created_at",
+            params![WEBHOOK_PENDING, now],
+        )
+    }
+
+    pub fn dead_webhook_deliveries(&self) -> Result<Vec<WebhookDelivery>> {
+        self.webhook_deliveries(
+            "WHERE status = ?1 ORDER BY created_at",
+            params![WEBHOOK_DEAD],
+        )
+    }
+
+    fn webhook_deliveries(
+        &self,
+        filter: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<WebhookDelivery>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, payload, attempts, last_error, created_at FROM webhook_deliveries {}",
+            filter
+        ))?;
+        let deliveries = stmt
+            .query_map(params, |row| {
+                Ok(WebhookDelivery {
+                    id: row.get(0)?,
+                    payload: row.get(1)?,
+                    attempts: row.get(2)?,
+                    last_error: row.get(3)?,
+                    created_at: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query webhook deliveries")?;
+
+        deliveries
+            .into_iter()
+            .map(|mut delivery| {
+                delivery.payload = self.open_content(delivery.payload)?;
+                Ok(delivery)
+            })
+            .collect()
+    }
+
+    pub fn webhook_delivered(&self, id: &str, attempts: u32) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE webhook_deliveries SET status = ?1, attempts = ?2, last_error = NULL, delivered_at = ?3
+             WHERE id = ?4",
+            params![WEBHOOK_DELIVERED, attempts, unix_timestamp(), id],
+        )
+        .context(format!("Failed to update webhook delivery: {}", id))?;
+
+        Ok(())
+    }
+
+    // Retry at `next_attempt_at`, or give up and keep it as a dead letter when None
+    pub fn webhook_failed(
+        &self,
+        id: &str,
+        attempts: u32,
+        next_attempt_at: Option<i64>,
+        error: &str,
+    ) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let status = if next_attempt_at.is_some() {
+            WEBHOOK_PENDING
+        } else {
+            WEBHOOK_DEAD
+        };
+        conn.execute(
+            "UPDATE webhook_deliveries
+             SET status = ?1, attempts = ?2, next_attempt_at = COALESCE(?3, next_attempt_at), last_error = ?4
+             WHERE id = ?5",
+            params![status, attempts, next_attempt_at, error, id],
+        )
+        .context(format!("Failed to update webhook delivery: {}", id))?;
+
+        Ok(())
+    }
+
+    // Send deliveries again with a fresh attempt count: the given ones whatever
+    // their state, e.g. for a consumer that lost them, or every dead letter
+    pub fn redeliver_webhooks(&self, ids: Option<&[String]>) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let now = unix_timestamp();
+        let rows_affected = match ids {
+            None => conn.execute(
+                "UPDATE webhook_deliveries SET status = ?1, attempts = 0, next_attempt_at = ?2
+                 WHERE status = ?3",
+                params![WEBHOOK_PENDING, now, WEBHOOK_DEAD],
+            ),
+            Some(ids) => ids.iter().try_fold(0, |total, id| {
+                conn.execute(
+                    "UPDATE webhook_deliveries SET status = ?1, attempts = 0, next_attempt_at = ?2
+                     WHERE id = ?3",
+                    params![WEBHOOK_PENDING, now, id],
+                )
+                .map(|rows_affected| total + rows_affected)
+            }),
+        }
+        .context("Failed to queue webhook redelivery")?;
+
+        Ok(rows_affected)
+    }
+
+    // Queue (recipient, content) pairs in one transaction, so a batch is never half
+    // queued. Urgent SMS skip quiet hours. Returns the ids of the queued SMS
+    pub fn queue_outbound(
+        &self,
+        batch_id: Option<&str>,
+        device_id: Option<&str>,
+        urgent: bool,
+        messages: &[(String, String)],
+    ) -> Result<Vec<i64>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let now = unix_timestamp();
+        let mut ids = Vec::with_capacity(messages.len());
+        for (recipient, content) in messages {
+            tx.execute(
+                "INSERT INTO outbound_sms (batch_id, device_id, recipient, content, status, created_at, urgent)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    batch_id,
+                    device_id,
+                    recipient,
+                    self.seal_content(content)?,
+                    OUTBOUND_PENDING,
+                    now,
+                    urgent
+                ],
+            )
+            .context(format!("Failed to queue SMS to {}", recipient))?;
+            ids.push(tx.last_insert_rowid());
+        }
+        tx.commit().context("Failed to queue outbound SMS")?;
+
+        Ok(ids)
+    }
+
+    // An SMS sent right away instead of through the queue, a forward or an
+    // opt-out confirmation. It starts as sending, so the device's SMS_SENT
+    // finishes it like a queued one and the cost estimate counts it
+    pub fn insert_direct_outbound(
+        &self,
+        device_id: Option<&str>,
+        recipient: &str,
+        content: &str,
+    ) -> Result<i64> {
+        let content = self.seal_content(content)?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO outbound_sms (device_id, recipient, content, status, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                device_id,
+                recipient,
+                content,
+                OUTBOUND_SENDING,
+                unix_timestamp()
+            ],
+        )
+        .context(format!("Failed to record SMS to {}", recipient))?;
+
+        Ok(conn.last_insert_rowid())
+    }
+
+    // Id of an SMS saying exactly `content` received since `since`, the content
+    // is compared after decrypting
+    pub fn find_received(&self, since: i64, content: &str) -> Result<Option<String>> {
+        let received = {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt =
+                conn.prepare("SELECT id, content FROM sms_messages WHERE created_at >= ?1")?;
+            stmt.query_map(params![since], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query received messages")?
+        };
+        for (id, stored) in received {
+            if self.open_content(stored)? == content {
+                return Ok(Some(id));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Status of a queued SMS and the error it failed with, None when it is gone
+    pub fn outbound_status(&self, id: i64) -> Result<Option<(String, Option<String>)>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT status, error FROM outbound_sms WHERE id = ?1",
+            params![id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .context(format!("Failed to look up outbound SMS {}", id))
+    }
+
+    // Returns true when the template is new, false when it replaced one
+    pub fn save_template(&self, name: &str, body: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let existed = conn
+            .query_row(
+                "SELECT 1 FROM templates WHERE name = ?1",
+                params![name],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        conn.execute(
+            "INSERT INTO templates (name, body, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(name) DO UPDATE SET body = ?2, updated_at = ?3",
+            params![name, body, unix_timestamp()],
+        )
+        .context(format!("Failed to save template {}", name))?;
+
+        Ok(!existed)
+    }
+
+    // Returns false when there was no such template
+    pub fn delete_template(&self, name: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let deleted = conn
+            .execute("DELETE FROM templates WHERE name = ?1", params![name])
+            .context(format!("Failed to delete template {}", name))?;
+
+        Ok(deleted > 0)
+    }
+
+    pub fn template(&self, name: &str) -> Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT body FROM templates WHERE name = ?1",
+            params![name],
+            |row| row.get(0),
+        )
+        .optional()
+        .context(format!("Failed to look up template {}", name))
+    }
+
+    // Every template, by name
+    pub fn templates(&self) -> Result<Vec<Template>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare("SELECT name, body, updated_at FROM templates ORDER BY name")?;
+        let templates = stmt
+            .query_map([], |row| {
+                Ok(Template {
+                    name: row.get(0)?,
+                    body: row.get(1)?,
+                    updated_at: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query templates")?;
+
+        Ok(templates)
+    }
+
+    // Returns false when the number was already on the list
+    pub fn add_opt_out(&self, number: &str, source: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let added = conn
+            .execute(
+                "INSERT OR IGNORE INTO opt_outs (number, source, created_at) VALUES (?1, ?2, ?3)",
+                params![number, source, unix_timestamp()],
+            )
+            .context(format!("Failed to add {} to the opt-out list", number))?;
+
+        Ok(added > 0)
+    }
+
+    // Removes every entry `matches` accepts, returns how many there were
+    pub fn remove_opt_outs(&self, matches: impl Fn(&str) -> bool) -> Result<usize> {
+        let numbers = self
+            .opt_outs()?
+            .into_iter()
+            .filter(|entry| matches(&entry.number))
+            .map(|entry| entry.number)
+            .collect::<Vec<_>>();
+        let conn = self.conn.lock().unwrap();
+        for number in &numbers {
+            conn.execute("DELETE FROM opt_outs WHERE number = ?1", params![number])
+                .context(format!("Failed to remove {} from the opt-out list", number))?;
+        }
+
+        Ok(numbers.len())
+    }
+
+    // The opt-out list, latest first
+    pub fn opt_outs(&self) -> Result<Vec<OptOut>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT number, source, created_at FROM opt_outs ORDER BY created_at DESC, number",
+        )?;
+        let entries = stmt
+            .query_map([], |row| {
+                Ok(OptOut {
+                    number: row.get(0)?,
+                    source: row.get(1)?,
+                    created_at: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query the opt-out list")?;
+
+        Ok(entries)
+    }
+
+    // Take the oldest pending SMS this device may send and mark it as being sent.
+    // SMS that are not urgent and whose recipient is `held` stay queued
+    pub fn claim_outbound(
+        &self,
+        device_id: Option<&str>,
+        held: impl Fn(&str) -> bool,
+    ) -> Result<Option<OutboundSms>> {
+        let conn = self.conn.lock().unwrap();
+        let next = {
+            let mut stmt = conn.prepare(
+                "SELECT id, batch_id, recipient, content, urgent FROM outbound_sms
+                 WHERE status = ?1 AND (device_id IS NULL OR device_id IS ?2)
+                 ORDER BY id",
+            )?;
+            let mut rows = stmt.query(params![OUTBOUND_PENDING, device_id])?;
+            let mut next = None;
+            while let Some(row) = rows.next().context("Failed to query outbound SMS")? {
+                let recipient: String = row.get(2)?;
+                let urgent: bool = row.get(4)?;
+                if !urgent && held(&recipient) {
+                    continue;
+                }
+                next = Some(OutboundSms {
+                    id: row.get(0)?,
+                    batch_id: row.get(1)?,
+                    recipient,
+                    content: row.get(3)?,
+                });
+                break;
+            }
+            next
+        };
+        let next = match next {
+            Some(mut sms) => {
+                sms.content = self.open_content(sms.content)?;
+                Some(sms)
+            }
+            None => None,
+        };
+
+        if let Some(sms) = &next {
+            conn.execute(
+                "UPDATE outbound_sms SET status = ?1 WHERE id = ?2",
+                params![OUTBOUND_SENDING, sms.id],
+            )
+            .context(format!("Failed to claim outbound SMS: {}", sms.id))?;
+        }
+
+        Ok(next)
+    }
+
+    pub fn finish_outbound(&self, id: i64, sent: bool, error: Option<&str>) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let status = if sent { OUTBOUND_SENT } else { OUTBOUND_FAILED };
+        conn.execute(
+            "UPDATE outbound_sms SET status = ?1, error = ?2, sent_at = ?3 WHERE id = ?4",
+            params![status, error, sent.then(unix_timestamp), id],
+        )
+        .context(format!("Failed to update outbound SMS: {}", id))?;
+
+        Ok(())
+    }
+
+    // Returns false when notifications were already paused
+    pub fn pause_notifications(&self) -> Result<bool> {
+        self.set_flag(NOTIFICATIONS_PAUSED_KEY)
+    }
+
+    // Returns when the pause started, None when notifications were not paused
+    pub fn resume_notifications(&self) -> Result<Option<i64>> {
+        self.clear_flag(NOTIFICATIONS_PAUSED_KEY)
+    }
+
+    pub fn notifications_paused_since(&self) -> Result<Option<i64>> {
+        self.flag_since(NOTIFICATIONS_PAUSED_KEY)
+            .context("Failed to read notification pause")
+    }
+
+    // Returns false when maintenance was already started
+    pub fn start_maintenance(&self) -> Result<bool> {
+        self.set_flag(MAINTENANCE_KEY)
+    }
+
+    // Returns when maintenance started, None when it was not active
+    pub fn end_maintenance(&self) -> Result<Option<i64>> {
+        self.clear_flag(MAINTENANCE_KEY)
+    }
+
+    pub fn maintenance_since(&self) -> Result<Option<i64>> {
+        self.flag_since(MAINTENANCE_KEY)
+            .context("Failed to read maintenance state")
+    }
+
+    pub fn schedule_last_run(&self, name: &str) -> Result<Option<i64>> {
+        self.flag_since(&format!("{}{}", SCHEDULE_KEY_PREFIX, name))
+            .context(format!("Failed to read last run of schedule {}", name))
+    }
+
+    pub fn set_schedule_run(&self, name: &str, at: i64) -> Result<()> {
+        self.set_time(&format!("{}{}", SCHEDULE_KEY_PREFIX, name), at)
+            .context(format!("Failed to record run of schedule {}", name))
+    }
+
+    pub fn last_vacuum(&self) -> Result<Option<i64>> {
+        self.flag_since(LAST_VACUUM_KEY)
+            .context("Failed to read the last compaction time")
+    }
+
+    pub fn set_last_vacuum(&self, at: i64) -> Result<()> {
+        self.set_time(LAST_VACUUM_KEY, at)
+            .context("Failed to record the compaction time")
+    }
+
+    pub fn file_stats(&self) -> Result<FileStats> {
+        let conn = self.conn.lock().unwrap();
+        let pragma = |name: &str| -> Result<i64> {
+            conn.query_row(&format!("PRAGMA {}", name), [], |row| row.get(0))
+                .context(format!("Failed to read PRAGMA {}", name))
+        };
+
+        Ok(FileStats {
+            page_size: pragma("page_size")?,
+            page_count: pragma("page_count")?,
+            free_pages: pragma("freelist_count")?,
+        })
+    }
+
+    // Lets SQLite refresh the statistics its query planner relies on
+    pub fn optimize(&self) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute_batch("PRAGMA optimize")
+            .context("Failed to optimize the database")
+    }
+
+    // Rewrites the file without its free pages, holding the connection meanwhile
+    pub fn vacuum(&self) -> Result<()> {
+        {
+            let conn = self.conn.lock().unwrap();
+            conn.execute_batch("VACUUM")
+                .context("Failed to compact the database")?;
+        }
+        self.set_last_vacuum(unix_timestamp())
+    }
+
+    pub fn last_backup(&self) -> Result<Option<i64>> {
+        self.flag_since(LAST_BACKUP_KEY)
+            .context("Failed to read the last backup time")
+    }
+
+    pub fn set_last_backup(&self, at: i64) -> Result<()> {
+        self.set_time(LAST_BACKUP_KEY, at)
+            .context("Failed to record the backup time")
+    }
+
+    // Writes a consistent copy of the database to `path`, which must not exist
+    pub fn snapshot(&self, path: &Path) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "VACUUM INTO ?1",
+            params![path.to_string_lossy().into_owned()],
+        )
+        .context(format!("Failed to write a snapshot to {}", path.display()))?;
+
+        Ok(())
+    }
+
+    // Encrypt message text stored before the content key was set, or decrypt it
+    // all before the key is removed. Returns how many values were rewritten
+    pub fn reseal_content(&self, encrypt: bool) -> Result<usize> {
+        let Some(cipher) = &self.cipher else {
+            anyhow::bail!("Set [database] content_key first");
+        };
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut rewritten = 0;
+        for (table, column) in CONTENT_COLUMNS {
+            let rows = tx
+                .prepare(&format!(
+                    "SELECT rowid, {0} FROM {1} WHERE {0} IS NOT NULL",
+                    column, table
+                ))?
+                .query_map([], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .context(format!("Failed to read {}", table))?;
+            for (rowid, value) in rows {
+                if content_cipher::is_sealed(&value) == encrypt {
+                    continue;
+                }
+                let value = if encrypt {
+                    cipher.seal(&value)?
+                } else {
+                    cipher
+                        .open(&value)
+                        .context(format!("Failed to decrypt row {} of {}", rowid, table))?
+                };
+                tx.execute(
+                    &format!("UPDATE {} SET {} = ?1 WHERE rowid = ?2", table, column),
+                    params![value, rowid],
+                )?;
+                rewritten += 1;
+            }
+        }
+        tx.commit().context("Failed to rewrite message content")?;
+
+        Ok(rewritten)
+    }
+
+    // Encrypts message text when a content key is set
+    fn seal_content(&self, content: &str) -> Result<String> {
+        match &self.cipher {
+            Some(cipher) => cipher.seal(content),
+            None => Ok(content.to_string()),
+        }
+    }
+
+    fn open_content(&self, stored: String) -> Result<String> {
+        match &self.cipher {
+            Some(cipher) => cipher.open(&stored),
+            None if content_cipher::is_sealed(&stored) => {
+                anyhow::bail!("Message content is encrypted, set [database] content_key")
+            }
+            None => Ok(stored),
+        }
+    }
+
+    fn set_time(&self, key: &str, at: i64) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
+            params![key, at.to_string()],
+        )?;
+
+        Ok(())
+    }
+
+    // Settings flags hold the time they were set
+    fn set_flag(&self, key: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let rows_affected = conn.execute(
+            "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
+            params![key, unix_timestamp().to_string()],
+        )?;
+
+        Ok(rows_affected > 0)
+    }
+
+    fn clear_flag(&self, key: &str) -> Result<Option<i64>> {
+        let since = self.flag_since(key)?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM settings WHERE key = ?1", params![key])?;
+
+        Ok(since)
+    }
+
+    fn flag_since(&self, key: &str) -> Result<Option<i64>> {
+        let conn = self.conn.lock().unwrap();
+        let value: Option<String> = conn
+            .query_row(
+                "SELECT value FROM settings WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(value.and_then(|value| value.parse().ok()))
+    }
+
+    // Ask the running server to run `command` on a device, returns the request id
+    pub fn queue_admin_command(&self, device_id: &str, command: &str) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO admin_commands (device_id, command, created_at) VALUES (?1, ?2, ?3)",
+            params![device_id, command, unix_timestamp()],
+        )
+        .context(format!("Failed to queue {} for {}", command, device_id))?;
+
+        Ok(conn.last_insert_rowid())
+    }
+
+    // Pending commands of a device, marked as handled
+    pub fn take_admin_commands(&self, device_id: &str) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "UPDATE admin_commands SET handled_at = ?1
+             WHERE device_id = ?2 AND handled_at IS NULL
+             RETURNING command",
+        )?;
+        let commands = stmt
+            .query_map(params![unix_timestamp(), device_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()
+            .context("Failed to read admin commands")?;
+
+        Ok(commands)
+    }
+
+    pub fn admin_command_handled(&self, id: i64) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let handled = conn
+            .query_row(
+                "SELECT handled_at IS NOT NULL FROM admin_commands WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .context(format!("Failed to look up admin command {}", id))?;
+
+        Ok(handled)
+    }
+
+    // Drop pending commands, so they don't run when a server starts later.
+    // Returns how many were dropped
+    pub fn discard_admin_commands(&self, ids: Option<&[i64]>) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let rows_affected = match ids {
+            Some(ids) => ids.iter().try_fold(0, |count, id| {
+                conn.execute(
+                    "DELETE FROM admin_commands WHERE id = ?1 AND handled_at IS NULL",
+                    params![id],
+                )
+                .map(|rows| count + rows)
+            }),
+            None => conn.execute("DELETE FROM admin_commands WHERE handled_at IS NULL", []),
+        }
+        .context("Failed to discard admin commands")?;
+
+        Ok(rows_affected)
+    }
+
+    // SMS left in "sending" by a previous run may or may not have gone out,
+    // they are failed rather than sent twice
+    pub fn fail_interrupted_outbound(&self) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let rows_affected = conn
+            .execute(
+                "UPDATE outbound_sms SET status = ?1, error = 'interrupted by a restart'
+                 WHERE status = ?2",
+                params![OUTBOUND_FAILED, OUTBOUND_SENDING],
+            )
+            .context("Failed to reset interrupted outbound SMS")?;
+
+        Ok(rows_affected)
+    }
+
+    // Progress of the given batch, or of every batch with messages still to send
+    // Number of queued SMS in each status
+    pub fn outbound_counts(&self) -> Result<Vec<(String, u64)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT status, COUNT(*) FROM outbound_sms GROUP BY status ORDER BY status")?;
+        let counts = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to count outbound SMS")?;
+
+        Ok(counts)
+    }
+
+    // Outbound SMS sent since `since`, oldest first
+    pub fn sent_outbound(&self, since: i64) -> Result<Vec<SentOutbound>> {
+        let sent = {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare(
+                "SELECT device_id, recipient, content, sent_at FROM outbound_sms
+                 WHERE status = ?1 AND sent_at >= ?2
+                 ORDER BY sent_at",
+            )?;
+            stmt.query_map(params![OUTBOUND_SENT, since], |row| {
+                Ok(SentOutbound {
+                    device_id: row.get(0)?,
+                    recipient: row.get(1)?,
+                    content: row.get(2)?,
+                    sent_at: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query sent SMS")?
+        };
+
+        sent.into_iter()
+            .map(|mut sms| {
+                sms.content = self.open_content(sms.content)?;
+                Ok(sms)
+            })
+            .collect()
+    }
+
+    pub fn batch_progress(&self, batch_id: Option<&str>) -> Result<Vec<BatchProgress>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT batch_id, COUNT(*),
+                    SUM(status IN (?1, ?2)), SUM(status = ?3), SUM(status = ?4)
+             FROM outbound_sms
+             WHERE batch_id IS NOT NULL AND (?5 IS NULL OR batch_id = ?5)
+             GROUP BY batch_id
+             HAVING ?5 IS NOT NULL OR SUM(status IN (?1, ?2)) > 0
+             ORDER BY MIN(id)",
+        )?;
+        let batches = stmt
+            .query_map(
+                params![
+                    OUTBOUND_PENDING,
+                    OUTBOUND_SENDING,
+                    OUTBOUND_SENT,
+                    OUTBOUND_FAILED,
+                    batch_id
+                ],
+                |row| {
+                    Ok(BatchProgress {
+                        batch_id: row.get(0)?,
+                        total: row.get::<_, i64>(1)? as u64,
+                        pending: row.get::<_, i64>(2)? as u64,
+                        sent: row.get::<_, i64>(3)? as u64,
+                        failed: row.get::<_, i64>(4)? as u64,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query outbound batches")?;
+
+        Ok(batches)
+    }
+
+    // Seed the history from an archive in one transaction. Imported messages count
+    // as acknowledged and notified, so nothing is sent for them. Messages already
+    // stored with the same number, time and content are skipped, returns
+    // (imported, duplicates).
+    pub fn import_history(&self, entries: &[HistoryEntry]) -> Result<(usize, usize)> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let now = unix_timestamp();
+        let mut imported = 0;
+        for entry in entries {
+            // Content may be encrypted, so candidates are found by number and time
+            // and their content compared once opened
+            let candidates = if entry.outbound {
+                "SELECT content FROM outbound_sms
+                 WHERE recipient = ?1 AND COALESCE(sent_at, created_at) = ?2"
+            } else {
+                "SELECT content FROM sms_messages WHERE sender = ?1 AND received_at = ?2"
+            };
+            let stored = tx
+                .prepare(candidates)?
+                .query_map(params![entry.number, entry.at], |row| {
+                    row.get::<_, String>(0)
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .context(format!("Failed to look up messages of {}", entry.number))?;
+            let mut duplicate = false;
+            for content in stored {
+                duplicate |= self.open_content(content)? == entry.content;
+            }
+            if duplicate {
+                continue;
+            }
+
+            let content = self.seal_content(&entry.content)?;
+            let inserted = if entry.outbound {
+                tx.execute(
+                    "INSERT INTO outbound_sms (recipient, content, status, created_at, sent_at)
+                     VALUES (?1, ?2, ?3, ?4, ?4)",
+                    params![entry.number, content, entry.status, entry.at],
+                )
+            } else {
+                tx.execute(
+                    "INSERT OR IGNORE INTO sms_messages
+                         (id, sender, content, received_at, acknowledged, ack_sent_at, created_at, notified_at, status)
+                     VALUES (?1, ?2, ?3, ?4, 1, ?5, ?5, ?5, ?6)",
+                    params![
+                        import_id(self.cipher.as_deref(), &entry.number, entry.at, &entry.content),
+                        entry.number,
+                        content,
+                        entry.at,
+                        now,
+                        SMS_ACKED
+                    ],
+                )
+            }
+            .context(format!("Failed to import message from {}", entry.number))?;
+            imported += inserted;
+        }
+        tx.commit().context("Failed to import messages")?;
+
+        Ok((imported, entries.len() - imported))
+    }
+
+    // Received SMS stored before `before` that need nothing more: acknowledged,
+    // notified or spam, and not escalating
+    pub fn archivable_sms(&self, before: i64) -> Result<Vec<ArchivedSms>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, sender, content, received_at, metas, acknowledged, ack_sent_at, created_at,
+                    spam_score, is_spam, device_id, notified_at, sender_type, sender_country,
+                    message_class, handled_at, handled_by, encoding_suspect, raw_payload
+             FROM sms_messages
+             WHERE created_at < ?1 AND status = ?2
+               AND id NOT IN (SELECT message_id FROM escalations WHERE acknowledged_at IS NULL)
+             ORDER BY created_at",
+        )?;
+        let messages = stmt
+            .query_map(params![before, SMS_ACKED], |row| {
+                Ok(ArchivedSms {
+                    id: row.get(0)?,
+                    sender: row.get(1)?,
+                    content: row.get(2)?,
+                    received_at: row.get(3)?,
+                    metas: row.get(4)?,
+                    acknowledged: row.get(5)?,
+                    ack_sent_at: row.get(6)?,
+                    created_at: row.get(7)?,
+                    spam_score: row.get(8)?,
+                    is_spam: row.get(9)?,
+                    device_id: row.get(10)?,
+                    notified_at: row.get(11)?,
+                    sender_type: row.get(12)?,
+                    sender_country: row.get(13)?,
+                    message_class: row.get(14)?,
+                    handled_at: row.get(15)?,
+                    handled_by: row.get(16)?,
+                    encoding_suspect: row.get(17)?,
+                    raw_payload: row.get(18)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query messages to archive")?;
+
+        Ok(messages)
+    }
+
+    // Outbound SMS queued before `before` that were sent or gave up
+    pub fn archivable_outbound(&self, before: i64) -> Result<Vec<ArchivedOutbound>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, batch_id, device_id, recipient, content, status, error, created_at, sent_at
+             FROM outbound_sms
+             WHERE created_at < ?1 AND status IN (?2, ?3)
+             ORDER BY created_at",
+        )?;
+        let messages = stmt
+            .query_map(params![before, OUTBOUND_SENT, OUTBOUND_FAILED], |row| {
+                Ok(ArchivedOutbound {
+                    id: row.get(0)?,
+                    batch_id: row.get(1)?,
+                    device_id: row.get(2)?,
+                    recipient: row.get(3)?,
+                    content: row.get(4)?,
+                    status: row.get(5)?,
+                    error: row.get(6)?,
+                    created_at: row.get(7)?,
+                    sent_at: row.get(8)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query outbound messages to archive")?;
+
+        Ok(messages)
+    }
+
+    // Drop archived messages along with their timings and finished escalations
+    pub fn delete_archived(&self, sms_ids: &[String], outbound_ids: &[i64]) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for id in sms_ids {
+            tx.execute("DELETE FROM sms_messages WHERE id = ?1", params![id])?;
+            tx.execute("DELETE FROM sms_timings WHERE sms_id = ?1", params![id])?;
+            tx.execute("DELETE FROM escalations WHERE message_id = ?1", params![id])?;
+        }
+        for id in outbound_ids {
+            tx.execute("DELETE FROM outbound_sms WHERE id = ?1", params![id])?;
+        }
+        tx.commit().context("Failed to delete archived messages")?;
+
+        Ok(())
+    }
+
+    // Put archived messages back, those already present are skipped. Returns
+    // how many were restored
+    pub fn restore_archived(
+        &self,
+        messages: &[ArchivedSms],
+        outbound: &[ArchivedOutbound],
+    ) -> Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut restored = 0;
+        for msg in messages {
+            restored += tx
+                .execute(
+                    "INSERT OR IGNORE INTO sms_messages
+                         (id, sender, content, received_at, metas, acknowledged, ack_sent_at, created_at,
+                          spam_score, is_spam, device_id, notified_at, sender_type, sender_country,
+                          message_class, handled_at, handled_by, encoding_suspect, raw_payload)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19)",
+                    params![
+                        msg.id,
+                        msg.sender,
+                        msg.content,
+                        msg.received_at,
+                        msg.metas,
+                        msg.acknowledged,
+                        msg.ack_sent_at,
+                        msg.created_at,
+                        msg.spam_score,
+                        msg.is_spam,
+                        msg.device_id,
+                        msg.notified_at,
+                        msg.sender_type,
+                        msg.sender_country,
+                        msg.message_class,
+                        msg.handled_at,
+                        msg.handled_by,
+                        msg.encoding_suspect,
+                        msg.raw_payload,
+                    ],
+                )
+                .context(format!("Failed to restore SMS message: {}", msg.id))?;
+        }
+        // Archives don't carry the status, it follows from the other columns
+        tx.execute(
+            &format!(
+                "UPDATE sms_messages SET status = {} WHERE status = ?1",
+                SMS_STATUS_OF_ROW
+            ),
+            params![SMS_STORED],
+        )
+        .context("Failed to set the status of restored messages")?;
+        for msg in outbound {
+            restored += tx
+                .execute(
+                    "INSERT OR IGNORE INTO outbound_sms
+                         (id, batch_id, device_id, recipient, content, status, error, created_at, sent_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    params![
+                        msg.id,
+                        msg.batch_id,
+                        msg.device_id,
+                        msg.recipient,
+                        msg.content,
+                        msg.status,
+                        msg.error,
+                        msg.created_at,
+                        msg.sent_at,
+                    ],
+                )
+                .context(format!("Failed to restore outbound SMS: {}", msg.id))?;
+        }
+        tx.commit().context("Failed to restore archived messages")?;
+
+        Ok(restored)
+    }
+
+    // Messages from or to numbers `matches` accepts, with the notification
+    // history, webhook events and audit entries that mention them
+    pub fn correspondent_data(&self, matches: &dyn Fn(&str) -> bool) -> Result<CorrespondentData> {
+        let conn = self.conn.lock().unwrap();
+        let mut data = CorrespondentData::default();
+        let mut numbers = Vec::new();
+
+        let received = conn
+            .prepare("SELECT id, sender, content FROM sms_messages")?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query messages")?;
+        for (id, sender, content) in received {
+            if !matches(&sender) {
+                continue;
+            }
+            let content = self.open_content(content)?;
+            let hash = notification_hash(self.cipher.as_deref(), &sender, &content);
+            let recorded = conn
+                .query_row(
+                    "SELECT 1 FROM notification_history WHERE hash = ?1",
+                    params![hash],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+            if recorded && !data.notification_hashes.contains(&hash) {
+                data.notification_hashes.push(hash);
+            }
+            data.sms_ids.push(id);
+            numbers.push(sender);
+        }
+
+        let outbound = conn
+            .prepare("SELECT id, recipient FROM outbound_sms")?
+            .query_map([], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query outbound SMS")?;
+        for (id, recipient) in outbound {
+            if matches(&recipient) {
+                data.outbound_ids.push(id);
+                numbers.push(recipient);
+            }
+        }
+        numbers.sort();
+        numbers.dedup();
+        // Notification titles and audit details hold the number as it was written
+        let mentioned = |text: &str| {
+            numbers
+                .iter()
+                .chain(&data.sms_ids)
+                .any(|token| mentions(text, token))
+        };
+
+        let deliveries = conn
+            .prepare("SELECT id, payload FROM webhook_deliveries")?
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query webhook deliveries")?;
+        let mut webhook_ids = Vec::new();
+        for (id, payload) in deliveries {
+            if mentioned(&self.open_content(payload)?) {
+                webhook_ids.push(id);
+            }
+        }
+
+        let audit = conn
+            .prepare("SELECT id, detail FROM audit_log WHERE detail IS NOT NULL")?
+            .query_map([], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query audit log")?;
+        let audit_ids = audit
+            .into_iter()
+            .filter(|(_, detail)| mentioned(detail))
+            .map(|(id, _)| id)
+            .collect();
+
+        data.webhook_ids = webhook_ids;
+        data.audit_ids = audit_ids;
+        Ok(data)
+    }
+
+    // Hard-delete the rows found by `correspondent_data` in one transaction
+    pub fn erase_correspondent(&self, data: &CorrespondentData) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for id in &data.sms_ids {
+            tx.execute("DELETE FROM sms_messages WHERE id = ?1", params![id])?;
+            tx.execute("DELETE FROM sms_timings WHERE sms_id = ?1", params![id])?;
+            tx.execute("DELETE FROM escalations WHERE message_id = ?1", params![id])?;
+        }
+        for id in &data.outbound_ids {
+            tx.execute("DELETE FROM outbound_sms WHERE id = ?1", params![id])?;
+        }
+        for hash in &data.notification_hashes {
+            tx.execute(
+                "DELETE FROM notification_history WHERE hash = ?1",
+                params![hash],
+            )?;
+        }
+        for id in &data.webhook_ids {
+            tx.execute("DELETE FROM webhook_deliveries WHERE id = ?1", params![id])?;
+        }
+        for id in &data.audit_ids {
+            tx.execute("DELETE FROM audit_log WHERE id = ?1", params![id])?;
+        }
+        tx.commit().context("Failed to erase messages")?;
+
+        Ok(())
+    }
+
+    // Received and queued messages in chronological order
+    pub fn history(&self) -> Result<Vec<HistoryEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT 0, sender, content, received_at,
+                    CASE WHEN is_spam = 1 THEN 'spam' END
+             FROM sms_messages
+             UNION ALL
+             SELECT 1, recipient, content, COALESCE(sent_at, created_at), status
+             FROM outbound_sms
+             ORDER BY 4",
+        )?;
+        let entries = stmt
+            .query_map([], |row| {
+                Ok(HistoryEntry {
+                    outbound: row.get(0)?,
+                    number: row.get(1)?,
+                    content: row.get(2)?,
+                    at: row.get(3)?,
+                    status: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to query message history")?;
+
+        entries
+            .into_iter()
+            .map(|mut entry| {
+                entry.content = self.open_content(entry.content)?;
+                Ok(entry)
+            })
+            .collect()
+    }
+
+    pub fn count_total(&self) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM sms_messages", [], |row| row.get(0))
+            .context("Failed to count total messages")?;
+
+        Ok(count)
+    }
+
+    // How many messages are at each step, steps without any left out
+    pub fn count_by_status(&self) -> Result<Vec<(String, i64)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT status, COUNT(*) FROM sms_messages GROUP BY status ORDER BY status")?;
+        let counts = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to count messages by status")?;
+
+        Ok(counts)
+    }
+
+    pub fn count_unacknowledged(&self) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sms_messages WHERE acknowledged = 0",
+                [],
                 |row| row.get(0),
             )
             .context("Failed to count unacknowledged messages")?;
 
         Ok(count)
     }
+
+    // Stored SMS whose payload was not valid UTF-8
+    pub fn count_encoding_suspect(&self) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sms_messages WHERE encoding_suspect = 1",
+                [],
+                |row| row.get(0),
+            )
+            .context("Failed to count messages with invalid UTF-8")?;
+
+        Ok(count)
+    }
 }
 
 // Implement Clone manually since Connection isn't Clone
@@ -116,6 +2980,7 @@ impl Clone for Database {
     fn clone(&self) -> Self {
         Database {
             conn: Arc::clone(&self.conn),
+            cipher: self.cipher.clone(),
         }
     }
 }
@@ -0,0 +1,95 @@
+use crate::config::DeviceLogConfig;
+use crate::database;
+use crate::serial_port::DeviceLogPayload;
+use crate::timezone::TimeFormatter;
+use anyhow::{Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+// Rotating file of log lines forwarded by the devices, shared by all connections
+#[derive(Clone)]
+pub struct DeviceLog {
+    path: String,
+    max_bytes: u64,
+    max_files: u32,
+    time: TimeFormatter,
+    file: Arc<Mutex<File>>,
+}
+
+impl DeviceLog {
+    pub fn new(config: &DeviceLogConfig, time: TimeFormatter) -> Result<Self> {
+        let file = open_append(&config.path)?;
+
+        Ok(DeviceLog {
+            path: config.path.clone(),
+            max_bytes: config.max_bytes,
+            max_files: config.max_files,
+            time,
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    pub fn write(&self, device: &str, entry: &DeviceLogPayload) {
+        let line = format!(
+            "{} {:<5} [{}] {}: {}\n",
+            self.time
+                .display(entry.timestamp.unwrap_or_else(database::unix_timestamp)),
+            entry.level.to_uppercase(),
+            device,
+            entry.tag,
+            entry.message.trim_end()
+        );
+
+        let mut file = self.file.lock().unwrap();
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > self.max_bytes {
+            match self.rotate() {
+                Ok(new_file) => *file = new_file,
+                Err(e) => log::warn!("Failed to rotate device log {}: {}", self.path, e),
+            }
+        }
+
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            log::warn!("Failed to write device log {}: {}", self.path, e);
+        }
+    }
+
+    // device.log -> device.log.1 -> ... -> device.log.<max_files>, the oldest is dropped
+    fn rotate(&self) -> Result<File> {
+        for index in (1..self.max_files).rev() {
+            let from = format!("{}.{}", self.path, index);
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, format!("{}.{}", self.path, index + 1))
+                    .context(format!("Failed to rename {}", from))?;
+            }
+        }
+        fs::rename(&self.path, format!("{}.1", self.path))
+            .context(format!("Failed to rename {}", self.path))?;
+
+        open_append(&self.path)
+    }
+}
+
+fn open_append(path: &str) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .context(format!("Failed to open device log: {}", path))
+}
+
+// Last `count` lines across the current file and the most recent rotation
+pub fn tail(config: &DeviceLogConfig, count: usize) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+    for path in [format!("{}.1", config.path), config.path.clone()] {
+        match fs::read_to_string(&path) {
+            Ok(content) => lines.extend(content.lines().map(str::to_string)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context(format!("Failed to read device log: {}", path)),
+        }
+    }
+
+    let skip = lines.len().saturating_sub(count);
+    Ok(lines.split_off(skip))
+}
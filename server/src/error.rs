@@ -0,0 +1,99 @@
+use crate::port_access;
+use std::fmt;
+
+// Failure categories with their own process exit codes, so supervisors and scripts
+// can tell "no device attached" from "bad config". Anything else exits with 1.
+#[derive(Debug)]
+pub enum AppError {
+    Config(String),
+    PortNotFound(String),
+    PortPermissionDenied(String),
+    Database(String),
+    Protocol(String),
+    // An internal task kept failing and the watchdog gave up
+    Stalled(String),
+}
+
+impl AppError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AppError::Config(_) => 2,
+            AppError::PortNotFound(_) => 3,
+            AppError::PortPermissionDenied(_) => 4,
+            AppError::Database(_) => 5,
+            AppError::Protocol(_) => 6,
+            AppError::Stalled(_) => 7,
+        }
+    }
+
+    // Stable name stored with the connection status
+    pub fn category(&self) -> &'static str {
+        match self {
+            AppError::Config(_) => "config",
+            AppError::PortNotFound(_) => "port_not_found",
+            AppError::PortPermissionDenied(_) => "port_permission_denied",
+            AppError::Database(_) => "database",
+            AppError::Protocol(_) => "protocol",
+            AppError::Stalled(_) => "stalled",
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Config(message)
+            | AppError::PortNotFound(message)
+            | AppError::PortPermissionDenied(message)
+            | AppError::Database(message)
+            | AppError::Protocol(message)
+            | AppError::Stalled(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+pub fn config_error(error: anyhow::Error) -> anyhow::Error {
+    AppError::Config(format!("{:#}", error)).into()
+}
+
+// Categorize a failure to open a serial port, other I/O errors stay untyped.
+// Permission problems are logged together with instructions to fix them.
+pub fn port_open_error(port_name: &str, error: &tokio_serial::Error) -> anyhow::Error {
+    let message = format!("Failed to open serial port '{}': {}", port_name, error);
+    match error.kind() {
+        tokio_serial::ErrorKind::NoDevice
+        | tokio_serial::ErrorKind::Io(std::io::ErrorKind::NotFound) => {
+            AppError::PortNotFound(message).into()
+        }
+        tokio_serial::ErrorKind::Io(std::io::ErrorKind::PermissionDenied) => {
+            for hint in port_access::hints(port_name) {
+                log::error!("{}", hint);
+            }
+            AppError::PortPermissionDenied(message).into()
+        }
+        _ => anyhow::anyhow!(message),
+    }
+}
+
+fn app_error(error: &anyhow::Error) -> Option<&AppError> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<AppError>())
+}
+
+// Exit code of the first typed error in the chain
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    app_error(error).map_or(1, AppError::exit_code)
+}
+
+pub fn category(error: &anyhow::Error) -> &'static str {
+    app_error(error).map_or("other", AppError::category)
+}
+
+// Report a fatal error and exit with the code of its category
+pub fn exit(context: &str, error: &anyhow::Error) -> ! {
+    eprintln!("{}: {:#}", context, error);
+    std::process::exit(exit_code(error))
+}
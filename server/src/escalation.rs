@@ -0,0 +1,122 @@
+use crate::ack_callback::AckLinks;
+use crate::config::EscalationConfig;
+use crate::database::{self, Database};
+use crate::i18n::Translations;
+use crate::notification::{Notifier, SendOptions};
+use crate::watchdog::Heartbeat;
+use std::sync::Arc;
+use std::time::Duration;
+
+// How often pending escalations are checked
+const CHECK_INTERVAL_SECS: u64 = 30;
+
+#[derive(Clone)]
+pub struct Escalator {
+    db: Database,
+    notifier: Arc<dyn Notifier>,
+    translations: Translations,
+    ack_links: Option<AckLinks>,
+    interval_secs: i64,
+    max_repeats: u32,
+}
+
+impl Escalator {
+    pub fn new(
+        config: &EscalationConfig,
+        db: Database,
+        notifier: Arc<dyn Notifier>,
+        translations: Translations,
+        ack_links: Option<AckLinks>,
+    ) -> Self {
+        Escalator {
+            db,
+            notifier,
+            translations,
+            ack_links,
+            interval_secs: (config.interval_minutes * 60) as i64,
+            max_repeats: config.max_repeats,
+        }
+    }
+
+    // Schedule repeated notifications for a message until it is acknowledged
+    pub fn start(&self, message_id: &str, title: &str, content: &str) {
+        let next_at = database::unix_timestamp() + self.interval_secs;
+        if let Err(e) = self
+            .db
+            .insert_escalation(message_id, title, content, next_at)
+        {
+            log::warn!("Failed to schedule escalation for {}: {}", message_id, e);
+        }
+    }
+
+    pub async fn run(self, heartbeat: Heartbeat) {
+        log::info!(
+            "Escalation task started (interval {}s, max {} repeats)",
+            self.interval_secs,
+            self.max_repeats
+        );
+
+        loop {
+            self.resend_due().await;
+            heartbeat.beat(Duration::from_secs(CHECK_INTERVAL_SECS));
+            tokio::time::sleep(Duration::from_secs(CHECK_INTERVAL_SECS)).await;
+        }
+    }
+
+    async fn resend_due(&self) {
+        let now = database::unix_timestamp();
+        let due = match self.db.due_escalations(now, self.max_repeats) {
+            Ok(due) => due,
+            Err(e) => {
+                log::warn!("Failed to load pending escalations: {}", e);
+                return;
+            }
+        };
+
+        for escalation in due {
+            let repeat = escalation.repeats + 1;
+            log::info!(
+                "Re-sending unacknowledged notification for {} (repeat {}/{})",
+                escalation.message_id,
+                repeat,
+                self.max_repeats
+            );
+
+            let title = self.translations.text(
+                "escalation_title",
+                &[("count", &repeat.to_string()), ("title", &escalation.title)],
+            );
+            let action_url = self
+                .ack_links
+                .as_ref()
+                .map(|links| links.url(&escalation.message_id));
+            let options = SendOptions {
+                action_url: action_url.as_deref(),
+                message_id: Some(&escalation.message_id),
+                ..Default::default()
+            };
+            if let Err(e) = self
+                .notifier
+                .send_with_options(&title, &escalation.content, &options)
+                .await
+            {
+                log::warn!("Failed to send escalation notification: {}", e);
+            }
+
+            if let Err(e) = self
+                .db
+                .record_escalation_repeat(&escalation.message_id, now + self.interval_secs)
+            {
+                log::warn!("Failed to update escalation: {}", e);
+            }
+
+            if repeat >= self.max_repeats {
+                log::warn!(
+                    "Escalation for {} reached {} repeats without acknowledgment, giving up",
+                    escalation.message_id,
+                    self.max_repeats
+                );
+            }
+        }
+    }
+}
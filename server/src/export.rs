@@ -0,0 +1,156 @@
+use crate::database::{HistoryEntry, OUTBOUND_FAILED, OUTBOUND_SENT};
+use crate::timezone::TimeFormatter;
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::path::Path;
+
+// Formats `export` writes and `import` reads
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum ArchiveFormat {
+    Csv,
+    Json,
+    // Android "SMS Backup & Restore" XML, restorable into a phone's SMS app
+    Xml,
+}
+
+pub fn write(
+    entries: &[HistoryEntry],
+    format: ArchiveFormat,
+    time: &TimeFormatter,
+    out: &mut dyn Write,
+) -> Result<()> {
+    match format {
+        ArchiveFormat::Csv => write_csv(entries, time, out),
+        ArchiveFormat::Json => write_json(entries, time, out),
+        ArchiveFormat::Xml => write_xml(entries, time, out),
+    }
+    .context("Failed to write export")
+}
+
+pub fn write_file(
+    entries: &[HistoryEntry],
+    format: ArchiveFormat,
+    time: &TimeFormatter,
+    path: &Path,
+) -> Result<()> {
+    let file =
+        std::fs::File::create(path).context(format!("Failed to create {}", path.display()))?;
+    let mut out = std::io::BufWriter::new(file);
+    write(entries, format, time, &mut out)?;
+    out.flush()
+        .context(format!("Failed to write {}", path.display()))
+}
+
+pub const DIRECTION_SENT: &str = "sent";
+pub const DIRECTION_RECEIVED: &str = "received";
+
+fn direction(entry: &HistoryEntry) -> &'static str {
+    if entry.outbound {
+        DIRECTION_SENT
+    } else {
+        DIRECTION_RECEIVED
+    }
+}
+
+fn write_csv(entries: &[HistoryEntry], time: &TimeFormatter, out: &mut dyn Write) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record([
+        "direction",
+        "number",
+        "content",
+        "timestamp",
+        "time",
+        "status",
+    ])?;
+    for entry in entries {
+        writer.write_record([
+            direction(entry),
+            &entry.number,
+            &entry.content,
+            &entry.at.to_string(),
+            &time.display(entry.at),
+            entry.status.as_deref().unwrap_or_default(),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_json(entries: &[HistoryEntry], time: &TimeFormatter, out: &mut dyn Write) -> Result<()> {
+    let messages = entries
+        .iter()
+        .map(|entry| {
+            serde_json::json!({
+                "direction": direction(entry),
+                "number": entry.number,
+                "content": entry.content,
+                "timestamp": entry.at,
+                "time": time.display(entry.at),
+                "status": entry.status,
+            })
+        })
+        .collect::<Vec<_>>();
+    serde_json::to_writer_pretty(&mut *out, &messages)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+// Message box of the Android SMS provider: 1 inbox, 2 sent, 5 failed, 6 queued
+pub const TYPE_INBOX: u8 = 1;
+pub const TYPE_SENT: u8 = 2;
+pub const TYPE_FAILED: u8 = 5;
+const TYPE_QUEUED: u8 = 6;
+
+fn message_type(entry: &HistoryEntry) -> u8 {
+    match entry.status.as_deref() {
+        _ if !entry.outbound => TYPE_INBOX,
+        Some(OUTBOUND_SENT) => TYPE_SENT,
+        Some(OUTBOUND_FAILED) => TYPE_FAILED,
+        _ => TYPE_QUEUED,
+    }
+}
+
+fn write_xml(entries: &[HistoryEntry], time: &TimeFormatter, out: &mut dyn Write) -> Result<()> {
+    writeln!(
+        out,
+        "<?xml version='1.0' encoding='UTF-8' standalone='yes' ?>"
+    )?;
+    writeln!(out, "<smses count=\"{}\">", entries.len())?;
+    for entry in entries {
+        // The app stores times in milliseconds
+        let date = entry.at * 1000;
+        writeln!(
+            out,
+            "  <sms protocol=\"0\" address=\"{}\" date=\"{}\" type=\"{}\" subject=\"null\" body=\"{}\" \
+             toa=\"null\" sc_toa=\"null\" service_center=\"null\" read=\"1\" status=\"-1\" locked=\"0\" \
+             date_sent=\"{}\" readable_date=\"{}\" contact_name=\"(Unknown)\" />",
+            escape_xml(&entry.number),
+            date,
+            message_type(entry),
+            escape_xml(&entry.content),
+            if entry.outbound { 0 } else { date },
+            escape_xml(&time.display(entry.at)),
+        )?;
+    }
+    writeln!(out, "</smses>")?;
+    Ok(())
+}
+
+// Attribute values, line breaks kept as character references like the app writes them
+fn escape_xml(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\n' => escaped.push_str("&#10;"),
+            '\r' => escaped.push_str("&#13;"),
+            // Not allowed in XML 1.0 at all
+            c if c.is_control() && c != '\t' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
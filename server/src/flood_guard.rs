@@ -0,0 +1,75 @@
+use std::time::{Duration, Instant};
+
+// Minimum time between two flood alerts of the same connection
+const ALERT_INTERVAL: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Admission {
+    Accepted,
+    // The frame exceeds the per-second limit and must be dropped
+    Throttled { first_in_window: bool },
+}
+
+// Protects the message pipeline from a device writing frames in a loop
+pub struct FloodGuard {
+    max_per_second: u32,
+    window_start: Instant,
+    window_count: u32,
+    throttled_total: u64,
+    oversized_total: u64,
+    last_alert: Option<Instant>,
+}
+
+impl FloodGuard {
+    pub fn new(max_per_second: u32) -> Self {
+        FloodGuard {
+            max_per_second,
+            window_start: Instant::now(),
+            window_count: 0,
+            throttled_total: 0,
+            oversized_total: 0,
+            last_alert: None,
+        }
+    }
+
+    pub fn admit(&mut self) -> Admission {
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            self.window_start = Instant::now();
+            self.window_count = 0;
+        }
+
+        self.window_count += 1;
+        if self.window_count <= self.max_per_second {
+            return Admission::Accepted;
+        }
+
+        self.throttled_total += 1;
+        Admission::Throttled {
+            first_in_window: self.window_count == self.max_per_second + 1,
+        }
+    }
+
+    pub fn record_oversized(&mut self) {
+        self.oversized_total += 1;
+    }
+
+    pub fn throttled_total(&self) -> u64 {
+        self.throttled_total
+    }
+
+    pub fn oversized_total(&self) -> u64 {
+        self.oversized_total
+    }
+
+    // Rate limits alerts so a flood doesn't turn into a notification flood
+    pub fn should_alert(&mut self) -> bool {
+        if self
+            .last_alert
+            .is_some_and(|last| last.elapsed() < ALERT_INTERVAL)
+        {
+            return false;
+        }
+        self.last_alert = Some(Instant::now());
+        true
+    }
+}
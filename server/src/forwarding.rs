@@ -0,0 +1,178 @@
+use crate::config::ForwardingConfig;
+use crate::rules::Rule;
+use crate::sender;
+use crate::serial_port::SendSmsPayload;
+
+// Minimum digits required before two numbers are compared by suffix,
+// so "+8613800000000" and "13800000000" are treated as the same number
+const MIN_SUFFIX_MATCH_DIGITS: usize = 7;
+
+// A prefix template split at its `{sender}` and `{rule}` placeholders
+enum Part {
+    Text(String),
+    Sender,
+    Rule,
+}
+
+pub struct Forwarder {
+    prefix: Vec<Part>,
+    // The template without its surrounding whitespace, matched against incoming SMS
+    marker: Vec<Part>,
+}
+
+impl Forwarder {
+    pub fn new(config: &ForwardingConfig) -> Self {
+        Forwarder {
+            prefix: parse_template(&config.prefix_template),
+            marker: parse_template(config.prefix_template.trim()),
+        }
+    }
+
+    // Build the outbound SMS for every forward target of the rule,
+    // skipping targets that would create a forwarding loop
+    pub fn plan(&self, rule: &Rule, sender: &str, content: &str) -> Vec<SendSmsPayload> {
+        self.plan_targets(&rule.name, &rule.forward_to, sender, content)
+    }
+
+    // Forward targets chosen by `name`, a rule or a message hook
+    pub fn plan_targets(
+        &self,
+        name: &str,
+        targets: &[String],
+        sender: &str,
+        content: &str,
+    ) -> Vec<SendSmsPayload> {
+        if self.is_forwarded_content(content) {
+            log::warn!(
+                "Rule '{}': message from {} is already a forwarded SMS, not forwarding again",
+                name,
+                sender
+            );
+            return Vec::new();
+        }
+
+        let prefix = render(&self.prefix, sender, name);
+
+        targets
+            .iter()
+            .filter(|target| {
+                if same_number(sender, target) {
+                    log::warn!(
+                        "Rule '{}': skipping forward to {} (target is the sender)",
+                        name,
+                        target
+                    );
+                    return false;
+                }
+                true
+            })
+            .map(|target| SendSmsPayload {
+                to: target.trim().to_string(),
+                content: format!("{}{}", prefix, content),
+                reference: None,
+                rid: None,
+            })
+            .collect()
+    }
+
+    // Messages starting with an expanded prefix were forwarded by us or another server
+    // with the same template. The whole template is matched, not only the text before
+    // the first placeholder, so templates starting with `{sender}` are recognized too.
+    fn is_forwarded_content(&self, content: &str) -> bool {
+        let has_text = self
+            .marker
+            .iter()
+            .any(|part| matches!(part, Part::Text(text) if !text.trim().is_empty()));
+        has_text && matches_prefix(&self.marker, content.trim_start())
+    }
+}
+
+// Fills in the `{sender}` and `{rule}` placeholders of a template in one pass,
+// so placeholders inside the sender or rule name stay as they are
+pub fn expand(template: &str, sender: &str, rule: &str) -> String {
+    render(&parse_template(template), sender, rule)
+}
+
+fn render(parts: &[Part], sender: &str, rule: &str) -> String {
+    parts
+        .iter()
+        .map(|part| match part {
+            Part::Text(text) => text.as_str(),
+            Part::Sender => sender,
+            Part::Rule => rule,
+        })
+        .collect()
+}
+
+fn parse_template(template: &str) -> Vec<Part> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut rest = template;
+    while let Some(c) = rest.chars().next() {
+        let placeholder = if let Some(tail) = rest.strip_prefix("{sender}") {
+            Some((Part::Sender, tail))
+        } else {
+            rest.strip_prefix("{rule}").map(|tail| (Part::Rule, tail))
+        };
+        match placeholder {
+            Some((part, tail)) => {
+                if !text.is_empty() {
+                    parts.push(Part::Text(std::mem::take(&mut text)));
+                }
+                parts.push(part);
+                rest = tail;
+            }
+            None => {
+                text.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    if !text.is_empty() {
+        parts.push(Part::Text(text));
+    }
+    parts
+}
+
+// A placeholder stands for at least one character on the same line
+fn matches_prefix(parts: &[Part], text: &str) -> bool {
+    match parts.split_first() {
+        None => true,
+        Some((Part::Text(literal), rest)) => text
+            .strip_prefix(literal.as_str())
+            .is_some_and(|text| matches_prefix(rest, text)),
+        Some((_, rest)) => {
+            for (index, c) in text.char_indices() {
+                if c == '\n' {
+                    break;
+                }
+                if matches_prefix(rest, &text[index + c.len_utf8()..]) {
+                    return true;
+                }
+            }
+            false
+        }
+    }
+}
+
+pub fn normalize_number(number: &str) -> String {
+    number.chars().filter(|c| c.is_ascii_digit()).collect()
+}
+
+// Alphanumeric ids are never numbers, even when they contain enough digits
+pub fn same_number(a: &str, b: &str) -> bool {
+    if sender::is_alphanumeric(a) || sender::is_alphanumeric(b) {
+        return false;
+    }
+    let a = normalize_number(a);
+    let b = normalize_number(b);
+    if a.is_empty() || b.is_empty() {
+        return false;
+    }
+    if a == b {
+        return true;
+    }
+
+    let (short, long) = if a.len() < b.len() { (a, b) } else { (b, a) };
+    short.len() >= MIN_SUFFIX_MATCH_DIGITS && long.ends_with(&short)
+}
@@ -0,0 +1,93 @@
+use crate::config::GrafanaConfig;
+use crate::database;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Tag of every annotation, next to the device and the event
+const TAG: &str = "air780e";
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Annotation<'a> {
+    // Unix time in milliseconds
+    time: i64,
+    tags: Vec<&'a str>,
+    text: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "dashboardUID")]
+    dashboard_uid: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    panel_id: Option<u64>,
+}
+
+// Posts device events (reconnects, reboots, SIM swaps) to the Grafana HTTP API
+// as annotations, so graphs show when they happened. Best effort: a failed post
+// is logged and not retried
+#[derive(Clone)]
+pub struct GrafanaAnnotations {
+    url: String,
+    token: String,
+    dashboard_uid: Option<String>,
+    panel_id: Option<u64>,
+    tags: Vec<String>,
+    client: reqwest::Client,
+}
+
+impl GrafanaAnnotations {
+    pub fn new(config: &GrafanaConfig) -> Option<Self> {
+        let url = config.url.as_deref()?.trim_end_matches('/');
+        Some(GrafanaAnnotations {
+            url: format!("{}/api/annotations", url),
+            token: config.api_token.clone(),
+            dashboard_uid: config.dashboard_uid.clone(),
+            panel_id: config.panel_id,
+            tags: config.tags.clone(),
+            client: reqwest::Client::new(),
+        })
+    }
+
+    // Annotate `event` of `device` now, without waiting for Grafana
+    pub fn annotate(&self, device: &str, event: &str, text: String) {
+        let annotations = self.clone();
+        let device = device.to_string();
+        let event = event.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = annotations.post(&device, &event, &text).await {
+                log::warn!(
+                    "Failed to annotate {} of {} in Grafana: {:#}",
+                    event,
+                    device,
+                    e
+                );
+            }
+        });
+    }
+
+    async fn post(&self, device: &str, event: &str, text: &str) -> Result<()> {
+        let mut tags = vec![TAG, device, event];
+        tags.extend(self.tags.iter().map(String::as_str));
+        let annotation = Annotation {
+            time: database::unix_timestamp_millis(),
+            tags,
+            text,
+            dashboard_uid: self.dashboard_uid.as_deref(),
+            panel_id: self.panel_id,
+        };
+        let response = self
+            .client
+            .post(&self.url)
+            .bearer_auth(&self.token)
+            .json(&annotation)
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await
+            .context("Failed to reach Grafana")?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("Grafana answered {}: {}", status, body.trim());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,280 @@
+#[cfg(unix)]
+use crate::config::Config;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use tokio::net::TcpListener;
+use tokio_serial::SerialStream;
+
+// Names the descriptors a restarted server takes over from the one before it
+const ENV: &str = "AIR780E_HANDOVER";
+// How long the sessions get to stop at a frame boundary, a device still
+// reconnecting or in maintenance is opened again by the new process
+#[cfg(unix)]
+const PARK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+// Restart without closing anything: on SIGHUP every device session stops
+// reading once no partial frame is left, and the server execs itself with its
+// serial ports and HTTP listeners left open. The new process finds them in
+// AIR780E_HANDOVER and uses them instead of opening and binding again, so what
+// a device sends meanwhile waits in the kernel buffer rather than getting lost
+#[derive(Serialize, Deserialize, Default)]
+struct Descriptors {
+    listeners: Vec<ListenerFd>,
+    ports: Vec<PortFd>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ListenerFd {
+    address: String,
+    fd: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PortFd {
+    device: String,
+    port_name: String,
+    baud_rate: u32,
+    fd: i32,
+}
+
+// A serial port kept open across the restart
+pub struct ParkedPort {
+    pub device: String,
+    pub port_name: String,
+    pub baud_rate: u32,
+    pub port: SerialStream,
+}
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+// Sessions that stopped for the restart, with or without a port to hand over
+static STOPPED: AtomicUsize = AtomicUsize::new(0);
+static PARKED: Mutex<Vec<ParkedPort>> = Mutex::new(Vec::new());
+static LISTENERS: Mutex<Vec<ListenerFd>> = Mutex::new(Vec::new());
+static INHERITED: Mutex<Option<Descriptors>> = Mutex::new(None);
+
+// Pick up what the previous process handed over, once at startup
+pub fn load() {
+    let Ok(value) = std::env::var(ENV) else {
+        return;
+    };
+    match serde_json::from_str::<Descriptors>(&value) {
+        Ok(descriptors) => {
+            log::info!(
+                "Restarted in place, taking over {} listener(s) and {} serial port(s)",
+                descriptors.listeners.len(),
+                descriptors.ports.len()
+            );
+            *INHERITED.lock().unwrap() = Some(descriptors);
+        }
+        Err(e) => log::warn!("Ignoring invalid {}: {}", ENV, e),
+    }
+}
+
+// Whether the sessions should stop for a restart
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+// A session stopped for the restart, `port` is kept open for the new process
+pub fn stopped(port: Option<ParkedPort>) {
+    if let Some(port) = port {
+        PARKED.lock().unwrap().push(port);
+    }
+    STOPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+// The listener the previous process had on `address`, otherwise a new one
+pub async fn listen(address: &str) -> std::io::Result<TcpListener> {
+    #[cfg(unix)]
+    let listener = match take_listener(address) {
+        Some(listener) => listener,
+        None => TcpListener::bind(address).await?,
+    };
+    #[cfg(not(unix))]
+    let listener = TcpListener::bind(address).await?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+        LISTENERS.lock().unwrap().push(ListenerFd {
+            address: address.to_string(),
+            fd: listener.as_raw_fd(),
+        });
+    }
+    Ok(listener)
+}
+
+#[cfg(unix)]
+fn take_listener(address: &str) -> Option<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    let fd = {
+        let mut inherited = INHERITED.lock().unwrap();
+        let listeners = &mut inherited.as_mut()?.listeners;
+        let index = listeners.iter().position(|l| l.address == address)?;
+        listeners.remove(index).fd
+    };
+    set_inheritable(fd, false);
+    // SAFETY: the descriptor was a listening socket of the previous process and
+    // nothing else in this one refers to it
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    let listener = listener
+        .set_nonblocking(true)
+        .and_then(|()| TcpListener::from_std(listener));
+    match listener {
+        Ok(listener) => {
+            log::info!("Took over the listener on {}", address);
+            Some(listener)
+        }
+        Err(e) => {
+            log::warn!("Failed to take over the listener on {}: {}", address, e);
+            None
+        }
+    }
+}
+
+// The serial port the previous process had open for `device`
+#[cfg(unix)]
+pub fn take_port(device: &str) -> Option<ParkedPort> {
+    use std::os::unix::io::FromRawFd;
+
+    let handed = {
+        let mut inherited = INHERITED.lock().unwrap();
+        let ports = &mut inherited.as_mut()?.ports;
+        let index = ports.iter().position(|p| p.device == device)?;
+        ports.remove(index)
+    };
+    set_inheritable(handed.fd, false);
+    // SAFETY: the descriptor was the open serial port of `device` in the
+    // previous process and nothing else in this one refers to it
+    let tty = unsafe { serialport::TTYPort::from_raw_fd(handed.fd) };
+    match SerialStream::try_from(tty) {
+        Ok(port) => Some(ParkedPort {
+            device: handed.device,
+            port_name: handed.port_name,
+            baud_rate: handed.baud_rate,
+            port,
+        }),
+        Err(e) => {
+            log::warn!(
+                "Failed to take over serial port {}: {}",
+                handed.port_name,
+                e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn take_port(_device: &str) -> Option<ParkedPort> {
+    None
+}
+
+// Descriptors are closed on exec unless told otherwise
+#[cfg(unix)]
+fn set_inheritable(fd: i32, inheritable: bool) -> bool {
+    // SAFETY: fcntl only reads and sets the descriptor flags of `fd`
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 {
+            return false;
+        }
+        let flags = if inheritable {
+            flags & !libc::FD_CLOEXEC
+        } else {
+            flags | libc::FD_CLOEXEC
+        };
+        libc::fcntl(fd, libc::F_SETFD, flags) == 0
+    }
+}
+
+// SIGHUP restarts the server in place with `sessions` device sessions to stop.
+// The new process loads `config_path` again, so a configuration that doesn't
+// load is reported and the server keeps running as it is
+#[cfg(unix)]
+pub async fn handle_signals(sessions: usize, config_path: String) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hup = match signal(SignalKind::hangup()) {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::warn!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+
+    while hup.recv().await.is_some() {
+        if let Err(e) = Config::load(&config_path) {
+            log::error!(
+                "SIGHUP received, not restarting, {} doesn't load: {:#}",
+                config_path,
+                e
+            );
+            continue;
+        }
+        log::warn!("SIGHUP received, restarting in place");
+        let e = restart(sessions).await;
+        // The sessions are gone, a supervisor has to start the server again
+        log::error!("Failed to restart: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+// Only returns when the new process could not be started
+#[cfg(unix)]
+async fn restart(sessions: usize) -> anyhow::Error {
+    use std::os::unix::process::CommandExt;
+
+    REQUESTED.store(true, Ordering::Relaxed);
+    let deadline = tokio::time::Instant::now() + PARK_TIMEOUT;
+    while STOPPED.load(Ordering::Relaxed) < sessions && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+
+    let listeners = LISTENERS.lock().unwrap().clone();
+    let ports = {
+        use std::os::unix::io::AsRawFd;
+        PARKED
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|parked| PortFd {
+                device: parked.device.clone(),
+                port_name: parked.port_name.clone(),
+                baud_rate: parked.baud_rate,
+                fd: parked.port.as_raw_fd(),
+            })
+            .collect::<Vec<_>>()
+    };
+    let fds = listeners
+        .iter()
+        .map(|l| l.fd)
+        .chain(ports.iter().map(|p| p.fd));
+    for fd in fds {
+        if !set_inheritable(fd, true) {
+            log::warn!("Descriptor {} cannot be handed over", fd);
+        }
+    }
+    log::info!(
+        "Handing {} listener(s) and {} serial port(s) over to the new process",
+        listeners.len(),
+        ports.len()
+    );
+
+    let descriptors = Descriptors { listeners, ports };
+    let value = match serde_json::to_string(&descriptors) {
+        Ok(value) => value,
+        Err(e) => return e.into(),
+    };
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => return e.into(),
+    };
+    let e = std::process::Command::new(exe)
+        .args(std::env::args_os().skip(1))
+        .env(ENV, value)
+        .exec();
+    e.into()
+}
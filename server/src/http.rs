@@ -0,0 +1,126 @@
+use anyhow::{Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// Only the request line and headers count towards this, a body has its own limit
+const MAX_HEAD_BYTES: usize = 8192;
+
+// Just enough HTTP/1.1 for the small listeners of the server: one request per
+// connection, answered and closed
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    params: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Reads the request line, the headers and a body of up to `max_body_bytes` as
+// given by Content-Length. None when the request is malformed or too large
+pub async fn read_request(
+    stream: &mut TcpStream,
+    max_body_bytes: usize,
+) -> Result<Option<Request>> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 1024];
+    let head_end = loop {
+        if let Some(end) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if data.len() > MAX_HEAD_BYTES {
+            return Ok(None);
+        }
+        let read = stream
+            .read(&mut buf)
+            .await
+            .context("Failed to read request")?;
+        if read == 0 {
+            return Ok(None);
+        }
+        data.extend_from_slice(&buf[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&data[..head_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return Ok(None);
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params = query
+        .split('&')
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = value.replace('+', " ");
+            let value = urlencoding::decode(&value).ok()?.into_owned();
+            Some((key.to_string(), value))
+        })
+        .collect();
+    let headers = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        params,
+        headers,
+        body: data.split_off(head_end + 4),
+    };
+    let length = match request.header("content-length") {
+        Some(length) => match length.parse::<usize>() {
+            Ok(length) if length <= max_body_bytes => length,
+            _ => return Ok(None),
+        },
+        None => 0,
+    };
+    while request.body.len() < length {
+        let read = stream
+            .read(&mut buf)
+            .await
+            .context("Failed to read request body")?;
+        if read == 0 {
+            return Ok(None);
+        }
+        request.body.extend_from_slice(&buf[..read]);
+    }
+    request.body.truncate(length);
+
+    Ok(Some(request))
+}
+
+pub async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
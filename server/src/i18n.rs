@@ -0,0 +1,53 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+const DEFAULT_LOCALE: &str = "en";
+
+// Built-in translation files, keyed by locale name
+const LOCALES: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en.toml")),
+    ("zh-CN", include_str!("../locales/zh-CN.toml")),
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct Translations {
+    strings: HashMap<String, String>,
+}
+
+impl Translations {
+    pub fn load(locale: &str) -> Result<Self> {
+        // Start from English so keys missing in a translation still render
+        let mut strings = parse(DEFAULT_LOCALE)?;
+        if locale != DEFAULT_LOCALE {
+            strings.extend(parse(locale)?);
+        }
+
+        Ok(Translations { strings })
+    }
+
+    // Look up a string and substitute `{name}` placeholders
+    pub fn text(&self, key: &str, args: &[(&str, &str)]) -> String {
+        let template = self.strings.get(key).map(String::as_str).unwrap_or(key);
+        args.iter()
+            .fold(template.to_string(), |text, (name, value)| {
+                text.replace(&format!("{{{}}}", name), value)
+            })
+    }
+}
+
+fn parse(locale: &str) -> Result<HashMap<String, String>> {
+    let content = LOCALES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(locale))
+        .map(|(_, content)| *content)
+        .ok_or_else(|| {
+            let available: Vec<_> = LOCALES.iter().map(|(name, _)| *name).collect();
+            anyhow::anyhow!(
+                "Unsupported locale '{}', available: {}",
+                locale,
+                available.join(", ")
+            )
+        })?;
+
+    toml::from_str(content).context(format!("Failed to parse translations for {}", locale))
+}
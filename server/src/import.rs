@@ -0,0 +1,167 @@
+use crate::database::{HistoryEntry, OUTBOUND_FAILED, OUTBOUND_SENT};
+use crate::export::{self, ArchiveFormat};
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+// A row of a CSV or JSON file written by `export`
+#[derive(Debug, Deserialize)]
+struct ArchivedMessage {
+    direction: String,
+    number: String,
+    content: String,
+    timestamp: i64,
+    #[serde(default)]
+    status: Option<String>,
+}
+
+// Messages read from an archive, and how many were skipped because they
+// cannot be imported (drafts, SMS still waiting to be sent, MMS)
+pub struct Archive {
+    pub entries: Vec<HistoryEntry>,
+    pub skipped: usize,
+}
+
+pub fn format_of(path: &Path) -> Result<ArchiveFormat> {
+    match path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("csv") => Ok(ArchiveFormat::Csv),
+        Some("json") => Ok(ArchiveFormat::Json),
+        Some("xml") => Ok(ArchiveFormat::Xml),
+        _ => anyhow::bail!(
+            "Cannot tell the format of {}, pass --format",
+            path.display()
+        ),
+    }
+}
+
+pub fn read(path: &Path, format: ArchiveFormat) -> Result<Archive> {
+    let text =
+        std::fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+    match format {
+        ArchiveFormat::Csv => {
+            let mut reader = csv::Reader::from_reader(text.as_bytes());
+            let messages = reader
+                .deserialize()
+                .collect::<csv::Result<Vec<ArchivedMessage>>>()
+                .context(format!("Failed to parse {}", path.display()))?;
+            Ok(from_archived(messages))
+        }
+        ArchiveFormat::Json => {
+            let messages: Vec<ArchivedMessage> = serde_json::from_str(&text)
+                .context(format!("Failed to parse {}", path.display()))?;
+            Ok(from_archived(messages))
+        }
+        ArchiveFormat::Xml => read_xml(&text),
+    }
+}
+
+// Only received messages and outbound SMS that are done, a pending one would
+// be sent by the running server
+fn from_archived(messages: Vec<ArchivedMessage>) -> Archive {
+    let mut archive = Archive {
+        entries: Vec::new(),
+        skipped: 0,
+    };
+    for message in messages {
+        let outbound = message.direction == export::DIRECTION_SENT;
+        let done = matches!(
+            message.status.as_deref(),
+            Some(OUTBOUND_SENT | OUTBOUND_FAILED)
+        );
+        if outbound && !done {
+            archive.skipped += 1;
+            continue;
+        }
+        archive.entries.push(HistoryEntry {
+            outbound,
+            number: message.number,
+            content: message.content,
+            at: message.timestamp,
+            status: if outbound { message.status } else { None },
+        });
+    }
+    archive
+}
+
+// SMS Backup & Restore XML, <sms> elements carry everything in attributes
+fn read_xml(text: &str) -> Result<Archive> {
+    let element = Regex::new(r"<(sms|mms)\s([^>]*?)/?>").unwrap();
+    let attribute = Regex::new(r#"([\w:]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap();
+
+    let mut archive = Archive {
+        entries: Vec::new(),
+        skipped: 0,
+    };
+    for captures in element.captures_iter(text) {
+        if &captures[1] == "mms" {
+            archive.skipped += 1;
+            continue;
+        }
+        let attributes = attribute
+            .captures_iter(&captures[2])
+            .map(|attr| {
+                let value = attr.get(2).or_else(|| attr.get(3)).unwrap().as_str();
+                (attr[1].to_string(), unescape_xml(value))
+            })
+            .collect::<HashMap<_, _>>();
+        let field = |name: &str| {
+            attributes
+                .get(name)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("<sms> without {}: {}", name, &captures[0]))
+        };
+
+        let status = match field("type")?.parse::<u8>() {
+            Ok(export::TYPE_INBOX) => None,
+            Ok(export::TYPE_SENT) => Some(OUTBOUND_SENT),
+            Ok(export::TYPE_FAILED) => Some(OUTBOUND_FAILED),
+            _ => {
+                archive.skipped += 1;
+                continue;
+            }
+        };
+        let date = field("date")?
+            .parse::<i64>()
+            .context(format!("Invalid date in {}", &captures[0]))?;
+        archive.entries.push(HistoryEntry {
+            outbound: status.is_some(),
+            number: field("address")?,
+            content: field("body")?,
+            // The app stores times in milliseconds
+            at: date / 1000,
+            status: status.map(str::to_string),
+        });
+    }
+    Ok(archive)
+}
+
+fn unescape_xml(value: &str) -> String {
+    let reference = Regex::new(r"&(#x[0-9a-fA-F]+|#[0-9]+|amp|lt|gt|quot|apos);").unwrap();
+    reference
+        .replace_all(value, |captures: &regex::Captures| {
+            let name = &captures[1];
+            let code = if let Some(hex) = name.strip_prefix("#x") {
+                u32::from_str_radix(hex, 16).ok()
+            } else if let Some(decimal) = name.strip_prefix('#') {
+                decimal.parse().ok()
+            } else {
+                None
+            };
+            match (code, name) {
+                (Some(code), _) => char::from_u32(code).map(String::from).unwrap_or_default(),
+                (None, "amp") => "&".to_string(),
+                (None, "lt") => "<".to_string(),
+                (None, "gt") => ">".to_string(),
+                (None, "quot") => "\"".to_string(),
+                _ => "'".to_string(),
+            }
+        })
+        .into_owned()
+}
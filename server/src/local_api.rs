@@ -0,0 +1,591 @@
+use crate::audit;
+use crate::config::Config;
+use crate::database::{self, Database};
+use crate::forwarding;
+use crate::handover;
+use crate::http;
+use crate::notification::{Notifier, SCHEMA_VERSION, SendOptions};
+use crate::opt_out;
+use crate::templates;
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+
+const MAX_BODY_BYTES: usize = 64 * 1024;
+// Events kept for `poll`, a client further behind misses the oldest ones
+const MAX_EVENTS: usize = 1000;
+const DEFAULT_POLL_SECS: u64 = 30;
+const MAX_POLL_SECS: u64 = 60;
+// Added to the longest poll for reading the request and writing the answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Audit log actor of SMS sent through the endpoint
+const ACTOR: &str = "local_api";
+const UNAUTHORIZED: &str = "Missing or wrong bearer token";
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+// A JSON-RPC 2.0 endpoint at `POST /rpc` for low-code tools on the same host.
+// It only listens on loopback and turns away what a browser could send on behalf
+// of a web page, optionally asking for a bearer token. Notifications are kept
+// as numbered events that `poll` hands out, waiting for new ones when there are none
+#[derive(Clone)]
+pub struct LocalApi {
+    listen: String,
+    token: Option<String>,
+    devices: Vec<String>,
+    db: Database,
+    events: Arc<Mutex<Events>>,
+    latest: Arc<watch::Sender<u64>>,
+}
+
+struct Events {
+    next_seq: u64,
+    recent: VecDeque<(u64, Value)>,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+type RpcResult = std::result::Result<Value, RpcError>;
+
+#[derive(Deserialize)]
+struct PollParams {
+    #[serde(default)]
+    after: u64,
+    timeout_secs: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct SendSmsParams {
+    to: String,
+    // Either the text or the name of a saved template with its variables
+    content: Option<String>,
+    template: Option<String>,
+    #[serde(default)]
+    variables: HashMap<String, String>,
+    device: Option<String>,
+    // Send even during quiet hours
+    #[serde(default)]
+    urgent: bool,
+}
+
+#[derive(Deserialize)]
+struct NumberParams {
+    number: String,
+}
+
+#[derive(Deserialize)]
+struct SmsStatusParams {
+    sms_id: i64,
+}
+
+#[derive(Deserialize)]
+struct MessageStatusParams {
+    id: String,
+}
+
+impl LocalApi {
+    pub fn new(config: &Config, db: Database) -> Self {
+        LocalApi {
+            listen: config.local_api.listen.clone().unwrap_or_default(),
+            token: Some(config.local_api.token.clone()).filter(|token| !token.is_empty()),
+            devices: config
+                .devices
+                .iter()
+                .map(|device| device.id.clone())
+                .collect(),
+            db,
+            events: Arc::new(Mutex::new(Events {
+                next_seq: 1,
+                recent: VecDeque::new(),
+            })),
+            latest: Arc::new(watch::Sender::new(0)),
+        }
+    }
+
+    pub fn notifier(&self) -> LocalApiNotifier {
+        LocalApiNotifier { api: self.clone() }
+    }
+
+    pub async fn run(self) {
+        let listener = match handover::listen(&self.listen).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!(
+                    "Failed to listen for local API requests on {}: {}",
+                    self.listen,
+                    e
+                );
+                return;
+            }
+        };
+        log::info!("Serving the local API on {}", self.listen);
+
+        let timeout = Duration::from_secs(MAX_POLL_SECS) + REQUEST_TIMEOUT;
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warn!("Failed to accept a local API request: {}", e);
+                    continue;
+                }
+            };
+            let api = self.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(timeout, api.handle(stream)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => log::debug!("Local API request from {}: {:#}", peer, e),
+                    Err(_) => log::debug!("Local API request from {} timed out", peer),
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        let Some(request) = http::read_request(&mut stream, MAX_BODY_BYTES).await? else {
+            return http::write_response(
+                &mut stream,
+                "400 Bad Request",
+                "text/plain",
+                "Invalid request",
+            )
+            .await;
+        };
+        if request.path != "/rpc" {
+            return http::write_response(&mut stream, "404 Not Found", "text/plain", "Not found")
+                .await;
+        }
+        if request.method != "POST" {
+            return http::write_response(
+                &mut stream,
+                "405 Method Not Allowed",
+                "text/plain",
+                "Use POST",
+            )
+            .await;
+        }
+
+        if let Some(reason) = self.refusal(&request) {
+            log::warn!("Refused a local API request: {}", reason);
+            let status = if reason == UNAUTHORIZED {
+                "401 Unauthorized"
+            } else {
+                "403 Forbidden"
+            };
+            return http::write_response(&mut stream, status, "text/plain", reason).await;
+        }
+
+        let response = self.call(&request.body).await;
+        http::write_response(
+            &mut stream,
+            "200 OK",
+            "application/json",
+            &response.to_string(),
+        )
+        .await
+    }
+
+    // Why a request is turned away. Browsers send an Origin with cross-site
+    // requests, can't send JSON to another site without a preflight that is
+    // never answered, and keep the attacker's name as Host after DNS rebinding
+    fn refusal(&self, request: &http::Request) -> Option<&'static str> {
+        if request.header("Origin").is_some() {
+            return Some("Cross-origin requests are not allowed");
+        }
+        if !request.header("Host").is_some_and(is_loopback_host) {
+            return Some("Host must be a loopback address");
+        }
+        let json = request.header("Content-Type").is_some_and(|value| {
+            value
+                .split(';')
+                .next()
+                .is_some_and(|kind| kind.trim().eq_ignore_ascii_case("application/json"))
+        });
+        if !json {
+            return Some("Content-Type must be application/json");
+        }
+        if let Some(token) = &self.token {
+            let presented = request
+                .header("Authorization")
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .unwrap_or_default();
+            if !same_token(presented.trim().as_bytes(), token.as_bytes()) {
+                return Some(UNAUTHORIZED);
+            }
+        }
+        None
+    }
+
+    // Answers one JSON-RPC request, batches are not supported
+    async fn call(&self, body: &[u8]) -> Value {
+        let request: Value = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(e) => {
+                return error_response(Value::Null, RpcError::new(PARSE_ERROR, e.to_string()));
+            }
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let Some(method) = request.get("method").and_then(Value::as_str) else {
+            return error_response(id, RpcError::new(INVALID_REQUEST, "Missing method"));
+        };
+        let params = request.get("params").cloned().unwrap_or_else(|| json!({}));
+
+        let result = match method {
+            "poll" => match parse_params(params) {
+                Ok(params) => self.poll(params).await,
+                Err(e) => Err(e),
+            },
+            "send_sms" => parse_params(params).and_then(|params| self.send_sms(params)),
+            "sms_status" => parse_params(params).and_then(|params| self.sms_status(params)),
+            "message_status" => parse_params(params).and_then(|params| self.message_status(params)),
+            "devices" => self.list_devices(),
+            "templates" => self.list_templates(),
+            "opt_outs" => self.list_opt_outs(),
+            "opt_out" => parse_params(params).and_then(|params| self.opt_out(params)),
+            "opt_in" => parse_params(params).and_then(|params| self.opt_in(params)),
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method: {}", method),
+            )),
+        };
+        match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(e) => error_response(id, e),
+        }
+    }
+
+    // Events numbered above `after`, waiting up to `timeout_secs` for one.
+    // `next` is the `after` of the following call
+    async fn poll(&self, params: PollParams) -> RpcResult {
+        let timeout = Duration::from_secs(
+            params
+                .timeout_secs
+                .unwrap_or(DEFAULT_POLL_SECS)
+                .min(MAX_POLL_SECS),
+        );
+        let mut latest = self.latest.subscribe();
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let events = self.events_after(params.after);
+            if !events.is_empty() {
+                let next = events
+                    .last()
+                    .and_then(|event| event["seq"].as_u64())
+                    .unwrap_or(params.after);
+                return Ok(json!({"events": events, "next": next}));
+            }
+            let next = params.after.min(*latest.borrow_and_update());
+            if tokio::time::timeout_at(deadline, latest.changed())
+                .await
+                .is_err()
+            {
+                return Ok(json!({"events": [], "next": next}));
+            }
+        }
+    }
+
+    fn events_after(&self, after: u64) -> Vec<Value> {
+        let events = self.events.lock().unwrap();
+        // A restart starts the numbering over, so an `after` ahead of it is old
+        let after = if after >= events.next_seq { 0 } else { after };
+        events
+            .recent
+            .iter()
+            .filter(|(seq, _)| *seq > after)
+            .map(|(_, event)| event.clone())
+            .collect()
+    }
+
+    fn send_sms(&self, params: SendSmsParams) -> RpcResult {
+        if forwarding::normalize_number(&params.to).is_empty() {
+            return Err(RpcError::new(INVALID_PARAMS, "Invalid `to` number"));
+        }
+        let content = match (params.content, &params.template) {
+            (Some(content), None) => content,
+            (None, Some(name)) => templates::render(&self.db, name, &params.variables)
+                .map_err(|e| RpcError::new(INVALID_PARAMS, format!("{:#}", e)))?,
+            _ => {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    "Give either `content` or `template`",
+                ));
+            }
+        };
+        if content.trim().is_empty() {
+            return Err(RpcError::new(INVALID_PARAMS, "Empty `content`"));
+        }
+        if let Some(device) = &params.device
+            && !self.devices.contains(device)
+        {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                format!("Unknown device: {}", device),
+            ));
+        }
+
+        let messages = [(params.to.clone(), content)];
+        let sms_id = self
+            .db
+            .queue_outbound(None, params.device.as_deref(), params.urgent, &messages)
+            .map_err(server_error)?
+            .first()
+            .copied()
+            .ok_or_else(|| RpcError::new(SERVER_ERROR, "SMS was not queued"))?;
+        audit::record(
+            &self.db,
+            ACTOR,
+            "send_sms",
+            Some(&format!("to {}", params.to)),
+        );
+        Ok(json!({"sms_id": sms_id, "status": database::OUTBOUND_PENDING}))
+    }
+
+    fn sms_status(&self, params: SmsStatusParams) -> RpcResult {
+        match self
+            .db
+            .outbound_status(params.sms_id)
+            .map_err(server_error)?
+        {
+            Some((status, error)) => {
+                Ok(json!({"sms_id": params.sms_id, "status": status, "error": error}))
+            }
+            None => Err(RpcError::new(
+                INVALID_PARAMS,
+                format!("No queued SMS {}", params.sms_id),
+            )),
+        }
+    }
+
+    // Lifecycle of a received SMS: stored, notified, held or acked
+    fn message_status(&self, params: MessageStatusParams) -> RpcResult {
+        match self.db.sms_progress(&params.id).map_err(server_error)? {
+            Some(progress) => Ok(json!({
+                "id": params.id,
+                "status": progress.status,
+                "device": progress.device_id,
+                "stored_at": progress.stored_at,
+                "notified_at": progress.notified_at,
+                "ack_sent_at": progress.ack_sent_at,
+                "spam": progress.is_spam,
+            })),
+            None => Err(RpcError::new(
+                INVALID_PARAMS,
+                format!("No received SMS {}", params.id),
+            )),
+        }
+    }
+
+    fn list_devices(&self) -> RpcResult {
+        let states = self.db.connection_statuses().map_err(server_error)?;
+        let devices = self
+            .db
+            .devices()
+            .map_err(server_error)?
+            .into_iter()
+            .map(|device| {
+                let state = states
+                    .iter()
+                    .find(|status| status.device_id == device.device_id)
+                    .map(|status| status.state.clone());
+                json!({
+                    "device_id": device.device_id,
+                    "state": state,
+                    "imei": device.imei,
+                    "number": device.number,
+                    "iccid": device.iccid,
+                    "firmware": device.firmware,
+                    "script_version": device.script_version,
+                    "updated_at": device.updated_at,
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(json!({"devices": devices}))
+    }
+
+    fn list_templates(&self) -> RpcResult {
+        let templates = self
+            .db
+            .templates()
+            .map_err(server_error)?
+            .into_iter()
+            .map(|template| {
+                json!({
+                    "name": template.name,
+                    "body": template.body,
+                    "updated_at": template.updated_at,
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(json!({"templates": templates}))
+    }
+
+    fn list_opt_outs(&self) -> RpcResult {
+        let entries = self
+            .db
+            .opt_outs()
+            .map_err(server_error)?
+            .into_iter()
+            .map(|entry| {
+                json!({
+                    "number": entry.number,
+                    "source": entry.source,
+                    "created_at": entry.created_at,
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(json!({"opt_outs": entries}))
+    }
+
+    fn opt_out(&self, params: NumberParams) -> RpcResult {
+        if forwarding::normalize_number(&params.number).is_empty() {
+            return Err(RpcError::new(INVALID_PARAMS, "Invalid `number`"));
+        }
+        let added = !opt_out::opted_out(&self.db, &params.number).map_err(server_error)?
+            && self
+                .db
+                .add_opt_out(&params.number, ACTOR)
+                .map_err(server_error)?;
+        if added {
+            audit::record(&self.db, ACTOR, "opt_out", Some(&params.number));
+        }
+        Ok(json!({"number": params.number, "added": added}))
+    }
+
+    fn opt_in(&self, params: NumberParams) -> RpcResult {
+        let removed = self
+            .db
+            .remove_opt_outs(|number| forwarding::same_number(number, &params.number))
+            .map_err(server_error)?;
+        if removed > 0 {
+            audit::record(&self.db, ACTOR, "opt_in", Some(&params.number));
+        }
+        Ok(json!({"number": params.number, "removed": removed > 0}))
+    }
+
+    fn push_event(&self, mut event: Value) {
+        let seq = {
+            let mut events = self.events.lock().unwrap();
+            let seq = events.next_seq;
+            events.next_seq += 1;
+            event["seq"] = json!(seq);
+            events.recent.push_back((seq, event));
+            if events.recent.len() > MAX_EVENTS {
+                events.recent.pop_front();
+            }
+            seq
+        };
+        self.latest.send_replace(seq);
+    }
+}
+
+fn parse_params<T: serde::de::DeserializeOwned>(params: Value) -> std::result::Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+fn server_error(e: anyhow::Error) -> RpcError {
+    log::warn!("{:#}", e);
+    RpcError::new(SERVER_ERROR, format!("{:#}", e))
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": error.code, "message": error.message},
+    })
+}
+
+// Keeps notifications as events for `poll`, in the shape of the webhook payload
+pub struct LocalApiNotifier {
+    api: LocalApi,
+}
+
+#[async_trait]
+impl Notifier for LocalApiNotifier {
+    async fn send(&self, title: &str, content: &str) -> Result<()> {
+        self.send_with_options(title, content, &SendOptions::default())
+            .await
+    }
+
+    async fn send_grouped(&self, title: &str, content: &str, group: Option<&str>) -> Result<()> {
+        let options = SendOptions {
+            group,
+            ..Default::default()
+        };
+        self.send_with_options(title, content, &options).await
+    }
+
+    async fn send_with_options(
+        &self,
+        title: &str,
+        content: &str,
+        options: &SendOptions<'_>,
+    ) -> Result<()> {
+        let mut event = json!({
+            "schema_version": SCHEMA_VERSION,
+            "event": "notification",
+            "title": title,
+            "body": content,
+            "created_at": database::unix_timestamp(),
+        });
+        if let Some(message_id) = options.message_id {
+            event["message_id"] = json!(message_id);
+        }
+        if let Some(group) = options.group {
+            event["group"] = json!(group);
+        }
+        if let Some(ack_url) = options.action_url {
+            event["ack_url"] = json!(ack_url);
+        }
+        if let Some(expires_at) = options.expires_at {
+            event["expires_at"] = json!(expires_at);
+        }
+        self.api.push_event(event);
+        Ok(())
+    }
+}
+
+// "localhost", "127.0.0.1" or "[::1]", with or without a port
+fn is_loopback_host(host: &str) -> bool {
+    let host = host.trim();
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    name.eq_ignore_ascii_case("localhost")
+        || name
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+// Compares in constant time, so the token can't be guessed byte by byte
+fn same_token(presented: &[u8], token: &[u8]) -> bool {
+    presented.len() == token.len()
+        && presented
+            .iter()
+            .zip(token)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
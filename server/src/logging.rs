@@ -0,0 +1,43 @@
+use log::LevelFilter;
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+pub fn init() {
+    env_logger::Builder::from_default_env()
+        .filter_level(DEFAULT_LEVEL)
+        // Our own modules are only limited by the runtime-adjustable max level,
+        // dependencies stay at the default level
+        .filter_module(env!("CARGO_CRATE_NAME"), LevelFilter::Trace)
+        .init();
+    log::set_max_level(DEFAULT_LEVEL);
+}
+
+// Switch between debug and the default level, returning the new level
+pub fn toggle_debug() -> LevelFilter {
+    let level = if log::max_level() >= LevelFilter::Debug {
+        DEFAULT_LEVEL
+    } else {
+        LevelFilter::Debug
+    };
+    log::set_max_level(level);
+    level
+}
+
+// SIGUSR1 toggles debug logging without restarting the service
+#[cfg(unix)]
+pub async fn handle_signals() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut usr1 = match signal(SignalKind::user_defined1()) {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::warn!("Failed to listen for SIGUSR1: {}", e);
+            return;
+        }
+    };
+
+    while usr1.recv().await.is_some() {
+        let level = toggle_debug();
+        log::warn!("SIGUSR1 received, log level set to {}", level);
+    }
+}
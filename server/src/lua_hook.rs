@@ -0,0 +1,67 @@
+use crate::config::LuaRulesConfig;
+use crate::rules::{HookActions, HookMessage};
+use anyhow::{Context, Result};
+use mlua::{Function, HookTriggers, Lua, LuaSerdeExt, Value};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// VM instructions run between two checks of the time limit
+const CHECK_INSTRUCTIONS: u32 = 10_000;
+
+// A rules.lua script defining process(sms), which returns nil or a table of
+// actions. The script is loaded once, so its globals persist between messages
+pub struct LuaHook {
+    lua: Mutex<Lua>,
+    timeout: Duration,
+}
+
+impl LuaHook {
+    pub fn new(config: &LuaRulesConfig, path: &str) -> Result<Self> {
+        let source =
+            std::fs::read_to_string(path).context(format!("Failed to read Lua rules {}", path))?;
+        let lua = Lua::new();
+        lua.load(&source)
+            .set_name(path)
+            .exec()
+            .context(format!("Failed to run Lua rules {}", path))?;
+        if !matches!(
+            lua.globals().get::<_, Value>("process")?,
+            Value::Function(_)
+        ) {
+            anyhow::bail!("Lua rules {} do not define a process(sms) function", path);
+        }
+
+        Ok(LuaHook {
+            lua: Mutex::new(lua),
+            timeout: Duration::from_millis(config.timeout_ms),
+        })
+    }
+
+    pub fn process(&self, message: &HookMessage) -> Result<HookActions> {
+        let lua = self.lua.lock().unwrap();
+        let deadline = Instant::now() + self.timeout;
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(CHECK_INSTRUCTIONS),
+            move |_, _| {
+                if Instant::now() > deadline {
+                    return Err(mlua::Error::RuntimeError(
+                        "process() exceeded its time limit".to_string(),
+                    ));
+                }
+                Ok(())
+            },
+        );
+        let result = lua
+            .globals()
+            .get::<_, Function>("process")
+            .and_then(|process| process.call::<_, Value>(lua.to_value(message)?));
+        lua.remove_hook();
+
+        match result.context("Lua process() failed")? {
+            Value::Nil => Ok(HookActions::default()),
+            value => lua
+                .from_value(value)
+                .context("Lua process() returned invalid actions"),
+        }
+    }
+}
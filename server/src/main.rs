@@ -1,49 +1,786 @@
+use anyhow::Context;
+use clap::{Parser, Subcommand};
 use std::sync::Arc;
+use tokio::sync::watch;
 
+mod ack_callback;
+mod archive;
+mod audit;
+mod backup;
+mod bulk;
+mod compaction;
 mod config;
 mod connection;
+mod connection_state;
+mod content_cipher;
+mod conversations;
+mod correlation;
 mod database;
+mod device_log;
+mod error;
+mod escalation;
+mod export;
+mod flood_guard;
+mod forwarding;
+mod grafana;
+mod handover;
+mod http;
+mod i18n;
+mod import;
+mod local_api;
+mod logging;
+mod lua_hook;
+mod maintenance;
+mod message_class;
+mod metrics;
+mod mqtt;
+mod network;
 mod notification;
+mod ops_alerts;
+mod opt_out;
+mod otp;
+mod port_access;
+mod pricing;
+mod process_stats;
+mod protocol;
+mod quiet_hours;
+mod rules;
+mod schedule;
+mod script_update;
+mod self_test;
+mod sender;
+mod sender_queue;
 mod serial_port;
+mod sigv4;
+mod spam;
+mod stored_sms;
+mod templates;
+mod timezone;
+mod udev;
+mod usage;
+mod wasm_hook;
+mod watchdog;
+mod webhook;
 
 use config::Config;
-use connection::SerialConnection;
+use connection::{AdminCommand, SerialConnection};
+use connection_state::ConnectionState;
 use database::Database;
-use notification::{BarkNotifier, Notifier};
+use device_log::DeviceLog;
+use escalation::Escalator;
+use i18n::Translations;
+use notification::{BarkNotifier, FanoutNotifier, LimitedNotifier, Notifier, PreviewNotifier};
+
+#[derive(Parser)]
+#[command(version, about = "Air780E SMS UART server")]
+struct Cli {
+    /// Configuration file (.toml, .yaml/.yml or .json)
+    #[arg(short, long, global = true, default_value = "config.toml")]
+    config: String,
+    /// Never open the serial ports and only read the database, for a second
+    /// instance next to the one talking to the device
+    #[arg(long, global = true)]
+    read_only: bool,
+    /// Tokio runtime to run on, overriding [runtime] flavor
+    #[arg(long, global = true, value_enum)]
+    runtime: Option<config::RuntimeFlavor>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Acknowledge a message, stopping its repeated notifications and recording who handled it
+    Ack { message_id: String },
+    /// Show message counts and the last reported info of each device
+    Status,
+    /// List conversations, or show the messages exchanged with one number
+    Conversations {
+        number: Option<String>,
+        /// Number of conversations or messages to print, most recent ones
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Estimate what the SMS sent cost per billing cycle, device and [pricing] prefix
+    Costs {
+        /// Number of cycles to print, most recent ones
+        #[arg(short = 'n', long, default_value_t = 3)]
+        cycles: usize,
+    },
+    /// Export received and sent messages, oldest first
+    Export {
+        #[arg(long, value_enum, default_value_t = export::ArchiveFormat::Csv)]
+        format: export::ArchiveFormat,
+        /// File to write, standard output when omitted
+        #[arg(short, long)]
+        output: Option<std::path::PathBuf>,
+    },
+    /// Add messages from an exported or SMS Backup & Restore archive to the history,
+    /// without notifying
+    Import {
+        file: std::path::PathBuf,
+        /// Guessed from the file extension when omitted
+        #[arg(long, value_enum)]
+        format: Option<export::ArchiveFormat>,
+    },
+    /// Make the running server close the serial session and connect again
+    Reconnect {
+        /// Device id from [[devices]], every device when omitted
+        #[arg(long)]
+        device: Option<String>,
+    },
+    /// Like reconnect, also forgetting the detected baud rate so detection starts over
+    Rescan {
+        /// Device id from [[devices]], every device when omitted
+        #[arg(long)]
+        device: Option<String>,
+    },
+    /// List stored messages the devices never got an ACK for, or have the
+    /// running server acknowledge or notify them again
+    Unacked {
+        #[command(subcommand)]
+        action: UnackedCommand,
+        /// Device id from [[devices]], every device when omitted
+        #[arg(long, global = true)]
+        device: Option<String>,
+    },
+    /// Release the serial ports for external tools such as LuaTools, the devices
+    /// buffer SMS meanwhile
+    Maintenance {
+        #[command(subcommand)]
+        action: MaintenanceCommand,
+    },
+    /// Hold SMS notifications, messages are still stored and acknowledged
+    PauseNotifications,
+    /// Send the notifications held since pause-notifications and notify again
+    ResumeNotifications,
+    /// Inspect and redeliver webhook events
+    Webhook {
+        #[command(subcommand)]
+        action: WebhookCommand,
+    },
+    /// List the [[schedules]] with their last and next run
+    Schedules,
+    /// Compact the database file now
+    Vacuum,
+    /// Encrypt message text stored before [database] content_key was set
+    EncryptContent {
+        /// Decrypt it all instead, before removing the key
+        #[arg(long)]
+        decrypt: bool,
+    },
+    /// Move messages older than [archive] after_days to the archive now
+    Archive,
+    /// Put messages from archive files back into the database
+    RestoreArchive {
+        #[arg(required = true)]
+        files: Vec<std::path::PathBuf>,
+    },
+    /// Upload a database snapshot to the [backup] targets now
+    Backup,
+    /// Delete every message from or to a number, with its notification history,
+    /// webhook events, audit entries and archived copies
+    EraseSender {
+        number: String,
+        /// Only count what would be deleted
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Print who sent SMS, acknowledged messages or changed settings
+    AuditLog {
+        /// Number of entries to print
+        #[arg(short = 'n', long, default_value_t = 50)]
+        lines: usize,
+    },
+    /// Print the latest log lines forwarded by the devices
+    DeviceLogs {
+        /// Number of lines to print
+        #[arg(short = 'n', long, default_value_t = 50)]
+        lines: usize,
+    },
+    /// Push a Lua script to the device over UART (stop the server first)
+    FlashScript {
+        file: std::path::PathBuf,
+        /// Device id from [[devices]], required when several are configured
+        #[arg(long)]
+        device: Option<String>,
+        /// Serial port to use instead of the configured one
+        #[arg(long)]
+        port: Option<String>,
+    },
+    /// Queue an SMS to every recipient of a CSV file, sent one by one by the running server
+    SendBulk {
+        /// CSV file with a header row and a `number` column
+        recipients: std::path::PathBuf,
+        /// Message text, {column} placeholders are filled from the recipient's row
+        #[arg(long, required_unless_present_any = ["template_file", "template_name"])]
+        template: Option<String>,
+        /// Read the message text from a file instead
+        #[arg(long, conflicts_with = "template")]
+        template_file: Option<std::path::PathBuf>,
+        /// Use a template saved with `templates set` instead
+        #[arg(long, conflicts_with_all = ["template", "template_file"])]
+        template_name: Option<String>,
+        /// Device id from [[devices]] to send from, any device when omitted
+        #[arg(long)]
+        device: Option<String>,
+        /// Report progress until every message is sent or failed
+        #[arg(long)]
+        wait: bool,
+        /// Send even during [quiet_hours]
+        #[arg(long)]
+        urgent: bool,
+    },
+    /// Have the running server text a number and wait for the SMS to be received,
+    /// exiting with 0 when it came back in time
+    SelfTest {
+        /// Device id from [[devices]] to send from
+        #[arg(long)]
+        device: Option<String>,
+        /// Number to text, instead of [self_test] number or the device's own
+        #[arg(long)]
+        to: Option<String>,
+        /// Seconds to wait for the SMS, instead of [self_test] timeout_secs
+        #[arg(long)]
+        timeout: Option<u64>,
+    },
+    /// List, save or delete message templates, usable by send-bulk, the local API and MQTT
+    Templates {
+        #[command(subcommand)]
+        action: TemplateCommand,
+    },
+    /// List or change the numbers that asked not to receive SMS, queued SMS to them are not sent
+    OptOuts {
+        #[command(subcommand)]
+        action: OptOutCommand,
+    },
+    /// Print a udev rule giving the device a stable /dev/air780e symlink (stop the server first)
+    GenerateUdevRule {
+        /// Device id from [[devices]], required when several are configured
+        #[arg(long)]
+        device: Option<String>,
+        /// Serial port to use instead of the configured one
+        #[arg(long)]
+        port: Option<String>,
+        /// Symlink name, defaults to air780e or air780e-<device id>
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// List or delete the SMS the device keeps until they are acknowledged (stop the server first)
+    Stored {
+        #[command(subcommand)]
+        action: StoredCommand,
+        /// Device id from [[devices]], required when several are configured
+        #[arg(long, global = true)]
+        device: Option<String>,
+        /// Serial port to use instead of the configured one
+        #[arg(long, global = true)]
+        port: Option<String>,
+    },
+    /// Manage the configuration file
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum StoredCommand {
+    /// Print the stored SMS, oldest first
+    List,
+    /// Delete stored SMS by id, the device no longer retries them
+    Delete {
+        #[arg(required_unless_present = "all")]
+        ids: Vec<String>,
+        /// Delete every stored SMS
+        #[arg(long, conflicts_with = "ids")]
+        all: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum TemplateCommand {
+    /// Print the saved templates
+    List,
+    /// Save a template, replacing one of the same name. {name} placeholders are
+    /// filled when it is sent
+    Set {
+        name: String,
+        #[arg(required_unless_present = "file")]
+        body: Option<String>,
+        /// Read the text from a file instead
+        #[arg(long, conflicts_with = "body")]
+        file: Option<std::path::PathBuf>,
+    },
+    /// Delete a template
+    Delete { name: String },
+}
+
+#[derive(Subcommand)]
+enum OptOutCommand {
+    /// Print the opted-out numbers, latest first
+    List,
+    /// Stop sending to a number
+    Add { number: String },
+    /// Allow sending to a number again
+    Remove { number: String },
+}
+
+#[derive(Subcommand)]
+enum WebhookCommand {
+    /// Print the deliveries that failed every attempt
+    Dead,
+    /// Send deliveries again, the running server picks them up
+    Redeliver {
+        #[arg(required_unless_present = "all")]
+        ids: Vec<String>,
+        /// Redeliver every dead delivery
+        #[arg(long, conflicts_with = "ids")]
+        all: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum UnackedCommand {
+    /// Print the messages, oldest first
+    List,
+    /// Send their ACKs, so the devices stop retransmitting them
+    ReplayAcks,
+    /// Send their notifications again
+    Renotify,
+}
+
+#[derive(Subcommand)]
+enum MaintenanceCommand {
+    /// Close the serial ports and keep them closed
+    Start,
+    /// Reconnect and import the SMS buffered by the devices
+    End,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Write a commented default configuration file
+    Init {
+        #[arg(default_value = "config.toml")]
+        path: String,
+        /// Overwrite an existing file
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    if let Some(Command::Config {
+        action: ConfigCommand::Init { path, force },
+    }) = &cli.command
+    {
+        match Config::write_default(path, *force) {
+            Ok(()) => println!("Default configuration written to {}", path),
+            Err(e) => error::exit("Failed to write configuration", &e),
+        }
+        return;
+    }
 
-#[tokio::main]
-async fn main() {
     // Initialize logger
-    env_logger::Builder::from_default_env()
-        .filter_level(log::LevelFilter::Info)
-        .init();
+    logging::init();
 
     log::info!("=== Air780E UART Server Starting ===");
 
     // Load configuration
-    let config = match Config::load("config.toml") {
+    let config = match Config::load(&cli.config) {
         Ok(cfg) => {
             log::info!("Configuration loaded successfully");
             cfg
         }
         Err(e) => {
-            eprintln!("Failed to load configuration: {}", e);
-            eprintln!("Make sure config.toml exists in the current directory");
-            std::process::exit(1);
+            eprintln!("Failed to load configuration: {:#}", e);
+            if !std::path::Path::new(&cli.config).exists() {
+                eprintln!("Make sure {} exists", cli.config);
+                eprintln!("Run `air780e-uart-server config init` to create a default one");
+            }
+            std::process::exit(error::exit_code(&e));
         }
     };
 
-    // Initialize database
-    let db = match Database::new(&config.database.path) {
+    let flavor = cli.runtime.unwrap_or(config.runtime.flavor);
+    let runtime = match build_runtime(&config.runtime, flavor) {
+        Ok(runtime) => runtime,
+        // Only the [runtime] settings can make this fail
+        Err(e) => error::exit(
+            "Failed to start the async runtime",
+            &error::config_error(e.into()),
+        ),
+    };
+    runtime.block_on(run(cli, config));
+}
+
+fn build_runtime(
+    config: &config::RuntimeConfig,
+    flavor: config::RuntimeFlavor,
+) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = match flavor {
+        config::RuntimeFlavor::MultiThread => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            if config.worker_threads > 0 {
+                builder.worker_threads(config.worker_threads);
+            }
+            builder
+        }
+        config::RuntimeFlavor::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+    };
+    log::debug!("Starting the {:?} runtime", flavor);
+    builder
+        .enable_all()
+        .max_blocking_threads(config.max_blocking_threads)
+        .thread_stack_size(config.thread_stack_kib * 1024)
+        .build()
+}
+
+async fn run(cli: Cli, config: Config) {
+    #[cfg(unix)]
+    tokio::spawn(logging::handle_signals());
+
+    if cli.read_only
+        && matches!(
+            cli.command,
+            Some(
+                Command::FlashScript { .. }
+                    | Command::Stored { .. }
+                    | Command::GenerateUdevRule { .. }
+            )
+        )
+    {
+        let e = error::AppError::Config("--read-only never opens the serial port".to_string());
+        error::exit("This command uses the serial port", &e.into());
+    }
+
+    // Commands that only use the serial port or files don't open the database
+    let read_only = cli.read_only;
+    let db = || open_database(&config, read_only);
+
+    match cli.command {
+        None if read_only => run_read_only(&config, db()).await,
+        None => serve(&config, &cli.config, db()).await,
+        Some(Command::Config { .. }) => unreachable!("handled before loading the config"),
+        Some(Command::DeviceLogs { lines }) => match device_log::tail(&config.device_log, lines) {
+            Ok(lines) => lines.iter().for_each(|line| println!("{}", line)),
+            Err(e) => error::exit("Failed to read device log", &e),
+        },
+        Some(Command::FlashScript { file, device, port }) => {
+            if let Err(e) = flash_script(&config, device.as_deref(), port.as_deref(), &file).await {
+                error::exit("Failed to flash script", &e);
+            }
+        }
+        Some(Command::Stored {
+            action,
+            device,
+            port,
+        }) => {
+            if let Err(e) =
+                manage_stored(&config, &action, device.as_deref(), port.as_deref()).await
+            {
+                error::exit("Failed to manage stored SMS", &e);
+            }
+        }
+        Some(Command::GenerateUdevRule { device, port, name }) => {
+            match generate_udev_rule(&config, device.as_deref(), port.as_deref(), name.as_deref())
+                .await
+            {
+                Ok(rule) => print!("{}", rule),
+                Err(e) => error::exit("Failed to generate udev rule", &e),
+            }
+        }
+        Some(Command::Ack { message_id }) => {
+            let db = db();
+            let user = audit::cli_user();
+            match db.mark_handled(&message_id, &user) {
+                Ok(Some(handled_by)) if handled_by != user => println!(
+                    "Message {} was already acknowledged by {}",
+                    message_id, handled_by
+                ),
+                Ok(Some(_)) => {
+                    audit::record(&db, &user, "ack", Some(&message_id));
+                    println!("Message {} acknowledged", message_id)
+                }
+                Ok(None) => println!("No message {}", message_id),
+                Err(e) => error::exit("Failed to acknowledge message", &e),
+            }
+        }
+        Some(Command::SendBulk {
+            recipients,
+            template,
+            template_file,
+            template_name,
+            device,
+            wait,
+            urgent,
+        }) => {
+            let db = db();
+            let template = match bulk_template(&db, template, template_file, template_name) {
+                Ok(template) => template,
+                Err(e) => error::exit("Failed to read template", &e),
+            };
+            if let Err(e) = send_bulk(
+                &config,
+                &db,
+                &recipients,
+                &template,
+                device.as_deref(),
+                wait,
+                urgent,
+            )
+            .await
+            {
+                error::exit("Failed to send bulk SMS", &e);
+            }
+        }
+        Some(Command::SelfTest {
+            device,
+            to,
+            timeout,
+        }) => match self_test(&config, &db(), device.as_deref(), to.as_deref(), timeout).await {
+            Ok(round_trip) => println!(
+                "Self-test passed: SMS {} received after {}s",
+                round_trip.sms_id,
+                round_trip.elapsed.as_secs()
+            ),
+            Err(e) => error::exit("Self-test failed", &e),
+        },
+        Some(Command::Reconnect { device }) => {
+            admin_command(&config, &db(), device, AdminCommand::Reconnect).await
+        }
+        Some(Command::Rescan { device }) => {
+            admin_command(&config, &db(), device, AdminCommand::Rescan).await
+        }
+        Some(Command::Unacked { action, device }) => match action {
+            UnackedCommand::List => {
+                if let Err(e) = print_unacknowledged(&config, &db(), device.as_deref()) {
+                    error::exit("Failed to list unacknowledged messages", &e);
+                }
+            }
+            UnackedCommand::ReplayAcks => {
+                admin_command(&config, &db(), device, AdminCommand::ReplayAcks).await
+            }
+            UnackedCommand::Renotify => {
+                admin_command(&config, &db(), device, AdminCommand::Renotify).await
+            }
+        },
+        Some(Command::Export { format, output }) => {
+            if let Err(e) = export_messages(&config, &db(), format, output.as_deref()) {
+                error::exit("Failed to export messages", &e);
+            }
+        }
+        Some(Command::Import { file, format }) => {
+            if let Err(e) = import_messages(&db(), &file, format) {
+                error::exit("Failed to import messages", &e);
+            }
+        }
+        Some(Command::Templates { action }) => {
+            if let Err(e) = manage_templates(&config, &db(), &action) {
+                error::exit("Failed to manage templates", &e);
+            }
+        }
+        Some(Command::OptOuts { action }) => {
+            if let Err(e) = manage_opt_outs(&config, &db(), &action) {
+                error::exit("Failed to manage opt-outs", &e);
+            }
+        }
+        Some(Command::Webhook { action }) => {
+            if let Err(e) = manage_webhook(&config, &db(), &action) {
+                error::exit("Failed to manage webhook deliveries", &e);
+            }
+        }
+        Some(Command::Archive) => {
+            if let Err(e) = archive_now(&config, &db()) {
+                error::exit("Failed to archive messages", &e);
+            }
+        }
+        Some(Command::Backup) => {
+            if let Err(e) = backup_now(&config, &db()).await {
+                error::exit("Backup failed", &e);
+            }
+        }
+        Some(Command::EraseSender { number, dry_run }) => {
+            if let Err(e) = erase_sender(&config, &db(), &number, dry_run) {
+                error::exit(&format!("Failed to erase messages of {}", number), &e);
+            }
+        }
+        Some(Command::RestoreArchive { files }) => {
+            if let Err(e) = restore_archives(&db(), &files) {
+                error::exit("Failed to restore archive", &e);
+            }
+        }
+        Some(Command::Vacuum) => {
+            let db = db();
+            let before = db.file_stats();
+            match compaction::vacuum(db.clone()).await {
+                Ok(after) => {
+                    if let Ok(before) = before {
+                        println!(
+                            "Database compacted from {} to {} bytes",
+                            before.size_bytes(),
+                            after.size_bytes()
+                        );
+                    }
+                }
+                Err(e) => error::exit("Failed to compact the database", &e),
+            }
+        }
+        Some(Command::EncryptContent { decrypt }) => {
+            let db = db();
+            match db.reseal_content(!decrypt) {
+                Ok(count) => {
+                    let action = if decrypt {
+                        "decrypt_content"
+                    } else {
+                        "encrypt_content"
+                    };
+                    audit::record(&db, &audit::cli_user(), action, Some(&count.to_string()));
+                    println!(
+                        "{} {} value(s)",
+                        if decrypt { "Decrypted" } else { "Encrypted" },
+                        count
+                    );
+                }
+                Err(e) => error::exit("Failed to rewrite message content", &e),
+            }
+        }
+        Some(Command::Schedules) => {
+            if let Err(e) = print_schedules(&config, &db()) {
+                error::exit("Failed to list schedules", &e);
+            }
+        }
+        Some(Command::AuditLog { lines }) => {
+            if let Err(e) = print_audit_log(&config, &db(), lines) {
+                error::exit("Failed to read audit log", &e);
+            }
+        }
+        Some(Command::Maintenance { action }) => {
+            if let Err(e) = manage_maintenance(&config, &db(), &action).await {
+                error::exit("Failed to change maintenance mode", &e);
+            }
+        }
+        Some(Command::PauseNotifications) => {
+            let db = db();
+            match db.pause_notifications() {
+                Ok(true) => {
+                    audit::record(&db, &audit::cli_user(), "pause_notifications", None);
+                    println!("Notifications paused")
+                }
+                Ok(false) => println!("Notifications are already paused"),
+                Err(e) => error::exit("Failed to pause notifications", &e),
+            }
+        }
+        Some(Command::ResumeNotifications) => {
+            let db = db();
+            match db.resume_notifications() {
+                Ok(Some(_)) => {
+                    audit::record(&db, &audit::cli_user(), "resume_notifications", None);
+                    println!("Notifications resumed, the running server sends the held ones")
+                }
+                Ok(None) => println!("Notifications are not paused"),
+                Err(e) => error::exit("Failed to resume notifications", &e),
+            }
+        }
+        Some(Command::Conversations { number, limit }) => {
+            if let Err(e) = print_conversations(&config, &db(), number.as_deref(), limit) {
+                error::exit("Failed to read conversations", &e);
+            }
+        }
+        Some(Command::Costs { cycles }) => {
+            if let Err(e) = print_costs(&config, &db(), cycles) {
+                error::exit("Failed to estimate costs", &e);
+            }
+        }
+        Some(Command::Status) => {
+            if let Err(e) = print_status(&config, &db()) {
+                error::exit("Failed to read status", &e);
+            }
+        }
+    }
+}
+
+// Opens the database or exits with its error code
+fn open_database(config: &Config, read_only: bool) -> Database {
+    let opened = if read_only {
+        Database::open_read_only(&config.database.path)
+    } else {
+        Database::new(&config.database.path)
+    };
+    let opened = opened.and_then(|database| {
+        Ok(match config.database.content_cipher()? {
+            Some(cipher) => database.with_content_cipher(cipher),
+            None => database,
+        })
+    });
+    match opened {
         Ok(database) => {
             log::info!("Database initialized: {}", config.database.path);
             database
         }
         Err(e) => {
-            log::error!("Failed to initialize database: {}", e);
-            std::process::exit(1);
+            log::error!("Failed to initialize database: {:#}", e);
+            std::process::exit(error::exit_code(&e));
         }
-    };
+    }
+}
+
+async fn admin_command(
+    config: &Config,
+    db: &Database,
+    device: Option<String>,
+    command: AdminCommand,
+) {
+    if let Err(e) = request_admin_command(config, db, device.as_deref(), command).await {
+        error::exit(&format!("Failed to request {}", command.as_str()), &e);
+    }
+}
+
+// The text `send-bulk` sends, given inline, in a file or by template name
+fn bulk_template(
+    db: &Database,
+    template: Option<String>,
+    file: Option<std::path::PathBuf>,
+    name: Option<String>,
+) -> anyhow::Result<String> {
+    match (template, file, name) {
+        (Some(template), _, _) => Ok(template),
+        (None, Some(path), _) => Ok(std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?
+            .trim_end()
+            .to_string()),
+        (None, None, Some(name)) => db
+            .template(&name)?
+            .with_context(|| format!("No template named {}, see `templates list`", name)),
+        (None, None, None) => unreachable!("clap requires a template"),
+    }
+}
+
+// Runs the connection loops and background tasks until Ctrl+C
+async fn serve(config: &Config, config_path: &str, db: Database) {
+    match db.discard_admin_commands(None) {
+        Ok(0) => {}
+        Ok(count) => log::warn!(
+            "Discarded {} reconnect request(s) queued while the server was not running",
+            count
+        ),
+        Err(e) => log::warn!("Failed to discard stale admin commands: {}", e),
+    }
+
+    match db.fail_interrupted_outbound() {
+        Ok(0) => {}
+        Ok(count) => log::warn!(
+            "{} outbound SMS were being sent when the server stopped, marked as failed",
+            count
+        ),
+        Err(e) => log::warn!("Failed to check interrupted outbound SMS: {}", e),
+    }
+
+    // Descriptors kept open by the process this one replaced
+    handover::load();
 
     // Print database stats
     if let Ok(total) = db.count_total()
@@ -55,28 +792,264 @@ async fn main() {
             unack
         );
     }
+    // Stored and held messages are notified by the recovery, stored and notified
+    // ones are ACKed when the device retransmits them
+    if let Ok(counts) = db.count_by_status() {
+        let unfinished: Vec<_> = counts
+            .iter()
+            .filter(|(status, _)| status != database::SMS_ACKED)
+            .map(|(status, count)| format!("{} {}", count, status))
+            .collect();
+        if !unfinished.is_empty() {
+            log::info!(
+                "Messages left unfinished by the last run: {}",
+                unfinished.join(", ")
+            );
+        }
+    }
+
+    // Supervises the background tasks and the connection loops
+    let watchdog = watchdog::Watchdog::new(&config.watchdog);
+
+    // Load notification translations
+    let translations = match Translations::load(&config.locale) {
+        Ok(translations) => translations,
+        Err(e) => {
+            log::error!("Failed to load translations: {:#}", e);
+            std::process::exit(error::exit_code(&error::config_error(e)));
+        }
+    };
+
+    // Anomalies of the server itself, apart from the SMS notifications
+    let ops_alerts = ops_alerts::OpsAlerts::new(config, translations.clone());
 
     // Initialize notifier
-    let notifier: Arc<dyn Notifier> = if config.notification.enabled {
-        log::info!("Bark notifications enabled");
-        Arc::new(BarkNotifier::new(
-            config.notification.bark_server_url.clone(),
-            config.notification.bark_device_key.clone(),
-        ))
+    let mut notifiers: Vec<Arc<dyn Notifier>> = Vec::new();
+    if config.notification.enabled {
+        let keys = config.notification.bark_device_keys();
+        log::info!("Bark notifications enabled ({} device(s))", keys.len());
+        for key in keys {
+            let bark = PreviewNotifier::wrap(
+                Arc::new(BarkNotifier::new(
+                    config.notification.bark_server_url.clone(),
+                    key,
+                )),
+                config.notification.preview_chars,
+                ack_callback::AckLinks::new(&config.ack_callback),
+            );
+            notifiers.push(LimitedNotifier::wrap(
+                bark,
+                config.notification.max_length,
+                config.notification.overflow,
+            ));
+        }
     } else {
         log::warn!("Notifications disabled in config");
-        Arc::new(BarkNotifier::new(String::new(), String::new()))
+    }
+    if config.webhook.enabled() {
+        let deliverer =
+            webhook::WebhookDeliverer::new(&config.webhook, db.clone(), ops_alerts.clone());
+        notifiers.push(LimitedNotifier::wrap(
+            Arc::new(deliverer.notifier()),
+            config.webhook.max_length,
+            config.webhook.overflow,
+        ));
+        watchdog.spawn("webhook", move |heartbeat| deliverer.clone().run(heartbeat));
+    }
+    if config.local_api.enabled() {
+        let api = local_api::LocalApi::new(config, db.clone());
+        notifiers.push(Arc::new(api.notifier()));
+        watchdog.spawn("local_api", move |_| api.clone().run());
+    }
+    notifiers.extend(notification::plugins(&config.notifier_plugins));
+    let notifier: Arc<dyn Notifier> = match notifiers.len() {
+        0 => Arc::new(BarkNotifier::new(String::new(), String::new())),
+        1 => notifiers.remove(0),
+        _ => Arc::new(FanoutNotifier::new(notifiers)),
     };
 
-    // Create connection manager
-    let mut connection = SerialConnection::new(config.serial.clone(), db.clone(), notifier);
+    if config.spam.enabled {
+        log::info!(
+            "Spam classifier enabled (threshold {})",
+            config.spam.threshold
+        );
+    }
 
-    log::info!("Starting serial connection loop...");
-    log::info!(
-        "Port: {}, Baud: {}",
-        config.serial.port_name,
-        config.serial.baud_rate
+    // Start escalation task for critical messages
+    let ack_links = ack_callback::AckLinks::new(&config.ack_callback);
+    let escalator = Escalator::new(
+        &config.escalation,
+        db.clone(),
+        notifier.clone(),
+        translations.clone(),
+        ack_links.clone(),
     );
+    let task = escalator.clone();
+    watchdog.spawn("escalation", move |heartbeat| task.clone().run(heartbeat));
+    if let Some(links) = ack_links {
+        let server = ack_callback::AckServer::new(&config.ack_callback, links, db.clone());
+        watchdog.spawn("ack_callback", move |_| server.clone().run());
+    }
+    let mqtt_bridge = config
+        .mqtt
+        .enabled()
+        .then(|| mqtt::MqttBridge::new(config, db.clone()));
+    if let Some(bridge) = mqtt_bridge.clone() {
+        watchdog.spawn("mqtt", move |_| bridge.clone().run());
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(maintenance::handle_signals(db.clone()));
+
+    let sampler = process_stats::ProcessSampler::new(db.clone());
+    watchdog.spawn("process_stats", move |heartbeat| {
+        sampler.clone().run(heartbeat)
+    });
+
+    let compactor = compaction::Compactor::new(&config.database, db.clone());
+    watchdog.spawn("compaction", move |_| compactor.clone().run());
+
+    if config.archive.enabled() {
+        match timezone::TimeFormatter::new(config.timezone.as_deref()) {
+            Ok(time) => {
+                let archiver = archive::Archiver::new(&config.archive, time, db.clone());
+                watchdog.spawn("archive", move |_| archiver.clone().run());
+            }
+            Err(e) => {
+                log::error!("Failed to start archiving: {:#}", e);
+                std::process::exit(error::exit_code(&error::config_error(e)));
+            }
+        }
+    }
+
+    if config.backup.enabled() {
+        let backup_config = config.backup.clone();
+        let archive_dir = config.archive.directory.clone();
+        let db = db.clone();
+        watchdog.spawn("backup", move |_| {
+            backup::Backup::new(&backup_config, archive_dir.as_deref(), db.clone()).run()
+        });
+    }
+
+    if !config.schedules.is_empty() {
+        let scheduler = timezone::TimeFormatter::new(config.timezone.as_deref())
+            .and_then(|time| schedule::Scheduler::new(&config.schedules, time, db.clone()));
+        match scheduler {
+            Ok(scheduler) => {
+                watchdog.spawn("scheduler", move |heartbeat| {
+                    scheduler.clone().run(heartbeat)
+                });
+            }
+            Err(e) => {
+                log::error!("Failed to start the scheduler: {:#}", e);
+                std::process::exit(error::exit_code(&error::config_error(e)));
+            }
+        }
+    }
+
+    if config.metrics.enabled() {
+        let exporter = metrics::MetricsExporter::new(&config.metrics, db.clone());
+        watchdog.spawn("metrics", move |heartbeat| exporter.clone().run(heartbeat));
+    }
+
+    // Device-side log lines go to their own rotating file
+    let device_log = if config.device_log.enabled {
+        let opened = timezone::TimeFormatter::new(config.timezone.as_deref())
+            .and_then(|time| DeviceLog::new(&config.device_log, time));
+        match opened {
+            Ok(device_log) => {
+                log::info!("Device logs written to {}", config.device_log.path);
+                Some(device_log)
+            }
+            Err(e) => {
+                log::error!("Failed to open device log: {:#}", e);
+                std::process::exit(error::exit_code(&error::config_error(e)));
+            }
+        }
+    } else {
+        None
+    };
+
+    // Create one connection manager per device
+    let mut connections = tokio::task::JoinSet::new();
+    for device in config.device_profiles() {
+        let label = device.id.clone().unwrap_or_else(|| "default".to_string());
+        log::info!(
+            "Starting serial connection loop for device {} (port: {}, baud: {})",
+            label,
+            device.serial.port_label(),
+            match device.serial.baud_rate {
+                config::AUTO_BAUD_RATE => "auto".to_string(),
+                rate => rate.to_string(),
+            }
+        );
+
+        let connection = match SerialConnection::new(
+            config,
+            device.clone(),
+            db.clone(),
+            notifier.clone(),
+            escalator.clone(),
+            translations.clone(),
+            device_log.clone(),
+        ) {
+            Ok(connection) => connection,
+            Err(e) => {
+                log::error!("Failed to initialize connection: {:#}", e);
+                std::process::exit(error::exit_code(&e));
+            }
+        };
+
+        // A restart after a stall or panic starts over with a new connection
+        let mut first = Some(connection);
+        let config = config.clone();
+        let db = db.clone();
+        let notifier = notifier.clone();
+        let escalator = escalator.clone();
+        let translations = translations.clone();
+        let device_log = device_log.clone();
+        let ops_alerts = ops_alerts.clone();
+        let mqtt_bridge = mqtt_bridge.clone();
+        let watchdog = watchdog.clone();
+        connections.spawn(async move {
+            let name = format!("connection {}", label);
+            let result = watchdog
+                .run(&name, |heartbeat| {
+                    let connection = first.take().map(Ok).unwrap_or_else(|| {
+                        SerialConnection::new(
+                            &config,
+                            device.clone(),
+                            db.clone(),
+                            notifier.clone(),
+                            escalator.clone(),
+                            translations.clone(),
+                            device_log.clone(),
+                        )
+                    });
+                    let ops_alerts = ops_alerts.clone();
+                    let mqtt_bridge = mqtt_bridge.clone();
+                    let label = label.clone();
+                    async move {
+                        let mut connection = connection?;
+                        connection.set_heartbeat(heartbeat);
+                        if let Some(bridge) = &mqtt_bridge {
+                            bridge.follow_state(&label, connection.subscribe_state());
+                        }
+                        log_state_changes(label, connection.subscribe_state());
+                        connection.set_ops_alerts(ops_alerts);
+                        connection.maintain_loop().await
+                    }
+                })
+                .await;
+            (label, result)
+        });
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(handover::handle_signals(
+        connections.len(),
+        config_path.to_string(),
+    ));
 
     // Setup Ctrl+C handler
     let (tx, mut rx) = tokio::sync::mpsc::channel::<()>(1);
@@ -88,18 +1061,1013 @@ async fn main() {
         let _ = tx.send(()).await;
     });
 
-    // Run connection loop with graceful shutdown
-    tokio::select! {
-        result = connection.maintain_loop() => {
-            match result {
-                Ok(_) => log::info!("Connection loop ended normally"),
-                Err(e) => log::error!("Connection loop failed: {}", e),
+    // Run connection loops until all of them end or a shutdown is requested,
+    // exiting with the code of the last failure if none is left
+    let mut exit_code = 0;
+    loop {
+        tokio::select! {
+            joined = connections.join_next() => {
+                match joined {
+                    Some(Ok((label, Ok(_)))) => {
+                        log::info!("Connection loop for device {} ended normally", label)
+                    }
+                    Some(Ok((label, Err(e)))) => {
+                        log::error!("Connection loop for device {} failed: {:#}", label, e);
+                        exit_code = error::exit_code(&e);
+                    }
+                    Some(Err(e)) => {
+                        log::error!("Connection task panicked: {}", e);
+                        exit_code = 1;
+                    }
+                    None => break,
+                }
+            }
+            _ = rx.recv() => {
+                log::info!("Shutdown signal received");
+                exit_code = 0;
+                break;
             }
         }
-        _ = rx.recv() => {
-            log::info!("Shutdown signal received");
+    }
+
+    log::info!("=== Air780E UART Server Stopped ===");
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+}
+
+fn log_state_changes(label: String, mut state: watch::Receiver<ConnectionState>) {
+    tokio::spawn(async move {
+        let mut previous = state.borrow().clone();
+        while state.changed().await.is_ok() {
+            let current = state.borrow_and_update().clone();
+            log::info!(
+                "Device {} connection state: {} (was {})",
+                label,
+                current,
+                previous
+            );
+            previous = current;
         }
+    });
+}
+
+// Serve what needs only the database until Ctrl+C, the other instance keeps
+// talking to the devices
+async fn run_read_only(config: &Config, db: Database) {
+    log::info!("Read-only mode, the serial ports are not opened");
+    if config.metrics.enabled() {
+        tokio::spawn(
+            metrics::MetricsExporter::new(&config.metrics, db).run(watchdog::Heartbeat::default()),
+        );
+    } else {
+        log::warn!("Nothing to serve in read-only mode without [metrics]");
     }
 
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        log::error!("Failed to listen for Ctrl+C: {}", e);
+    }
     log::info!("=== Air780E UART Server Stopped ===");
 }
+
+// Timestamps in the configured timezone, a bad one is a config error
+fn time_formatter(config: &Config) -> anyhow::Result<timezone::TimeFormatter> {
+    timezone::TimeFormatter::new(config.timezone.as_deref()).map_err(error::config_error)
+}
+
+fn export_messages(
+    config: &Config,
+    db: &Database,
+    format: export::ArchiveFormat,
+    output: Option<&std::path::Path>,
+) -> anyhow::Result<()> {
+    let time = time_formatter(config)?;
+    let entries = db.history()?;
+    match output {
+        Some(path) => {
+            export::write_file(&entries, format, &time, path)?;
+            eprintln!(
+                "Exported {} message(s) to {}",
+                entries.len(),
+                path.display()
+            );
+        }
+        None => export::write(&entries, format, &time, &mut std::io::stdout().lock())?,
+    }
+    Ok(())
+}
+
+fn import_messages(
+    db: &Database,
+    file: &std::path::Path,
+    format: Option<export::ArchiveFormat>,
+) -> anyhow::Result<()> {
+    let format = match format {
+        Some(format) => format,
+        None => import::format_of(file)?,
+    };
+    let archive = import::read(file, format)?;
+    let (imported, duplicates) = db.import_history(&archive.entries)?;
+    let summary = format!(
+        "{} message(s) imported from {}, {} already stored, {} skipped",
+        imported,
+        file.display(),
+        duplicates,
+        archive.skipped
+    );
+    audit::record(db, &audit::cli_user(), "import", Some(&summary));
+    println!("{}", summary);
+    Ok(())
+}
+
+fn archive_now(config: &Config, db: &Database) -> anyhow::Result<()> {
+    if !config.archive.enabled() {
+        return Err(error::AppError::Config("Set [archive] directory first".to_string()).into());
+    }
+    let time = time_formatter(config)?;
+    let count = archive::Archiver::new(&config.archive, time, db.clone()).archive()?;
+    audit::record(db, &audit::cli_user(), "archive", Some(&count.to_string()));
+    println!("{} message(s) archived", count);
+    Ok(())
+}
+
+async fn backup_now(config: &Config, db: &Database) -> anyhow::Result<()> {
+    if !config.backup.enabled() {
+        return Err(error::AppError::Config(
+            "Set [backup.s3] or [backup.webdav] first".to_string(),
+        )
+        .into());
+    }
+    backup::Backup::new(
+        &config.backup,
+        config.archive.directory.as_deref(),
+        db.clone(),
+    )
+    .backup()
+    .await?;
+    audit::record(db, &audit::cli_user(), "backup", None);
+    println!("Backup uploaded");
+    Ok(())
+}
+
+fn erase_sender(config: &Config, db: &Database, number: &str, dry_run: bool) -> anyhow::Result<()> {
+    let matches = |other: &str| conversations::same_counterpart(number, other);
+    let data = db.correspondent_data(&matches)?;
+    let archive_dir = config
+        .archive
+        .directory
+        .as_deref()
+        .map(std::path::Path::new);
+    // Archives first, so a failure there leaves the database to retry with
+    let archived = match archive_dir {
+        Some(dir) => archive::erase(dir, &matches, dry_run)?,
+        None => 0,
+    };
+    if !dry_run {
+        db.erase_correspondent(&data)?;
+    }
+
+    let summary = format!(
+        "{} received SMS, {} sent SMS, {} notification history entries, {} webhook events, {} audit entries and {} archived messages",
+        data.sms_ids.len(),
+        data.outbound_ids.len(),
+        data.notification_hashes.len(),
+        data.webhook_ids.len(),
+        data.audit_ids.len(),
+        archived
+    );
+    if dry_run {
+        println!("Would delete {}", summary);
+    } else {
+        // The entry leaves the number out, it is what was erased
+        audit::record(db, &audit::cli_user(), "erase_sender", Some(&summary));
+        println!("Deleted {}", summary);
+    }
+    Ok(())
+}
+
+fn restore_archives(db: &Database, files: &[std::path::PathBuf]) -> anyhow::Result<()> {
+    for file in files {
+        let (restored, present) = archive::restore(db, file)?;
+        let summary = format!(
+            "{} message(s) restored from {}, {} already stored",
+            restored,
+            file.display(),
+            present
+        );
+        audit::record(db, &audit::cli_user(), "restore_archive", Some(&summary));
+        println!("{}", summary);
+    }
+    Ok(())
+}
+
+fn manage_templates(
+    config: &Config,
+    db: &Database,
+    action: &TemplateCommand,
+) -> anyhow::Result<()> {
+    match action {
+        TemplateCommand::List => {
+            let time = time_formatter(config)?;
+            let templates = db.templates()?;
+            for template in &templates {
+                println!(
+                    "{} (saved {})",
+                    template.name,
+                    time.display(template.updated_at)
+                );
+                println!("  {}", template.body.replace('\n', "\n  "));
+            }
+            println!("{} template(s)", templates.len());
+        }
+        TemplateCommand::Set { name, body, file } => {
+            if !templates::valid_name(name) {
+                anyhow::bail!(
+                    "Invalid template name {}, use letters, digits, '-', '_' and '.'",
+                    name
+                );
+            }
+            let body = match (body, file) {
+                (Some(body), _) => body.clone(),
+                (None, Some(path)) => std::fs::read_to_string(path)
+                    .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?
+                    .trim_end()
+                    .to_string(),
+                (None, None) => unreachable!("clap requires a body"),
+            };
+            if body.trim().is_empty() {
+                anyhow::bail!("Template {} is empty", name);
+            }
+            let added = db.save_template(name, &body)?;
+            audit::record(db, &audit::cli_user(), "template_set", Some(name));
+            if added {
+                println!("Template {} saved", name);
+            } else {
+                println!("Template {} replaced", name);
+            }
+        }
+        TemplateCommand::Delete { name } => {
+            if !db.delete_template(name)? {
+                anyhow::bail!("No template named {}", name);
+            }
+            audit::record(db, &audit::cli_user(), "template_delete", Some(name));
+            println!("Template {} deleted", name);
+        }
+    }
+    Ok(())
+}
+
+fn manage_opt_outs(config: &Config, db: &Database, action: &OptOutCommand) -> anyhow::Result<()> {
+    match action {
+        OptOutCommand::List => {
+            let time = time_formatter(config)?;
+            let entries = db.opt_outs()?;
+            for entry in &entries {
+                println!(
+                    "{} since {} ({})",
+                    entry.number,
+                    time.display(entry.created_at),
+                    entry.source
+                );
+            }
+            println!("{} opted-out number(s)", entries.len());
+        }
+        OptOutCommand::Add { number } => {
+            if forwarding::normalize_number(number).is_empty() {
+                anyhow::bail!("Invalid number {}", number);
+            }
+            let user = audit::cli_user();
+            if opt_out::opted_out(db, number)? {
+                println!("{} has already opted out", number);
+                return Ok(());
+            }
+            db.add_opt_out(number, &user)?;
+            audit::record(db, &user, "opt_out", Some(number));
+            println!("{} opted out, queued SMS to it are not sent", number);
+        }
+        OptOutCommand::Remove { number } => {
+            let removed = db.remove_opt_outs(|entry| forwarding::same_number(entry, number))?;
+            if removed == 0 {
+                anyhow::bail!("{} is not on the opt-out list", number);
+            }
+            audit::record(db, &audit::cli_user(), "opt_in", Some(number));
+            println!("{} may be sent SMS again", number);
+        }
+    }
+    Ok(())
+}
+
+fn manage_webhook(config: &Config, db: &Database, action: &WebhookCommand) -> anyhow::Result<()> {
+    match action {
+        WebhookCommand::Dead => {
+            let time = time_formatter(config)?;
+            let dead = db.dead_webhook_deliveries()?;
+            for delivery in &dead {
+                println!(
+                    "{} {} after {} attempt(s): {}",
+                    delivery.id,
+                    time.display(delivery.created_at),
+                    delivery.attempts,
+                    delivery.last_error.as_deref().unwrap_or("-")
+                );
+                println!("  {}", delivery.payload);
+            }
+            println!("{} dead delivery(ies)", dead.len());
+        }
+        WebhookCommand::Redeliver { ids, all } => {
+            let ids = (!*all).then_some(ids.as_slice());
+            let count = db.redeliver_webhooks(ids)?;
+            let detail = match ids {
+                Some(ids) => ids.join(", "),
+                None => "all dead".to_string(),
+            };
+            audit::record(db, &audit::cli_user(), "webhook_redeliver", Some(&detail));
+            println!("{} delivery(ies) queued again", count);
+        }
+    }
+    Ok(())
+}
+
+fn print_unacknowledged(
+    config: &Config,
+    db: &Database,
+    device: Option<&str>,
+) -> anyhow::Result<()> {
+    let time = time_formatter(config)?;
+    let profiles = match device {
+        Some(_) => vec![select_profile(config, device)?],
+        None => config.device_profiles(),
+    };
+    let mut count = 0;
+    for profile in profiles {
+        for unacked in db.get_unacknowledged(profile.id.as_deref())? {
+            let msg = &unacked.message;
+            println!(
+                "{} {} from {}{}{}: {}",
+                msg.id,
+                time.display(unacked.created_at),
+                msg.sender,
+                profile
+                    .id
+                    .as_deref()
+                    .map(|id| format!(" on {}", id))
+                    .unwrap_or_default(),
+                if unacked.notified_at.is_some() {
+                    ""
+                } else {
+                    " (not notified)"
+                },
+                msg.content
+            );
+            count += 1;
+        }
+    }
+    println!("{} unacknowledged message(s)", count);
+    Ok(())
+}
+
+fn print_schedules(config: &Config, db: &Database) -> anyhow::Result<()> {
+    let time = time_formatter(config)?;
+    let scheduler = schedule::Scheduler::new(&config.schedules, time, db.clone())?;
+    if scheduler.schedules().is_empty() {
+        println!("No schedules configured");
+    }
+    for schedule in scheduler.schedules() {
+        let last_run = db.schedule_last_run(&schedule.name)?;
+        println!(
+            "{} ({}) to {}{}: last run {}, next run {}",
+            schedule.name,
+            schedule.expression,
+            schedule.to,
+            schedule
+                .device
+                .as_ref()
+                .map(|device| format!(" via {}", device))
+                .unwrap_or_default(),
+            last_run
+                .map(|at| time.display(at))
+                .unwrap_or_else(|| "never".to_string()),
+            scheduler
+                .next_run(schedule)?
+                .map(|at| time.display(at))
+                .unwrap_or_else(|| "once the server has started".to_string())
+        );
+    }
+    Ok(())
+}
+
+fn print_audit_log(config: &Config, db: &Database, lines: usize) -> anyhow::Result<()> {
+    let time = time_formatter(config)?;
+    for entry in db.audit_entries(lines)? {
+        println!(
+            "{} {} {}{}",
+            time.display(entry.created_at),
+            entry.actor,
+            entry.action,
+            entry
+                .detail
+                .map(|detail| format!(": {}", detail))
+                .unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+fn print_conversations(
+    config: &Config,
+    db: &Database,
+    number: Option<&str>,
+    limit: usize,
+) -> anyhow::Result<()> {
+    let time = time_formatter(config)?;
+    let conversations = conversations::group(db.history()?);
+
+    let Some(number) = number else {
+        if conversations.is_empty() {
+            println!("No messages yet");
+        }
+        for conversation in conversations.iter().take(limit) {
+            let last = conversation.last();
+            println!(
+                "{}: {} message(s), last {} {} {}",
+                conversation.number,
+                conversation.entries.len(),
+                time.display(last.at),
+                if last.outbound { "sent" } else { "received" },
+                preview(&last.content)
+            );
+        }
+        return Ok(());
+    };
+
+    let conversation = conversations::find(conversations, number)
+        .ok_or_else(|| anyhow::anyhow!("No messages with {}", number))?;
+    let skip = conversation.entries.len().saturating_sub(limit);
+    for entry in &conversation.entries[skip..] {
+        println!(
+            "{} {} {}{}",
+            time.display(entry.at),
+            if entry.outbound { "->" } else { "<-" },
+            entry.content,
+            entry
+                .status
+                .as_ref()
+                .map(|status| format!(" [{}]", status))
+                .unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+// First line of a message, shortened for one-line listings
+fn preview(content: &str) -> String {
+    let line = content.lines().next().unwrap_or_default();
+    if line.chars().count() > 40 || content.lines().count() > 1 {
+        format!("\"{}...\"", line.chars().take(40).collect::<String>())
+    } else {
+        format!("\"{}\"", line)
+    }
+}
+
+fn print_status(config: &Config, db: &Database) -> anyhow::Result<()> {
+    let time = time_formatter(config)?;
+
+    println!(
+        "Messages: {} total, {} unacknowledged",
+        db.count_total()?,
+        db.count_unacknowledged()?
+    );
+    let counts = db.count_by_status()?;
+    if !counts.is_empty() {
+        let counts: Vec<_> = counts
+            .iter()
+            .map(|(status, count)| format!("{} {}", count, status))
+            .collect();
+        println!("By status: {}", counts.join(", "));
+    }
+    if let Some(since) = db.maintenance_since()? {
+        println!("Maintenance mode on since {}", time.display(since));
+    }
+    if let Some(since) = db.notifications_paused_since()? {
+        println!("Notifications paused since {}", time.display(since));
+    }
+
+    if let Some(process) = db.process_stats()? {
+        let or_dash = |value: Option<u64>| {
+            value
+                .map(|value| value.to_string())
+                .unwrap_or_else(|| "-".to_string())
+        };
+        println!(
+            "Process {}: {} MiB resident, {} open descriptor(s), {} thread(s), {} task(s), {} queued on {} worker(s) (sampled {})",
+            process.pid,
+            or_dash(process.rss_bytes.map(|bytes| bytes / (1024 * 1024))),
+            or_dash(process.open_fds),
+            or_dash(process.threads),
+            process.alive_tasks,
+            process.global_queue_depth,
+            process.workers,
+            time.display(process.updated_at)
+        );
+    }
+
+    for status in db.connection_statuses()? {
+        let stats = &status.stats;
+        println!(
+            "Connection {}: {} (updated {}), connected since {}, {} reconnect(s)",
+            status.device_id,
+            status.state,
+            time.display(status.updated_at),
+            stats
+                .connected_at
+                .map(|at| time.display(at))
+                .unwrap_or_else(|| "-".to_string()),
+            stats.reconnects
+        );
+        println!(
+            "  Session: {} frame(s), {} parse failure(s), {} ACK(s), {} byte(s) read, {} byte(s) written",
+            stats.frames_received,
+            stats.parse_failures,
+            stats.acks_sent,
+            stats.bytes_read,
+            stats.bytes_written
+        );
+        println!(
+            "  Line: {} framing error(s), {} frame(s) with invalid UTF-8, {} byte(s) dropped",
+            stats.framing_errors, stats.invalid_utf8_frames, stats.dropped_bytes
+        );
+        println!(
+            "  Queued: {} held-back notification(s), {} pending read receipt(s)",
+            stats.held_notifications, stats.pending_receipts
+        );
+        if let Some(category) = &status.error_category {
+            println!(
+                "  Last error [{}]: {}",
+                category,
+                status.error.as_deref().unwrap_or("-")
+            );
+        }
+    }
+
+    for record in db.network_statuses()? {
+        println!(
+            "Network {}: {}, operator {}, {}, rssi {} (reported {})",
+            record.device_id,
+            network::registration_name(record.status),
+            record.operator.as_deref().unwrap_or("-"),
+            record.network.as_deref().unwrap_or("-"),
+            record
+                .rssi
+                .map(|rssi| rssi.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            time.display(record.updated_at)
+        );
+        if let Some(event) = db.last_network_event(&record.device_id)? {
+            println!(
+                "  On {}{} since {}{}",
+                event.operator,
+                if event.roaming { " (roaming)" } else { "" },
+                time.display(event.changed_at),
+                event
+                    .previous_operator
+                    .map(|previous| format!(", was {}", previous))
+                    .unwrap_or_default()
+            );
+        }
+    }
+
+    let usage = usage::UsageTracker::new(&config.usage, time);
+    let cycle = usage.cycle_start(database::unix_timestamp());
+    for record in db.monthly_usage(Some(&cycle))? {
+        let of = |limit| {
+            usage
+                .limit(limit)
+                .map(|allowed| match limit {
+                    database::UsageLimit::Sms => format!(" of {}", allowed),
+                    database::UsageLimit::Data => format!(" of {} MiB", usage::mib(allowed)),
+                })
+                .unwrap_or_default()
+        };
+        println!(
+            "Usage {} since {}: {} SMS sent{}, {} MiB data{} ({} MiB sent, {} MiB received, updated {})",
+            record.device_id,
+            record.cycle_start,
+            record.sms_sent,
+            of(database::UsageLimit::Sms),
+            usage::mib(record.data_bytes()),
+            of(database::UsageLimit::Data),
+            usage::mib(record.data_tx_bytes),
+            usage::mib(record.data_rx_bytes),
+            time.display(record.updated_at)
+        );
+    }
+
+    let pricing = pricing::Pricing::new(config)?;
+    if pricing.enabled() {
+        // Enough to reach back to the start of the current cycle
+        let sent = db.sent_outbound(database::unix_timestamp() - 32 * 86400)?;
+        let mut devices: Vec<(String, u64, u64, f64)> = Vec::new();
+        for line in pricing.estimate(&sent, &usage) {
+            if line.cycle_start != cycle {
+                continue;
+            }
+            match devices
+                .iter_mut()
+                .find(|(device, ..)| *device == line.device)
+            {
+                Some((_, messages, parts, cost)) => {
+                    *messages += line.messages;
+                    *parts += line.parts;
+                    *cost += line.cost;
+                }
+                None => devices.push((line.device, line.messages, line.parts, line.cost)),
+            }
+        }
+        for (device, messages, parts, cost) in devices {
+            println!(
+                "Estimated cost {} since {}: {} for {} SMS ({} part(s))",
+                device,
+                cycle,
+                pricing.amount(cost),
+                messages,
+                parts
+            );
+        }
+    }
+
+    for batch in db.batch_progress(None)? {
+        println!(
+            "Outbound batch {}: {}/{} sent, {} failed, {} pending",
+            batch.batch_id, batch.sent, batch.total, batch.failed, batch.pending
+        );
+    }
+
+    let devices = db.devices()?;
+    if devices.is_empty() {
+        println!("No device has reported its info yet");
+    }
+    for device in devices {
+        println!(
+            "Device {}: IMEI {}, number {}, ICCID {}, firmware {}, script {} (reported {})",
+            device.device_id,
+            device.imei,
+            device.number,
+            device.iccid,
+            device.firmware.as_deref().unwrap_or("unknown"),
+            device.script_version.as_deref().unwrap_or("unknown"),
+            time.display(device.updated_at)
+        );
+    }
+
+    Ok(())
+}
+
+fn print_costs(config: &Config, db: &Database, cycles: usize) -> anyhow::Result<()> {
+    let pricing = pricing::Pricing::new(config)?;
+    if !pricing.enabled() {
+        println!("No [pricing] configured, nothing to estimate");
+        return Ok(());
+    }
+    let time = time_formatter(config)?;
+    let usage = usage::UsageTracker::new(&config.usage, time);
+
+    let lines = pricing.estimate(&db.sent_outbound(0)?, &usage);
+    let mut starts: Vec<&str> = lines.iter().map(|line| line.cycle_start.as_str()).collect();
+    starts.dedup();
+    let shown = &starts[starts.len().saturating_sub(cycles)..];
+    if shown.is_empty() {
+        println!("No SMS sent yet");
+    }
+    for start in shown {
+        let cycle: Vec<_> = lines
+            .iter()
+            .filter(|line| line.cycle_start == *start)
+            .collect();
+        println!(
+            "Cycle since {}: {} for {} SMS",
+            start,
+            pricing.amount(cycle.iter().map(|line| line.cost).sum()),
+            cycle.iter().map(|line| line.messages).sum::<u64>()
+        );
+        for line in cycle {
+            println!(
+                "  {} to {}: {} for {} SMS ({} part(s))",
+                line.device,
+                line.prefix.as_deref().unwrap_or("other"),
+                pricing.amount(line.cost),
+                line.messages,
+                line.parts
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn send_bulk(
+    config: &Config,
+    db: &Database,
+    recipients: &std::path::Path,
+    template: &str,
+    device: Option<&str>,
+    wait: bool,
+    urgent: bool,
+) -> anyhow::Result<()> {
+    if let Some(id) = device {
+        select_profile(config, Some(id))?;
+    }
+
+    let mut recipients = bulk::load_recipients(recipients)?;
+    let mut opted_out = Vec::new();
+    for recipient in &recipients {
+        if opt_out::opted_out(db, &recipient.number)? {
+            opted_out.push(recipient.number.clone());
+        }
+    }
+    if !opted_out.is_empty() {
+        println!(
+            "Skipping {} recipient(s) who opted out: {}",
+            opted_out.len(),
+            opted_out.join(", ")
+        );
+        recipients.retain(|recipient| !opted_out.contains(&recipient.number));
+    }
+    if recipients.is_empty() {
+        anyhow::bail!("No recipients to send to");
+    }
+    // Render everything before queueing, so a bad row queues nothing
+    let messages = recipients
+        .iter()
+        .map(|recipient| {
+            bulk::render(template, recipient)
+                .map(|content| (recipient.number.clone(), content))
+                .map_err(|e| anyhow::anyhow!("{}: {}", recipient.number, e))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let batch_id = chrono::Local::now()
+        .format("bulk-%Y%m%d-%H%M%S")
+        .to_string();
+    db.queue_outbound(Some(&batch_id), device, urgent, &messages)?;
+    let user = audit::cli_user();
+    for (number, _) in &messages {
+        let detail = format!("to {} (batch {})", number, batch_id);
+        audit::record(db, &user, "send_sms", Some(&detail));
+    }
+    println!(
+        "Queued {} message(s) as batch {}, the running server sends them one by one",
+        messages.len(),
+        batch_id
+    );
+    if !wait {
+        return Ok(());
+    }
+
+    let mut last_reported = None;
+    loop {
+        let Some(progress) = db.batch_progress(Some(&batch_id))?.pop() else {
+            anyhow::bail!("Batch {} disappeared from the database", batch_id);
+        };
+        let report = format!(
+            "{}/{} sent, {} failed, {} pending",
+            progress.sent, progress.total, progress.failed, progress.pending
+        );
+        if last_reported.as_ref() != Some(&report) {
+            println!("{}", report);
+            last_reported = Some(report);
+        }
+        if progress.is_finished() {
+            return Ok(());
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    }
+}
+
+async fn self_test(
+    config: &Config,
+    db: &Database,
+    device: Option<&str>,
+    to: Option<&str>,
+    timeout: Option<u64>,
+) -> anyhow::Result<self_test::RoundTrip> {
+    let profile = select_profile(config, device)?;
+    let label = profile.id.as_deref().unwrap_or("default");
+    let number = match to.or(config.self_test.number.as_deref()) {
+        Some(number) => number.to_string(),
+        None => db
+            .devices()?
+            .into_iter()
+            .find(|record| record.device_id == label && !record.number.is_empty())
+            .map(|record| record.number)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Device {} has not reported its own number, set [self_test] number or pass --to",
+                    label
+                )
+            })?,
+    };
+    let timeout = timeout.unwrap_or(config.self_test.timeout_secs);
+
+    println!(
+        "Sending a test SMS from {} to {}, waiting up to {}s",
+        label, number, timeout
+    );
+    self_test::run(
+        db,
+        profile.id.as_deref(),
+        &number,
+        std::time::Duration::from_secs(timeout),
+    )
+    .await
+}
+
+// Queue the command for the running server and wait until every device picked it up
+async fn request_admin_command(
+    config: &Config,
+    db: &Database,
+    device: Option<&str>,
+    command: AdminCommand,
+) -> anyhow::Result<()> {
+    let profiles = match device {
+        Some(_) => vec![select_profile(config, device)?],
+        None => config.device_profiles(),
+    };
+    let mut pending = Vec::new();
+    for profile in profiles {
+        let label = profile.id.unwrap_or_else(|| "default".to_string());
+        let id = db.queue_admin_command(&label, command.as_str())?;
+        audit::record(db, &audit::cli_user(), command.as_str(), Some(&label));
+        pending.push((id, label));
+    }
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while !pending.is_empty() && std::time::Instant::now() < deadline {
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        let mut waiting = Vec::new();
+        for (id, label) in pending {
+            if db.admin_command_handled(id)? {
+                println!("Device {}: {} started", label, command.as_str());
+            } else {
+                waiting.push((id, label));
+            }
+        }
+        pending = waiting;
+    }
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    let ids = pending.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+    db.discard_admin_commands(Some(&ids))?;
+    anyhow::bail!(
+        "No running server picked up the request for {}, is it connected?",
+        pending
+            .iter()
+            .map(|(_, label)| label.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+async fn manage_maintenance(
+    config: &Config,
+    db: &Database,
+    action: &MaintenanceCommand,
+) -> anyhow::Result<()> {
+    match action {
+        MaintenanceCommand::Start => {
+            if !db.start_maintenance()? {
+                println!("Maintenance is already on");
+                return Ok(());
+            }
+            audit::record(db, &audit::cli_user(), "maintenance_start", None);
+            let devices = config
+                .device_profiles()
+                .into_iter()
+                .map(|profile| profile.id.unwrap_or_else(|| "default".to_string()))
+                .collect::<Vec<_>>();
+            let busy = maintenance::wait_released(db, &devices).await?;
+            if busy.is_empty() {
+                println!("Maintenance started, the serial ports are released");
+            } else {
+                println!(
+                    "Maintenance started, no running server released the port of {} yet",
+                    busy.join(", ")
+                );
+            }
+        }
+        MaintenanceCommand::End => match db.end_maintenance()? {
+            Some(_) => {
+                audit::record(db, &audit::cli_user(), "maintenance_end", None);
+                println!("Maintenance ended, the running server reconnects")
+            }
+            None => println!("Maintenance is not on"),
+        },
+    }
+    Ok(())
+}
+
+// Device profile picked by --device for the one-off subcommands
+fn select_profile(config: &Config, device: Option<&str>) -> anyhow::Result<config::DeviceProfile> {
+    let mut profiles = config.device_profiles();
+    match device {
+        Some(id) => profiles
+            .into_iter()
+            .find(|profile| profile.id.as_deref() == Some(id))
+            .ok_or_else(|| anyhow::anyhow!("No device with id '{}' in the configuration", id)),
+        None if profiles.len() == 1 => Ok(profiles.remove(0)),
+        None => anyhow::bail!("Several devices are configured, pick one with --device"),
+    }
+}
+
+async fn flash_script(
+    config: &Config,
+    device: Option<&str>,
+    port: Option<&str>,
+    file: &std::path::Path,
+) -> anyhow::Result<()> {
+    let profile = select_profile(config, device)?;
+    script_update::flash(&profile.serial, port, file).await?;
+    println!("{} flashed", file.display());
+    Ok(())
+}
+
+async fn manage_stored(
+    config: &Config,
+    action: &StoredCommand,
+    device: Option<&str>,
+    port: Option<&str>,
+) -> anyhow::Result<()> {
+    let profile = select_profile(config, device)?;
+    match action {
+        StoredCommand::List => {
+            let time = time_formatter(config)?;
+            let stored = stored_sms::list(&profile.serial, port).await?;
+            for sms in &stored.messages {
+                let state = if sms.acked {
+                    "awaiting read receipt".to_string()
+                } else {
+                    format!("{} retries", sms.retry_count)
+                };
+                println!(
+                    "{} {} from {}, {}: {}",
+                    sms.id,
+                    time.display(sms.received_at),
+                    sms.sender,
+                    state,
+                    preview(&sms.content)
+                );
+            }
+            println!(
+                "{}/{} SMS stored on the device",
+                stored.messages.len(),
+                stored.capacity
+            );
+            if let (Some(used), Some(total)) = (stored.fskv_used, stored.fskv_total) {
+                println!("Flash storage: {}/{} bytes used", used, total);
+            }
+        }
+        StoredCommand::Delete { ids, all } => {
+            let command = serial_port::DeleteStoredCommand {
+                ids: ids.clone(),
+                all: *all,
+                rid: None,
+            };
+            let result = stored_sms::delete(&profile.serial, port, &command).await?;
+            let detail = format!(
+                "{} on device {}",
+                if *all {
+                    "all".to_string()
+                } else {
+                    ids.join(", ")
+                },
+                profile.id.as_deref().unwrap_or("default")
+            );
+            let db = Database::new(&config.database.path)?;
+            audit::record(&db, &audit::cli_user(), "delete_stored", Some(&detail));
+            println!(
+                "Deleted {} SMS, {} left on the device",
+                result.deleted, result.remaining
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn generate_udev_rule(
+    config: &Config,
+    device: Option<&str>,
+    port: Option<&str>,
+    name: Option<&str>,
+) -> anyhow::Result<String> {
+    let profile = select_profile(config, device)?;
+    // The handshake picks the interface the script answers on among the module's ports
+    let (port_name, _) = port_access::find_device(&profile.serial, port).await?;
+
+    let symlink = match (name, &profile.id) {
+        (Some(name), _) => name.to_string(),
+        (None, Some(id)) => format!("{}-{}", udev::SYMLINK_PREFIX, id),
+        (None, None) => udev::SYMLINK_PREFIX.to_string(),
+    };
+    udev::rule(&port_name, &symlink)
+}
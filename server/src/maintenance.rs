@@ -0,0 +1,62 @@
+use crate::database::Database;
+use anyhow::Result;
+
+// Returns whether maintenance mode is on after the toggle
+pub fn toggle(db: &Database) -> Result<bool> {
+    if db.end_maintenance()?.is_some() {
+        return Ok(false);
+    }
+    db.start_maintenance()?;
+    Ok(true)
+}
+
+// SIGUSR2 toggles maintenance mode, e.g. from a flashing script
+#[cfg(unix)]
+pub async fn handle_signals(db: Database) {
+    use crate::audit;
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut usr2 = match signal(SignalKind::user_defined2()) {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::warn!("Failed to listen for SIGUSR2: {}", e);
+            return;
+        }
+    };
+
+    while usr2.recv().await.is_some() {
+        match toggle(&db) {
+            Ok(true) => {
+                log::warn!("SIGUSR2 received, starting maintenance");
+                audit::record(&db, "SIGUSR2", "maintenance_start", None);
+            }
+            Ok(false) => {
+                log::warn!("SIGUSR2 received, ending maintenance");
+                audit::record(&db, "SIGUSR2", "maintenance_end", None);
+            }
+            Err(e) => log::error!("SIGUSR2 received, failed to toggle maintenance: {:#}", e),
+        }
+    }
+}
+
+// Wait until the running server reports every device in maintenance, the
+// serial ports are free then
+pub async fn wait_released(db: &Database, devices: &[String]) -> Result<Vec<String>> {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    loop {
+        let statuses = db.connection_statuses()?;
+        let busy = devices
+            .iter()
+            .filter(|device| {
+                statuses
+                    .iter()
+                    .any(|status| &status.device_id == *device && status.state != "maintenance")
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if busy.is_empty() || std::time::Instant::now() >= deadline {
+            return Ok(busy);
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    }
+}
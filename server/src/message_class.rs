@@ -0,0 +1,42 @@
+use serde_json::Value;
+
+// Class 0 ("flash") SMS are shown on the screen at once instead of being stored,
+// carriers use them for urgent alerts
+pub const FLASH: u8 = 0;
+
+// Message class from the metas the module reports with an SMS. Firmwares either
+// report the class itself or only the data coding scheme (3GPP TS 23.038)
+pub fn from_metas(metas: &Value) -> Option<u8> {
+    if metas.get("flash").and_then(Value::as_bool) == Some(true) {
+        return Some(FLASH);
+    }
+    let class = ["class", "mclass"]
+        .iter()
+        .find_map(|key| metas.get(key).and_then(Value::as_u64));
+    if let Some(class) = class {
+        return u8::try_from(class).ok().filter(|class| *class <= 3);
+    }
+    let dcs = metas.get("dcs").and_then(Value::as_u64)?;
+    from_dcs(u8::try_from(dcs).ok()?)
+}
+
+fn from_dcs(dcs: u8) -> Option<u8> {
+    let has_class = match dcs >> 4 {
+        // General data coding, bit 4 tells whether bits 1..0 hold a class
+        0x0..=0x7 => dcs & 0x10 != 0,
+        // Data coding / message class group
+        0xf => true,
+        _ => false,
+    };
+    has_class.then_some(dcs & 0x03)
+}
+
+pub fn name(class: u8) -> &'static str {
+    match class {
+        0 => "flash",
+        1 => "class 1",
+        2 => "class 2 (SIM)",
+        3 => "class 3",
+        _ => "unknown",
+    }
+}
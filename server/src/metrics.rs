@@ -0,0 +1,537 @@
+use crate::config::MetricsConfig;
+use crate::database::Database;
+use crate::network;
+use crate::watchdog::Heartbeat;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::time::Duration;
+
+// Buckets of the SMS latency histograms, in seconds. OTP codes are typically
+// valid for a couple of minutes
+const LATENCY_BUCKETS: &[f64] = &[
+    0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+// Writes the metrics to a node_exporter textfile and/or pushes them to a
+// Pushgateway, for deployments that don't want a /metrics port
+#[derive(Clone)]
+pub struct MetricsExporter {
+    db: Database,
+    textfile_path: Option<String>,
+    pushgateway_url: Option<String>,
+    job: String,
+    interval: Duration,
+    client: reqwest::Client,
+}
+
+impl MetricsExporter {
+    pub fn new(config: &MetricsConfig, db: Database) -> Self {
+        MetricsExporter {
+            db,
+            textfile_path: config.textfile_path.clone(),
+            pushgateway_url: config.pushgateway_url.clone(),
+            job: config.job.clone(),
+            interval: Duration::from_secs(config.interval_secs),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn run(self, heartbeat: Heartbeat) {
+        log::info!(
+            "Metrics export started (every {}s)",
+            self.interval.as_secs()
+        );
+
+        loop {
+            if let Err(e) = self.export().await {
+                log::warn!("Failed to export metrics: {:#}", e);
+            }
+            heartbeat.beat(self.interval);
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+
+    async fn export(&self) -> Result<()> {
+        let text = render(&self.db)?;
+        if let Some(path) = &self.textfile_path {
+            write_textfile(Path::new(path), &text)?;
+        }
+        if let Some(url) = &self.pushgateway_url {
+            self.push(url, text).await?;
+        }
+        Ok(())
+    }
+
+    // PUT replaces every metric of the job, so series of removed devices disappear
+    async fn push(&self, url: &str, text: String) -> Result<()> {
+        let url = format!(
+            "{}/metrics/job/{}",
+            url.trim_end_matches('/'),
+            urlencoding::encode(&self.job)
+        );
+        let response = self
+            .client
+            .put(&url)
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(text)
+            .send()
+            .await
+            .context(format!("Failed to push metrics to {}", url))?;
+        if !response.status().is_success() {
+            anyhow::bail!("Pushgateway returned status {}", response.status());
+        }
+        Ok(())
+    }
+}
+
+// node_exporter may read the file at any time, so it is replaced in one rename
+fn write_textfile(path: &Path, text: &str) -> Result<()> {
+    let tmp = path.with_extension("prom.tmp");
+    std::fs::write(&tmp, text).context(format!("Failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).context(format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+// Samples of one metric, which the text format requires to be contiguous
+struct Family {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    samples: Vec<String>,
+}
+
+#[derive(Default)]
+struct Exposition {
+    families: Vec<Family>,
+}
+
+impl Exposition {
+    fn add(
+        &mut self,
+        name: &'static str,
+        kind: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        value: impl std::fmt::Display,
+    ) {
+        self.push(name, kind, help, sample(name, labels, value));
+    }
+
+    // Cumulative buckets, sum and count of the observed values
+    fn add_histogram(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        buckets: &[f64],
+        values: &[f64],
+    ) {
+        for le in buckets {
+            let count = values.iter().filter(|value| *value <= le).count();
+            let le = le.to_string();
+            let mut labels = labels.to_vec();
+            labels.push(("le", &le));
+            self.push(
+                name,
+                "histogram",
+                help,
+                sample(&format!("{}_bucket", name), &labels, count),
+            );
+        }
+        let mut labels = labels.to_vec();
+        labels.push(("le", "+Inf"));
+        self.push(
+            name,
+            "histogram",
+            help,
+            sample(&format!("{}_bucket", name), &labels, values.len()),
+        );
+        let labels = &labels[..labels.len() - 1];
+        let sum: f64 = values.iter().sum();
+        self.push(
+            name,
+            "histogram",
+            help,
+            sample(&format!("{}_sum", name), labels, sum),
+        );
+        self.push(
+            name,
+            "histogram",
+            help,
+            sample(&format!("{}_count", name), labels, values.len()),
+        );
+    }
+
+    fn push(&mut self, name: &'static str, kind: &'static str, help: &'static str, sample: String) {
+        match self.families.iter_mut().find(|family| family.name == name) {
+            Some(family) => family.samples.push(sample),
+            None => self.families.push(Family {
+                name,
+                kind,
+                help,
+                samples: vec![sample],
+            }),
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut text = String::new();
+        for family in &self.families {
+            let _ = writeln!(text, "# HELP {} {}", family.name, family.help);
+            let _ = writeln!(text, "# TYPE {} {}", family.name, family.kind);
+            for sample in &family.samples {
+                let _ = writeln!(text, "{}", sample);
+            }
+        }
+        text
+    }
+}
+
+fn sample(name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) -> String {
+    let labels = labels
+        .iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, escape(value)))
+        .collect::<Vec<_>>();
+    if labels.is_empty() {
+        format!("{} {}", name, value)
+    } else {
+        format!("{}{{{}}} {}", name, labels.join(","), value)
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Metrics read from the database, where every connection keeps its state and counters
+pub fn render(db: &Database) -> Result<String> {
+    let mut metrics = Exposition::default();
+
+    metrics.add(
+        "air780e_messages_stored",
+        "gauge",
+        "SMS stored in the database",
+        &[],
+        db.count_total()?,
+    );
+    metrics.add(
+        "air780e_messages_unacknowledged",
+        "gauge",
+        "Stored SMS whose ACK was not sent",
+        &[],
+        db.count_unacknowledged()?,
+    );
+    metrics.add(
+        "air780e_messages_encoding_suspect",
+        "gauge",
+        "Stored SMS whose payload was not valid UTF-8 and kept its original bytes",
+        &[],
+        db.count_encoding_suspect()?,
+    );
+    for (status, count) in db.count_by_status()? {
+        metrics.add(
+            "air780e_messages_by_status",
+            "gauge",
+            "Stored SMS by the step they reached: stored, notified, held or acked",
+            &[("status", status.as_str())],
+            count,
+        );
+    }
+
+    let file = db.file_stats()?;
+    metrics.add(
+        "air780e_database_size_bytes",
+        "gauge",
+        "Size of the SQLite database file",
+        &[],
+        file.size_bytes(),
+    );
+    metrics.add(
+        "air780e_database_free_bytes",
+        "gauge",
+        "Free space inside the database file, reclaimed by VACUUM",
+        &[],
+        file.free_bytes(),
+    );
+    if let Some(at) = db.last_vacuum()? {
+        metrics.add(
+            "air780e_database_last_vacuum_timestamp_seconds",
+            "gauge",
+            "When the database was last compacted",
+            &[],
+            at,
+        );
+    }
+
+    if let Some(at) = db.last_backup()? {
+        metrics.add(
+            "air780e_backup_last_success_timestamp_seconds",
+            "gauge",
+            "When the last offsite backup was uploaded",
+            &[],
+            at,
+        );
+    }
+
+    if let Some(process) = db.process_stats()? {
+        if let Some(bytes) = process.rss_bytes {
+            metrics.add(
+                "air780e_process_resident_memory_bytes",
+                "gauge",
+                "Resident memory of the server process",
+                &[],
+                bytes,
+            );
+        }
+        if let Some(fds) = process.open_fds {
+            metrics.add(
+                "air780e_process_open_fds",
+                "gauge",
+                "Open file descriptors of the server process",
+                &[],
+                fds,
+            );
+        }
+        if let Some(threads) = process.threads {
+            metrics.add(
+                "air780e_process_threads",
+                "gauge",
+                "Threads of the server process",
+                &[],
+                threads,
+            );
+        }
+        metrics.add(
+            "air780e_runtime_workers",
+            "gauge",
+            "Worker threads of the tokio runtime",
+            &[],
+            process.workers,
+        );
+        metrics.add(
+            "air780e_runtime_alive_tasks",
+            "gauge",
+            "Tokio tasks that have not finished",
+            &[],
+            process.alive_tasks,
+        );
+        metrics.add(
+            "air780e_runtime_global_queue_depth",
+            "gauge",
+            "Tasks waiting in the tokio runtime's global queue",
+            &[],
+            process.global_queue_depth,
+        );
+        metrics.add(
+            "air780e_process_updated_timestamp_seconds",
+            "gauge",
+            "When the process stats were last sampled",
+            &[],
+            process.updated_at,
+        );
+    }
+
+    for status in db.connection_statuses()? {
+        let device = [("device", status.device_id.as_str())];
+        let stats = &status.stats;
+        metrics.add(
+            "air780e_connected",
+            "gauge",
+            "Whether the serial connection is up",
+            &device,
+            u8::from(status.state == "connected"),
+        );
+        metrics.add(
+            "air780e_reconnects_total",
+            "counter",
+            "Reconnects since the server started",
+            &device,
+            stats.reconnects,
+        );
+        metrics.add(
+            "air780e_reconnect_attempts",
+            "gauge",
+            "Failed attempts of the reconnect in progress",
+            &device,
+            stats.reconnect_attempts,
+        );
+        metrics.add(
+            "air780e_session_frames_received",
+            "gauge",
+            "Frames received in the current session",
+            &device,
+            stats.frames_received,
+        );
+        metrics.add(
+            "air780e_session_parse_failures",
+            "gauge",
+            "Frames that failed to parse in the current session",
+            &device,
+            stats.parse_failures,
+        );
+        metrics.add(
+            "air780e_session_acks_sent",
+            "gauge",
+            "ACKs sent in the current session",
+            &device,
+            stats.acks_sent,
+        );
+        metrics.add(
+            "air780e_session_bytes_read",
+            "gauge",
+            "Bytes read in the current session",
+            &device,
+            stats.bytes_read,
+        );
+        metrics.add(
+            "air780e_session_bytes_written",
+            "gauge",
+            "Bytes written in the current session",
+            &device,
+            stats.bytes_written,
+        );
+        metrics.add(
+            "air780e_session_framing_errors",
+            "gauge",
+            "Frames in the current session whose id:TYPE:payload shape could not be read",
+            &device,
+            stats.framing_errors,
+        );
+        metrics.add(
+            "air780e_session_invalid_utf8_frames",
+            "gauge",
+            "Frames in the current session holding invalid UTF-8",
+            &device,
+            stats.invalid_utf8_frames,
+        );
+        metrics.add(
+            "air780e_session_dropped_bytes",
+            "gauge",
+            "Bytes of frames dropped in the current session: oversized, throttled or unparseable",
+            &device,
+            stats.dropped_bytes,
+        );
+        metrics.add(
+            "air780e_held_notifications",
+            "gauge",
+            "Notifications held back behind a failed one of the same sender",
+            &device,
+            stats.held_notifications,
+        );
+        metrics.add(
+            "air780e_pending_read_receipts",
+            "gauge",
+            "Read receipts waiting to be sent to the device",
+            &device,
+            stats.pending_receipts,
+        );
+        metrics.add(
+            "air780e_status_updated_timestamp_seconds",
+            "gauge",
+            "When the connection status was last saved",
+            &device,
+            status.updated_at,
+        );
+    }
+
+    for record in db.network_statuses()? {
+        let device = [("device", record.device_id.as_str())];
+        metrics.add(
+            "air780e_network_registered",
+            "gauge",
+            "Whether the module is registered to a mobile network",
+            &device,
+            u8::from(network::is_registered(record.status)),
+        );
+        metrics.add(
+            "air780e_network_roaming",
+            "gauge",
+            "Whether the module is roaming",
+            &device,
+            u8::from(network::is_roaming(record.status)),
+        );
+        if let Some(rssi) = record.rssi {
+            metrics.add(
+                "air780e_network_rssi",
+                "gauge",
+                "Signal strength reported by the module",
+                &device,
+                rssi,
+            );
+        }
+    }
+
+    // The latest cycle of each device, the counts start over with every cycle
+    for record in db.monthly_usage(None)? {
+        let device = record.device_id.as_str();
+        let cycle = record.cycle_start.as_str();
+        metrics.add(
+            "air780e_usage_sms_sent",
+            "gauge",
+            "SMS sent in the current billing cycle",
+            &[("device", device), ("cycle", cycle)],
+            record.sms_sent,
+        );
+        for (direction, bytes) in [("tx", record.data_tx_bytes), ("rx", record.data_rx_bytes)] {
+            metrics.add(
+                "air780e_usage_data_bytes",
+                "gauge",
+                "Mobile data used in the current billing cycle",
+                &[
+                    ("device", device),
+                    ("cycle", cycle),
+                    ("direction", direction),
+                ],
+                bytes,
+            );
+        }
+    }
+
+    for (status, count) in db.outbound_counts()? {
+        metrics.add(
+            "air780e_outbound_sms",
+            "gauge",
+            "Queued outbound SMS by status",
+            &[("status", status.as_str())],
+            count,
+        );
+    }
+
+    // Seconds spent in each stage from the device receiving an SMS to the ACK.
+    // The first stages start from the device clock, which may be off
+    let mut latencies: BTreeMap<(String, &str), Vec<f64>> = BTreeMap::new();
+    for timing in db.sms_timings()? {
+        let received_ms = timing.received_at * 1000;
+        let mut stages = vec![
+            ("device_to_read", timing.read_at_ms - received_ms),
+            ("read_to_stored", timing.stored_at_ms - timing.read_at_ms),
+        ];
+        if let Some(notified_at_ms) = timing.notified_at_ms {
+            stages.push(("stored_to_notified", notified_at_ms - timing.stored_at_ms));
+            stages.push(("notified_to_ack", timing.ack_sent_at_ms - notified_at_ms));
+            stages.push(("device_to_notified", notified_at_ms - received_ms));
+        }
+        for (stage, millis) in stages {
+            latencies
+                .entry((timing.device_id.clone(), stage))
+                .or_default()
+                .push(millis.max(0) as f64 / 1000.0);
+        }
+    }
+    for ((device, stage), values) in &latencies {
+        metrics.add_histogram(
+            "air780e_sms_latency_seconds",
+            "Time received SMS spent in each stage until the ACK",
+            &[("device", device.as_str()), ("stage", stage)],
+            LATENCY_BUCKETS,
+            values,
+        );
+    }
+
+    Ok(metrics.render())
+}
@@ -0,0 +1,344 @@
+use crate::audit;
+use crate::config::{Config, MqttConfig};
+use crate::connection::AdminCommand;
+use crate::connection_state::ConnectionState;
+use crate::database::{Database, OUTBOUND_FAILED, OUTBOUND_SENT};
+use crate::forwarding;
+use crate::templates;
+use anyhow::{Context, Result};
+use rumqttc::{
+    AsyncClient, Event, LastWill, MqttOptions, Packet, Publish, QoS, TlsConfiguration, Transport,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// How long a reply waits for the connection loop to pick up a reconnect
+const ADMIN_COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+// A queued SMS still unsent after this is reported as such
+const SEND_RESULT_TIMEOUT: Duration = Duration::from_secs(600);
+const SEND_POLL_INTERVAL: Duration = Duration::from_secs(2);
+// Audit log actor of commands taken from the broker
+const ACTOR: &str = "mqtt";
+
+// Takes commands from `{prefix}/{device}/cmd/{command}` and publishes what came of
+// them on `{prefix}/{device}/reply/{command}`. Payloads are JSON objects, an `id`
+// in the command is echoed in its replies. The connection state of each device is
+// kept retained on `{prefix}/{device}/state`, and `{prefix}/status` says whether
+// the server itself is online
+#[derive(Clone)]
+pub struct MqttBridge {
+    config: MqttConfig,
+    // Device labels as used by the CLI, "default" without [[devices]]
+    devices: Vec<String>,
+    // Without [[devices]] the one device sends every SMS not bound to a device
+    lone_device: bool,
+    // Current state name of each device, fed by the connections' state machines
+    states: Arc<watch::Sender<BTreeMap<String, &'static str>>>,
+    db: Database,
+}
+
+#[derive(Deserialize, Default)]
+struct CommandPayload {
+    id: Option<serde_json::Value>,
+    to: Option<String>,
+    content: Option<String>,
+    // Name of a saved template, sent instead of `content`
+    template: Option<String>,
+    #[serde(default)]
+    variables: HashMap<String, String>,
+    // Send even during quiet hours
+    #[serde(default)]
+    urgent: bool,
+}
+
+impl MqttBridge {
+    pub fn new(config: &Config, db: Database) -> Self {
+        let devices = config
+            .device_profiles()
+            .into_iter()
+            .map(|profile| profile.id.unwrap_or_else(|| "default".to_string()))
+            .filter(|label| {
+                let usable = !label.contains(['/', '+', '#']);
+                if !usable {
+                    log::warn!("Device {} cannot be used in MQTT topics", label);
+                }
+                usable
+            })
+            .collect();
+        MqttBridge {
+            config: config.mqtt.clone(),
+            devices,
+            lone_device: config.devices.is_empty(),
+            states: Arc::new(watch::Sender::new(BTreeMap::new())),
+            db,
+        }
+    }
+
+    // Follows the state machine of a device's connection. A connection restarted
+    // by the watchdog is followed anew
+    pub fn follow_state(&self, device: &str, mut state: watch::Receiver<ConnectionState>) {
+        if !self.devices.iter().any(|label| label == device) {
+            return;
+        }
+        let states = self.states.clone();
+        let device = device.to_string();
+        tokio::spawn(async move {
+            loop {
+                let name = state.borrow_and_update().name();
+                states.send_if_modified(|states| states.insert(device.clone(), name) != Some(name));
+                if state.changed().await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    pub async fn run(self) {
+        let host = self.config.host.clone().unwrap_or_default();
+        let mut options = MqttOptions::new(&self.config.client_id, &host, self.config.port);
+        options.set_keep_alive(KEEP_ALIVE);
+        if let Some(username) = &self.config.username {
+            options.set_credentials(username, &self.config.password);
+        }
+        if self.config.tls {
+            let tls = match &self.config.ca_file {
+                Some(path) => match std::fs::read(path) {
+                    Ok(ca) => TlsConfiguration::SimpleNative {
+                        ca,
+                        client_auth: None,
+                    },
+                    Err(e) => {
+                        log::error!("Failed to read MQTT CA file {}: {}", path, e);
+                        return;
+                    }
+                },
+                None => TlsConfiguration::Native,
+            };
+            options.set_transport(Transport::tls_with_config(tls));
+        }
+        // The broker tells subscribers once the server is gone without saying so
+        let status_topic = format!("{}/status", self.config.topic_prefix);
+        options.set_last_will(LastWill::new(
+            &status_topic,
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        let (client, mut events) = AsyncClient::new(options, 32);
+
+        let mut states = self.states.subscribe();
+        // What the broker retains, None while disconnected
+        let mut published: Option<BTreeMap<String, &'static str>> = None;
+        loop {
+            tokio::select! {
+                event = events.poll() => match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        log::info!("Connected to MQTT broker {}:{}", host, self.config.port);
+                        try_publish(&client, &status_topic, "online");
+                        let mut retained = BTreeMap::new();
+                        self.publish_states(&client, &mut retained);
+                        published = Some(retained);
+                        // The session is not kept, so subscribe again after every reconnect
+                        let bridge = self.clone();
+                        let client = client.clone();
+                        tokio::spawn(async move { bridge.subscribe(&client).await });
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        let bridge = self.clone();
+                        let client = client.clone();
+                        tokio::spawn(async move { bridge.handle(&client, publish).await });
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::warn!("MQTT broker {}:{}: {}", host, self.config.port, e);
+                        published = None;
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                    }
+                },
+                Ok(()) = states.changed() => {
+                    if let Some(retained) = &mut published {
+                        self.publish_states(&client, retained);
+                    }
+                }
+            }
+        }
+    }
+
+    // Publishes the states that differ from what the broker retains
+    fn publish_states(&self, client: &AsyncClient, retained: &mut BTreeMap<String, &'static str>) {
+        let states = self.states.borrow().clone();
+        for (device, state) in states {
+            if retained.get(&device) == Some(&state) {
+                continue;
+            }
+            let topic = format!("{}/{}/state", self.config.topic_prefix, device);
+            if try_publish(client, &topic, state) {
+                retained.insert(device, state);
+            }
+        }
+    }
+
+    async fn subscribe(&self, client: &AsyncClient) {
+        for device in &self.devices {
+            let topic = format!("{}/{}/cmd/#", self.config.topic_prefix, device);
+            if let Err(e) = client.subscribe(&topic, QoS::AtLeastOnce).await {
+                log::warn!("Failed to subscribe to {}: {}", topic, e);
+            }
+        }
+    }
+
+    async fn handle(&self, client: &AsyncClient, publish: Publish) {
+        let Some((device, command)) = self.parse_topic(&publish.topic) else {
+            log::debug!("Ignored MQTT message on {}", publish.topic);
+            return;
+        };
+        let reply_topic = format!("{}/{}/reply/{}", self.config.topic_prefix, device, command);
+        let payload = if publish.payload.iter().all(u8::is_ascii_whitespace) {
+            Ok(CommandPayload::default())
+        } else {
+            serde_json::from_slice::<CommandPayload>(&publish.payload)
+        };
+        let payload = match payload {
+            Ok(payload) => payload,
+            Err(e) => {
+                let reply = json!({"status": "error", "error": format!("Invalid JSON: {}", e)});
+                publish_reply(client, &reply_topic, reply).await;
+                return;
+            }
+        };
+        log::info!("MQTT command {} for device {}", command, device);
+
+        let replies = match command {
+            "send-sms" => self.send_sms(client, &reply_topic, &device, &payload).await,
+            "reconnect" => self.reconnect(&device).await,
+            _ => Err(anyhow::anyhow!("Unknown command: {}", command)),
+        };
+        let mut reply = match replies {
+            Ok(reply) => reply,
+            Err(e) => json!({"status": "error", "error": format!("{:#}", e)}),
+        };
+        if let Some(id) = payload.id {
+            reply["id"] = id;
+        }
+        publish_reply(client, &reply_topic, reply).await;
+    }
+
+    // (device, command) of a topic this bridge subscribed to
+    fn parse_topic<'a>(&self, topic: &'a str) -> Option<(String, &'a str)> {
+        let rest = topic
+            .strip_prefix(self.config.topic_prefix.as_str())?
+            .strip_prefix('/')?;
+        let (device, command) = rest.split_once("/cmd/")?;
+        if !self.devices.iter().any(|label| label == device) || command.contains('/') {
+            return None;
+        }
+        Some((device.to_string(), command))
+    }
+
+    // Queue the SMS, confirm that right away and reply again once it was sent or failed
+    async fn send_sms(
+        &self,
+        client: &AsyncClient,
+        reply_topic: &str,
+        device: &str,
+        payload: &CommandPayload,
+    ) -> Result<serde_json::Value> {
+        let to = payload.to.as_deref().unwrap_or_default();
+        if forwarding::normalize_number(to).is_empty() {
+            anyhow::bail!("Missing or invalid `to` number");
+        }
+        let content = match (&payload.content, &payload.template) {
+            (content, None) => content.clone().unwrap_or_default(),
+            (None, Some(name)) => templates::render(&self.db, name, &payload.variables)?,
+            (Some(_), Some(_)) => anyhow::bail!("Give either `content` or `template`"),
+        };
+        if content.trim().is_empty() {
+            anyhow::bail!("Missing `content`");
+        }
+
+        let device_id = (!self.lone_device).then_some(device);
+        let sms_id = *self
+            .db
+            .queue_outbound(
+                None,
+                device_id,
+                payload.urgent,
+                &[(to.to_string(), content)],
+            )?
+            .first()
+            .context("SMS was not queued")?;
+        audit::record(&self.db, ACTOR, "send_sms", Some(&format!("to {}", to)));
+
+        let mut queued = json!({"status": "queued", "sms_id": sms_id});
+        if let Some(id) = &payload.id {
+            queued["id"] = id.clone();
+        }
+        publish_reply(client, reply_topic, queued).await;
+
+        let deadline = tokio::time::Instant::now() + SEND_RESULT_TIMEOUT;
+        while tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(SEND_POLL_INTERVAL).await;
+            match self.db.outbound_status(sms_id)? {
+                Some((status, _)) if status == OUTBOUND_SENT => {
+                    return Ok(json!({"status": "sent", "sms_id": sms_id}));
+                }
+                Some((status, error)) if status == OUTBOUND_FAILED => {
+                    return Ok(json!({"status": "failed", "sms_id": sms_id, "error": error}));
+                }
+                Some(_) => {}
+                None => anyhow::bail!("SMS {} disappeared from the queue", sms_id),
+            }
+        }
+        anyhow::bail!(
+            "SMS {} was not sent within {}s, it stays queued",
+            sms_id,
+            SEND_RESULT_TIMEOUT.as_secs()
+        )
+    }
+
+    // Ask the connection loop to reconnect and wait until it picked that up
+    async fn reconnect(&self, device: &str) -> Result<serde_json::Value> {
+        let command = AdminCommand::Reconnect;
+        let id = self.db.queue_admin_command(device, command.as_str())?;
+        audit::record(&self.db, ACTOR, command.as_str(), Some(device));
+
+        let deadline = tokio::time::Instant::now() + ADMIN_COMMAND_TIMEOUT;
+        while tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            if self.db.admin_command_handled(id)? {
+                return Ok(json!({"status": "started"}));
+            }
+        }
+        self.db.discard_admin_commands(Some(&[id]))?;
+        anyhow::bail!(
+            "Device {} did not pick up the request, is it connected?",
+            device
+        )
+    }
+}
+
+// Retained, and queued without waiting so the event loop is never blocked on it
+fn try_publish(client: &AsyncClient, topic: &str, payload: &str) -> bool {
+    match client.try_publish(topic, QoS::AtLeastOnce, true, payload) {
+        Ok(()) => true,
+        Err(e) => {
+            log::warn!("Failed to publish to {}: {}", topic, e);
+            false
+        }
+    }
+}
+
+async fn publish_reply(client: &AsyncClient, topic: &str, reply: serde_json::Value) {
+    if let Err(e) = client
+        .publish(topic, QoS::AtLeastOnce, false, reply.to_string())
+        .await
+    {
+        log::warn!("Failed to publish to {}: {}", topic, e);
+    }
+}
@@ -0,0 +1,58 @@
+use std::time::{Duration, Instant};
+
+// Meaning of the registration status reported in NET_STATUS
+pub fn registration_name(status: i32) -> &'static str {
+    match status {
+        0 => "not registered",
+        1 => "registered",
+        2 => "searching",
+        3 => "registration denied",
+        5 => "registered (roaming)",
+        _ => "unknown",
+    }
+}
+
+pub fn is_registered(status: i32) -> bool {
+    matches!(status, 1 | 5)
+}
+
+pub fn is_roaming(status: i32) -> bool {
+    status == 5
+}
+
+// How long the module has been without network registration, so an outage is
+// alerted once when it lasts too long and its end is reported after that
+#[derive(Debug, Default)]
+pub struct RegistrationWatch {
+    status: i32,
+    lost_at: Option<Instant>,
+    alerted: bool,
+}
+
+impl RegistrationWatch {
+    // Returns the outage duration when registration comes back after an alert
+    pub fn update(&mut self, status: i32) -> Option<Duration> {
+        self.status = status;
+        if !is_registered(status) {
+            self.lost_at.get_or_insert_with(Instant::now);
+            return None;
+        }
+
+        let lost_at = self.lost_at.take()?;
+        std::mem::take(&mut self.alerted).then(|| lost_at.elapsed())
+    }
+
+    pub fn status(&self) -> i32 {
+        self.status
+    }
+
+    // Returns the outage duration once it exceeds `threshold`
+    pub fn overdue(&mut self, threshold: Duration) -> Option<Duration> {
+        let lost_at = self.lost_at?;
+        if self.alerted || threshold.is_zero() || lost_at.elapsed() < threshold {
+            return None;
+        }
+        self.alerted = true;
+        Some(lost_at.elapsed())
+    }
+}
@@ -1,9 +1,67 @@
-use anyhow::Result;
+use crate::ack_callback::AckLinks;
+use crate::config::{NotifierPluginConfig, Overflow};
+use anyhow::{Context, Result};
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+// Version of the JSON sent to webhooks and plugins. Fields are only added within
+// a version, consumers ignore the ones they don't know. Removing, renaming or
+// changing the meaning of a field needs a new version
+pub const SCHEMA_VERSION: u32 = 1;
 
 #[async_trait]
 pub trait Notifier: Send + Sync {
     async fn send(&self, title: &str, content: &str) -> Result<()>;
+
+    // Notifiers without groups ignore it
+    async fn send_grouped(&self, title: &str, content: &str, group: Option<&str>) -> Result<()> {
+        let _ = group;
+        self.send(title, content).await
+    }
+
+    // Notifiers ignore the options they can't express
+    async fn send_with_options(
+        &self,
+        title: &str,
+        content: &str,
+        options: &SendOptions<'_>,
+    ) -> Result<()> {
+        self.send_grouped(title, content, options.group).await
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SendOptions<'a> {
+    pub group: Option<&'a str>,
+    // Acknowledges the message when opened
+    pub action_url: Option<&'a str>,
+    // Unix time after which the notification is stale, e.g. for one-time codes
+    pub expires_at: Option<i64>,
+    // Text worth copying from the notification, such as a one-time code
+    pub copy: Option<&'a str>,
+    // The SMS the notification is about
+    pub message_id: Option<&'a str>,
+}
+
+// Body of Bark's POST /push, unset options are left out
+#[derive(Serialize)]
+struct BarkPush<'a> {
+    device_key: &'a str,
+    title: &'a str,
+    body: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<&'a str>,
+    #[serde(rename = "isArchive", skip_serializing_if = "Option::is_none")]
+    is_archive: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    copy: Option<&'a str>,
 }
 
 pub struct BarkNotifier {
@@ -25,17 +83,40 @@ impl BarkNotifier {
 #[async_trait]
 impl Notifier for BarkNotifier {
     async fn send(&self, title: &str, content: &str) -> Result<()> {
-        let url = format!(
-            "{}/{}/{}/{}",
-            self.server_url.trim_end_matches('/'),
-            self.device_key,
-            urlencoding::encode(title),
-            urlencoding::encode(content)
-        );
+        self.send_grouped(title, content, None).await
+    }
+
+    async fn send_grouped(&self, title: &str, content: &str, group: Option<&str>) -> Result<()> {
+        let options = SendOptions {
+            group,
+            ..Default::default()
+        };
+        self.send_with_options(title, content, &options).await
+    }
+
+    // Bark opens `url` when the notification is tapped. It can't remove a
+    // notification later, stale ones are at least kept out of its history.
+    // Sent as JSON, so any text arrives as it is, unlike in a URL path
+    async fn send_with_options(
+        &self,
+        title: &str,
+        content: &str,
+        options: &SendOptions<'_>,
+    ) -> Result<()> {
+        let url = format!("{}/push", self.server_url.trim_end_matches('/'));
+        let push = BarkPush {
+            device_key: &self.device_key,
+            title,
+            body: content,
+            group: options.group,
+            url: options.action_url,
+            is_archive: options.expires_at.map(|_| "0"),
+            copy: options.copy,
+        };
 
         log::debug!("Sending Bark notification to: {}", url);
 
-        match self.client.get(&url).send().await {
+        match self.client.post(&url).json(&push).send().await {
             Ok(response) => {
                 if response.status().is_success() {
                     log::info!("Bark notification sent successfully");
@@ -54,3 +135,387 @@ impl Notifier for BarkNotifier {
         }
     }
 }
+
+// Sends to every notifier, failing with the errors of those that failed
+pub struct FanoutNotifier {
+    notifiers: Vec<Arc<dyn Notifier>>,
+}
+
+impl FanoutNotifier {
+    pub fn new(notifiers: Vec<Arc<dyn Notifier>>) -> Self {
+        FanoutNotifier { notifiers }
+    }
+}
+
+#[async_trait]
+impl Notifier for FanoutNotifier {
+    async fn send(&self, title: &str, content: &str) -> Result<()> {
+        self.send_grouped(title, content, None).await
+    }
+
+    async fn send_grouped(&self, title: &str, content: &str, group: Option<&str>) -> Result<()> {
+        let options = SendOptions {
+            group,
+            ..Default::default()
+        };
+        self.send_with_options(title, content, &options).await
+    }
+
+    async fn send_with_options(
+        &self,
+        title: &str,
+        content: &str,
+        options: &SendOptions<'_>,
+    ) -> Result<()> {
+        // Delivered once any target got it. Failing the whole send would have
+        // the retries push again to the targets that already have it
+        let mut errors = Vec::new();
+        for notifier in &self.notifiers {
+            if let Err(e) = notifier.send_with_options(title, content, options).await {
+                errors.push(format!("{:#}", e));
+            }
+        }
+        if errors.len() == self.notifiers.len() {
+            anyhow::bail!("{}", errors.join("; "));
+        }
+        for error in &errors {
+            log::warn!("Notification target failed, the others got it: {}", error);
+        }
+        Ok(())
+    }
+}
+
+// Keeps bodies within what the wrapped notifier can deliver: longer ones are cut
+// with a note of how much is missing, or sent as numbered parts
+pub struct LimitedNotifier {
+    inner: Arc<dyn Notifier>,
+    max_chars: usize,
+    overflow: Overflow,
+}
+
+impl LimitedNotifier {
+    // `max_chars` 0 leaves the notifier as it is
+    pub fn wrap(
+        inner: Arc<dyn Notifier>,
+        max_chars: usize,
+        overflow: Overflow,
+    ) -> Arc<dyn Notifier> {
+        if max_chars == 0 {
+            return inner;
+        }
+        Arc::new(LimitedNotifier {
+            inner,
+            max_chars,
+            overflow,
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for LimitedNotifier {
+    async fn send(&self, title: &str, content: &str) -> Result<()> {
+        self.send_grouped(title, content, None).await
+    }
+
+    async fn send_grouped(&self, title: &str, content: &str, group: Option<&str>) -> Result<()> {
+        let options = SendOptions {
+            group,
+            ..Default::default()
+        };
+        self.send_with_options(title, content, &options).await
+    }
+
+    async fn send_with_options(
+        &self,
+        title: &str,
+        content: &str,
+        options: &SendOptions<'_>,
+    ) -> Result<()> {
+        if content.chars().count() <= self.max_chars {
+            return self.inner.send_with_options(title, content, options).await;
+        }
+        match self.overflow {
+            Overflow::Truncate => {
+                let content = truncate(content, self.max_chars);
+                self.inner.send_with_options(title, &content, options).await
+            }
+            Overflow::Split => {
+                let parts = split(content, self.max_chars);
+                for (i, part) in parts.iter().enumerate() {
+                    let title = format!("{} ({}/{})", title, i + 1, parts.len());
+                    self.inner.send_with_options(&title, part, options).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Sends only the first characters of SMS notifications, with a link to the whole
+// message when acknowledgment links are served. Other notifications pass as they are
+pub struct PreviewNotifier {
+    inner: Arc<dyn Notifier>,
+    chars: usize,
+    links: Option<AckLinks>,
+}
+
+impl PreviewNotifier {
+    // `chars` 0 leaves the notifier as it is
+    pub fn wrap(
+        inner: Arc<dyn Notifier>,
+        chars: usize,
+        links: Option<AckLinks>,
+    ) -> Arc<dyn Notifier> {
+        if chars == 0 {
+            return inner;
+        }
+        Arc::new(PreviewNotifier {
+            inner,
+            chars,
+            links,
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for PreviewNotifier {
+    async fn send(&self, title: &str, content: &str) -> Result<()> {
+        self.inner.send(title, content).await
+    }
+
+    async fn send_grouped(&self, title: &str, content: &str, group: Option<&str>) -> Result<()> {
+        self.inner.send_grouped(title, content, group).await
+    }
+
+    async fn send_with_options(
+        &self,
+        title: &str,
+        content: &str,
+        options: &SendOptions<'_>,
+    ) -> Result<()> {
+        let Some(message_id) = options.message_id else {
+            return self.inner.send_with_options(title, content, options).await;
+        };
+        let mut preview = truncate(content, self.chars);
+        if let Some(links) = &self.links {
+            preview.push_str("\n\n");
+            preview.push_str(&links.view_url(message_id));
+        }
+        // A one-time code to copy would give away what the preview leaves out
+        let options = SendOptions {
+            copy: None,
+            ..*options
+        };
+        self.inner
+            .send_with_options(title, &preview, &options)
+            .await
+    }
+}
+
+// Cut `content` to at most `max_chars` characters ending in "…(+N)", N being the
+// characters left out
+fn truncate(content: &str, max_chars: usize) -> String {
+    let total = content.chars().count();
+    if total <= max_chars {
+        return content.to_string();
+    }
+    // The longest the note can get, the count left out is never above the total
+    let note_chars = format!("…(+{})", total).chars().count();
+    if max_chars <= note_chars {
+        return content.chars().take(max_chars).collect();
+    }
+    let kept = break_before(content, max_chars - note_chars);
+    let shown = content[..kept].trim_end();
+    let missing = total - shown.chars().count();
+    format!("{}…(+{})", shown, missing)
+}
+
+// Split `content` into parts of at most `max_chars` characters
+fn split(content: &str, max_chars: usize) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = content;
+    while rest.chars().count() > max_chars {
+        let end = break_before(rest, max_chars);
+        parts.push(rest[..end].trim_end());
+        rest = rest[end..].trim_start();
+    }
+    if !rest.is_empty() {
+        parts.push(rest);
+    }
+    parts
+}
+
+// Byte offset to cut `text` at for at most `max_chars` characters, after the
+// last line break or space in the final fifth if there is one
+fn break_before(text: &str, max_chars: usize) -> usize {
+    let end = text
+        .char_indices()
+        .nth(max_chars)
+        .map_or(text.len(), |(offset, _)| offset);
+    let earliest = text
+        .char_indices()
+        .nth(max_chars - max_chars / 5)
+        .map_or(0, |(offset, _)| offset);
+    match text[earliest..end].rfind(char::is_whitespace) {
+        Some(offset) if earliest + offset > 0 => earliest + offset,
+        _ => end,
+    }
+}
+
+// What a plugin reads on stdin
+#[derive(Serialize)]
+struct PluginRequest<'a> {
+    schema_version: u32,
+    title: &'a str,
+    body: &'a str,
+    group: Option<&'a str>,
+    // Opening it acknowledges the message, e.g. behind a chat button
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ack_url: Option<&'a str>,
+    // Unix time to delete the notification at, e.g. with ntfy or Telegram
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<i64>,
+}
+
+// What a plugin writes on stdout, an empty output with exit status 0 is a success
+#[derive(Deserialize)]
+struct PluginResponse {
+    ok: bool,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+// Runs an external program for every notification
+pub struct ProcessNotifier {
+    name: String,
+    path: String,
+    args: Vec<String>,
+    timeout: Duration,
+}
+
+impl ProcessNotifier {
+    pub fn new(config: &NotifierPluginConfig) -> Self {
+        ProcessNotifier {
+            name: config.name.clone(),
+            path: config.path.clone(),
+            args: config.args.clone(),
+            timeout: Duration::from_secs(config.timeout_secs),
+        }
+    }
+
+    async fn run(&self, request: &[u8]) -> Result<std::process::Output> {
+        let mut child = Command::new(&self.path)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context(format!("Failed to start {}", self.path))?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            // A plugin may exit without reading its input
+            if let Err(e) = stdin.write_all(request).await {
+                log::debug!(
+                    "Notifier plugin {} did not read its input: {}",
+                    self.name,
+                    e
+                );
+            }
+        }
+
+        let output = child
+            .wait_with_output()
+            .await
+            .context(format!("Failed to wait for {}", self.path))?;
+        Ok(output)
+    }
+}
+
+#[async_trait]
+impl Notifier for ProcessNotifier {
+    async fn send(&self, title: &str, content: &str) -> Result<()> {
+        self.send_grouped(title, content, None).await
+    }
+
+    async fn send_grouped(&self, title: &str, content: &str, group: Option<&str>) -> Result<()> {
+        let options = SendOptions {
+            group,
+            ..Default::default()
+        };
+        self.send_with_options(title, content, &options).await
+    }
+
+    async fn send_with_options(
+        &self,
+        title: &str,
+        content: &str,
+        options: &SendOptions<'_>,
+    ) -> Result<()> {
+        let mut request = serde_json::to_vec(&PluginRequest {
+            schema_version: SCHEMA_VERSION,
+            title,
+            body: content,
+            group: options.group,
+            ack_url: options.action_url,
+            expires_at: options.expires_at,
+        })?;
+        request.push(b'\n');
+
+        let output = match tokio::time::timeout(self.timeout, self.run(&request)).await {
+            Ok(output) => output?,
+            Err(_) => anyhow::bail!(
+                "Notifier plugin {} timed out after {}s",
+                self.name,
+                self.timeout.as_secs()
+            ),
+        };
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        for line in stderr.lines().filter(|line| !line.trim().is_empty()) {
+            log::debug!("Notifier plugin {}: {}", self.name, line);
+        }
+        if !output.status.success() {
+            anyhow::bail!(
+                "Notifier plugin {} exited with {}: {}",
+                self.name,
+                output.status,
+                stderr.trim()
+            );
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if stdout.trim().is_empty() {
+            log::info!("Notifier plugin {} sent the notification", self.name);
+            return Ok(());
+        }
+        let response: PluginResponse = serde_json::from_str(stdout.trim()).context(format!(
+            "Notifier plugin {} returned invalid JSON",
+            self.name
+        ))?;
+        if !response.ok {
+            anyhow::bail!(
+                "Notifier plugin {} failed: {}",
+                self.name,
+                response.error.as_deref().unwrap_or("no error given")
+            );
+        }
+        log::info!("Notifier plugin {} sent the notification", self.name);
+        Ok(())
+    }
+}
+
+// Notifiers of the [[notifier_plugins]] entries, in config order
+pub fn plugins(configs: &[NotifierPluginConfig]) -> Vec<Arc<dyn Notifier>> {
+    configs
+        .iter()
+        .map(|config| {
+            log::info!("Notifier plugin {} enabled ({})", config.name, config.path);
+            LimitedNotifier::wrap(
+                Arc::new(ProcessNotifier::new(config)),
+                config.max_length,
+                config.overflow,
+            )
+        })
+        .collect()
+}
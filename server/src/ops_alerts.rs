@@ -0,0 +1,265 @@
+use crate::config::{self, Config};
+use crate::database;
+use crate::i18n::Translations;
+use crate::notification::{
+    BarkNotifier, FanoutNotifier, LimitedNotifier, Notifier, SCHEMA_VERSION,
+};
+use crate::webhook;
+use anyhow::{Context, Result};
+use ring::hmac;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What went wrong, also the prefix of the alert's translation keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OpsAlert {
+    // Frames kept failing to parse
+    ParseFailures,
+    // A webhook delivery failed every attempt
+    DeadLetter,
+    // The frame throttle tripped on a device flooding the serial port
+    Flood,
+    // Storing a message failed
+    DatabaseError,
+}
+
+impl OpsAlert {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OpsAlert::ParseFailures => "parse_failures",
+            OpsAlert::DeadLetter => "dead_letter",
+            OpsAlert::Flood => "flood",
+            OpsAlert::DatabaseError => "database_error",
+        }
+    }
+}
+
+// What the ops webhook receives, versioned like the [webhook] payload
+#[derive(Serialize)]
+struct OpsAlertEvent<'a> {
+    schema_version: u32,
+    event: &'a str,
+    kind: &'a str,
+    device: Option<&'a str>,
+    title: &'a str,
+    body: &'a str,
+    created_at: i64,
+}
+
+// Sends anomalies of the server itself to their own Bark devices and webhook,
+// apart from the SMS notifications. Cloned into every connection and the
+// webhook deliverer, they share when each alert was last sent
+#[derive(Clone, Default)]
+pub struct OpsAlerts {
+    inner: Option<Arc<Inner>>,
+}
+
+struct Inner {
+    bark: Option<Arc<dyn Notifier>>,
+    webhook_url: Option<String>,
+    webhook_key: Option<hmac::Key>,
+    client: reqwest::Client,
+    translations: Translations,
+    min_interval: Duration,
+    parse_failures: u32,
+    parse_failure_window: Duration,
+    last_sent: Mutex<HashMap<(OpsAlert, Option<String>), Instant>>,
+}
+
+impl OpsAlerts {
+    pub fn new(config: &Config, translations: Translations) -> Self {
+        let alerts = &config.ops_alerts;
+        if !alerts.enabled {
+            return OpsAlerts { inner: None };
+        }
+
+        // Without targets of their own the alerts go to [notification]'s
+        // devices, but never to its webhook: a dead letter alert queued behind
+        // the deliveries that are failing would not get through either
+        let keys = match &alerts.bark_device_key {
+            Some(keys) => config::split_keys(keys),
+            None if alerts.webhook_url.is_none() && config.notification.enabled => {
+                config.notification.bark_device_keys()
+            }
+            None => Vec::new(),
+        };
+        let bark = (!keys.is_empty()).then(|| {
+            let notifiers = keys
+                .into_iter()
+                .map(|key| {
+                    LimitedNotifier::wrap(
+                        Arc::new(BarkNotifier::new(
+                            config.notification.bark_server_url.clone(),
+                            key,
+                        )),
+                        config.notification.max_length,
+                        config.notification.overflow,
+                    )
+                })
+                .collect();
+            Arc::new(FanoutNotifier::new(notifiers)) as Arc<dyn Notifier>
+        });
+        if bark.is_none() && alerts.webhook_url.is_none() {
+            log::warn!("Operational alerts enabled without anywhere to send them");
+        }
+
+        OpsAlerts {
+            inner: Some(Arc::new(Inner {
+                bark,
+                webhook_url: alerts.webhook_url.clone(),
+                webhook_key: alerts
+                    .webhook_secret
+                    .as_ref()
+                    .map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())),
+                client: reqwest::Client::new(),
+                translations,
+                min_interval: Duration::from_secs(alerts.min_interval_secs),
+                parse_failures: alerts.parse_failures,
+                parse_failure_window: Duration::from_secs(alerts.parse_failure_window_secs),
+                last_sent: Mutex::new(HashMap::new()),
+            })),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    // Counts the parse failures of a session, never full when alerts are off
+    pub fn parse_failure_window(&self) -> FailureWindow {
+        match &self.inner {
+            Some(inner) => FailureWindow::new(inner.parse_failures, inner.parse_failure_window),
+            None => FailureWindow::new(0, Duration::ZERO),
+        }
+    }
+
+    // Send `alert` unless the same one for `device` went out within
+    // `min_interval_secs`. Failures are only logged
+    pub async fn send(&self, alert: OpsAlert, device: Option<&str>, args: &[(&str, &str)]) {
+        let Some(inner) = &self.inner else {
+            return;
+        };
+        {
+            let mut last_sent = inner.last_sent.lock().unwrap();
+            let key = (alert, device.map(str::to_string));
+            if last_sent
+                .get(&key)
+                .is_some_and(|at| at.elapsed() < inner.min_interval)
+            {
+                log::debug!(
+                    "Skipping operational alert {}, sent recently",
+                    alert.as_str()
+                );
+                return;
+            }
+            last_sent.insert(key, Instant::now());
+        }
+
+        let mut args = args.to_vec();
+        if let Some(device) = device {
+            args.push(("device", device));
+        }
+        let kind = alert.as_str();
+        let title = inner.translations.text(&format!("{}_title", kind), &args);
+        let body = inner.translations.text(&format!("{}_body", kind), &args);
+        log::warn!("Operational alert: {}: {}", title, body);
+
+        if let Some(bark) = &inner.bark
+            && let Err(e) = bark.send(&title, &body).await
+        {
+            log::warn!("Failed to send operational alert: {:#}", e);
+        }
+        if let Some(url) = &inner.webhook_url
+            && let Err(e) = inner.post(url, kind, device, &title, &body).await
+        {
+            log::warn!("Failed to send operational alert to {}: {:#}", url, e);
+        }
+    }
+}
+
+impl Inner {
+    async fn post(
+        &self,
+        url: &str,
+        kind: &str,
+        device: Option<&str>,
+        title: &str,
+        body: &str,
+    ) -> Result<()> {
+        let payload = serde_json::to_string(&OpsAlertEvent {
+            schema_version: SCHEMA_VERSION,
+            event: "ops_alert",
+            kind,
+            device,
+            title,
+            body,
+            created_at: database::unix_timestamp(),
+        })?;
+        let mut request = self
+            .client
+            .post(url)
+            .timeout(REQUEST_TIMEOUT)
+            .header("Content-Type", "application/json");
+        if let Some(key) = &self.webhook_key {
+            request = webhook::signed(request, key, &payload);
+        }
+
+        let response = request
+            .body(payload)
+            .send()
+            .await
+            .context(format!("Failed to reach {}", url))?;
+        if !response.status().is_success() {
+            anyhow::bail!("Webhook returned status {}", response.status());
+        }
+        Ok(())
+    }
+}
+
+// Failures within a sliding window, full once `threshold` of them happened
+// within `window`
+pub struct FailureWindow {
+    threshold: usize,
+    window: Duration,
+    failures: VecDeque<Instant>,
+}
+
+impl FailureWindow {
+    fn new(threshold: u32, window: Duration) -> Self {
+        FailureWindow {
+            threshold: threshold as usize,
+            window,
+            failures: VecDeque::new(),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    // Returns how many failures filled the window, which then starts over
+    pub fn record(&mut self) -> Option<usize> {
+        if self.threshold == 0 {
+            return None;
+        }
+        let now = Instant::now();
+        while self
+            .failures
+            .front()
+            .is_some_and(|at| now.duration_since(*at) > self.window)
+        {
+            self.failures.pop_front();
+        }
+        self.failures.push_back(now);
+        if self.failures.len() < self.threshold {
+            return None;
+        }
+        let count = self.failures.len();
+        self.failures.clear();
+        Some(count)
+    }
+}
@@ -0,0 +1,64 @@
+use crate::config::OptOutConfig;
+use crate::database::Database;
+use crate::forwarding;
+use anyhow::Result;
+
+// Source and audit log actor of opt-outs sent by SMS
+pub const SOURCE_SMS: &str = "sms";
+
+// What an SMS consisting of just a keyword asks for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Request {
+    OptOut,
+    OptIn,
+}
+
+// Recognizes "STOP" style replies, whose senders are put on the opt-out list
+// that keeps queued SMS from being sent to them, and "START" ones taking them off
+pub struct OptOutKeywords {
+    stop: Vec<String>,
+    start: Vec<String>,
+    pub stop_reply: Option<String>,
+    pub start_reply: Option<String>,
+}
+
+impl OptOutKeywords {
+    pub fn new(config: &OptOutConfig) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+        let normalize = |keywords: &[String]| keywords.iter().map(|k| normalize(k)).collect();
+        Some(OptOutKeywords {
+            stop: normalize(&config.keywords),
+            start: normalize(&config.resume_keywords),
+            stop_reply: config.reply.clone(),
+            start_reply: config.resume_reply.clone(),
+        })
+    }
+
+    // Only the whole message counts, so "don't stop" or a STOP in a sentence doesn't
+    pub fn detect(&self, content: &str) -> Option<Request> {
+        let content = normalize(content);
+        if self.stop.contains(&content) {
+            Some(Request::OptOut)
+        } else if self.start.contains(&content) {
+            Some(Request::OptIn)
+        } else {
+            None
+        }
+    }
+}
+
+// Case and surrounding whitespace or punctuation don't matter, "Stop." is STOP
+fn normalize(text: &str) -> String {
+    text.trim_matches(|c: char| c.is_whitespace() || c.is_ascii_punctuation() || "。！".contains(c))
+        .to_uppercase()
+}
+
+// Whether `number` asked not to be texted, under any of its spellings
+pub fn opted_out(db: &Database, number: &str) -> Result<bool> {
+    Ok(db
+        .opt_outs()?
+        .iter()
+        .any(|entry| forwarding::same_number(&entry.number, number)))
+}
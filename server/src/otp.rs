@@ -0,0 +1,68 @@
+use crate::config::OtpConfig;
+use regex::Regex;
+
+// Words that accompany one-time codes, matched case-insensitively
+const KEYWORDS: &[&str] = &[
+    "code",
+    "otp",
+    "verification",
+    "verify",
+    "passcode",
+    "password",
+    "pin",
+    "验证码",
+    "校验码",
+    "动态码",
+    "确认码",
+    "动态密码",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Otp {
+    // Who sent the code, e.g. "Alipay" from "【Alipay】" or an alphanumeric sender
+    pub service: String,
+    pub code: String,
+}
+
+// Recognizes SMS carrying one-time codes, whose notifications are grouped by
+// service and expire once the code is useless
+pub struct OtpDetector {
+    code: Regex,
+    service: Regex,
+    expire_secs: i64,
+}
+
+impl OtpDetector {
+    pub fn new(config: &OtpConfig) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+        Some(OtpDetector {
+            // 4 to 8 digits, optionally split in two halves like "123-456"
+            code: Regex::new(r"(?:^|[^\d])(\d{3,4}[- ]\d{3,4}|\d{4,8})(?:[^\d]|$)").unwrap(),
+            service: Regex::new(r"^\s*[【\[]([^】\]]{1,30})[】\]]|[【\[]([^】\]]{1,30})[】\]]\s*$")
+                .unwrap(),
+            expire_secs: config.expire_minutes as i64 * 60,
+        })
+    }
+
+    pub fn detect(&self, sender: &str, content: &str) -> Option<Otp> {
+        let lower = content.to_lowercase();
+        if !KEYWORDS.iter().any(|keyword| lower.contains(keyword)) {
+            return None;
+        }
+        let code = self.code.captures(content)?.get(1)?.as_str().to_string();
+        let service = self
+            .service
+            .captures(content)
+            .and_then(|captures| captures.get(1).or_else(|| captures.get(2)))
+            .map(|name| name.as_str().trim().to_string())
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| sender.to_string());
+        Some(Otp { service, code })
+    }
+
+    pub fn expire_secs(&self) -> i64 {
+        self.expire_secs
+    }
+}
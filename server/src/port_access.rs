@@ -0,0 +1,266 @@
+use crate::config::{AUTO_BAUD_RATE, SerialConfig, is_auto_port};
+use crate::error::{self, AppError};
+use crate::serial_port;
+use anyhow::{Context, Result};
+use tokio_serial::SerialPortBuilderExt;
+
+// A failed handshake looks the same whether the device is silent or the port
+// cannot be opened at all, report the latter as a permission problem
+pub fn check(port_name: &str) -> Result<()> {
+    match tokio_serial::new(port_name, 115200).open_native_async() {
+        Err(e) if e.kind() == tokio_serial::ErrorKind::Io(std::io::ErrorKind::PermissionDenied) => {
+            Err(error::port_open_error(port_name, &e))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Locate the device for a one-off command, the server must not hold the port.
+// The configured ports are tried in order, the error of the last one is returned
+pub async fn find_device(
+    serial: &SerialConfig,
+    port_override: Option<&str>,
+) -> Result<(String, u32)> {
+    let baud_rates = if serial.baud_rate == AUTO_BAUD_RATE {
+        serial_port::AUTO_BAUD_RATES.to_vec()
+    } else {
+        vec![serial.baud_rate]
+    };
+
+    let candidates = match port_override {
+        Some(port_name) => vec![port_name.to_string()],
+        None => serial.port_names.clone(),
+    };
+    let mut last_error = None;
+    for port_name in &candidates {
+        match find_on(serial, port_name, &baud_rates).await {
+            Ok(found) => return Ok(found),
+            Err(e) => {
+                log::debug!("{:#}", e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No port configured")))
+}
+
+async fn find_on(
+    serial: &SerialConfig,
+    port_name: &str,
+    baud_rates: &[u32],
+) -> Result<(String, u32)> {
+    if is_auto_port(port_name) {
+        let found = serial_port::scan_ports(serial, baud_rates)
+            .await
+            .context("Failed to list available ports")?;
+        let device = serial_port::select_device(found, serial.expected_imei.as_deref())?;
+        return device.map(|device| (device.port_name, device.baud_rate)).ok_or_else(|| {
+            AppError::PortNotFound(
+                "No device answered on any port, is the server still running? Stop it first or pass --port"
+                    .to_string(),
+            )
+            .into()
+        });
+    }
+
+    let probed = serial_port::probe_port(
+        port_name,
+        baud_rates,
+        serial.handshake_timeout_ms,
+        serial.compression,
+    )
+    .await;
+    if probed.is_none() {
+        check(port_name)?;
+    }
+    let (baud_rate, _) = probed.ok_or_else(|| {
+        AppError::PortNotFound(format!(
+            "No device answered on {}, is the server still running? Stop it first",
+            port_name
+        ))
+    })?;
+    Ok((port_name.to_string(), baud_rate))
+}
+
+// The process holding the port, from UUCP lock files and open file descriptors
+#[cfg(target_os = "linux")]
+pub fn in_use(port_name: &str) -> Option<String> {
+    let target = std::fs::canonicalize(port_name).ok()?;
+    let kernel_name = target.file_name()?.to_str()?;
+
+    for dir in ["/var/lock", "/run/lock"] {
+        let Ok(content) = std::fs::read_to_string(format!("{}/LCK..{}", dir, kernel_name)) else {
+            continue;
+        };
+        // Stale lock files of dead processes are ignored
+        if let Ok(pid) = content.trim().parse::<u32>()
+            && std::path::Path::new(&format!("/proc/{}", pid)).exists()
+        {
+            return Some(process_label(pid));
+        }
+    }
+
+    let own_pid = std::process::id();
+    let processes = std::fs::read_dir("/proc").ok()?;
+    for process in processes.filter_map(|entry| entry.ok()) {
+        let Some(pid) = process
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        if pid == own_pid {
+            continue;
+        }
+        // Processes of other users cannot be inspected without root
+        if has_open(&process.path(), &target) {
+            return Some(process_label(pid));
+        }
+    }
+    None
+}
+
+// Whether this process still has the port open, e.g. after closing a session
+#[cfg(target_os = "linux")]
+pub fn held_by_self(port_name: &str) -> bool {
+    std::fs::canonicalize(port_name)
+        .is_ok_and(|target| has_open(std::path::Path::new("/proc/self"), &target))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn held_by_self(_port_name: &str) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn has_open(process: &std::path::Path, target: &std::path::Path) -> bool {
+    let Ok(fds) = std::fs::read_dir(process.join("fd")) else {
+        return false;
+    };
+    fds.filter_map(|fd| fd.ok())
+        .any(|fd| std::fs::read_link(fd.path()).is_ok_and(|path| path == target))
+}
+
+// Other systems refuse to open a port twice, the probe fails harmlessly
+#[cfg(not(target_os = "linux"))]
+pub fn in_use(_port_name: &str) -> Option<String> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn process_label(pid: u32) -> String {
+    match std::fs::read_to_string(format!("/proc/{}/comm", pid)) {
+        Ok(name) => format!("{} (pid {})", name.trim(), pid),
+        Err(_) => format!("pid {}", pid),
+    }
+}
+
+// Steps to get access to a port this process is not allowed to open
+#[cfg(unix)]
+pub fn hints(port_name: &str) -> Vec<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let user = current_user();
+    let Ok(metadata) = std::fs::metadata(port_name) else {
+        return vec![format!(
+            "User '{}' is not allowed to open {}, run the server as a user with access to the port",
+            user, port_name
+        )];
+    };
+    let group = port_group(port_name).unwrap_or_else(|| metadata.gid().to_string());
+
+    let mut hints = vec![format!(
+        "User '{}' is not allowed to open {} (owner group '{}', mode {:o})",
+        user,
+        port_name,
+        group,
+        metadata.mode() & 0o777
+    )];
+    if group_members(&group).contains(&user) {
+        // Group changes only apply to new login sessions
+        hints.push(format!(
+            "'{}' is already in group '{}', log out and back in (or restart the service) to pick it up",
+            user, group
+        ));
+    } else {
+        hints.push(format!(
+            "Add the user to the group: sudo usermod -aG {} {} (then log out and back in)",
+            group, user
+        ));
+    }
+    hints.push(format!(
+        "Or grant access with a udev rule, e.g. in /etc/udev/rules.d/99-air780e.rules: \
+         SUBSYSTEM==\"tty\", ATTRS{{idVendor}}==\"19d1\", GROUP=\"{}\", MODE=\"0660\" \
+         (then run: sudo udevadm control --reload && sudo udevadm trigger)",
+        group
+    ));
+    hints
+}
+
+// On Windows "access denied" means another program holds the port
+#[cfg(not(unix))]
+pub fn hints(port_name: &str) -> Vec<String> {
+    vec![format!(
+        "{} is in use, close other programs using it (LuaTools, serial monitors, another server instance)",
+        port_name
+    )]
+}
+
+// Name of the group owning the port, e.g. "dialout"
+#[cfg(unix)]
+pub fn port_group(port_name: &str) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let gid = std::fs::metadata(port_name).ok()?.gid();
+    Some(group_name(gid).unwrap_or_else(|| gid.to_string()))
+}
+
+#[cfg(unix)]
+fn current_user() -> String {
+    if let Ok(user) = std::env::var("USER").or_else(|_| std::env::var("LOGNAME")) {
+        return user;
+    }
+    std::process::Command::new("id")
+        .arg("-un")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|user| user.trim().to_string())
+        .filter(|user| !user.is_empty())
+        .unwrap_or_else(|| "$USER".to_string())
+}
+
+// /etc/group lines look like "dialout:x:20:alice,bob"
+#[cfg(unix)]
+fn group_entries() -> Vec<Vec<String>> {
+    std::fs::read_to_string("/etc/group")
+        .unwrap_or_default()
+        .lines()
+        .map(|line| line.split(':').map(str::to_string).collect::<Vec<_>>())
+        .filter(|fields| fields.len() >= 4)
+        .collect()
+}
+
+#[cfg(unix)]
+fn group_name(gid: u32) -> Option<String> {
+    group_entries()
+        .into_iter()
+        .find(|fields| fields[2] == gid.to_string())
+        .map(|fields| fields[0].clone())
+}
+
+#[cfg(unix)]
+fn group_members(group: &str) -> Vec<String> {
+    group_entries()
+        .into_iter()
+        .find(|fields| fields[0] == group)
+        .map(|fields| {
+            fields[3]
+                .split(',')
+                .filter(|member| !member.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
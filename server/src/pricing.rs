@@ -0,0 +1,148 @@
+use crate::config::Config;
+use crate::database::SentOutbound;
+use crate::forwarding;
+use crate::sender::SenderClassifier;
+use crate::usage::UsageTracker;
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+// GSM 03.38 characters taking one septet, and those of its extension table
+// taking two. Any other character makes the whole SMS UCS-2
+const GSM_BASIC: &str = "@£$¥èéùìòÇ\nØø\rÅåΔ_ΦΓΛΩΠΨΣΘΞÆæßÉ !\"#¤%&'()*+,-./0123456789:;<=>?\
+                         ¡ABCDEFGHIJKLMNOPQRSTUVWXYZÄÖÑÜ§¿abcdefghijklmnopqrstuvwxyzäöñüà";
+const GSM_EXTENDED: &str = "^{}\\[~]|€\x0c";
+
+// Estimated cost of the SMS one device sent to one prefix within a cycle
+#[derive(Debug, Clone)]
+pub struct CostLine {
+    pub cycle_start: String,
+    pub device: String,
+    // Matched prefix, None for the default price
+    pub prefix: Option<String>,
+    pub messages: u64,
+    pub parts: u64,
+    pub cost: f64,
+}
+
+// Prices each SMS part by the longest configured prefix of its destination
+#[derive(Debug, Clone)]
+pub struct Pricing {
+    currency: String,
+    default_price: f64,
+    // Longest prefix first
+    prefixes: Vec<(String, f64)>,
+    senders: SenderClassifier,
+}
+
+impl Pricing {
+    pub fn new(config: &Config) -> Result<Self> {
+        let senders = SenderClassifier::new(config.senders.home_country.as_deref())?;
+        let mut prefixes: Vec<_> = config
+            .pricing
+            .prefixes
+            .iter()
+            .map(|rate| (normalize_prefix(&rate.prefix), rate.price))
+            .collect();
+        prefixes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+        Ok(Pricing {
+            currency: config.pricing.currency.clone(),
+            default_price: config.pricing.default_price,
+            prefixes,
+            senders,
+        })
+    }
+
+    // Without any price there is nothing to estimate
+    pub fn enabled(&self) -> bool {
+        self.default_price > 0.0 || !self.prefixes.is_empty()
+    }
+
+    // The prefix `recipient` matches and the price of one part. Numbers are
+    // compared in international form, short codes as written
+    pub fn rate(&self, recipient: &str) -> (Option<&str>, f64) {
+        let number = self
+            .senders
+            .international(recipient)
+            .unwrap_or_else(|| normalize_prefix(recipient));
+        self.prefixes
+            .iter()
+            .find(|(prefix, _)| number.starts_with(prefix.as_str()))
+            .map(|(prefix, price)| (Some(prefix.as_str()), *price))
+            .unwrap_or((None, self.default_price))
+    }
+
+    // Costs per cycle, device and prefix, oldest cycle first
+    pub fn estimate(&self, sent: &[SentOutbound], usage: &UsageTracker) -> Vec<CostLine> {
+        let mut lines = BTreeMap::new();
+        for sms in sent {
+            let (prefix, price) = self.rate(&sms.recipient);
+            let cycle_start = usage.cycle_start(sms.sent_at);
+            let device = sms.device_id.as_deref().unwrap_or("any").to_string();
+            let prefix = prefix.map(str::to_string);
+            let line = lines
+                .entry((cycle_start.clone(), device.clone(), prefix.clone()))
+                .or_insert_with(|| CostLine {
+                    cycle_start,
+                    device,
+                    prefix,
+                    messages: 0,
+                    parts: 0,
+                    cost: 0.0,
+                });
+            let parts = parts(&sms.content);
+            line.messages += 1;
+            line.parts += parts;
+            line.cost += price * parts as f64;
+        }
+        lines.into_values().collect()
+    }
+
+    // An amount with the currency, e.g. "1.20 CNY"
+    pub fn amount(&self, cost: f64) -> String {
+        if self.currency.is_empty() {
+            format!("{:.2}", cost)
+        } else {
+            format!("{:.2} {}", cost, self.currency)
+        }
+    }
+}
+
+// How many SMS `content` is sent as: 160 GSM characters or 70 UCS-2 ones fit
+// in one, a longer text is split into parts of 153 or 67
+pub fn parts(content: &str) -> u64 {
+    let septets: Option<usize> = content
+        .chars()
+        .map(|c| {
+            if GSM_BASIC.contains(c) {
+                Some(1)
+            } else if GSM_EXTENDED.contains(c) {
+                Some(2)
+            } else {
+                None
+            }
+        })
+        .sum();
+    let (units, single, part) = match septets {
+        Some(septets) => (septets, 160, 153),
+        None => (content.encode_utf16().count(), 70, 67),
+    };
+    if units <= single {
+        1
+    } else {
+        units.div_ceil(part) as u64
+    }
+}
+
+// "00852 1234" is the same prefix as "+8521234"
+fn normalize_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim();
+    let digits = forwarding::normalize_number(prefix);
+    if prefix.starts_with('+') {
+        format!("+{}", digits)
+    } else if let Some(rest) = digits.strip_prefix("00") {
+        format!("+{}", rest)
+    } else {
+        digits
+    }
+}
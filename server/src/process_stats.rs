@@ -0,0 +1,57 @@
+use crate::database::{self, Database, ProcessStats};
+use crate::watchdog::Heartbeat;
+use std::time::Duration;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+// Samples the server's own memory, descriptors, threads and tokio tasks into the
+// database, so `status` and the metrics show a slow leak, e.g. serial ports not
+// closed by a reconnect loop, long before the OOM killer does
+#[derive(Clone)]
+pub struct ProcessSampler {
+    db: Database,
+}
+
+impl ProcessSampler {
+    pub fn new(db: Database) -> Self {
+        ProcessSampler { db }
+    }
+
+    pub async fn run(self, heartbeat: Heartbeat) {
+        loop {
+            if let Err(e) = self.db.save_process_stats(&sample()) {
+                log::warn!("Failed to store process stats: {:#}", e);
+            }
+            heartbeat.beat(SAMPLE_INTERVAL);
+            tokio::time::sleep(SAMPLE_INTERVAL).await;
+        }
+    }
+}
+
+fn sample() -> ProcessStats {
+    let runtime = tokio::runtime::Handle::current().metrics();
+    let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+    ProcessStats {
+        pid: std::process::id(),
+        // Only Linux has /proc, elsewhere these stay empty
+        rss_bytes: status_field(&status, "VmRSS").map(|kib| kib * 1024),
+        open_fds: std::fs::read_dir("/proc/self/fd")
+            .ok()
+            // Not counting the one reading the directory
+            .map(|entries| entries.count().saturating_sub(1) as u64),
+        threads: status_field(&status, "Threads"),
+        workers: runtime.num_workers() as u64,
+        alive_tasks: runtime.num_alive_tasks() as u64,
+        global_queue_depth: runtime.global_queue_depth() as u64,
+        updated_at: database::unix_timestamp(),
+    }
+}
+
+// The number of a `Name:   1234 kB` line of /proc/self/status
+fn status_field(status: &str, name: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+        .and_then(|value| value.split_whitespace().next())
+        .and_then(|value| value.parse().ok())
+}
@@ -0,0 +1,426 @@
+// The serial protocol: frames, the payloads they carry and their encoding.
+// It only depends on external crates so tests/protocol.rs and the fuzz targets
+// in fuzz/ can build it on its own
+
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::{Engine as _, engine::general_purpose};
+use flate2::{Decompress, FlushDecompress, Status};
+use serde::{Deserialize, Serialize};
+
+// Field put between the type and a payload zlib-compressed before base64 encoding
+pub const COMPRESSED_FLAG: &str = "z";
+// Upper bound on a decompressed payload, guards against compression bombs
+pub const MAX_DECOMPRESSED_BYTES: u64 = 1024 * 1024;
+// Output space added on each inflate step, bounds the overshoot past the limit
+const INFLATE_CHUNK_BYTES: usize = 16 * 1024;
+
+// Device firmware encodes payloads with either alphabet, padded or not
+const LENIENT: GeneralPurposeConfig =
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent);
+const STANDARD_LENIENT: GeneralPurpose = GeneralPurpose::new(&alphabet::STANDARD, LENIENT);
+const URL_SAFE_LENIENT: GeneralPurpose = GeneralPurpose::new(&alphabet::URL_SAFE, LENIENT);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmsPayload {
+    pub id: String,
+    pub sender: String,
+    pub content: String,
+    pub received_at: i64,
+    pub metas: Option<serde_json::Value>,
+    // The payload as received when it was not valid UTF-8, the fields then
+    // hold U+FFFD in place of the invalid bytes
+    #[serde(skip)]
+    pub raw_payload: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceInfoPayload {
+    pub imei: String,
+    pub number: String,
+    pub status: i32,
+    pub rssi: i32,
+    pub iccid: String,
+    pub timestamp: i64,
+    // "zlib" when the device accepted the compression offer
+    #[serde(default)]
+    pub compression: Option<String>,
+    // Module firmware and device script versions, missing on older scripts
+    #[serde(default)]
+    pub firmware: Option<String>,
+    #[serde(default)]
+    pub script_version: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendSmsPayload {
+    pub to: String,
+    pub content: String,
+    // Echoed back in SMS_SENT so the result can be matched to a queued message
+    #[serde(rename = "ref", default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    // Used by the device as the id of its SMS_SENT reply, see correlation.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rid: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmsSentPayload {
+    pub to: String,
+    pub success: bool,
+    #[serde(rename = "ref", default)]
+    pub reference: Option<String>,
+}
+
+// A log line forwarded by the device
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceLogPayload {
+    pub level: String,
+    pub tag: String,
+    pub message: String,
+    pub timestamp: Option<i64>,
+}
+
+// Steps of the CMD:UPDATE_SCRIPT transfer, `data` chunks are base64 encoded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum UpdateScriptCommand {
+    Begin { name: String, size: usize, crc: u32 },
+    Chunk { seq: u32, data: String },
+    Commit,
+    Abort,
+}
+
+// Device reply to each UPDATE_SCRIPT step
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptUpdatePayload {
+    pub op: String,
+    pub seq: Option<u32>,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+// Network registration, sent by the device when it changes and after DEVICE_INFO
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetStatusPayload {
+    // mobile.status(): 0 not registered, 1 home network, 2 searching, 3 denied,
+    // 4 unknown, 5 roaming
+    pub status: i32,
+    // MCC and MNC of the serving cell such as "46000", missing while unregistered
+    #[serde(default)]
+    pub operator: Option<String>,
+    // Radio access technology, "LTE" on the Air780E
+    #[serde(default)]
+    pub network: Option<String>,
+    #[serde(default)]
+    pub rssi: Option<i32>,
+    // Mobile data counters since the module started, when the script reports them
+    #[serde(default)]
+    pub data_tx_bytes: Option<u64>,
+    #[serde(default)]
+    pub data_rx_bytes: Option<u64>,
+}
+
+// An SMS kept in the device queue until the server acknowledges it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredSms {
+    pub id: String,
+    pub sender: String,
+    pub content: String,
+    pub received_at: i64,
+    pub retry_count: u32,
+    // ACKed and kept until its read receipt
+    #[serde(default)]
+    pub acked: bool,
+}
+
+// Reply to LIST_STORED. Once `capacity` is reached new SMS are sent only once,
+// without being kept for retries
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredSmsPayload {
+    // Left out by the device when empty
+    #[serde(default)]
+    pub messages: Vec<StoredSms>,
+    pub capacity: usize,
+    // Flash key-value store usage in bytes
+    #[serde(default)]
+    pub fskv_used: Option<u64>,
+    #[serde(default)]
+    pub fskv_total: Option<u64>,
+}
+
+// CMD:DELETE_STORED argument, `all` deletes every stored SMS regardless of `ids`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeleteStoredCommand {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ids: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub all: bool,
+    // Used by the device as the id of its reply, see correlation.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rid: Option<String>,
+}
+
+// CMD:GET_DEVICE_INFO argument from script 1.6.0, older scripts take "z" for
+// the compression offer and nothing else
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceInfoCommand {
+    pub compression: bool,
+    pub rid: String,
+}
+
+// Argument of commands that only need their reply matched, such as LIST_STORED
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestId {
+    pub rid: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredDeletedPayload {
+    pub deleted: usize,
+    pub remaining: usize,
+}
+
+#[derive(Debug, Clone)]
+pub enum MessageType {
+    DeviceInfo(DeviceInfoPayload),
+    SmsReceived(SmsPayload),
+    SmsSent(SmsSentPayload),
+    ScriptUpdate(ScriptUpdatePayload),
+    DeviceLog(DeviceLogPayload),
+    NetStatus(NetStatusPayload),
+    StoredSms(StoredSmsPayload),
+    StoredDeleted(StoredDeletedPayload),
+    SystemInit(serde_json::Value),
+    HeartBeat(serde_json::Value),
+    Unknown(String),
+}
+
+impl MessageType {
+    // The type as it appears in the frame
+    pub fn kind(&self) -> &str {
+        match self {
+            MessageType::DeviceInfo(_) => "DEVICE_INFO",
+            MessageType::SmsReceived(_) => "SMS_RECEIVED",
+            MessageType::SmsSent(_) => "SMS_SENT",
+            MessageType::ScriptUpdate(_) => "SCRIPT_UPDATE",
+            MessageType::DeviceLog(_) => "DEVICE_LOG",
+            MessageType::NetStatus(_) => "NET_STATUS",
+            MessageType::StoredSms(_) => "STORED_SMS",
+            MessageType::StoredDeleted(_) => "STORED_DELETED",
+            MessageType::SystemInit(_) => "SYSTEM_INIT",
+            MessageType::HeartBeat(_) => "HEART_BEAT",
+            MessageType::Unknown(kind) => kind,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ParsedMessage {
+    pub id: String,
+    pub message_type: MessageType,
+}
+
+// A frame split into its fields, the payload decoded to JSON text
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub id: String,
+    pub kind: String,
+    pub json: String,
+    // The decoded payload when it was not valid UTF-8 and `json` had to be
+    // decoded lossily
+    pub raw: Option<Vec<u8>>,
+}
+
+// Parse format: {uuid}:{type}:{base64}\r\n or {uuid}:{type}:z:{base64}\r\n
+pub fn decode_frame(line: &str) -> Option<Frame> {
+    // Line breaks on both ends are skipped, a device ending lines with "\n\r"
+    // leaves the "\r" in front of the next frame
+    let line = line.trim_matches(['\r', '\n']);
+
+    // No field holds a colon, so a line with more of them than one frame has
+    // is two frames run together, e.g. one cut short by a reconnect
+    let fields: Vec<&str> = line.split(':').collect();
+    let (id, kind, compressed, data) = match fields[..] {
+        [id, kind, data] => (id, kind, false, data),
+        [id, kind, COMPRESSED_FLAG, data] => (id, kind, true, data),
+        _ => return None,
+    };
+
+    if !is_valid_id(id) || !is_valid_kind(kind) || data.is_empty() {
+        return None;
+    }
+
+    // Decode base64, inflating compressed payloads
+    let decoded = decode_base64(data)?;
+    let decoded = if compressed {
+        inflate(&decoded)?
+    } else {
+        decoded
+    };
+    // A flipped bit in an SMS text is no reason to lose the whole message
+    let (json, raw) = match String::from_utf8(decoded) {
+        Ok(json) => (json, None),
+        Err(e) => {
+            let raw = e.into_bytes();
+            (String::from_utf8_lossy(&raw).into_owned(), Some(raw))
+        }
+    };
+
+    Some(Frame {
+        id: id.to_string(),
+        kind: kind.to_string(),
+        json,
+        raw,
+    })
+}
+
+// A payload mixing both alphabets is rejected rather than guessed at
+pub fn decode_base64(data: &str) -> Option<Vec<u8>> {
+    let engine = if data.contains(['-', '_']) {
+        &URL_SAFE_LENIENT
+    } else {
+        &STANDARD_LENIENT
+    };
+    engine.decode(data).ok()
+}
+
+// UUIDs from the device and the server's own ids such as "CMD" use letters,
+// digits, "-" and "_". Anything else is garbage from a corrupted line
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+// Types are SCREAMING_SNAKE_CASE, which also tells them apart from the "z" flag
+fn is_valid_kind(kind: &str) -> bool {
+    !kind.is_empty()
+        && kind
+            .bytes()
+            .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_')
+}
+
+// The id of an `ACK_REQ:{id}` line, sent by scripts from 1.7.0 instead of the
+// whole SMS when retrying one they got no ACK for
+pub fn parse_ack_request(line: &str) -> Option<&str> {
+    let id = line.trim_matches(['\r', '\n']).strip_prefix("ACK_REQ:")?;
+    is_valid_id(id).then_some(id)
+}
+
+// The line `decode_frame` reads back, as the server writes commands
+pub fn encode_frame(id: &str, kind: &str, json: &str) -> String {
+    format!(
+        "{}:{}:{}\r\n",
+        id,
+        kind,
+        general_purpose::STANDARD.encode(json)
+    )
+}
+
+pub fn parse_message(line: &str) -> Option<ParsedMessage> {
+    let Frame {
+        id,
+        kind,
+        json,
+        raw,
+    } = decode_frame(line)?;
+
+    log::debug!(
+        "Parsed message - ID: {}, Type: {}, JSON: {}",
+        id,
+        kind,
+        json
+    );
+
+    let message_type = match kind.as_str() {
+        "DEVICE_INFO" => {
+            let payload: DeviceInfoPayload = serde_json::from_str(&json).ok()?;
+            MessageType::DeviceInfo(payload)
+        }
+        "SMS_RECEIVED" => {
+            let mut payload: SmsPayload = serde_json::from_str(&json).ok()?;
+            payload.raw_payload = raw;
+            MessageType::SmsReceived(payload)
+        }
+        "SMS_SENT" => {
+            let payload: SmsSentPayload = serde_json::from_str(&json).ok()?;
+            MessageType::SmsSent(payload)
+        }
+        "SCRIPT_UPDATE" => {
+            let payload: ScriptUpdatePayload = serde_json::from_str(&json).ok()?;
+            MessageType::ScriptUpdate(payload)
+        }
+        "DEVICE_LOG" => {
+            let payload: DeviceLogPayload = serde_json::from_str(&json).ok()?;
+            MessageType::DeviceLog(payload)
+        }
+        "NET_STATUS" => {
+            let payload: NetStatusPayload = serde_json::from_str(&json).ok()?;
+            MessageType::NetStatus(payload)
+        }
+        "STORED_SMS" => {
+            let payload: StoredSmsPayload = serde_json::from_str(&json).ok()?;
+            MessageType::StoredSms(payload)
+        }
+        "STORED_DELETED" => {
+            let payload: StoredDeletedPayload = serde_json::from_str(&json).ok()?;
+            MessageType::StoredDeleted(payload)
+        }
+        "SYSTEM_INIT" => {
+            let payload: serde_json::Value = serde_json::from_str(&json).ok()?;
+            MessageType::SystemInit(payload)
+        }
+        "HEART_BEAT" => {
+            let payload: serde_json::Value = serde_json::from_str(&json).ok()?;
+            MessageType::HeartBeat(payload)
+        }
+        _ => MessageType::Unknown(kind),
+    };
+
+    Some(ParsedMessage { id, message_type })
+}
+
+// Only a stream that ends with its checksum and nothing after it is accepted,
+// a payload cut inside the trailer still inflates to the whole text
+fn inflate(compressed: &[u8]) -> Option<Vec<u8>> {
+    let mut inflater = Decompress::new(true);
+    let mut decompressed = Vec::new();
+    loop {
+        let consumed = inflater.total_in() as usize;
+        decompressed.reserve(INFLATE_CHUNK_BYTES);
+        let status = match inflater.decompress_vec(
+            &compressed[consumed..],
+            &mut decompressed,
+            FlushDecompress::None,
+        ) {
+            Ok(status) => status,
+            Err(e) => {
+                log::warn!("Failed to decompress payload: {}", e);
+                return None;
+            }
+        };
+        if decompressed.len() as u64 > MAX_DECOMPRESSED_BYTES {
+            log::warn!(
+                "Decompressed payload exceeds {} bytes, dropping",
+                MAX_DECOMPRESSED_BYTES
+            );
+            return None;
+        }
+        match status {
+            Status::StreamEnd => break,
+            // Stuck with room left for output, so the input ran out
+            Status::BufError if decompressed.len() < decompressed.capacity() => {
+                log::warn!("Failed to decompress payload: truncated stream");
+                return None;
+            }
+            Status::BufError | Status::Ok => {}
+        }
+    }
+    if inflater.total_in() as usize != compressed.len() {
+        log::warn!("Failed to decompress payload: trailing data");
+        return None;
+    }
+    Some(decompressed)
+}
@@ -0,0 +1,92 @@
+use crate::config::QuietHoursConfig;
+use crate::forwarding;
+use crate::timezone::TimeFormatter;
+use anyhow::{Context, Result};
+use chrono::NaiveTime;
+
+// Local time range in which queued SMS are held back, the end is exclusive
+#[derive(Debug, Clone, Copy)]
+pub struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Window {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            // Wraps midnight, e.g. 22:00-08:00
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+// "HH:MM-HH:MM", None for "" which means no quiet hours
+pub fn parse_window(text: &str) -> Result<Option<Window>> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+    let (start, end) = text
+        .split_once('-')
+        .context("Expected HH:MM-HH:MM, e.g. 22:00-08:00")?;
+    let parse = |time: &str| {
+        NaiveTime::parse_from_str(time.trim(), "%H:%M")
+            .context(format!("Invalid time {}, expected HH:MM", time.trim()))
+    };
+    let window = Window {
+        start: parse(start)?,
+        end: parse(end)?,
+    };
+    if window.start == window.end {
+        anyhow::bail!("Window {} is empty", text);
+    }
+    Ok(Some(window))
+}
+
+// Decides whether a queued SMS may go out now or waits for the morning
+#[derive(Debug, Clone)]
+pub struct QuietHours {
+    time: TimeFormatter,
+    window: Option<Window>,
+    // Normalized numbers with their own window
+    recipients: Vec<(String, Option<Window>)>,
+}
+
+impl QuietHours {
+    pub fn new(config: &QuietHoursConfig, time: TimeFormatter) -> Result<Self> {
+        let window = config
+            .window
+            .as_deref()
+            .map(parse_window)
+            .transpose()?
+            .flatten();
+        let mut recipients = Vec::new();
+        for recipient in &config.recipients {
+            let window = parse_window(&recipient.window)?;
+            for number in &recipient.numbers {
+                recipients.push((forwarding::normalize_number(number), window));
+            }
+        }
+        Ok(QuietHours {
+            time,
+            window,
+            recipients,
+        })
+    }
+
+    // Whether texting `recipient` at `epoch` falls into its quiet hours
+    pub fn holds(&self, recipient: &str, epoch: i64) -> bool {
+        let number = forwarding::normalize_number(recipient);
+        let window = self
+            .recipients
+            .iter()
+            .find(|(n, _)| *n == number)
+            .map_or(self.window, |(_, window)| *window);
+        match (window, self.time.time_of_day(epoch)) {
+            (Some(window), Some(time)) => window.contains(time),
+            _ => false,
+        }
+    }
+}
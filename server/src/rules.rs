@@ -0,0 +1,189 @@
+use crate::config::RuleConfig;
+use crate::forwarding;
+use crate::sender::{self, SenderInfo, SenderType};
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    sender: Option<Regex>,
+    content: Option<Regex>,
+    sender_type: Option<SenderType>,
+    countries: Vec<String>,
+    foreign: Option<bool>,
+    sender_ids: Vec<String>,
+    message_class: Option<u8>,
+    pub forward_to: Vec<String>,
+    pub critical: bool,
+    pub spam: bool,
+    group: Option<String>,
+}
+
+impl Rule {
+    pub fn from_config(config: &RuleConfig) -> Result<Self> {
+        let sender = config
+            .sender
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .context(format!("Invalid sender regex in rule '{}'", config.name))?;
+        let content = config
+            .content
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .context(format!("Invalid content regex in rule '{}'", config.name))?;
+        let sender_type = config
+            .sender_type
+            .as_deref()
+            .map(|kind| {
+                SenderType::parse(kind).context(format!(
+                    "Invalid sender_type '{}' in rule '{}'",
+                    kind, config.name
+                ))
+            })
+            .transpose()?;
+
+        Ok(Rule {
+            name: config.name.clone(),
+            sender,
+            content,
+            sender_type,
+            countries: config
+                .countries
+                .iter()
+                .map(|country| country.to_uppercase())
+                .collect(),
+            foreign: config.foreign,
+            sender_ids: config
+                .sender_ids
+                .iter()
+                .map(|id| sender::normalize_id(id))
+                .collect(),
+            message_class: config.message_class,
+            forward_to: config.forward_to.clone(),
+            critical: config.critical,
+            spam: config.spam,
+            group: config.group.clone(),
+        })
+    }
+
+    pub fn matches(
+        &self,
+        sender: &str,
+        info: &SenderInfo,
+        content: &str,
+        class: Option<u8>,
+    ) -> bool {
+        // A rule without any matcher applies to every message
+        self.sender.as_ref().is_none_or(|re| re.is_match(sender))
+            && self.content.as_ref().is_none_or(|re| re.is_match(content))
+            && self.sender_type.is_none_or(|kind| kind == info.kind)
+            && (self.countries.is_empty()
+                || info
+                    .country
+                    .as_ref()
+                    .is_some_and(|country| self.countries.contains(country)))
+            && self
+                .foreign
+                .is_none_or(|foreign| info.foreign == Some(foreign))
+            && (self.sender_ids.is_empty()
+                || info.id.as_ref().is_some_and(|id| {
+                    self.sender_ids
+                        .iter()
+                        .any(|pattern| sender::id_matches(pattern, id))
+                }))
+            && self
+                .message_class
+                .is_none_or(|wanted| class == Some(wanted))
+    }
+}
+
+// The received SMS as passed to a message hook
+#[derive(Debug, Clone, Serialize)]
+pub struct HookMessage {
+    pub id: String,
+    pub sender: String,
+    // "shortcode", "number" or "alphanumeric"
+    pub sender_type: &'static str,
+    pub sender_country: Option<String>,
+    // 0 for flash SMS
+    pub message_class: Option<u8>,
+    pub content: String,
+    pub received_at: i64,
+    pub device_id: Option<String>,
+}
+
+// What a message hook decided, applied on top of the matching rules
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct HookActions {
+    // Don't notify, the message is still stored and acknowledged
+    pub suppress: bool,
+    // Replaces the content in the notification and forwarded SMS
+    pub content: Option<String>,
+    pub group: Option<String>,
+    pub critical: bool,
+    pub forward_to: Vec<String>,
+}
+
+impl HookActions {
+    // Actions of a later hook: its content and group win, flags and targets add up
+    pub fn merge(&mut self, later: HookActions) {
+        self.suppress |= later.suppress;
+        self.critical |= later.critical;
+        if later.content.is_some() {
+            self.content = later.content;
+        }
+        if later.group.is_some() {
+            self.group = later.group;
+        }
+        self.forward_to.extend(later.forward_to);
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RuleEngine {
+    rules: Vec<Rule>,
+}
+
+impl RuleEngine {
+    pub fn new(configs: &[RuleConfig]) -> Result<Self> {
+        let rules = configs
+            .iter()
+            .map(Rule::from_config)
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(RuleEngine { rules })
+    }
+
+    // Notification group of the first matching rule that sets one
+    pub fn group(
+        &self,
+        sender: &str,
+        info: &SenderInfo,
+        content: &str,
+        class: Option<u8>,
+    ) -> Option<String> {
+        self.matching(sender, info, content, class)
+            .find_map(|rule| {
+                rule.group
+                    .as_ref()
+                    .map(|group| forwarding::expand(group, sender, &rule.name))
+            })
+    }
+
+    pub fn matching<'a>(
+        &'a self,
+        sender: &'a str,
+        info: &'a SenderInfo,
+        content: &'a str,
+        class: Option<u8>,
+    ) -> impl Iterator<Item = &'a Rule> + 'a {
+        self.rules
+            .iter()
+            .filter(move |rule| rule.matches(sender, info, content, class))
+    }
+}
@@ -0,0 +1,142 @@
+use crate::audit;
+use crate::config::ScheduleConfig;
+use crate::database::{self, Database};
+use crate::timezone::TimeFormatter;
+use crate::watchdog::Heartbeat;
+use anyhow::Result;
+use croner::Cron;
+use std::time::Duration;
+
+// How often schedules are checked for a due run
+const CHECK_INTERVAL_SECS: u64 = 30;
+
+#[derive(Clone)]
+pub struct Schedule {
+    pub name: String,
+    pub expression: String,
+    cron: Cron,
+    pub to: String,
+    body: String,
+    pub device: Option<String>,
+    urgent: bool,
+}
+
+impl Schedule {
+    pub fn from_config(config: &ScheduleConfig) -> Result<Self> {
+        let cron = Cron::new(&config.cron).parse().map_err(|e| {
+            anyhow::anyhow!(
+                "Invalid cron expression '{}' in schedule '{}': {}",
+                config.cron,
+                config.name,
+                e
+            )
+        })?;
+
+        Ok(Schedule {
+            name: config.name.clone(),
+            expression: config.cron.clone(),
+            cron,
+            to: config.to.clone(),
+            body: config.body.clone(),
+            device: config.device.clone(),
+            urgent: config.urgent,
+        })
+    }
+}
+
+// Queues the SMS of every due schedule, the connections send them like bulk SMS.
+// A schedule first runs at its next occurrence after being added, and runs
+// missed while the server was down are made up once at startup
+#[derive(Clone)]
+pub struct Scheduler {
+    db: Database,
+    schedules: Vec<Schedule>,
+    time: TimeFormatter,
+}
+
+impl Scheduler {
+    pub fn new(configs: &[ScheduleConfig], time: TimeFormatter, db: Database) -> Result<Self> {
+        let schedules = configs
+            .iter()
+            .map(Schedule::from_config)
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Scheduler {
+            db,
+            schedules,
+            time,
+        })
+    }
+
+    pub fn schedules(&self) -> &[Schedule] {
+        &self.schedules
+    }
+
+    // When the schedule runs next, None when it never ran and is not yet tracked
+    pub fn next_run(&self, schedule: &Schedule) -> Result<Option<i64>> {
+        let last_run = self.db.schedule_last_run(&schedule.name)?;
+        Ok(last_run.and_then(|last_run| self.time.next_cron(&schedule.cron, last_run)))
+    }
+
+    pub async fn run(self, heartbeat: Heartbeat) {
+        log::info!(
+            "Scheduler started with {} schedule(s)",
+            self.schedules.len()
+        );
+
+        loop {
+            for schedule in &self.schedules {
+                if let Err(e) = self.run_if_due(schedule) {
+                    log::warn!("Schedule '{}': {:#}", schedule.name, e);
+                }
+            }
+            heartbeat.beat(Duration::from_secs(CHECK_INTERVAL_SECS));
+            tokio::time::sleep(Duration::from_secs(CHECK_INTERVAL_SECS)).await;
+        }
+    }
+
+    fn run_if_due(&self, schedule: &Schedule) -> Result<()> {
+        let now = database::unix_timestamp();
+        let Some(last_run) = self.db.schedule_last_run(&schedule.name)? else {
+            return self.db.set_schedule_run(&schedule.name, now);
+        };
+        let Some(next_run) = self.time.next_cron(&schedule.cron, last_run) else {
+            return Ok(());
+        };
+        if next_run > now {
+            return Ok(());
+        }
+
+        let body = self.render(schedule, now);
+        let batch_id = format!("schedule-{}", schedule.name);
+        self.db.queue_outbound(
+            Some(&batch_id),
+            schedule.device.as_deref(),
+            schedule.urgent,
+            &[(schedule.to.clone(), body)],
+        )?;
+        self.db.set_schedule_run(&schedule.name, now)?;
+        log::info!(
+            "Schedule '{}': queued SMS to {}",
+            schedule.name,
+            schedule.to
+        );
+        audit::record(
+            &self.db,
+            "scheduler",
+            "send_sms",
+            Some(&format!("to {} (schedule {})", schedule.to, schedule.name)),
+        );
+
+        Ok(())
+    }
+
+    fn render(&self, schedule: &Schedule, now: i64) -> String {
+        schedule
+            .body
+            .replace("{name}", &schedule.name)
+            .replace("{date}", &self.time.format(now, "%Y-%m-%d"))
+            .replace("{time}", &self.time.format(now, "%H:%M"))
+            .replace("{month}", &self.time.format(now, "%Y-%m"))
+    }
+}
@@ -0,0 +1,166 @@
+use crate::config::SerialConfig;
+use crate::error::{self, AppError};
+use crate::port_access;
+use crate::serial_port::{self, FrameRead, MessageType, ScriptUpdatePayload, UpdateScriptCommand};
+use anyhow::{Context, Result};
+use base64::{Engine as _, engine::general_purpose};
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
+use tokio_serial::SerialPortBuilderExt;
+
+// Raw script bytes per UPDATE_SCRIPT chunk, kept well below the device line buffer
+const CHUNK_BYTES: usize = 512;
+// How long to wait for the device to answer each step
+const REPLY_TIMEOUT_MS: u64 = 5000;
+// Chunks are re-sent this many times before the transfer is aborted
+const CHUNK_ATTEMPTS: u32 = 3;
+// Scripts that run before the rollback check cannot be rolled back once they fail to boot
+const PROTECTED_SCRIPTS: &[&str] = &["main.lua", "script_updater.lua", "util.lua"];
+
+// Push a device-side script over UART. The device verifies the checksum before
+// replacing the script and restores the previous version if it fails to boot.
+pub async fn flash(serial: &SerialConfig, port_override: Option<&str>, file: &Path) -> Result<()> {
+    let name = file
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context(format!("Invalid script path: {}", file.display()))?
+        .to_string();
+    if !name.ends_with(".lua") {
+        anyhow::bail!("Only .lua scripts can be flashed, got {}", name);
+    }
+    if PROTECTED_SCRIPTS.contains(&name.as_str()) {
+        anyhow::bail!(
+            "{} cannot be updated over UART, flash it with LuaTools",
+            name
+        );
+    }
+    let script =
+        std::fs::read(file).context(format!("Failed to read script: {}", file.display()))?;
+
+    let (port_name, baud_rate) = port_access::find_device(serial, port_override).await?;
+    log::info!("Flashing {} to {} at {} baud", name, port_name, baud_rate);
+
+    let port = tokio_serial::new(&port_name, baud_rate)
+        .timeout(Duration::from_millis(serial.timeout_ms))
+        .open_native_async()
+        .map_err(|e| error::port_open_error(&port_name, &e))?;
+    let (reader, mut writer) = tokio::io::split(port);
+    let mut transfer = Transfer {
+        reader: BufReader::new(reader),
+        frame: Vec::new(),
+        max_frame_bytes: serial.max_frame_bytes,
+    };
+
+    let result = transfer.run(&mut writer, &name, &script).await;
+    if result.is_err() {
+        // The device discards the partial upload and keeps the current script
+        let _ = serial_port::send_update_script(&mut writer, &UpdateScriptCommand::Abort).await;
+    }
+    result
+}
+
+struct Transfer<R> {
+    reader: BufReader<R>,
+    frame: Vec<u8>,
+    max_frame_bytes: usize,
+}
+
+impl<R: AsyncRead + Unpin> Transfer<R> {
+    async fn run<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        name: &str,
+        script: &[u8],
+    ) -> Result<()> {
+        let mut crc = flate2::Crc::new();
+        crc.update(script);
+        let begin = UpdateScriptCommand::Begin {
+            name: name.to_string(),
+            size: script.len(),
+            crc: crc.sum(),
+        };
+        serial_port::send_update_script(writer, &begin).await?;
+        self.expect_ok("begin", None).await?;
+
+        let chunks = script.chunks(CHUNK_BYTES).collect::<Vec<_>>();
+        for (seq, chunk) in chunks.iter().enumerate() {
+            let seq = seq as u32;
+            let command = UpdateScriptCommand::Chunk {
+                seq,
+                data: general_purpose::STANDARD.encode(chunk),
+            };
+
+            let mut attempt = 1;
+            loop {
+                serial_port::send_update_script(writer, &command).await?;
+                match self.expect_ok("chunk", Some(seq)).await {
+                    Ok(()) => break,
+                    Err(e) if attempt < CHUNK_ATTEMPTS => {
+                        log::warn!("Chunk {} failed ({}), re-sending", seq, e);
+                        attempt += 1;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            log::info!("Sent chunk {}/{}", seq + 1, chunks.len());
+        }
+
+        serial_port::send_update_script(writer, &UpdateScriptCommand::Commit).await?;
+        self.expect_ok("commit", None)
+            .await
+            .context("Device rejected the script")?;
+
+        log::info!(
+            "{} flashed ({} bytes), the device restarts and rolls back if it fails to boot",
+            name,
+            script.len()
+        );
+        Ok(())
+    }
+
+    // Wait for the SCRIPT_UPDATE reply to `op`, ignoring unrelated frames
+    async fn expect_ok(&mut self, op: &str, seq: Option<u32>) -> Result<()> {
+        let reply = tokio::time::timeout(
+            Duration::from_millis(REPLY_TIMEOUT_MS),
+            self.next_reply(op, seq),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("No reply to {} within {}ms", op, REPLY_TIMEOUT_MS))??;
+
+        if !reply.ok {
+            return Err(AppError::Protocol(format!(
+                "Device reported an error on {}: {}",
+                op,
+                reply.error.as_deref().unwrap_or("unknown error")
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    async fn next_reply(&mut self, op: &str, seq: Option<u32>) -> Result<ScriptUpdatePayload> {
+        loop {
+            match serial_port::read_frame(&mut self.reader, &mut self.frame, self.max_frame_bytes)
+                .await?
+            {
+                FrameRead::Eof => anyhow::bail!("Connection closed"),
+                FrameRead::Oversized(_) => continue,
+                FrameRead::Frame => {}
+            }
+
+            let Some(msg) = std::str::from_utf8(&self.frame)
+                .ok()
+                .and_then(serial_port::parse_message)
+            else {
+                continue;
+            };
+            match msg.message_type {
+                MessageType::ScriptUpdate(reply) if reply.op == op && reply.seq == seq => {
+                    return Ok(reply);
+                }
+                other => log::debug!("Ignoring frame during script update: {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use crate::audit;
+use crate::database::{self, Database, OUTBOUND_FAILED};
+use anyhow::{Context, Result};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::time::{Duration, Instant};
+
+// Starts the text of every test SMS. Received back, it is stored and
+// acknowledged like any SMS but never notified, forwarded or given to the
+// rules and hooks
+pub const PREFIX: &str = "AIR780E-SELF-TEST";
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn is_self_test(content: &str) -> bool {
+    content.trim_start().starts_with(PREFIX)
+}
+
+// A test SMS that made the round trip
+pub struct RoundTrip {
+    pub sms_id: String,
+    pub elapsed: Duration,
+}
+
+// Have the running server send a test SMS to `number` from `device`, and wait
+// until it was received. Fails when the device could not send it or it is not
+// back within `timeout`
+pub async fn run(
+    db: &Database,
+    device: Option<&str>,
+    number: &str,
+    timeout: Duration,
+) -> Result<RoundTrip> {
+    let mut token = [0u8; 6];
+    SystemRandom::new()
+        .fill(&mut token)
+        .map_err(|_| anyhow::anyhow!("Failed to generate a self-test token"))?;
+    let token: String = token.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let content = format!("{} {}", PREFIX, token);
+
+    let started = Instant::now();
+    let since = database::unix_timestamp();
+    // Urgent, so quiet hours never make the test fail
+    let ids = db.queue_outbound(None, device, true, &[(number.to_string(), content.clone())])?;
+    let id = ids[0];
+    audit::record(
+        db,
+        &audit::cli_user(),
+        "self_test",
+        Some(&format!("to {}", number)),
+    );
+
+    loop {
+        if let Some(sms_id) = db.find_received(since, &content)? {
+            return Ok(RoundTrip {
+                sms_id,
+                elapsed: started.elapsed(),
+            });
+        }
+        let (status, error) = db
+            .outbound_status(id)?
+            .context("The test SMS disappeared from the outbound queue")?;
+        if status == OUTBOUND_FAILED {
+            anyhow::bail!(
+                "The device could not send the test SMS to {}: {}",
+                number,
+                error.as_deref().unwrap_or("unknown error")
+            );
+        }
+        if started.elapsed() >= timeout {
+            anyhow::bail!(
+                "The test SMS to {} was not received back within {}s (outbound SMS {} is {})",
+                number,
+                timeout.as_secs(),
+                id,
+                status
+            );
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
@@ -0,0 +1,144 @@
+use crate::forwarding;
+use anyhow::Result;
+use phonenumber::Mode;
+use phonenumber::country::Id;
+
+// Senders of at most this many digits, written without a country code, are short codes
+const SHORTCODE_MAX_DIGITS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SenderType {
+    Shortcode,
+    Number,
+    // Names such as "AMAZON", used by most OTP senders
+    Alphanumeric,
+}
+
+impl SenderType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SenderType::Shortcode => "shortcode",
+            SenderType::Number => "number",
+            SenderType::Alphanumeric => "alphanumeric",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "shortcode" => Some(SenderType::Shortcode),
+            "number" => Some(SenderType::Number),
+            "alphanumeric" => Some(SenderType::Alphanumeric),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SenderInfo {
+    pub kind: SenderType,
+    // ISO 3166 code such as "CN", None when it can't be told
+    pub country: Option<String>,
+    // Whether the country differs from the configured home country
+    pub foreign: Option<bool>,
+    // Normalized alphanumeric id, see `normalize_id`
+    pub id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SenderClassifier {
+    home: Option<Id>,
+}
+
+impl SenderClassifier {
+    pub fn new(home_country: Option<&str>) -> Result<Self> {
+        let home = home_country
+            .map(|code| {
+                code.to_uppercase()
+                    .parse::<Id>()
+                    .map_err(|_| anyhow::anyhow!("Unknown country code '{}'", code))
+            })
+            .transpose()?;
+
+        Ok(SenderClassifier { home })
+    }
+
+    // Numbers without a country code are read as numbers of the home country
+    pub fn classify(&self, sender: &str) -> SenderInfo {
+        let sender = sender.trim();
+        if is_alphanumeric(sender) {
+            return SenderInfo {
+                kind: SenderType::Alphanumeric,
+                country: None,
+                foreign: None,
+                id: Some(normalize_id(sender)),
+            };
+        }
+
+        let international = sender.starts_with('+') || sender.starts_with("00");
+        let digits = forwarding::normalize_number(sender);
+        if !international && digits.len() <= SHORTCODE_MAX_DIGITS {
+            // Short codes only work within their own network
+            return SenderInfo {
+                kind: SenderType::Shortcode,
+                country: self.home.map(|home| home.as_ref().to_string()),
+                foreign: self.home.map(|_| false),
+                id: None,
+            };
+        }
+
+        let number = if let Some(rest) = sender.strip_prefix("00") {
+            format!("+{}", rest)
+        } else {
+            sender.to_string()
+        };
+        let country = phonenumber::parse(self.home, &number)
+            .ok()
+            .and_then(|number| number.country().id());
+        SenderInfo {
+            kind: SenderType::Number,
+            country: country.map(|id| id.as_ref().to_string()),
+            foreign: country
+                .zip(self.home)
+                .map(|(country, home)| country != home),
+            id: None,
+        }
+    }
+
+    // The number in international form such as "+8613800000000", None for
+    // short codes, alphanumeric ids and numbers that don't parse
+    pub fn international(&self, number: &str) -> Option<String> {
+        let number = number.trim();
+        if self.classify(number).kind != SenderType::Number {
+            return None;
+        }
+        let number = match number.strip_prefix("00") {
+            Some(rest) => format!("+{}", rest),
+            None => number.to_string(),
+        };
+        phonenumber::parse(self.home, &number)
+            .ok()
+            .map(|number| number.format().mode(Mode::E164).to_string())
+    }
+}
+
+// Networks differ in the case and spacing of alphanumeric ids, "Amazon" and
+// "AMAZON " are the same sender
+pub fn normalize_id(id: &str) -> String {
+    id.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_uppercase()
+}
+
+pub fn is_alphanumeric(sender: &str) -> bool {
+    sender.chars().any(char::is_alphabetic)
+}
+
+// Matches an alphanumeric id against a rule pattern, `BANK*` matches every id
+// starting with "BANK"
+pub fn id_matches(pattern: &str, id: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => id.starts_with(prefix),
+        None => id == pattern,
+    }
+}
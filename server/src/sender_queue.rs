@@ -0,0 +1,77 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+// A notification waiting in its sender's queue
+#[derive(Debug, Clone)]
+pub struct QueuedNotification {
+    pub id: String,
+    pub sender: String,
+    pub content: String,
+    pub title: String,
+    pub body: String,
+    pub group: Option<String>,
+}
+
+// FIFO queues per sender for `[notification] strict_order`: a notification that
+// failed holds back the later ones of its sender until it gets through, so
+// multi-part messages never arrive out of order
+#[derive(Default)]
+pub struct SenderQueues {
+    queues: Mutex<HashMap<String, VecDeque<QueuedNotification>>>,
+}
+
+impl SenderQueues {
+    // Messages already queued are not queued twice, e.g. when the held
+    // notifications are sent after a pause
+    pub fn push(&self, notification: QueuedNotification) {
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(notification.sender.clone()).or_default();
+        if !queue.iter().any(|queued| queued.id == notification.id) {
+            queue.push_back(notification);
+        }
+    }
+
+    pub fn waiting(&self, sender: &str) -> usize {
+        self.queues
+            .lock()
+            .unwrap()
+            .get(sender)
+            .map_or(0, VecDeque::len)
+    }
+
+    pub fn front(&self, sender: &str) -> Option<QueuedNotification> {
+        self.queues
+            .lock()
+            .unwrap()
+            .get(sender)
+            .and_then(|queue| queue.front().cloned())
+    }
+
+    pub fn pop(&self, sender: &str) {
+        let mut queues = self.queues.lock().unwrap();
+        if let Some(queue) = queues.get_mut(sender) {
+            queue.pop_front();
+            if queue.is_empty() {
+                queues.remove(sender);
+            }
+        }
+    }
+
+    // Notifications waiting across all senders
+    pub fn len(&self) -> usize {
+        self.queues
+            .lock()
+            .unwrap()
+            .values()
+            .map(VecDeque::len)
+            .sum()
+    }
+
+    pub fn senders(&self) -> Vec<String> {
+        self.queues.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.lock().unwrap().is_empty()
+    }
+}
@@ -1,92 +1,177 @@
-use regex::Regex;
-use serde::{Deserialize, Serialize};
+use crate::config::SerialConfig;
+use crate::error::AppError;
+use crate::port_access;
+pub use crate::protocol::*;
+use crate::udev;
+use serde::Serialize;
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
 use tokio::task::JoinSet;
 use tokio_serial::SerialPortBuilderExt;
 
-const TIMEOUT_MS: u64 = 1000;
-// The handshake command
+// The handshake command, the ":z" argument offers zlib compression to the device
 const INIT_CMD: &[u8] = b"CMD:GET_DEVICE_INFO\r\n";
+const INIT_CMD_COMPRESSED: &[u8] = b"CMD:GET_DEVICE_INFO:z\r\n";
+const LIST_STORED_CMD: &[u8] = b"CMD:LIST_STORED\r\n";
+const READ_RECEIPTS_CMD: &[u8] = b"CMD:READ_RECEIPTS\r\n";
+const ACK_REQUESTS_CMD: &[u8] = b"CMD:ACK_REQUESTS\r\n";
+// Common UART speeds probed when the baud rate is "auto", most likely first
+pub const AUTO_BAUD_RATES: &[u32] = &[115200, 9600, 57600, 230400];
 // Auto-detection retry settings (infinite retries for background service)
 const AUTO_DETECT_RETRY_DELAY_MS: u64 = 30000; // 30 seconds between retries
+// USB devices that are never the modem and may misbehave when sent the handshake,
+// as (vendor id, product id or None for any product of the vendor)
+const PROBE_DENY_LIST: &[(u16, Option<u16>, &str)] = &[
+    (0x2341, None, "Arduino"),
+    (0x2a03, None, "Arduino"),
+    (0x1366, None, "SEGGER J-Link"),
+    (0x0483, Some(0x3748), "ST-Link"),
+    (0x0483, Some(0x374b), "ST-Link"),
+    (0x0483, Some(0x374e), "ST-Link"),
+    (0x0483, Some(0x374f), "ST-Link"),
+    (0x0483, Some(0x3753), "ST-Link"),
+    (0x0d28, Some(0x0204), "CMSIS-DAP probe"),
+    (0x1d50, Some(0x6018), "Black Magic Probe"),
+    (0x2e8a, Some(0x000c), "Raspberry Pi Debug Probe"),
+    (0x303a, None, "Espressif board"),
+];
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SmsPayload {
-    pub id: String,
-    pub sender: String,
-    pub content: String,
-    pub received_at: i64,
-    pub metas: Option<serde_json::Value>,
+// Numeric components of a dotted version such as "1.2.0", trailing zeros
+// trimmed so "1.2" and "1.2.0" compare equal
+pub fn parse_version(version: &str) -> Option<Vec<u32>> {
+    let mut parts = version
+        .trim()
+        .trim_start_matches(['v', 'V'])
+        .split('.')
+        .map(|part| part.parse::<u32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    while parts.last() == Some(&0) {
+        parts.pop();
+    }
+    Some(parts)
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DeviceInfoPayload {
-    pub imei: String,
-    pub number: String,
-    pub status: i32,
-    pub rssi: i32,
-    pub iccid: String,
-    pub timestamp: i64,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameRead {
+    Frame,
+    // The frame was longer than the limit and has been skipped up to its newline
+    Oversized(usize),
+    Eof,
 }
 
-#[derive(Debug, Clone)]
-pub enum MessageType {
-    DeviceInfo(DeviceInfoPayload),
-    SmsReceived(SmsPayload),
-    SystemInit(serde_json::Value),
-    HeartBeat(serde_json::Value),
-    Unknown(String),
+// Counts the bytes written to the device for the session statistics
+pub struct CountingWriter<W> {
+    inner: W,
+    written: u64,
 }
 
-#[derive(Debug, Clone)]
-pub struct ParsedMessage {
-    pub id: String,
-    pub message_type: MessageType,
-}
-
-pub fn parse_message(line: &str) -> Option<ParsedMessage> {
-    // Parse format: {uuid}:{type}:{base64}\r\n
-    let re = Regex::new(r"^(.+?):(.+?):(.+?)[\r\n]*$").ok()?;
-    let captures = re.captures(line)?;
-
-    let id = captures.get(1)?.as_str().to_string();
-    let msg_type = captures.get(2)?.as_str();
-    let base64_data = captures.get(3)?.as_str();
-
-    // Decode base64
-    use base64::{Engine as _, engine::general_purpose};
-    let decoded = general_purpose::STANDARD.decode(base64_data).ok()?;
-    let json_str = String::from_utf8(decoded).ok()?;
-
-    log::debug!(
-        "Parsed message - ID: {}, Type: {}, JSON: {}",
-        id,
-        msg_type,
-        json_str
-    );
+impl<W> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        CountingWriter { inner, written: 0 }
+    }
 
-    let message_type = match msg_type {
-        "DEVICE_INFO" => {
-            let payload: DeviceInfoPayload = serde_json::from_str(&json_str).ok()?;
-            MessageType::DeviceInfo(payload)
-        }
-        "SMS_RECEIVED" => {
-            let payload: SmsPayload = serde_json::from_str(&json_str).ok()?;
-            MessageType::SmsReceived(payload)
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = &poll {
+            this.written += *n as u64;
         }
-        "SYSTEM_INIT" => {
-            let payload: serde_json::Value = serde_json::from_str(&json_str).ok()?;
-            MessageType::SystemInit(payload)
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+// Read one '\n'-terminated frame into `buf`, skipping frames longer than `max_len`
+// without buffering them. A read cancelled by a timeout leaves the partial frame
+// in `buf`, the next call completes it.
+pub async fn read_frame<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    max_len: usize,
+) -> std::io::Result<FrameRead> {
+    if buf.ends_with(b"\n") {
+        buf.clear();
+    }
+    let bytes_read = (&mut *reader)
+        .take((max_len + 1).saturating_sub(buf.len()) as u64)
+        .read_until(b'\n', buf)
+        .await?;
+
+    if bytes_read == 0 {
+        return Ok(FrameRead::Eof);
+    }
+    if buf.ends_with(b"\n") {
+        return Ok(FrameRead::Frame);
+    }
+    if buf.len() <= max_len {
+        // Only the end of the stream stops short of both the newline and the
+        // limit, the fragment would not parse
+        log::debug!("Stream ended in the middle of a {} byte frame", buf.len());
+        buf.clear();
+        return Ok(FrameRead::Eof);
+    }
+
+    // Discard the rest of the oversized frame
+    let mut skipped = buf.len();
+    buf.clear();
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            break;
         }
-        "HEART_BEAT" => {
-            let payload: serde_json::Value = serde_json::from_str(&json_str).ok()?;
-            MessageType::HeartBeat(payload)
+        match available.iter().position(|b| *b == b'\n') {
+            Some(pos) => {
+                reader.consume(pos + 1);
+                skipped += pos + 1;
+                break;
+            }
+            None => {
+                let len = available.len();
+                reader.consume(len);
+                skipped += len;
+            }
         }
-        _ => MessageType::Unknown(msg_type.to_string()),
-    };
+    }
 
-    Some(ParsedMessage { id, message_type })
+    Ok(FrameRead::Oversized(skipped))
+}
+
+// A frame still waiting for its newline that parses into a whole message. The
+// device went quiet after sending it. A cut on a base64 boundary still decodes,
+// so only a payload that also parses counts, anything less keeps buffering
+pub fn is_unterminated_frame(buf: &[u8]) -> bool {
+    !buf.is_empty()
+        && !buf.ends_with(b"\n")
+        && std::str::from_utf8(buf)
+            .ok()
+            .and_then(parse_message)
+            .is_some()
 }
 
 pub async fn send_ack<W: AsyncWriteExt + Unpin>(writer: &mut W, uuid: &str) -> std::io::Result<()> {
@@ -97,47 +182,141 @@ pub async fn send_ack<W: AsyncWriteExt + Unpin>(writer: &mut W, uuid: &str) -> s
     Ok(())
 }
 
-pub async fn auto_detect_port(baud_rate: u32) -> Option<String> {
+// Answers an ACK_REQ for an SMS the server never stored
+pub async fn send_resend<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    id: &str,
+) -> std::io::Result<()> {
+    writer
+        .write_all(format!("RESEND:{}\r\n", id).as_bytes())
+        .await?;
+    writer.flush().await?;
+    log::info!("Asked the device to resend message: {}", id);
+    Ok(())
+}
+
+pub async fn send_sms<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    payload: &SendSmsPayload,
+) -> std::io::Result<()> {
+    send_command(writer, "SEND_SMS", payload).await?;
+    log::info!("Sent SEND_SMS command to {}", payload.to);
+    Ok(())
+}
+
+pub async fn send_update_script<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    command: &UpdateScriptCommand,
+) -> std::io::Result<()> {
+    send_command(writer, "UPDATE_SCRIPT", command).await
+}
+
+// Scripts before 1.6.0 ignore the request id
+pub async fn send_list_stored<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    rid: Option<&str>,
+) -> std::io::Result<()> {
+    match rid {
+        Some(rid) => {
+            let request = RequestId {
+                rid: rid.to_string(),
+            };
+            send_command(writer, "LIST_STORED", &request).await
+        }
+        None => {
+            writer.write_all(LIST_STORED_CMD).await?;
+            writer.flush().await
+        }
+    }
+}
+
+// Ask the device to keep ACKed SMS until their read receipt
+pub async fn send_read_receipts_on<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+) -> std::io::Result<()> {
+    writer.write_all(READ_RECEIPTS_CMD).await?;
+    writer.flush().await
+}
+
+// Let the device ask for missing ACKs instead of sending the whole SMS again
+pub async fn send_ack_requests_on<W: AsyncWriteExt + Unpin>(writer: &mut W) -> std::io::Result<()> {
+    writer.write_all(ACK_REQUESTS_CMD).await?;
+    writer.flush().await
+}
+
+pub async fn send_read_receipt<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    id: &str,
+) -> std::io::Result<()> {
+    writer
+        .write_all(format!("CMD:SMS_READ:{}\r\n", id).as_bytes())
+        .await?;
+    writer.flush().await?;
+    log::info!("Sent read receipt for message: {}", id);
+    Ok(())
+}
+
+pub async fn send_delete_stored<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    command: &DeleteStoredCommand,
+) -> std::io::Result<()> {
+    send_command(writer, "DELETE_STORED", command).await
+}
+
+// CMD:{name}:{base64 json}
+async fn send_command<W: AsyncWriteExt + Unpin, T: Serialize>(
+    writer: &mut W,
+    name: &str,
+    payload: &T,
+) -> std::io::Result<()> {
+    let json_str = serde_json::to_string(payload)?;
+    let cmd = encode_frame("CMD", name, &json_str);
+    writer.write_all(cmd.as_bytes()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+// A port that answered the handshake, with the identity the device reported
+#[derive(Debug, Clone)]
+pub struct DetectedDevice {
+    pub port_name: String,
+    pub baud_rate: u32,
+    pub info: DeviceInfoPayload,
+}
+
+impl fmt::Display for DetectedDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}@{} (IMEI {}, number {})",
+            self.port_name, self.baud_rate, self.info.imei, self.info.number
+        )
+    }
+}
+
+// Keeps scanning until a matching device answers, fails when the choice is ambiguous
+pub async fn auto_detect_port(
+    serial: &SerialConfig,
+    baud_rates: Vec<u32>,
+) -> Result<DetectedDevice, AppError> {
     let mut attempt = 0;
     loop {
         attempt += 1;
         log::info!("Auto-detecting port (attempt {})", attempt);
 
-        let ports = tokio_serial::available_ports();
-        if let Err(err) = ports {
-            log::error!("Failed to list available ports, err={}", err);
-            log::info!("Retrying in {}ms...", AUTO_DETECT_RETRY_DELAY_MS);
-            tokio::time::sleep(Duration::from_millis(AUTO_DETECT_RETRY_DELAY_MS)).await;
-            continue;
-        }
-
-        let ports = ports.unwrap();
-        log::info!("Scanning {} ports...", ports.len());
-        let mut check_tasks = JoinSet::new();
-        for port in ports {
-            let port_name = port.port_name.clone();
-            check_tasks.spawn(async move { check_port(&port_name, baud_rate).await });
-        }
-
-        let mut results = Vec::new();
-        while let Some(res) = check_tasks.join_next().await {
-            if let Ok(port) = res
-                && let Some(valid_port) = port
-            {
-                results.push(valid_port);
+        let results = match scan_ports(serial, &baud_rates).await {
+            Ok(results) => results,
+            Err(err) => {
+                log::error!("Failed to list available ports, err={}", err);
+                log::info!("Retrying in {}ms...", AUTO_DETECT_RETRY_DELAY_MS);
+                tokio::time::sleep(Duration::from_millis(AUTO_DETECT_RETRY_DELAY_MS)).await;
+                continue;
             }
-        }
+        };
 
-        log::info!("--------------------------------------------------");
-        log::info!(
-            "Scan Complete. Found {} valid device(s), name=[{}]",
-            results.len(),
-            results.join(", ")
-        );
-
-        if !results.is_empty() {
-            log::info!("Successfully detected port: {}", results[0]);
-            return Some(results[0].clone());
+        if let Some(device) = select_device(results, serial.expected_imei.as_deref())? {
+            log::info!("Successfully detected port: {}", device);
+            return Ok(device);
         }
 
         log::warn!(
@@ -148,23 +327,189 @@ pub async fn auto_detect_port(baud_rate: u32) -> Option<String> {
     }
 }
 
-pub async fn check_port(port_name: &str, baud_rate: u32) -> Option<String> {
-    // Compile regex to match: {id}:DEVICE_INFO:{base64}\r\n
-    // Explanation:
-    // ^          Start of line
-    // (.+)       Group 1: The ID (any character except :)
-    // :DEVICE_INFO: Literal string
-    // ([a-zA-Z0-9+/=]+) Group 2: Base64 characters
-    // \s*$       End of line (allowing for \r\n)
-    let re = Regex::new(r"^(.+):DEVICE_INFO:([a-zA-Z0-9+/=]+)\s*$").ok()?;
+// The device with the pinned IMEI, or the only one that answered. Picking one of
+// several modems at random would attach to the wrong SIM, so that is an error.
+pub fn select_device(
+    found: Vec<DetectedDevice>,
+    expected_imei: Option<&str>,
+) -> Result<Option<DetectedDevice>, AppError> {
+    if let Some(imei) = expected_imei {
+        let (matching, others): (Vec<_>, Vec<_>) = found
+            .into_iter()
+            .partition(|device| device.info.imei == imei);
+        for device in &others {
+            log::info!("Ignoring {}, expecting IMEI {}", device, imei);
+        }
+        return Ok(matching.into_iter().next());
+    }
+
+    if found.len() > 1 {
+        return Err(AppError::Config(format!(
+            "Several devices answered: {}. Set expected_imei to choose one",
+            found
+                .iter()
+                .map(|device| device.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )));
+    }
+    Ok(found.into_iter().next())
+}
+
+// Probe every available port once, returning those that answered the handshake
+pub async fn scan_ports(
+    serial: &SerialConfig,
+    baud_rates: &[u32],
+) -> tokio_serial::Result<Vec<DetectedDevice>> {
+    let ports = tokio_serial::available_ports()?;
+    log::info!("Scanning {} ports...", ports.len());
+    let mut check_tasks = JoinSet::new();
+    for port in ports {
+        if let Some(reason) = probe_skip_reason(&port, &serial.probe_allow) {
+            log::info!("Skipping {}: {}", port.port_name, reason);
+            continue;
+        }
+        let port_name = udev::stable_path(&port.port_name).unwrap_or(port.port_name);
+        let baud_rates = baud_rates.to_vec();
+        let handshake_timeout_ms = serial.handshake_timeout_ms;
+        let compression = serial.compression;
+        check_tasks.spawn(async move {
+            probe_port(&port_name, &baud_rates, handshake_timeout_ms, compression)
+                .await
+                .map(|(baud_rate, info)| DetectedDevice {
+                    port_name,
+                    baud_rate,
+                    info,
+                })
+        });
+    }
 
+    let mut results = Vec::new();
+    while let Some(res) = check_tasks.join_next().await {
+        if let Ok(port) = res
+            && let Some(valid_port) = port
+        {
+            results.push(valid_port);
+        }
+    }
+    results.sort_by_key(|device| {
+        (
+            udev::preference(&device.port_name),
+            device.port_name.clone(),
+        )
+    });
+
+    log::info!("--------------------------------------------------");
+    log::info!(
+        "Scan Complete. Found {} valid device(s), name=[{}]",
+        results.len(),
+        results
+            .iter()
+            .map(|device| device.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    Ok(results)
+}
+
+// Ports the handshake should not be sent to, unless listed in `probe_allow`
+// by name or as "vid:pid"
+fn probe_skip_reason(
+    port: &tokio_serial::SerialPortInfo,
+    probe_allow: &[String],
+) -> Option<String> {
+    let usb = match &port.port_type {
+        tokio_serial::SerialPortType::UsbPort(usb) => Some((usb.vid, usb.pid)),
+        _ => None,
+    };
+    let allowed = probe_allow.iter().any(|entry| {
+        *entry == port.port_name
+            || usb.is_some_and(|(vid, pid)| {
+                entry.eq_ignore_ascii_case(&format!("{:04x}:{:04x}", vid, pid))
+            })
+    });
+    if allowed {
+        return None;
+    }
+
+    if let Some((vid, pid)) = usb
+        && let Some((_, _, name)) = PROBE_DENY_LIST
+            .iter()
+            .find(|(deny_vid, deny_pid, _)| *deny_vid == vid && deny_pid.is_none_or(|p| p == pid))
+    {
+        return Some(format!("{} ({:04x}:{:04x}) is not a modem", name, vid, pid));
+    }
+
+    port_access::in_use(&port.port_name).map(|holder| format!("in use by {}", holder))
+}
+
+pub fn handshake_command(compression: bool) -> &'static [u8] {
+    if compression {
+        INIT_CMD_COMPRESSED
+    } else {
+        INIT_CMD
+    }
+}
+
+// GET_DEVICE_INFO outside the handshake, the reply is sent under `rid` by
+// scripts that take one
+pub async fn send_device_info_request<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    compression: bool,
+    rid: Option<&str>,
+) -> std::io::Result<()> {
+    match rid {
+        Some(rid) => {
+            let command = DeviceInfoCommand {
+                compression,
+                rid: rid.to_string(),
+            };
+            send_command(writer, "GET_DEVICE_INFO", &command).await
+        }
+        None => {
+            writer.write_all(handshake_command(compression)).await?;
+            writer.flush().await
+        }
+    }
+}
+
+// Try the handshake at each baud rate in turn, returning the first that answers
+pub async fn probe_port(
+    port_name: &str,
+    baud_rates: &[u32],
+    handshake_timeout_ms: u64,
+    compression: bool,
+) -> Option<(u32, DeviceInfoPayload)> {
+    for &baud_rate in baud_rates {
+        if let Some(info) =
+            check_port(port_name, baud_rate, handshake_timeout_ms, compression).await
+        {
+            return Some((baud_rate, info));
+        }
+        if baud_rates.len() > 1 {
+            log::debug!("No handshake on {} at {} baud", port_name, baud_rate);
+        }
+    }
+    None
+}
+
+pub async fn check_port(
+    port_name: &str,
+    baud_rate: u32,
+    handshake_timeout_ms: u64,
+    compression: bool,
+) -> Option<DeviceInfoPayload> {
     // Attempt to open the port
     let mut port = tokio_serial::new(port_name, baud_rate)
-        .timeout(Duration::from_millis(TIMEOUT_MS))
+        .timeout(Duration::from_millis(handshake_timeout_ms))
         .open_native_async()
         .ok()?;
-    let write_result =
-        tokio::time::timeout(Duration::from_millis(TIMEOUT_MS), port.write_all(INIT_CMD)).await;
+    let write_result = tokio::time::timeout(
+        Duration::from_millis(handshake_timeout_ms),
+        port.write_all(handshake_command(compression)),
+    )
+    .await;
     if write_result.is_err() || write_result.unwrap().is_err() {
         return None;
     }
@@ -172,15 +517,17 @@ pub async fn check_port(port_name: &str, baud_rate: u32) -> Option<String> {
     let mut reader = BufReader::new(port);
     let mut response = String::new();
     let read_result = tokio::time::timeout(
-        Duration::from_millis(TIMEOUT_MS),
+        Duration::from_millis(handshake_timeout_ms),
         reader.read_line(&mut response),
     )
     .await;
     match read_result {
         Ok(Ok(bytes_read)) if bytes_read > 0 => {
-            // Check against Regex
-            if let Some(_captures) = re.captures(&response) {
-                return Some(port_name.to_string());
+            // Expect {id}:DEVICE_INFO:{base64}\r\n
+            if let Some(MessageType::DeviceInfo(info)) =
+                parse_message(&response).map(|msg| msg.message_type)
+            {
+                return Some(info);
             }
         }
         _ => {
@@ -0,0 +1,97 @@
+use crate::config::SpamConfig;
+use crate::database::{self, Database};
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Debug, Serialize)]
+struct ApiRequest<'a> {
+    sender: &'a str,
+    content: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiResponse {
+    score: f64,
+}
+
+pub struct SpamClassifier {
+    config: SpamConfig,
+    patterns: Vec<(Regex, f64)>,
+    client: reqwest::Client,
+}
+
+impl SpamClassifier {
+    pub fn new(config: &SpamConfig) -> Result<Self> {
+        let patterns = config
+            .patterns
+            .iter()
+            .map(|p| {
+                Regex::new(&p.pattern)
+                    .map(|re| (re, p.weight))
+                    .context(format!("Invalid spam pattern: {}", p.pattern))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(SpamClassifier {
+            config: config.clone(),
+            patterns,
+            client: reqwest::Client::new(),
+        })
+    }
+
+    pub fn is_spam(&self, score: f64) -> bool {
+        self.config.enabled && score >= self.config.threshold
+    }
+
+    // Returns None when the classifier is disabled
+    pub async fn score(&self, db: &Database, sender: &str, content: &str) -> Option<f64> {
+        if !self.config.enabled {
+            return None;
+        }
+
+        let mut score: f64 = self
+            .patterns
+            .iter()
+            .filter(|(re, _)| re.is_match(content))
+            .map(|(_, weight)| weight)
+            .sum();
+
+        // Sender reputation based on previously stored messages. Spam is only held
+        // against a sender for a while, a reused or reassigned number recovers
+        let spam_since = match self.config.spam_sender_days {
+            0 => 0,
+            days => database::unix_timestamp() - days as i64 * 86400,
+        };
+        match db.sender_history(sender, spam_since) {
+            Ok(history) if history.spam > 0 => score += self.config.spam_sender_weight,
+            Ok(history) if history.known > 0 => score += self.config.known_sender_weight,
+            Ok(_) => {}
+            Err(e) => log::warn!("Failed to look up sender reputation: {}", e),
+        }
+
+        if let Some(url) = &self.config.api_url {
+            match self.query_api(url, sender, content).await {
+                Ok(api_score) => score += api_score,
+                Err(e) => log::warn!("Spam API request failed: {}", e),
+            }
+        }
+
+        log::debug!("Spam score for message from {}: {}", sender, score);
+        Some(score)
+    }
+
+    async fn query_api(&self, url: &str, sender: &str, content: &str) -> Result<f64> {
+        let response = self
+            .client
+            .post(url)
+            .timeout(Duration::from_millis(self.config.api_timeout_ms))
+            .json(&ApiRequest { sender, content })
+            .send()
+            .await?
+            .error_for_status()?;
+        let body: ApiResponse = response.json().await?;
+        Ok(body.score)
+    }
+}
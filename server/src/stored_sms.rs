@@ -0,0 +1,124 @@
+use crate::config::SerialConfig;
+use crate::correlation::{Correlator, Request};
+use crate::error;
+use crate::port_access;
+use crate::serial_port::{
+    self, DeleteStoredCommand, FrameRead, MessageType, StoredDeletedPayload, StoredSmsPayload,
+};
+use anyhow::Result;
+use std::time::Duration;
+use tokio::io::{AsyncRead, BufReader, ReadHalf, WriteHalf};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+// How long to wait for the device to answer
+const REPLY_TIMEOUT_MS: u64 = 5000;
+
+// SMS the device keeps until the server acknowledges them
+pub async fn list(serial: &SerialConfig, port_override: Option<&str>) -> Result<StoredSmsPayload> {
+    let (mut session, mut writer) = Session::open(serial, port_override).await?;
+    let request = session.correlator.request("LIST_STORED", "STORED_SMS");
+    serial_port::send_list_stored(&mut writer, Some(&request.rid)).await?;
+    session
+        .reply(request, |message| match message {
+            MessageType::StoredSms(stored) => Some(stored),
+            _ => None,
+        })
+        .await
+}
+
+pub async fn delete(
+    serial: &SerialConfig,
+    port_override: Option<&str>,
+    command: &DeleteStoredCommand,
+) -> Result<StoredDeletedPayload> {
+    let (mut session, mut writer) = Session::open(serial, port_override).await?;
+    let request = session
+        .correlator
+        .request("DELETE_STORED", "STORED_DELETED");
+    let command = DeleteStoredCommand {
+        rid: Some(request.rid.clone()),
+        ..command.clone()
+    };
+    serial_port::send_delete_stored(&mut writer, &command).await?;
+    session
+        .reply(request, |message| match message {
+            MessageType::StoredDeleted(result) => Some(result),
+            _ => None,
+        })
+        .await
+}
+
+struct Session<R> {
+    reader: BufReader<R>,
+    frame: Vec<u8>,
+    max_frame_bytes: usize,
+    correlator: Correlator,
+}
+
+impl Session<ReadHalf<SerialStream>> {
+    async fn open(
+        serial: &SerialConfig,
+        port_override: Option<&str>,
+    ) -> Result<(Self, WriteHalf<SerialStream>)> {
+        let (port_name, baud_rate) = port_access::find_device(serial, port_override).await?;
+        let port = tokio_serial::new(&port_name, baud_rate)
+            .timeout(Duration::from_millis(serial.timeout_ms))
+            .open_native_async()
+            .map_err(|e| error::port_open_error(&port_name, &e))?;
+        let (reader, writer) = tokio::io::split(port);
+
+        let session = Session {
+            reader: BufReader::new(reader),
+            frame: Vec::new(),
+            max_frame_bytes: serial.max_frame_bytes,
+            correlator: Correlator::new(),
+        };
+        Ok((session, writer))
+    }
+}
+
+impl<R: AsyncRead + Unpin> Session<R> {
+    // Read frames until the device answers `request`, ignoring SMS and logs
+    // sent meanwhile
+    async fn reply<T>(
+        &mut self,
+        request: Request,
+        extract: impl FnOnce(MessageType) -> Option<T>,
+    ) -> Result<T> {
+        let read = async {
+            loop {
+                match serial_port::read_frame(
+                    &mut self.reader,
+                    &mut self.frame,
+                    self.max_frame_bytes,
+                )
+                .await
+                {
+                    Ok(FrameRead::Eof) => return anyhow::anyhow!("Connection closed"),
+                    Ok(FrameRead::Oversized(_)) => continue,
+                    Ok(FrameRead::Frame) => {}
+                    Err(e) => return e.into(),
+                }
+
+                let Some(msg) = std::str::from_utf8(&self.frame)
+                    .ok()
+                    .and_then(serial_port::parse_message)
+                else {
+                    continue;
+                };
+                if let Some(other) = self.correlator.resolve(msg) {
+                    log::debug!("Ignoring {} frame", other.message_type.kind());
+                }
+            }
+        };
+
+        tokio::select! {
+            reply = request.response(Duration::from_millis(REPLY_TIMEOUT_MS), extract) => {
+                reply.map_err(|e| {
+                    anyhow::anyhow!("{}, the device script must be version 1.3.0 or newer", e)
+                })
+            }
+            e = read => Err(e),
+        }
+    }
+}
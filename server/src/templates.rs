@@ -0,0 +1,43 @@
+use crate::database::Database;
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::collections::HashMap;
+
+// Saved message texts ("I'll call back later") are kept in the database under a
+// name, so the CLI, the local API and MQTT can all send them. Their {placeholders}
+// are filled per message
+
+// Fill {name} placeholders, one without a value is an error rather than an SMS
+// going out with the raw placeholder
+pub fn fill(body: &str, variables: &HashMap<String, String>) -> Result<String> {
+    let placeholder = Regex::new(r"\{(\w+)\}").unwrap();
+    if let Some(missing) = placeholder
+        .captures_iter(body)
+        .map(|captures| captures[1].to_string())
+        .find(|name| !variables.contains_key(name))
+    {
+        anyhow::bail!("No value for placeholder {{{}}}", missing);
+    }
+
+    Ok(placeholder
+        .replace_all(body, |captures: &regex::Captures| {
+            variables[&captures[1]].clone()
+        })
+        .into_owned())
+}
+
+// The text of the saved template `name` with its placeholders filled
+pub fn render(db: &Database, name: &str, variables: &HashMap<String, String>) -> Result<String> {
+    let body = db
+        .template(name)?
+        .with_context(|| format!("No template named {}", name))?;
+    fill(&body, variables).context(format!("Template {}", name))
+}
+
+// Template names are used on the command line and in JSON, keep them simple
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
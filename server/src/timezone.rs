@@ -0,0 +1,82 @@
+use anyhow::Result;
+use chrono::{Local, NaiveDate, NaiveTime, TimeZone};
+use chrono_tz::Tz;
+use croner::Cron;
+
+const DISPLAY_FORMAT: &str = "%Y-%m-%d %H:%M:%S %:z";
+
+// Converts device epoch timestamps into the configured time zone,
+// falling back to the host's local time zone when none is configured
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TimeFormatter {
+    tz: Option<Tz>,
+}
+
+impl TimeFormatter {
+    pub fn new(timezone: Option<&str>) -> Result<Self> {
+        let tz = timezone
+            .map(|name| {
+                name.parse::<Tz>()
+                    .map_err(|e| anyhow::anyhow!("Invalid timezone '{}': {}", name, e))
+            })
+            .transpose()?;
+
+        Ok(TimeFormatter { tz })
+    }
+
+    // Human readable local time, e.g. "2025-01-31 08:15:00 +08:00"
+    pub fn display(&self, epoch: i64) -> String {
+        self.format(epoch, DISPLAY_FORMAT)
+    }
+
+    // Local time in a chrono format, e.g. "%Y-%m-%d"
+    pub fn format(&self, epoch: i64, format: &str) -> String {
+        let formatted = match self.tz {
+            Some(tz) => tz
+                .timestamp_opt(epoch, 0)
+                .single()
+                .map(|t| t.format(format).to_string()),
+            None => Local
+                .timestamp_opt(epoch, 0)
+                .single()
+                .map(|t| t.format(format).to_string()),
+        };
+
+        formatted.unwrap_or_else(|| epoch.to_string())
+    }
+
+    // Local wall-clock time of `epoch`
+    pub fn time_of_day(&self, epoch: i64) -> Option<NaiveTime> {
+        match self.tz {
+            Some(tz) => tz.timestamp_opt(epoch, 0).single().map(|t| t.time()),
+            None => Local.timestamp_opt(epoch, 0).single().map(|t| t.time()),
+        }
+    }
+
+    // Local calendar date of `epoch`
+    pub fn date(&self, epoch: i64) -> Option<NaiveDate> {
+        match self.tz {
+            Some(tz) => tz.timestamp_opt(epoch, 0).single().map(|t| t.date_naive()),
+            None => Local
+                .timestamp_opt(epoch, 0)
+                .single()
+                .map(|t| t.date_naive()),
+        }
+    }
+
+    // First local time after `epoch` matching the cron pattern
+    pub fn next_cron(&self, cron: &Cron, epoch: i64) -> Option<i64> {
+        match self.tz {
+            Some(tz) => tz
+                .timestamp_opt(epoch, 0)
+                .single()
+                .and_then(|t| cron.find_next_occurrence(&t, false).ok())
+                .map(|t| t.timestamp()),
+            None => Local
+                .timestamp_opt(epoch, 0)
+                .single()
+                .and_then(|t| cron.find_next_occurrence(&t, false).ok())
+                .map(|t| t.timestamp()),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+#[cfg(target_os = "linux")]
+use anyhow::Context;
+use anyhow::Result;
+
+// Symlink created by the generated rule, `/dev/air780e` or `/dev/air780e-<id>`
+pub const SYMLINK_PREFIX: &str = "air780e";
+#[cfg(target_os = "linux")]
+const BY_ID_DIR: &str = "/dev/serial/by-id";
+
+// Ranking of detected ports: our udev symlinks, then the by-id links, then ttyUSBn
+pub fn preference(port_name: &str) -> u8 {
+    if port_name.starts_with(&format!("/dev/{}", SYMLINK_PREFIX)) {
+        0
+    } else if port_name.starts_with("/dev/serial/by-id/") {
+        1
+    } else {
+        2
+    }
+}
+
+// A path that keeps pointing to the same device when ttyUSBn numbers change
+#[cfg(target_os = "linux")]
+pub fn stable_path(port_name: &str) -> Option<String> {
+    let target = std::fs::canonicalize(port_name).ok()?;
+
+    let mut links = symlinks_in("/dev", |name| name.starts_with(SYMLINK_PREFIX));
+    links.extend(symlinks_in(BY_ID_DIR, |_| true));
+    links
+        .into_iter()
+        .find(|link| std::fs::canonicalize(link).is_ok_and(|path| path == target))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn stable_path(_port_name: &str) -> Option<String> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn symlinks_in(dir: &str, filter: impl Fn(&str) -> bool) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut links = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_symlink()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| filter(name))
+        .map(|name| format!("{}/{}", dir, name))
+        .collect::<Vec<_>>();
+    links.sort();
+    links
+}
+
+// USB attributes udev matches the port by
+#[cfg(target_os = "linux")]
+struct UsbAttributes {
+    vendor: String,
+    product: String,
+    serial: Option<String>,
+    // The module exposes several serial interfaces, only one talks to the script
+    interface: Option<String>,
+}
+
+// Read the attributes from sysfs, walking up from the tty to its USB interface and device
+#[cfg(target_os = "linux")]
+fn usb_attributes(port_name: &str) -> Result<UsbAttributes> {
+    let real = std::fs::canonicalize(port_name)
+        .context(format!("Failed to resolve port: {}", port_name))?;
+    let kernel_name = real
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context(format!("Invalid port path: {}", port_name))?;
+    let device = std::fs::canonicalize(format!("/sys/class/tty/{}/device", kernel_name))
+        .context(format!("{} is not a USB serial device", port_name))?;
+
+    let read = |dir: &std::path::Path, attribute: &str| {
+        std::fs::read_to_string(dir.join(attribute))
+            .ok()
+            .map(|value| value.trim().to_string())
+    };
+
+    let mut interface = None;
+    let mut dir = Some(device.as_path());
+    while let Some(path) = dir {
+        if interface.is_none() {
+            interface = read(path, "bInterfaceNumber");
+        }
+        if let (Some(vendor), Some(product)) = (read(path, "idVendor"), read(path, "idProduct")) {
+            return Ok(UsbAttributes {
+                vendor,
+                product,
+                serial: read(path, "serial"),
+                interface,
+            });
+        }
+        dir = path.parent();
+    }
+
+    anyhow::bail!("{} is not a USB serial device", port_name)
+}
+
+// Rule giving the device's port a stable symlink, printed for the user to install
+#[cfg(target_os = "linux")]
+pub fn rule(port_name: &str, symlink: &str) -> Result<String> {
+    let usb = usb_attributes(port_name)?;
+    let group = crate::port_access::port_group(port_name).unwrap_or_else(|| "dialout".to_string());
+
+    let mut matches = vec![
+        "SUBSYSTEM==\"tty\"".to_string(),
+        format!("ATTRS{{idVendor}}==\"{}\"", usb.vendor),
+        format!("ATTRS{{idProduct}}==\"{}\"", usb.product),
+    ];
+    if let Some(serial) = &usb.serial {
+        matches.push(format!("ATTRS{{serial}}==\"{}\"", serial));
+    }
+    // ATTRS keys must all match on the same parent, so the interface comes from the environment
+    if let Some(interface) = &usb.interface {
+        matches.push(format!("ENV{{ID_USB_INTERFACE_NUM}}==\"{}\"", interface));
+    }
+
+    Ok(format!(
+        "# Air780E on {port}, save as /etc/udev/rules.d/99-{link}.rules and run:\n\
+         #   sudo udevadm control --reload && sudo udevadm trigger\n\
+         # then set port_name = \"/dev/{link}\" in the configuration\n\
+         {matches}, SYMLINK+=\"{link}\", GROUP=\"{group}\", MODE=\"0660\"\n",
+        port = port_name,
+        link = symlink,
+        matches = matches.join(", "),
+        group = group
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn rule(_port_name: &str, _symlink: &str) -> Result<String> {
+    anyhow::bail!("udev rules are only available on Linux")
+}
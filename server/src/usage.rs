@@ -0,0 +1,74 @@
+use crate::config::UsageConfig;
+use crate::database::{MonthlyUsage, UsageLimit};
+use crate::timezone::TimeFormatter;
+use chrono::{Datelike, Months, NaiveDate};
+
+const MIB: u64 = 1024 * 1024;
+
+// Assigns usage to the billing cycle it falls in and tells when a cycle's
+// usage nears what the plan includes
+#[derive(Debug, Clone)]
+pub struct UsageTracker {
+    config: UsageConfig,
+    time: TimeFormatter,
+}
+
+impl UsageTracker {
+    pub fn new(config: &UsageConfig, time: TimeFormatter) -> Self {
+        UsageTracker {
+            config: config.clone(),
+            time,
+        }
+    }
+
+    // First day of the cycle `epoch` falls in, as "YYYY-MM-DD"
+    pub fn cycle_start(&self, epoch: i64) -> String {
+        let date = self.time.date(epoch).unwrap_or_default();
+        cycle_start(date, self.config.cycle_start_day)
+            .format("%Y-%m-%d")
+            .to_string()
+    }
+
+    pub fn limit(&self, limit: UsageLimit) -> Option<u64> {
+        let value = match limit {
+            UsageLimit::Sms => self.config.sms_limit,
+            UsageLimit::Data => self.config.data_limit_mb * MIB,
+        };
+        (value > 0).then_some(value)
+    }
+
+    // The limits `usage` reached `alert_percent` of and was not alerted on
+    // yet, with the amount used and the limit
+    pub fn due_alerts(&self, usage: &MonthlyUsage) -> Vec<(UsageLimit, u64, u64)> {
+        [
+            (UsageLimit::Sms, usage.sms_sent, usage.sms_alerted),
+            (UsageLimit::Data, usage.data_bytes(), usage.data_alerted),
+        ]
+        .into_iter()
+        .filter(|(_, _, alerted)| !alerted)
+        .filter_map(|(limit, used, _)| {
+            let allowed = self.limit(limit)?;
+            (used.saturating_mul(100) >= allowed.saturating_mul(self.config.alert_percent))
+                .then_some((limit, used, allowed))
+        })
+        .collect()
+    }
+}
+
+// A cycle starting on `start_day` began this month once that day is reached,
+// otherwise last month
+pub fn cycle_start(date: NaiveDate, start_day: u32) -> NaiveDate {
+    let this_month = date.with_day(start_day).unwrap_or(date);
+    if date.day() >= start_day {
+        this_month
+    } else {
+        this_month
+            .checked_sub_months(Months::new(1))
+            .unwrap_or(this_month)
+    }
+}
+
+// Bytes as MiB with one decimal, e.g. "12.5"
+pub fn mib(bytes: u64) -> String {
+    format!("{:.1}", bytes as f64 / MIB as f64)
+}
@@ -0,0 +1,99 @@
+use crate::config::WasmHookConfig;
+use crate::rules::{HookActions, HookMessage};
+use anyhow::{Context, Result};
+use std::time::Duration;
+use wasmtime::{Engine, Instance, Module, Store};
+
+// Granularity of the per-call time limit
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+// Largest actions JSON a module may return
+const MAX_RESULT_BYTES: usize = 64 * 1024;
+
+// A sandboxed module exporting `memory`, `alloc(len) -> ptr` and
+// `on_sms(ptr, len) -> i64`. The message is written as JSON into memory from
+// `alloc`, on_sms returns the actions JSON as `ptr << 32 | len`, or 0 for none.
+// Modules get no imports, so they can't reach files, the network or the clock
+#[derive(Clone)]
+pub struct WasmHook {
+    engine: Engine,
+    module: Module,
+    fuel: u64,
+    deadline_ticks: u64,
+}
+
+impl WasmHook {
+    pub fn new(config: &WasmHookConfig, path: &str) -> Result<Self> {
+        let mut engine_config = wasmtime::Config::new();
+        engine_config
+            .consume_fuel(true)
+            .epoch_interruption(true)
+            .wasm_backtrace(false);
+        let engine = Engine::new(&engine_config)?;
+        let module = Module::from_file(&engine, path)
+            .context(format!("Failed to load WASM module {}", path))?;
+        for name in ["memory", "alloc", "on_sms"] {
+            if module.get_export(name).is_none() {
+                anyhow::bail!("WASM module {} does not export {}", path, name);
+            }
+        }
+
+        // The epoch only advances while something ticks it, one thread serves every call
+        let ticker = engine.clone();
+        std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(EPOCH_TICK);
+                ticker.increment_epoch();
+            }
+        });
+
+        Ok(WasmHook {
+            engine,
+            module,
+            fuel: config.fuel,
+            deadline_ticks: config
+                .timeout_ms
+                .div_ceil(EPOCH_TICK.as_millis() as u64)
+                .max(1),
+        })
+    }
+
+    // Every call gets a fresh instance, nothing is kept between messages
+    pub fn on_sms(&self, message: &HookMessage) -> Result<HookActions> {
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(self.fuel)?;
+        store.set_epoch_deadline(self.deadline_ticks);
+
+        let instance = Instance::new(&mut store, &self.module, &[])
+            .context("Failed to instantiate the WASM hook")?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("The WASM hook does not export its memory")?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let on_sms = instance.get_typed_func::<(i32, i32), i64>(&mut store, "on_sms")?;
+
+        let input = serde_json::to_vec(message)?;
+        let len = i32::try_from(input.len()).context("Message too large for the WASM hook")?;
+        let ptr = alloc
+            .call(&mut store, len)
+            .context("WASM hook alloc failed")?;
+        memory
+            .write(&mut store, ptr as u32 as usize, &input)
+            .context("WASM hook alloc returned an invalid pointer")?;
+
+        let result = on_sms
+            .call(&mut store, (ptr, len))
+            .context("WASM hook on_sms failed")? as u64;
+        if result == 0 {
+            return Ok(HookActions::default());
+        }
+        let (ptr, len) = ((result >> 32) as usize, (result & 0xffff_ffff) as usize);
+        if len > MAX_RESULT_BYTES {
+            anyhow::bail!("WASM hook returned {} bytes of actions", len);
+        }
+        let mut output = vec![0; len];
+        memory
+            .read(&store, ptr, &mut output)
+            .context("WASM hook on_sms returned an invalid pointer")?;
+        serde_json::from_slice(&output).context("WASM hook on_sms returned invalid actions")
+    }
+}
@@ -0,0 +1,143 @@
+use crate::config::WatchdogConfig;
+use crate::database;
+use crate::error::{self, AppError};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Duration, Instant};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+// Beaten by a supervised task to show it is making progress. Every beat says
+// when the next one is due, so tasks sleeping for hours aren't taken for stalled
+#[derive(Clone, Default)]
+pub struct Heartbeat {
+    // Unix milliseconds the next beat is due at, 0 while waiting for outside events
+    due_at: Arc<AtomicI64>,
+}
+
+impl Heartbeat {
+    pub fn beat(&self, next_within: Duration) {
+        let due_at = database::unix_timestamp_millis() + next_within.as_millis() as i64;
+        self.due_at.store(due_at, Ordering::Relaxed);
+    }
+
+    // Waiting may take any time, e.g. for a maintenance window to end
+    pub fn idle(&self) {
+        self.due_at.store(0, Ordering::Relaxed);
+    }
+
+    // Seconds past the due time, None when not late or idle
+    fn overdue_secs(&self) -> Option<i64> {
+        let due_at = self.due_at.load(Ordering::Relaxed);
+        let late = database::unix_timestamp_millis() - due_at;
+        (due_at != 0 && late > 0).then_some(late / 1000)
+    }
+}
+
+// Restarts tasks that panic, end or stop beating their heartbeat, and exits the
+// process when a task keeps failing so the service manager can take over
+#[derive(Clone)]
+pub struct Watchdog {
+    enabled: bool,
+    stall_secs: i64,
+    max_restarts: usize,
+    restart_window: Duration,
+}
+
+impl Watchdog {
+    pub fn new(config: &WatchdogConfig) -> Self {
+        Watchdog {
+            enabled: config.enabled,
+            stall_secs: config.stall_secs as i64,
+            max_restarts: config.max_restarts as usize,
+            restart_window: Duration::from_secs(config.restart_window_minutes * 60),
+        }
+    }
+
+    // Runs a task that should never end
+    pub fn spawn<F, Fut>(&self, name: &str, mut start: F)
+    where
+        F: FnMut(Heartbeat) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        if !self.enabled {
+            tokio::spawn(start(Heartbeat::default()));
+            return;
+        }
+        let watchdog = self.clone();
+        let name = name.to_string();
+        tokio::spawn(async move { watchdog.supervise(&name, true, start).await });
+    }
+
+    // Runs a task until it returns, restarting it only when it panics or stalls
+    pub async fn run<T, F, Fut>(&self, name: &str, mut start: F) -> T
+    where
+        T: Send + 'static,
+        F: FnMut(Heartbeat) -> Fut,
+        Fut: Future<Output = T> + Send + 'static,
+    {
+        if !self.enabled {
+            return start(Heartbeat::default()).await;
+        }
+        self.supervise(name, false, start).await
+    }
+
+    async fn supervise<T, F, Fut>(&self, name: &str, restart_on_end: bool, mut start: F) -> T
+    where
+        T: Send + 'static,
+        F: FnMut(Heartbeat) -> Fut,
+        Fut: Future<Output = T> + Send + 'static,
+    {
+        let mut restarts: VecDeque<Instant> = VecDeque::new();
+        loop {
+            let heartbeat = Heartbeat::default();
+            let mut task = tokio::spawn(start(heartbeat.clone()));
+            let reason = loop {
+                tokio::select! {
+                    joined = &mut task => match joined {
+                        Ok(output) if !restart_on_end => return output,
+                        Ok(_) => break "ended".to_string(),
+                        Err(e) if e.is_panic() => break "panicked".to_string(),
+                        Err(_) => break "was cancelled".to_string(),
+                    },
+                    _ = tokio::time::sleep(CHECK_INTERVAL) => {
+                        if let Some(late) = heartbeat
+                            .overdue_secs()
+                            .filter(|late| *late >= self.stall_secs)
+                        {
+                            // A task blocking its thread can't be stopped, the
+                            // restart limit ends the process then
+                            task.abort();
+                            // Cancelled tasks drop what they hold, such as a
+                            // serial port, when polled next; wait for that so
+                            // the new one can open it again
+                            let _ = tokio::time::timeout(RESTART_DELAY, &mut task).await;
+                            break format!("stalled ({}s past its heartbeat)", late);
+                        }
+                    }
+                }
+            };
+
+            let now = Instant::now();
+            restarts.retain(|at| now.duration_since(*at) < self.restart_window);
+            if restarts.len() >= self.max_restarts {
+                let e = AppError::Stalled(format!(
+                    "Task {} {} after {} restart(s) within {} minutes",
+                    name,
+                    reason,
+                    restarts.len(),
+                    self.restart_window.as_secs() / 60
+                ))
+                .into();
+                log::error!("{:#}", e);
+                error::exit("Watchdog giving up", &e);
+            }
+            restarts.push_back(now);
+            log::error!("Task {} {}, restarting it", name, reason);
+            tokio::time::sleep(RESTART_DELAY).await;
+        }
+    }
+}
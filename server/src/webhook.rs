@@ -0,0 +1,273 @@
+use crate::config::WebhookConfig;
+use crate::database::{self, Database, WebhookDelivery};
+use crate::notification::{Notifier, SCHEMA_VERSION, SendOptions};
+use crate::ops_alerts::{OpsAlert, OpsAlerts};
+use crate::watchdog::Heartbeat;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hmac};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+// How often due retries are looked for when nothing new is queued
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Longest wait between two attempts of a delivery
+const MAX_RETRY_DELAY_SECS: u64 = 6 * 3600;
+
+// What a webhook receives, see `SCHEMA_VERSION` for how it may change
+#[derive(Serialize)]
+struct WebhookEvent<'a> {
+    schema_version: u32,
+    // Also sent as `X-Idempotency-Key`, the same for every attempt and for
+    // every time the same notification of an SMS is sent again
+    id: &'a str,
+    event: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_id: Option<&'a str>,
+    title: &'a str,
+    body: &'a str,
+    created_at: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ack_url: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<i64>,
+}
+
+// Queues notifications as webhook deliveries, the deliverer sends them
+pub struct WebhookNotifier {
+    db: Database,
+    wake: Arc<Notify>,
+    rng: SystemRandom,
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    async fn send(&self, title: &str, content: &str) -> Result<()> {
+        self.send_grouped(title, content, None).await
+    }
+
+    async fn send_grouped(&self, title: &str, content: &str, group: Option<&str>) -> Result<()> {
+        let options = SendOptions {
+            group,
+            ..Default::default()
+        };
+        self.send_with_options(title, content, &options).await
+    }
+
+    async fn send_with_options(
+        &self,
+        title: &str,
+        content: &str,
+        options: &SendOptions<'_>,
+    ) -> Result<()> {
+        let event = "notification";
+        let id = match options.message_id {
+            Some(message_id) => idempotency_key(message_id, event, title),
+            None => {
+                let mut key = [0u8; 16];
+                self.rng
+                    .fill(&mut key)
+                    .map_err(|_| anyhow::anyhow!("Failed to generate a webhook idempotency key"))?;
+                hex(&key)
+            }
+        };
+        let payload = serde_json::to_string(&WebhookEvent {
+            schema_version: SCHEMA_VERSION,
+            id: &id,
+            event,
+            message_id: options.message_id,
+            title,
+            body: content,
+            created_at: database::unix_timestamp(),
+            group: options.group,
+            ack_url: options.action_url,
+            expires_at: options.expires_at,
+        })?;
+
+        self.db.insert_webhook_delivery(&id, &payload)?;
+        self.wake.notify_one();
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct WebhookDeliverer {
+    db: Database,
+    url: String,
+    key: Option<hmac::Key>,
+    max_attempts: u32,
+    retry_base_secs: u64,
+    client: reqwest::Client,
+    wake: Arc<Notify>,
+    alerts: OpsAlerts,
+}
+
+impl WebhookDeliverer {
+    pub fn new(config: &WebhookConfig, db: Database, alerts: OpsAlerts) -> Self {
+        WebhookDeliverer {
+            db,
+            url: config.url.clone().unwrap_or_default(),
+            key: config
+                .secret
+                .as_ref()
+                .map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())),
+            max_attempts: config.max_attempts,
+            retry_base_secs: config.retry_base_secs,
+            client: reqwest::Client::new(),
+            wake: Arc::new(Notify::new()),
+            alerts,
+        }
+    }
+
+    pub fn notifier(&self) -> WebhookNotifier {
+        WebhookNotifier {
+            db: self.db.clone(),
+            wake: self.wake.clone(),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    pub async fn run(self, heartbeat: Heartbeat) {
+        log::info!("Webhook deliveries to {} started", self.url);
+
+        loop {
+            self.deliver_due().await;
+            heartbeat.beat(POLL_INTERVAL);
+            tokio::select! {
+                _ = self.wake.notified() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    }
+
+    async fn deliver_due(&self) {
+        let due = match self.db.due_webhook_deliveries(database::unix_timestamp()) {
+            Ok(due) => due,
+            Err(e) => {
+                log::warn!("Failed to load webhook deliveries: {}", e);
+                return;
+            }
+        };
+
+        for delivery in due {
+            let attempts = delivery.attempts + 1;
+            let result = match self.post(&delivery, attempts).await {
+                Ok(()) => {
+                    log::info!("Webhook delivery {} sent", delivery.id);
+                    self.db.webhook_delivered(&delivery.id, attempts)
+                }
+                Err(e) if attempts >= self.max_attempts => {
+                    log::error!(
+                        "Webhook delivery {} failed {} times, giving up: {:#}",
+                        delivery.id,
+                        attempts,
+                        e
+                    );
+                    self.alerts
+                        .send(
+                            OpsAlert::DeadLetter,
+                            None,
+                            &[
+                                ("id", &delivery.id),
+                                ("attempts", &attempts.to_string()),
+                                ("error", &format!("{:#}", e)),
+                            ],
+                        )
+                        .await;
+                    self.db
+                        .webhook_failed(&delivery.id, attempts, None, &format!("{:#}", e))
+                }
+                Err(e) => {
+                    let delay = self.retry_delay(attempts);
+                    log::warn!(
+                        "Webhook delivery {} failed (attempt {}/{}), retrying in {}s: {:#}",
+                        delivery.id,
+                        attempts,
+                        self.max_attempts,
+                        delay,
+                        e
+                    );
+                    let next_at = database::unix_timestamp() + delay as i64;
+                    self.db.webhook_failed(
+                        &delivery.id,
+                        attempts,
+                        Some(next_at),
+                        &format!("{:#}", e),
+                    )
+                }
+            };
+            if let Err(e) = result {
+                log::warn!("{}", e);
+            }
+        }
+    }
+
+    fn retry_delay(&self, attempts: u32) -> u64 {
+        let factor = 1u64.checked_shl(attempts - 1).unwrap_or(u64::MAX);
+        self.retry_base_secs
+            .saturating_mul(factor)
+            .min(MAX_RETRY_DELAY_SECS)
+    }
+
+    // The stored payload is sent as is, so the body is the same on every
+    // attempt. The signature covers the time of the attempt as well
+    async fn post(&self, delivery: &WebhookDelivery, attempt: u32) -> Result<()> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .timeout(REQUEST_TIMEOUT)
+            .header("Content-Type", "application/json")
+            .header("X-Idempotency-Key", &delivery.id)
+            .header("X-Delivery-Attempt", attempt.to_string());
+        if let Some(key) = &self.key {
+            request = signed(request, key, &delivery.payload);
+        }
+
+        let response = request
+            .body(delivery.payload.clone())
+            .send()
+            .await
+            .context(format!("Failed to reach {}", self.url))?;
+        if !response.status().is_success() {
+            anyhow::bail!("Webhook returned status {}", response.status());
+        }
+        Ok(())
+    }
+}
+
+// Adds X-Signature-Timestamp and the X-Signature of `body` sent at that time.
+// Receivers reject old timestamps, so a captured delivery can't be replayed later
+pub fn signed(
+    request: reqwest::RequestBuilder,
+    key: &hmac::Key,
+    body: &str,
+) -> reqwest::RequestBuilder {
+    let timestamp = database::unix_timestamp();
+    request
+        .header("X-Signature-Timestamp", timestamp.to_string())
+        .header("X-Signature", signature(key, timestamp, body))
+}
+
+// HMAC-SHA256 of "{timestamp}.{body}"
+pub fn signature(key: &hmac::Key, timestamp: i64, body: &str) -> String {
+    let input = format!("{}.{}", timestamp, body);
+    format!("sha256={}", hex(hmac::sign(key, input.as_bytes()).as_ref()))
+}
+
+// Retries, startup recovery and `unacked renotify` send the same notification
+// of an SMS again, they get the same key. Escalation reminders and the parts of
+// a split body have titles of their own and so keys of their own
+fn idempotency_key(message_id: &str, event: &str, title: &str) -> String {
+    let input = format!("{}\n{}\n{}", message_id, event, title);
+    hex(&digest::digest(&digest::SHA256, input.as_bytes()).as_ref()[..16])
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
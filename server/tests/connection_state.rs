@@ -0,0 +1,131 @@
+// Transition rules and hooks of the connection state machine
+
+#[path = "../src/connection_state.rs"]
+#[allow(dead_code)]
+mod connection_state;
+
+use connection_state::{ConnectionState, StateMachine};
+use std::sync::{Arc, Mutex};
+
+use ConnectionState::*;
+
+// A machine moved into `state` along allowed transitions
+fn machine_in(state: ConnectionState) -> StateMachine {
+    let machine = StateMachine::default();
+    let path = match &state {
+        Initializing => vec![],
+        Validating => vec![Validating],
+        Connected => vec![Validating, Connected],
+        Reconnecting { .. } => vec![state.clone()],
+        Maintenance => vec![Maintenance],
+        Failed => vec![Failed],
+    };
+    for step in path {
+        assert!(machine.transition(step.clone()), "to {}", step);
+    }
+    assert_eq!(machine.get(), state);
+    machine
+}
+
+#[test]
+fn allowed_transitions_are_taken() {
+    let allowed = [
+        (Initializing, Validating),
+        (Initializing, Connected),
+        (Initializing, Reconnecting { attempts: 1 }),
+        (Validating, Validating),
+        (Validating, Connected),
+        (Validating, Reconnecting { attempts: 0 }),
+        (Connected, Reconnecting { attempts: 0 }),
+        (Reconnecting { attempts: 2 }, Initializing),
+        (Reconnecting { attempts: 2 }, Connected),
+        (Reconnecting { attempts: 2 }, Reconnecting { attempts: 3 }),
+        (Maintenance, Reconnecting { attempts: 0 }),
+        (Connected, Maintenance),
+        (Reconnecting { attempts: 1 }, Maintenance),
+        (Initializing, Failed),
+        (Connected, Failed),
+        (Maintenance, Failed),
+    ];
+    for (from, to) in allowed {
+        let machine = machine_in(from.clone());
+        // Validating again is the same state and no change
+        let changed = from != to;
+        assert_eq!(
+            machine.transition(to.clone()),
+            changed,
+            "{} to {}",
+            from,
+            to
+        );
+        assert_eq!(machine.get(), to, "{} to {}", from, to);
+    }
+}
+
+#[test]
+fn impossible_transitions_are_refused() {
+    let refused = [
+        (Connected, Validating),
+        (Connected, Initializing),
+        (Validating, Initializing),
+        (Maintenance, Connected),
+        (Maintenance, Validating),
+        (Maintenance, Initializing),
+        (Failed, Initializing),
+        (Failed, Connected),
+        (Failed, Reconnecting { attempts: 1 }),
+        (Failed, Maintenance),
+    ];
+    for (from, to) in refused {
+        let machine = machine_in(from.clone());
+        assert!(!machine.transition(to.clone()), "{} to {}", from, to);
+        assert_eq!(machine.get(), from, "{} to {}", from, to);
+    }
+}
+
+#[test]
+fn hooks_run_once_per_change() {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let mut machine = StateMachine::default();
+    let log = calls.clone();
+    machine.on_change(move |change| {
+        log.lock()
+            .unwrap()
+            .push(format!("change {} -> {}", change.from, change.to));
+    });
+    let log = calls.clone();
+    machine.on_enter("connected", move |change| {
+        log.lock()
+            .unwrap()
+            .push(format!("enter connected from {}", change.from));
+    });
+    let log = calls.clone();
+    machine.on_exit("connected", move |change| {
+        log.lock()
+            .unwrap()
+            .push(format!("exit connected to {}", change.to));
+    });
+
+    assert!(machine.transition(Validating));
+    // Setting the current state again is not a change
+    assert!(!machine.transition(Validating));
+    assert!(machine.transition(Connected));
+    assert!(!machine.transition(Connected));
+    // A refused transition runs no hook
+    assert!(!machine.transition(Initializing));
+    assert!(machine.transition(Reconnecting { attempts: 0 }));
+    // A new attempt count is a change, but neither enters nor leaves a state
+    assert!(machine.transition(Reconnecting { attempts: 1 }));
+
+    assert_eq!(
+        *calls.lock().unwrap(),
+        [
+            "change initializing -> validating",
+            "change validating -> connected",
+            "enter connected from validating",
+            "change connected -> reconnecting",
+            "exit connected to reconnecting",
+            "change reconnecting -> reconnecting (attempt 1)",
+        ]
+    );
+}
@@ -0,0 +1,540 @@
+// Runs the server binary against a fake device on a PTY pair and a mock Bark
+// server, for the scenarios in tests/e2e.rs.
+//
+// The PTY master isn't close-on-exec, so a server started while another test's
+// device is plugged in would keep that PTY open and hide its unplugging. Tests
+// therefore run one at a time and plug the device in after starting the server
+
+use base64::Engine;
+use rusqlite::{Connection, OpenFlags};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{MutexGuard, mpsc};
+use tokio::task::JoinHandle;
+use tokio_serial::SerialStream;
+
+const WAIT_TIMEOUT: Duration = Duration::from_secs(20);
+
+static ONE_AT_A_TIME: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+// A running server with its device plugged in, torn down in field order
+pub struct TestEnv {
+    pub server: Server,
+    pub device: FakeDevice,
+    pub bark: MockBark,
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl TestEnv {
+    pub async fn start(name: &str, extra_config: &str) -> Self {
+        Self::start_with_replies(name, extra_config, &[]).await
+    }
+
+    // Starts with the mock answering ("METHOD path", body) pairs, set before the
+    // server can make its first request
+    pub async fn start_with_replies(
+        name: &str,
+        extra_config: &str,
+        replies: &[(&str, &str)],
+    ) -> Self {
+        let guard = ONE_AT_A_TIME.lock().await;
+        let dir = test_dir(name);
+        let bark = MockBark::start().await;
+        for (request, body) in replies {
+            bark.reply(request, body);
+        }
+        let mut device = FakeDevice::new(&dir);
+        let server = Server::start(&dir, &device, &bark, extra_config);
+        device.plug();
+        server.wait_connected().await;
+        TestEnv {
+            server,
+            device,
+            bark,
+            _guard: guard,
+        }
+    }
+}
+
+// Polls `check` until it returns something or the timeout ends
+pub async fn wait_for<T>(what: &str, mut check: impl FnMut() -> Option<T>) -> T {
+    let deadline = Instant::now() + WAIT_TIMEOUT;
+    loop {
+        if let Some(value) = check() {
+            return value;
+        }
+        if Instant::now() > deadline {
+            panic!("Timed out waiting for {}", what);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+// A scratch directory per test, kept when the test fails
+fn test_dir(name: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "air780e-e2e-{}-{}-{}",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// "METHOD path", the body and the head with the headers of a request
+type Recorded = (String, Vec<u8>, String);
+
+// Answers every request with 200 and records its method, path, body and headers.
+// The body of the answer is `{"code":200}` unless `reply` set one
+pub struct MockBark {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Recorded>>>,
+    replies: Arc<Mutex<HashMap<String, String>>>,
+    task: JoinHandle<()>,
+}
+
+impl MockBark {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let replies = Arc::new(Mutex::new(HashMap::new()));
+        let answers = replies.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    continue;
+                };
+                let recorded = recorded.clone();
+                let answers = answers.clone();
+                tokio::spawn(async move {
+                    let mut data = Vec::new();
+                    let mut buf = [0u8; 4096];
+                    let head_end = loop {
+                        if let Some(end) = data.windows(4).position(|window| window == b"\r\n\r\n")
+                        {
+                            break end + 4;
+                        }
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(read) => data.extend_from_slice(&buf[..read]),
+                        }
+                    };
+                    let head = String::from_utf8_lossy(&data[..head_end]).into_owned();
+                    let length = head
+                        .lines()
+                        .filter_map(|line| line.split_once(':'))
+                        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+                    while data.len() < head_end + length {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(read) => data.extend_from_slice(&buf[..read]),
+                        }
+                    }
+                    let request_line = head.lines().next().unwrap_or_default();
+                    let mut parts = request_line.split(' ');
+                    let method = parts.next().unwrap_or_default();
+                    let path = parts.next().unwrap_or_default();
+                    let path = urlencoding::decode(path).map(|path| path.into_owned());
+                    let request = format!("{} {}", method, path.unwrap_or_default());
+                    let body = answers
+                        .lock()
+                        .unwrap()
+                        .get(&request)
+                        .cloned()
+                        .unwrap_or_else(|| r#"{"code":200}"#.to_string());
+                    recorded.lock().unwrap().push((
+                        request,
+                        data[head_end..head_end + length].to_vec(),
+                        head.clone(),
+                    ));
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        MockBark {
+            url,
+            requests,
+            replies,
+            task,
+        }
+    }
+
+    // Answer requests made as `request`, e.g. "GET /bucket?list-type=2", with `body`
+    pub fn reply(&self, request: &str, body: &str) {
+        self.replies
+            .lock()
+            .unwrap()
+            .insert(request.to_string(), body.to_string());
+    }
+
+    // Method and decoded path of every request
+    pub fn requests(&self) -> Vec<String> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(request, ..)| request.clone())
+            .collect()
+    }
+
+    // JSON bodies of the requests made as `request`, e.g. "POST /hook"
+    pub fn bodies(&self, request: &str) -> Vec<serde_json::Value> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(recorded, ..)| recorded == request)
+            .map(|(_, body, _)| serde_json::from_slice(body).expect("a JSON body"))
+            .collect()
+    }
+
+    // Bodies of the requests made as `request`, as sent
+    pub fn raw_bodies(&self, request: &str) -> Vec<String> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(recorded, ..)| recorded == request)
+            .map(|(_, body, _)| String::from_utf8_lossy(body).into_owned())
+            .collect()
+    }
+
+    // Value of header `name` of each request made as `request`
+    pub fn header(&self, request: &str, name: &str) -> Vec<Option<String>> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(recorded, ..)| recorded == request)
+            .map(|(_, _, head)| {
+                head.lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                    .map(|(_, value)| value.trim().to_string())
+            })
+            .collect()
+    }
+
+    // JSON bodies of the pushes sent to Bark's POST /push
+    pub fn pushes(&self) -> Vec<serde_json::Value> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(request, ..)| request == "POST /push")
+            .map(|(_, body, _)| serde_json::from_slice(body).expect("a JSON push"))
+            .collect()
+    }
+}
+
+impl Drop for MockBark {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+// The module's side of the serial link. The server opens `path`, a symlink to
+// the slave of the current PTY pair, so the device can be unplugged and plugged
+// in again
+pub struct FakeDevice {
+    pub path: PathBuf,
+    received: Arc<Mutex<Vec<String>>>,
+    outgoing: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl FakeDevice {
+    // Unplugged until `plug`
+    pub fn new(dir: &Path) -> Self {
+        FakeDevice {
+            path: dir.join("tty"),
+            received: Arc::new(Mutex::new(Vec::new())),
+            outgoing: None,
+            task: None,
+        }
+    }
+
+    // Creates a new PTY pair, the server sees a port that comes back
+    pub fn plug(&mut self) {
+        let (master, slave) = SerialStream::pair().unwrap();
+        let slave_name = tokio_serial::SerialPort::name(&slave).unwrap();
+        // The slave stays usable as long as the master is open
+        drop(slave);
+        let _ = std::fs::remove_file(&self.path);
+        std::os::unix::fs::symlink(&slave_name, &self.path).unwrap();
+
+        let (outgoing, receiver) = mpsc::unbounded_channel();
+        self.outgoing = Some(outgoing);
+        self.task = Some(tokio::spawn(run_device(
+            master,
+            receiver,
+            self.received.clone(),
+        )));
+    }
+
+    // Closes the PTY, the server's reads fail like after pulling the cable
+    pub fn unplug(&mut self) {
+        self.outgoing = None;
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+
+    pub fn send_raw(&self, data: &[u8]) {
+        if let Some(outgoing) = &self.outgoing {
+            let _ = outgoing.send(data.to_vec());
+        }
+    }
+
+    pub fn send(&self, id: &str, kind: &str, payload: &serde_json::Value) {
+        self.send_raw(frame(id, kind, payload).as_bytes());
+    }
+
+    pub fn send_sms(&self, id: &str, sender: &str, content: &str) {
+        self.send(
+            id,
+            "SMS_RECEIVED",
+            &serde_json::json!({
+                "id": id,
+                "sender": sender,
+                "content": content,
+                "received_at": 1_700_000_000,
+                "metas": {},
+            }),
+        );
+    }
+
+    // Lines the server wrote, such as `ACK:<id>`
+    pub fn received(&self) -> Vec<String> {
+        self.received.lock().unwrap().clone()
+    }
+
+    pub fn acks(&self, id: &str) -> usize {
+        let ack = format!("ACK:{}", id);
+        self.received().iter().filter(|line| **line == ack).count()
+    }
+}
+
+impl Drop for FakeDevice {
+    fn drop(&mut self) {
+        self.unplug();
+    }
+}
+
+pub fn frame(id: &str, kind: &str, payload: &serde_json::Value) -> String {
+    let encoded = base64::engine::general_purpose::STANDARD.encode(payload.to_string());
+    format!("{}:{}:{}\r\n", id, kind, encoded)
+}
+
+fn device_info() -> serde_json::Value {
+    serde_json::json!({
+        "imei": "860000000000001",
+        "number": "+8613800000000",
+        "status": 1,
+        "rssi": -70,
+        "iccid": "8986000000",
+        "timestamp": 1_700_000_000,
+    })
+}
+
+async fn run_device(
+    mut master: SerialStream,
+    mut outgoing: mpsc::UnboundedReceiver<Vec<u8>>,
+    received: Arc<Mutex<Vec<String>>>,
+) {
+    let mut buf = [0u8; 4096];
+    let mut line = Vec::new();
+    let mut info_id = 0;
+    loop {
+        tokio::select! {
+            data = outgoing.recv() => match data {
+                Some(data) => {
+                    let _ = master.write_all(&data).await;
+                }
+                None => return,
+            },
+            read = master.read(&mut buf) => match read {
+                Ok(read) if read > 0 => {
+                    for byte in &buf[..read] {
+                        if *byte != b'\n' {
+                            line.push(*byte);
+                            continue;
+                        }
+                        let text = String::from_utf8_lossy(&line).trim_end().to_string();
+                        line.clear();
+                        if text.starts_with("CMD:GET_DEVICE_INFO") {
+                            info_id += 1;
+                            let reply = frame(&format!("info-{}", info_id), "DEVICE_INFO", &device_info());
+                            let _ = master.write_all(reply.as_bytes()).await;
+                        }
+                        received.lock().unwrap().push(text);
+                    }
+                }
+                // Nobody has the slave open, e.g. between validation and the session
+                _ => tokio::time::sleep(Duration::from_millis(20)).await,
+            },
+        }
+    }
+}
+
+// The server binary with a config pointing at the fake device and mock Bark
+pub struct Server {
+    pub dir: PathBuf,
+    child: Child,
+}
+
+impl Server {
+    pub fn start(dir: &Path, device: &FakeDevice, bark: &MockBark, extra_config: &str) -> Self {
+        // MOCK_URL stands for the mock's address, e.g. as a webhook URL
+        let extra_config = extra_config.replace("MOCK_URL", &bark.url);
+        let config = format!(
+            r#"[serial]
+port_name = "{port}"
+retry_delay_ms = 200
+handshake_timeout_ms = 500
+max_retry_count = 50
+
+[database]
+path = "{db}"
+
+[notification]
+bark_server_url = "{bark}"
+bark_device_key = "test-key"
+dedup_window_secs = 0
+
+{extra_config}"#,
+            port = device.path.display(),
+            db = dir.join("sms.db").display(),
+            bark = bark.url,
+        );
+        std::fs::write(dir.join("config.toml"), config).unwrap();
+        let log = std::fs::File::create(dir.join("server.log")).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_air780e-uart-server"))
+            .arg("-c")
+            .arg(dir.join("config.toml"))
+            .current_dir(dir)
+            .env("RUST_LOG", "debug")
+            .stdout(log.try_clone().unwrap())
+            .stderr(log)
+            .stdin(Stdio::null())
+            .spawn()
+            .unwrap();
+        Server {
+            dir: dir.to_path_buf(),
+            child,
+        }
+    }
+
+    pub fn db(&self) -> Option<Connection> {
+        Connection::open_with_flags(self.dir.join("sms.db"), OpenFlags::SQLITE_OPEN_READ_ONLY).ok()
+    }
+
+    // Runs a query returning one integer, None while the database isn't ready
+    pub fn query_i64(&self, sql: &str) -> Option<i64> {
+        self.db()?.query_row(sql, [], |row| row.get(0)).ok()
+    }
+
+    pub fn state(&self) -> Option<String> {
+        self.db()?
+            .query_row(
+                "SELECT state FROM connection_status WHERE device_id = 'default'",
+                [],
+                |row| row.get(0),
+            )
+            .ok()
+    }
+
+    pub async fn wait_connected(&self) {
+        wait_for("the connection", || {
+            (self.state().as_deref() == Some("connected")).then_some(())
+        })
+        .await;
+    }
+
+    // Waits until `sessions` sessions got the DEVICE_INFO reply to their
+    // handshake. Raw writes before that may be cut by the reply
+    pub async fn wait_device_info(&self, sessions: usize) {
+        wait_for("the DEVICE_INFO round trip", || {
+            (self.log().matches("Device info - IMEI").count() >= sessions).then_some(())
+        })
+        .await;
+    }
+
+    // Runs a CLI command against the config and database of this server
+    pub fn cli(&self, args: &[&str]) -> std::process::Output {
+        self.cli_command(args).output().unwrap()
+    }
+
+    // Starts a CLI command that waits on the device, collect its output with
+    // `wait_with_output` once the device did its part
+    pub fn spawn_cli(&self, args: &[&str]) -> Child {
+        self.cli_command(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap()
+    }
+
+    fn cli_command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_air780e-uart-server"));
+        command
+            .arg("-c")
+            .arg(self.dir.join("config.toml"))
+            .args(args)
+            .current_dir(&self.dir);
+        command
+    }
+
+    // Sends `signal` such as "HUP" to the server process
+    pub fn signal(&self, signal: &str) {
+        let status = Command::new("kill")
+            .arg(format!("-{}", signal))
+            .arg(self.child.id().to_string())
+            .status()
+            .unwrap();
+        assert!(status.success(), "kill -{} failed", signal);
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    // Whether the process is still running, under the same PID
+    pub fn running(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    pub fn log(&self) -> String {
+        std::fs::read_to_string(self.dir.join("server.log")).unwrap_or_default()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        if !std::thread::panicking() {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        } else {
+            eprintln!("Server log: {}", self.dir.join("server.log").display());
+        }
+    }
+}
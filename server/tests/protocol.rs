@@ -0,0 +1,438 @@
+// Property tests for the frame parser and encoder. Failing cases are shrunk by
+// proptest and saved in protocol.proptest-regressions to be replayed on later runs
+
+#[path = "../src/protocol.rs"]
+#[allow(dead_code)]
+mod protocol;
+
+use base64::Engine;
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use proptest::prelude::*;
+use protocol::{Frame, MessageType, decode_frame, encode_frame, parse_ack_request, parse_message};
+use std::io::Write;
+
+const KINDS: &[&str] = &[
+    "DEVICE_INFO",
+    "SMS_RECEIVED",
+    "SMS_SENT",
+    "SCRIPT_UPDATE",
+    "DEVICE_LOG",
+    "NET_STATUS",
+    "STORED_SMS",
+    "STORED_DELETED",
+    "SYSTEM_INIT",
+    "HEART_BEAT",
+    "SOMETHING_NEW",
+];
+
+// Line endings the device or a terminal in between may produce
+const TERMINATORS: &[&str] = &["", "\n", "\r\n", "\r", "\r\r\n", "\n\r"];
+
+fn kind() -> impl Strategy<Value = &'static str> {
+    prop::sample::select(KINDS)
+}
+
+fn terminator() -> impl Strategy<Value = &'static str> {
+    prop::sample::select(TERMINATORS)
+}
+
+// UUIDs as the device sends them, or any other id of letters, digits, "-" and "_"
+fn id() -> impl Strategy<Value = String> {
+    prop_oneof![
+        "[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}",
+        "[A-Za-z0-9_-]{1,20}",
+    ]
+}
+
+fn text(max_len: usize) -> impl Strategy<Value = String> {
+    prop::collection::vec(
+        prop_oneof![
+            Just("\"".to_string()),
+            Just("\\".to_string()),
+            Just("\r\n".to_string()),
+            Just(":".to_string()),
+            Just("验证码".to_string()),
+            Just("\u{0}".to_string()),
+            any::<char>().prop_map(String::from),
+        ],
+        0..=max_len,
+    )
+    .prop_map(|parts| parts.concat())
+}
+
+// Mostly the characters frames are made of, so inputs get past the first checks
+fn frame_bytes(max_len: usize) -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(
+        prop_oneof![
+            1 => any::<u8>(),
+            2 => prop::sample::select(b"::::\r\n\r\nzZ=+/09AazABC-_ ".as_slice()),
+        ],
+        0..=max_len,
+    )
+}
+
+fn sms_json() -> impl Strategy<Value = (String, String)> {
+    (id(), text(12), text(80), any::<i64>()).prop_map(|(id, sender, content, received_at)| {
+        let json = serde_json::json!({
+            "id": id,
+            "sender": sender,
+            "content": content,
+            "received_at": received_at,
+            "metas": {},
+        });
+        (id, json.to_string())
+    })
+}
+
+fn compressed_frame(id: &str, kind: &str, json: &str) -> String {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(json.as_bytes()).unwrap();
+    let data = base64::engine::general_purpose::STANDARD.encode(encoder.finish().unwrap());
+    format!("{}:{}:z:{}\r\n", id, kind, data)
+}
+
+// Splits a stream on '\n' the way `read_frame` does
+fn lines(stream: &str) -> Vec<&str> {
+    stream
+        .split_inclusive('\n')
+        .filter(|line| !line.trim_matches(['\r', '\n']).is_empty())
+        .collect()
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(1000))]
+
+    #[test]
+    fn arbitrary_input_never_panics(bytes in frame_bytes(120)) {
+        let _ = parse_message(&String::from_utf8_lossy(&bytes));
+    }
+
+    #[test]
+    fn mutated_frames_never_panic(
+        (id, json) in sms_json(),
+        compressed in any::<bool>(),
+        edits in prop::collection::vec((any::<prop::sample::Index>(), 0..3u8, any::<u8>()), 1..5),
+    ) {
+        let frame = if compressed {
+            compressed_frame(&id, "SMS_RECEIVED", &json)
+        } else {
+            encode_frame(&id, "SMS_RECEIVED", &json)
+        };
+        let mut bytes = frame.into_bytes();
+        for (at, op, byte) in edits {
+            let at = at.index(bytes.len());
+            match op {
+                0 => bytes[at] ^= 1 << (byte % 8),
+                1 => {
+                    bytes.remove(at);
+                }
+                _ => bytes.insert(at, b":\r\n=z"[byte as usize % 5]),
+            }
+        }
+        let _ = parse_message(&String::from_utf8_lossy(&bytes));
+    }
+
+    #[test]
+    fn frames_round_trip(
+        id in id(),
+        kind in kind(),
+        json in text(60),
+        terminator in terminator(),
+    ) {
+        let expected = Frame {
+            id: id.clone(),
+            kind: kind.to_string(),
+            json: json.clone(),
+            raw: None,
+        };
+
+        let line = format!("{}{}", encode_frame(&id, kind, &json).trim_end(), terminator);
+        // The encoded payload of an empty string is empty, no frame at all
+        if json.is_empty() {
+            prop_assert_eq!(decode_frame(&line), None);
+        } else {
+            prop_assert_eq!(decode_frame(&line), Some(expected.clone()));
+        }
+
+        let line = format!("{}{}", compressed_frame(&id, kind, &json).trim_end(), terminator);
+        prop_assert_eq!(decode_frame(&line), Some(expected));
+    }
+
+    #[test]
+    fn every_base64_flavour_is_read(
+        id in id(),
+        kind in kind(),
+        json in text(60),
+        url_safe in any::<bool>(),
+        padded in any::<bool>(),
+    ) {
+        use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD};
+        prop_assume!(!json.is_empty());
+        let engine = match (url_safe, padded) {
+            (false, true) => STANDARD,
+            (false, false) => STANDARD_NO_PAD,
+            (true, true) => URL_SAFE,
+            (true, false) => URL_SAFE_NO_PAD,
+        };
+        let line = format!("{}:{}:{}\r\n", id, kind, engine.encode(&json));
+        prop_assert_eq!(
+            decode_frame(&line),
+            Some(Frame {
+                id,
+                kind: kind.to_string(),
+                json,
+                raw: None,
+            })
+        );
+    }
+
+    #[test]
+    fn streams_split_into_every_frame(
+        frames in prop::collection::vec(
+            (sms_json(), prop::sample::select(&["\n", "\r\n", "\r\r\n", "\n\r", "\n\n"][..])),
+            2..5,
+        ),
+    ) {
+        let mut stream = String::new();
+        for ((id, json), separator) in &frames {
+            stream.push_str(encode_frame(id, "SMS_RECEIVED", json).trim_end());
+            stream.push_str(separator);
+        }
+
+        let mut parsed = Vec::new();
+        for line in lines(&stream) {
+            let msg = parse_message(line);
+            prop_assert!(msg.is_some(), "unparsed line {:?}", line);
+            parsed.push(msg.unwrap().id);
+        }
+        let ids: Vec<String> = frames.into_iter().map(|((id, _), _)| id).collect();
+        prop_assert_eq!(parsed, ids);
+    }
+
+    #[test]
+    fn sms_payloads_round_trip((id, json) in sms_json()) {
+        let expected: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        for line in [
+            encode_frame(&id, "SMS_RECEIVED", &json),
+            compressed_frame(&id, "SMS_RECEIVED", &json),
+        ] {
+            let msg = parse_message(&line);
+            prop_assert!(msg.is_some(), "unparsed line {:?}", line);
+            let msg = msg.unwrap();
+            prop_assert_eq!(&msg.id, &id);
+            let MessageType::SmsReceived(sms) = msg.message_type else {
+                return Err(TestCaseError::fail(format!("wrong type for {:?}", line)));
+            };
+            prop_assert_eq!(&sms.id, &id);
+            prop_assert_eq!(sms.sender.as_str(), expected["sender"].as_str().unwrap());
+            prop_assert_eq!(sms.content.as_str(), expected["content"].as_str().unwrap());
+            prop_assert_eq!(sms.received_at, expected["received_at"].as_i64().unwrap());
+        }
+    }
+
+    #[test]
+    fn ids_with_colons_are_rejected(
+        parts in prop::collection::vec(id(), 2..4),
+        trailing_colon in any::<bool>(),
+        kind in kind(),
+        (_, json) in sms_json(),
+    ) {
+        let mut id = parts.join(":");
+        if trailing_colon {
+            id.push(':');
+        }
+        for line in [
+            encode_frame(&id, kind, &json),
+            compressed_frame(&id, kind, &json),
+        ] {
+            prop_assert_eq!(decode_frame(&line), None, "accepted {:?}", line);
+        }
+    }
+
+    // A frame cut short, e.g. by a reply written into the middle of it, runs
+    // into the next one. Neither may be taken under an id made of the other
+    #[test]
+    fn spliced_frames_are_rejected(
+        first_id in id(),
+        second_id in id(),
+        kind in kind(),
+        (_, json) in sms_json(),
+        cut in 0.0..1.0f64,
+        compressed in any::<bool>(),
+    ) {
+        let first = if compressed {
+            compressed_frame(&first_id, kind, &json)
+        } else {
+            encode_frame(&first_id, kind, &json)
+        };
+        let first = first.trim_end();
+        let payload_start = first.rfind(':').unwrap() + 1;
+        let end = payload_start + ((first.len() - payload_start) as f64 * cut) as usize;
+        let line = format!(
+            "{}{}",
+            &first[..end],
+            encode_frame(&second_id, "DEVICE_INFO", "{}")
+        );
+        prop_assert_eq!(decode_frame(&line), None, "accepted {:?}", line);
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(200))]
+
+    #[test]
+    fn truncated_payloads_are_rejected((id, json) in sms_json()) {
+        for line in [
+            encode_frame(&id, "SMS_RECEIVED", &json),
+            compressed_frame(&id, "SMS_RECEIVED", &json),
+        ] {
+            let line = line.trim_end();
+            let payload_start = line.rfind(':').unwrap() + 1;
+            for end in payload_start..line.len() {
+                let truncated = &line[..end];
+                // Padding is optional, dropping only that leaves the payload whole
+                if truncated.trim_end_matches('=') == line.trim_end_matches('=') {
+                    continue;
+                }
+                prop_assert!(parse_message(truncated).is_none(), "accepted {:?}", truncated);
+                if line.contains(":z:") {
+                    prop_assert_eq!(decode_frame(truncated), None, "inflated {:?}", truncated);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn oversized_decompression_is_refused() {
+    let json = " ".repeat(protocol::MAX_DECOMPRESSED_BYTES as usize + 1);
+    assert_eq!(
+        decode_frame(&compressed_frame("id", "HEART_BEAT", &json)),
+        None
+    );
+    let json = " ".repeat(protocol::MAX_DECOMPRESSED_BYTES as usize);
+    assert!(decode_frame(&compressed_frame("id", "HEART_BEAT", &json)).is_some());
+}
+
+#[test]
+fn malformed_fields_are_rejected() {
+    let payload = base64::engine::general_purpose::STANDARD.encode("{}");
+    for line in [
+        String::new(),
+        "\r\n".to_string(),
+        format!("{}\r\n", payload),
+        format!("HEART_BEAT:{}", payload),
+        // Empty fields
+        format!(":HEART_BEAT:{}", payload),
+        format!("id::{}", payload),
+        "id:HEART_BEAT:".to_string(),
+        "id:HEART_BEAT:z:".to_string(),
+        format!(":z:{}", payload),
+        // Ids with spaces, control characters or anything else than letters,
+        // digits, "-" and "_"
+        format!("an id:HEART_BEAT:{}", payload),
+        format!("id\t:HEART_BEAT:{}", payload),
+        format!("i\nd:HEART_BEAT:{}", payload),
+        format!("a:b:HEART_BEAT:{}", payload),
+        format!("::HEART_BEAT:{}", payload),
+        format!("id::HEART_BEAT:{}", payload),
+        format!("z:z:HEART_BEAT:{}", payload),
+        format!("id.1:HEART_BEAT:{}", payload),
+        format!("id/1:HEART_BEAT:{}", payload),
+        format!("验证:HEART_BEAT:{}", payload),
+        // Types outside SCREAMING_SNAKE_CASE
+        format!("id:heart_beat:{}", payload),
+        format!("id:HEART-BEAT:{}", payload),
+        format!("id:HEART BEAT:{}", payload),
+        // Payloads that are not base64, or carry a colon of their own
+        format!("id:HEART_BEAT:{}!", payload),
+        format!("id:HEART_BEAT:{} ", payload),
+        format!("id:HEART_BEAT:{}:{}", payload, payload),
+        format!("id:HEART_BEAT:x:{}", payload),
+        // Both base64 alphabets in one payload, or a broken padding
+        "id:HEART_BEAT:+-8=".to_string(),
+        "id:HEART_BEAT:/_8=".to_string(),
+        "id:HEART_BEAT:e30===".to_string(),
+        "id:HEART_BEAT:e=30".to_string(),
+        // Marked compressed without being zlib data
+        format!("id:HEART_BEAT:z:{}", payload),
+        // Line breaks inside the frame
+        format!("id:HEART_BEAT\r\n:{}", payload),
+    ] {
+        assert_eq!(decode_frame(&line), None, "{:?}", line);
+    }
+}
+
+#[test]
+fn edge_case_frames_are_read() {
+    let payload = base64::engine::general_purpose::STANDARD.encode("{}");
+    let cases = [
+        (format!("id:HEART_BEAT:{}", payload), "id", "HEART_BEAT"),
+        (
+            format!("req-42_7:HEART_BEAT:{}", payload),
+            "req-42_7",
+            "HEART_BEAT",
+        ),
+        // An id that looks like the compression flag is still an id
+        (format!("z:HEART_BEAT:{}", payload), "z", "HEART_BEAT"),
+        (format!("ACK:HEART_BEAT:{}", payload), "ACK", "HEART_BEAT"),
+        (format!("id:V2_TYPE_9:{}\r\n", payload), "id", "V2_TYPE_9"),
+        (
+            format!("\n\rid:HEART_BEAT:{}\n\r", payload),
+            "id",
+            "HEART_BEAT",
+        ),
+    ];
+    for (line, id, kind) in cases {
+        let frame = decode_frame(&line).unwrap_or_else(|| panic!("{:?}", line));
+        assert_eq!(frame.id, id, "{:?}", line);
+        assert_eq!(frame.kind, kind, "{:?}", line);
+        assert_eq!(frame.json, "{}", "{:?}", line);
+    }
+    let frame = decode_frame(&compressed_frame("z", "HEART_BEAT", "{}")).expect("frame");
+    assert_eq!(
+        (frame.id.as_str(), frame.kind.as_str()),
+        ("z", "HEART_BEAT")
+    );
+}
+
+#[test]
+fn ack_requests_are_told_apart_from_frames() {
+    assert_eq!(parse_ack_request("ACK_REQ:sms-1\r\n"), Some("sms-1"));
+    assert_eq!(parse_ack_request("\rACK_REQ:sms-2"), Some("sms-2"));
+    assert_eq!(parse_ack_request("ACK_REQ:a:b"), None);
+    assert_eq!(parse_ack_request("ACK_REQ:"), None);
+    assert_eq!(parse_ack_request("ACK_REQ:two words"), None);
+    assert_eq!(
+        parse_ack_request(&encode_frame("id", "ACK_REQ", "{}")),
+        None
+    );
+}
+
+#[test]
+fn line_break_left_in_front_is_skipped() {
+    // A device ending lines with "\n\r" leaves the "\r" in front of the next one
+    let line = format!("\r{}", encode_frame("id", "HEART_BEAT", "{}"));
+    let frame = decode_frame(&line).expect("frame");
+    assert_eq!(frame.id, "id");
+}
+
+#[test]
+fn invalid_utf8_payload_keeps_its_bytes() {
+    let raw = b"{\"id\":\"sms-1\",\"sender\":\"10086\",\"content\":\"bad \xc3\x28 byte\",\"received_at\":1700000000,\"metas\":null}";
+    let line = format!(
+        "sms-1:SMS_RECEIVED:{}\r\n",
+        base64::engine::general_purpose::STANDARD.encode(raw)
+    );
+    let frame = decode_frame(&line).expect("frame");
+    assert_eq!(frame.raw.as_deref(), Some(&raw[..]));
+
+    let message = parse_message(&line).expect("message");
+    let MessageType::SmsReceived(payload) = message.message_type else {
+        panic!("{:?}", message.message_type);
+    };
+    assert_eq!(payload.content, "bad \u{fffd}( byte");
+    assert_eq!(payload.raw_payload.as_deref(), Some(&raw[..]));
+}